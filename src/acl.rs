@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use ipv6::ip_to_key;
+
+#[derive(Deserialize, Clone)]
+pub struct AclConfig {
+    /// networks allowed to connect, e.g. ["10.0.0.0/8"], if present, all other networks are denied
+    pub allow: Option<Vec<String>>,
+    /// networks denied to connect, e.g. ["10.1.0.0/16"]
+    pub deny: Option<Vec<String>>,
+}
+
+/// Access control list for client addresses. The entry with the longest matching prefix decides,
+/// if no entry matches, clients are denied if there is an allow list, otherwise they are allowed.
+/// There is one hash map per prefix length, addresses are in the 128-bit flow key format (see ipv6::v4_to_key).
+pub struct Acl {
+    /// (prefix length, network -> allow), longest prefix first
+    prefixes: Vec<(u8, HashMap<u128, bool>)>,
+    default_allow: bool,
+}
+
+#[inline]
+fn mask(key: u128, prefix_len: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        key & (!0u128 << (128 - prefix_len as u32))
+    }
+}
+
+impl Acl {
+    pub fn new(config: &AclConfig) -> Acl {
+        let mut acl = Acl {
+            prefixes: Vec::new(),
+            default_allow: config.allow.as_ref().map_or(true, |allow| allow.is_empty()),
+        };
+        let lists = [(&config.allow, true), (&config.deny, false)];
+        for &(list, allow) in lists.iter() {
+            if let Some(list) = list {
+                for net in list {
+                    match net.parse::<IpNet>() {
+                        Ok(net) => acl.insert(&net, allow),
+                        Err(e) => error!("acl: invalid network {}: {}", net, e),
+                    }
+                }
+            }
+        }
+        acl.prefixes.sort_by(|a, b| b.0.cmp(&a.0));
+        acl
+    }
+
+    fn insert(&mut self, net: &IpNet, allow: bool) {
+        // IPv4 networks are IPv4-mapped
+        let (key, prefix_len) = match net {
+            IpNet::V4(net) => (ip_to_key(&IpAddr::V4(net.network())), 96 + net.prefix_len()),
+            IpNet::V6(net) => (ip_to_key(&IpAddr::V6(net.network())), net.prefix_len()),
+        };
+        let position = self.prefixes.iter().position(|(len, _)| *len == prefix_len);
+        let index = match position {
+            Some(index) => index,
+            None => {
+                self.prefixes.push((prefix_len, HashMap::new()));
+                self.prefixes.len() - 1
+            }
+        };
+        // deny wins, if a network is in both lists
+        let entry = self.prefixes[index].1.entry(mask(key, prefix_len)).or_insert(allow);
+        *entry = *entry && allow;
+    }
+
+    /// true, if the client address (flow key) may connect
+    #[inline]
+    pub fn permits(&self, client: u128) -> bool {
+        for (prefix_len, networks) in &self.prefixes {
+            if let Some(allow) = networks.get(&mask(client, *prefix_len)) {
+                return *allow;
+            }
+        }
+        self.default_allow
+    }
+}
@@ -0,0 +1,545 @@
+use std::net::{TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use netfcts::comm::PipelineId;
+use uuid::Uuid;
+
+use cmanager::{LiveConnection, ConnectionKey};
+use latency::LatencyHistogram;
+use protocols::Protocol;
+use reload::{request_reload, TargetEntry};
+use {Configuration, SharedState};
+
+/// time the pipelines get to report their connections, they report on their next timer tick
+const LISTING_WAIT_MS: u64 = 100;
+/// drain timeout, if none is configured
+const DEFAULT_DRAIN_TIMEOUT_MS: u64 = 30000;
+
+/// Collects the connections in use from all pipelines on request of the admin api.
+/// Pipelines compare the request number on each timer tick and report, when it has changed.
+#[derive(Clone)]
+pub struct ConnectionListing {
+    requested: Arc<AtomicUsize>,
+    reports: Arc<Mutex<HashMap<PipelineId, (usize, Vec<LiveConnection>)>>>,
+}
+
+impl ConnectionListing {
+    pub fn new() -> ConnectionListing {
+        ConnectionListing {
+            requested: Arc::new(AtomicUsize::new(0)),
+            reports: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[inline]
+    pub fn requested(&self) -> usize {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    pub fn report(&self, pipeline_id: &PipelineId, request: usize, connections: Vec<LiveConnection>) {
+        self.reports
+            .lock()
+            .unwrap()
+            .insert(pipeline_id.clone(), (request, connections));
+    }
+
+    /// requests a new listing and returns the reports of all pipelines, which answered within wait
+    fn collect(&self, wait: Duration) -> Vec<(PipelineId, Vec<LiveConnection>)> {
+        let request = self.requested.fetch_add(1, Ordering::AcqRel) + 1;
+        thread::sleep(wait);
+        self.reports
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (r, _))| *r == request)
+            .map(|(pipeline_id, (_, connections))| (pipeline_id.clone(), connections.clone()))
+            .collect()
+    }
+}
+
+/// a query of the ConnectionLookup, if kill is true, the owning pipeline kills the connection
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionQuery {
+    pub key: ConnectionKey,
+    pub kill: bool,
+}
+
+/// Looks up a connection by its uuid or client socket in all pipelines, e.g. on request of the control channel.
+/// As for the listing, the pipelines answer on their next timer tick, when the request number has changed.
+#[derive(Clone)]
+pub struct ConnectionLookup {
+    requested: Arc<AtomicUsize>,
+    query: Arc<Mutex<Option<(usize, ConnectionQuery)>>>,
+    answers: Arc<Mutex<HashMap<PipelineId, (usize, Option<LiveConnection>)>>>,
+}
+
+impl ConnectionLookup {
+    pub fn new() -> ConnectionLookup {
+        ConnectionLookup {
+            requested: Arc::new(AtomicUsize::new(0)),
+            query: Arc::new(Mutex::new(None)),
+            answers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[inline]
+    pub fn requested(&self) -> usize {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// the request number and the latest query
+    pub fn query(&self) -> Option<(usize, ConnectionQuery)> {
+        *self.query.lock().unwrap()
+    }
+
+    pub fn answer(&self, pipeline_id: &PipelineId, request: usize, connection: Option<LiveConnection>) {
+        self.answers
+            .lock()
+            .unwrap()
+            .insert(pipeline_id.clone(), (request, connection));
+    }
+
+    /// the pipeline and the connection for the key, None, if no pipeline found it within wait
+    pub fn find(&self, key: ConnectionKey, wait: Duration) -> Option<(PipelineId, LiveConnection)> {
+        self.request(ConnectionQuery { key, kill: false }, wait)
+    }
+
+    /// The owning pipeline sends RSTs on both legs, releases the connection and records an operator-initiated close.
+    /// Returns the pipeline and the connection as it was before, None, if no pipeline found it within wait.
+    pub fn kill(&self, key: ConnectionKey, wait: Duration) -> Option<(PipelineId, LiveConnection)> {
+        self.request(ConnectionQuery { key, kill: true }, wait)
+    }
+
+    fn request(&self, q: ConnectionQuery, wait: Duration) -> Option<(PipelineId, LiveConnection)> {
+        let request = {
+            let mut query = self.query.lock().unwrap();
+            let request = self.requested.fetch_add(1, Ordering::AcqRel) + 1;
+            *query = Some((request, q));
+            request
+        };
+        thread::sleep(wait);
+        self.answers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (r, c))| *r == request && c.is_some())
+            .map(|(pipeline_id, (_, c))| (pipeline_id.clone(), c.clone().unwrap()))
+            .next()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or("null".to_string(), |v| v.to_string())
+}
+
+fn connection_json(pipeline_id: &PipelineId, c: &LiveConnection, targets: &[TargetEntry]) -> String {
+    format!(
+        "{{\"pipeline\":{},\"uuid\":\"{}\",\"port\":{},\"source_ip\":\"{}\",\"client\":{},\"target\":{},\"client_state\":\"{:?}\",\"server_state\":\"{:?}\"}}",
+        json_string(&pipeline_id.to_string()),
+        c.uuid,
+        c.port,
+        c.source_ip,
+        json_option(c.client.map(|(ip, port)| json_string(&format!("{}:{}", ip, port)))),
+        json_option(
+            c.server_index
+                .and_then(|i| targets.get(i))
+                .map(|entry| json_string(&entry.config.id))
+        ),
+        c.client_state,
+        c.server_state,
+    )
+}
+
+struct Response {
+    status: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: String) -> Response {
+        Response { status: "200 OK", body }
+    }
+
+    fn accepted(body: String) -> Response {
+        Response {
+            status: "202 Accepted",
+            body,
+        }
+    }
+
+    fn error(status: &'static str, message: &str) -> Response {
+        Response {
+            status,
+            body: format!("{{\"error\":{}}}", json_string(message)),
+        }
+    }
+}
+
+/// the admin api, see spawn_admin_server
+struct Admin {
+    shared: SharedState,
+    max_per_target: Option<usize>,
+    drain_cycles: u64,
+}
+
+impl Admin {
+    fn targets(&self) -> Response {
+        let health = &self.shared.target_health;
+        let targets: Vec<String> = self
+            .shared
+            .targets
+            .targets()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.active)
+            .map(|(i, entry)| {
+                format!(
+                    "{{\"id\":{},\"ip\":\"{}\",\"port\":{},\"up\":{},\"disabled\":{},\"tripped\":{},\"fallback\":{},\"connections\":{},\"max_connections\":{}}}",
+                    json_string(&entry.config.id),
+                    entry.config.ip,
+                    entry.config.port,
+                    health.is_up(i),
+                    health.is_disabled(i),
+                    self.shared.breakers.is_open(i),
+                    entry.config.is_fallback(),
+                    self.shared.connections.target(i),
+                    json_option(entry.config.max_connections.map(|m| m as usize).or(self.max_per_target)),
+                )
+            }).collect();
+        Response::ok(format!("[{}]", targets.join(",")))
+    }
+
+    fn connections(&self) -> Response {
+        let targets = self.shared.targets.targets();
+        let mut connections = Vec::new();
+        for (pipeline_id, live) in self.shared.listing.collect(Duration::from_millis(LISTING_WAIT_MS)) {
+            for c in live {
+                connections.push(connection_json(&pipeline_id, &c, &targets));
+            }
+        }
+        Response::ok(format!("[{}]", connections.join(",")))
+    }
+
+    fn connection(&self, uuid: &str) -> Response {
+        let uuid = match Uuid::parse_str(uuid) {
+            Ok(uuid) => uuid,
+            Err(e) => return Response::error("400 Bad Request", &format!("invalid uuid {}: {}", uuid, e)),
+        };
+        match self
+            .shared
+            .lookup
+            .find(ConnectionKey::Uuid(uuid), Duration::from_millis(LISTING_WAIT_MS))
+        {
+            Some((pipeline_id, c)) => Response::ok(connection_json(&pipeline_id, &c, &self.shared.targets.targets())),
+            None => Response::error("404 Not Found", &format!("no connection {}", uuid)),
+        }
+    }
+
+    fn kill_connection(&self, uuid: &str) -> Response {
+        let uuid = match Uuid::parse_str(uuid) {
+            Ok(uuid) => uuid,
+            Err(e) => return Response::error("400 Bad Request", &format!("invalid uuid {}: {}", uuid, e)),
+        };
+        match self
+            .shared
+            .lookup
+            .kill(ConnectionKey::Uuid(uuid), Duration::from_millis(LISTING_WAIT_MS))
+        {
+            Some((pipeline_id, c)) => {
+                info!("admin api: killed connection {} on {}", uuid, pipeline_id);
+                Response::ok(connection_json(&pipeline_id, &c, &self.shared.targets.targets()))
+            }
+            None => Response::error("404 Not Found", &format!("no connection {}", uuid)),
+        }
+    }
+
+    fn stats(&self) -> Response {
+        let pipelines: Vec<String> = self
+            .shared
+            .stats
+            .snapshot()
+            .iter()
+            .map(|(pipeline_id, counters)| {
+                format!(
+                    "{{\"pipeline\":{},\"rx_packets\":{},\"tx_packets\":{},\"kni_packets\":{},\"dropped_packets\":{},\"active_connections\":{},\"wheel_occupancy\":{},\"wheel_overflows\":{},\"time_wait_slots\":{},\"checksum_offload\":{},\"syn_rate_limited\":{},\"syn_acl_denied\":{},\"paced_packets\":{},\"pacing_drops\":{},\"state_violations\":{},\"syn_retries\":{},\"reassembled_datagrams\":{},\"reassembly_pending\":{},\"fragment_drops\":{},\"fragmented_datagrams\":{},\"replicated_connections\":{},\"close_reasons\":{{{}}}}}",
+                    json_string(&pipeline_id.to_string()),
+                    counters.rx_packets,
+                    counters.tx_packets,
+                    counters.kni_packets,
+                    counters.dropped_packets,
+                    counters.active_connections,
+                    counters.wheel_occupancy,
+                    counters.wheel_overflows,
+                    counters.time_wait_slots,
+                    counters.checksum_offload,
+                    counters.syn_rate_limited,
+                    counters.syn_acl_denied,
+                    counters.paced_packets,
+                    counters.pacing_drops,
+                    counters.state_violations,
+                    counters.syn_retries,
+                    counters.reassembled_datagrams,
+                    counters.reassembly_pending,
+                    counters.fragment_drops,
+                    counters.fragmented_datagrams,
+                    counters.replicated_connections,
+                    counters
+                        .close_reasons
+                        .counts()
+                        .iter()
+                        .map(|(reason, count)| format!("\"{}\":{}", reason.name(), count))
+                        .collect::<Vec<String>>()
+                        .join(","),
+                )
+            }).collect();
+        Response::ok(format!("[{}]", pipelines.join(",")))
+    }
+
+    fn set_disabled(&self, id: &str, disabled: bool) -> Response {
+        let position = self
+            .shared
+            .targets
+            .targets()
+            .iter()
+            .position(|entry| entry.active && entry.config.id == id);
+        match position {
+            Some(i) => {
+                self.shared.target_health.set_disabled(i, disabled);
+                info!("admin api: target {} {}", id, if disabled { "disabled" } else { "enabled" });
+                Response::ok(format!("{{\"id\":{},\"disabled\":{}}}", json_string(id), disabled))
+            }
+            None => Response::error("404 Not Found", &format!("unknown target {}", id)),
+        }
+    }
+
+    fn latency(&self) -> Response {
+        fn histogram(h: &LatencyHistogram) -> String {
+            format!(
+                "{{\"count\":{},\"p50_ns\":{},\"p90_ns\":{},\"p99_ns\":{},\"p999_ns\":{},\"max_ns\":{}}}",
+                h.count(),
+                h.percentile(0.5),
+                h.percentile(0.9),
+                h.percentile(0.99),
+                h.percentile(0.999),
+                h.max()
+            )
+        }
+        let pipelines: Vec<String> = self
+            .shared
+            .latencies
+            .snapshot()
+            .iter()
+            .map(|(pipeline_id, latencies)| {
+                format!(
+                    "{{\"pipeline\":{},\"handshake\":{},\"binding\":{},\"forwarding\":{}}}",
+                    json_string(&pipeline_id.to_string()),
+                    histogram(&latencies.handshake),
+                    histogram(&latencies.binding),
+                    histogram(&latencies.forwarding),
+                )
+            }).collect();
+        Response::ok(format!("[{}]", pipelines.join(",")))
+    }
+
+    fn protocols(&self) -> Response {
+        let total = self.shared.protocols.total();
+        let targets = self.shared.targets.targets();
+        let mut entries = Vec::new();
+        for (i, target) in targets.iter().enumerate() {
+            for protocol in Protocol::all().iter() {
+                let count = total.get(i, *protocol);
+                if count.connections > 0 {
+                    entries.push(format!(
+                        "{{\"target\":{},\"protocol\":\"{}\",\"connections\":{},\"c2s_bytes\":{},\"s2c_bytes\":{}}}",
+                        json_string(&target.config.id),
+                        protocol.name(),
+                        count.connections,
+                        count.c2s_bytes,
+                        count.s2c_bytes,
+                    ));
+                }
+            }
+        }
+        Response::ok(format!("[{}]", entries.join(",")))
+    }
+
+    /// the queues from the pipelines to the exporters
+    fn channels(&self) -> Response {
+        let channels = [
+            ("events", self.shared.events.stats()),
+            ("ipfix", self.shared.flows.stats()),
+            ("sflow", self.shared.sflow.stats()),
+        ];
+        let entries: Vec<String> = channels
+            .iter()
+            .filter(|(_, stats)| stats.is_some())
+            .map(|(name, stats)| {
+                let stats = stats.unwrap();
+                format!(
+                    "{{\"channel\":\"{}\",\"capacity\":{},\"queued\":{},\"sent\":{},\"overflows\":{}}}",
+                    name, stats.capacity, stats.queued, stats.sent, stats.overflows,
+                )
+            }).collect();
+        Response::ok(format!("[{}]", entries.join(",")))
+    }
+
+    /// busy cycles of the scheduler threads in percent
+    fn cores(&self) -> Response {
+        let cores: Vec<String> = self
+            .shared
+            .stats
+            .cores()
+            .iter()
+            .map(|(core, load)| {
+                format!(
+                    "{{\"core\":{},\"utilization\":{:.1},\"average_utilization\":{:.1},\"busy_cycles\":{},\"idle_cycles\":{},\"rounds\":{},\"max_round_cycles\":{}}}",
+                    core,
+                    load.utilization,
+                    load.average_utilization(),
+                    load.busy_cycles,
+                    load.idle_cycles,
+                    load.rounds,
+                    load.max_round_cycles,
+                )
+            }).collect();
+        Response::ok(format!("[{}]", cores.join(",")))
+    }
+
+    fn groups(&self) -> Response {
+        let groups: Vec<String> = self
+            .shared
+            .groups
+            .groups(&self.shared.targets.targets())
+            .iter()
+            .map(|g| json_string(g))
+            .collect();
+        Response::ok(format!(
+            "{{\"active\":{},\"groups\":[{}]}}",
+            self.shared.groups.active().map_or("null".to_string(), |g| json_string(&g)),
+            groups.join(",")
+        ))
+    }
+
+    fn activate_group(&self, name: &str) -> Response {
+        match self
+            .shared
+            .groups
+            .switch(name, &self.shared.targets.targets(), &self.shared.target_health)
+        {
+            Ok(()) => {
+                info!("admin api: target group {} activated", name);
+                Response::ok(format!("{{\"active\":{}}}", json_string(name)))
+            }
+            Err(e) => Response::error("409 Conflict", &e),
+        }
+    }
+
+    fn handle(&self, method: &str, path: &str) -> Response {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            ("GET", ["targets"]) => self.targets(),
+            ("GET", ["connections"]) => self.connections(),
+            ("GET", ["connections", uuid]) => self.connection(uuid),
+            ("GET", ["stats"]) => self.stats(),
+            ("GET", ["groups"]) => self.groups(),
+            ("GET", ["latency"]) => self.latency(),
+            ("GET", ["protocols"]) => self.protocols(),
+            ("GET", ["channels"]) => self.channels(),
+            ("GET", ["cores"]) => self.cores(),
+            ("POST", ["connections", uuid, "kill"]) => self.kill_connection(uuid),
+            ("POST", ["groups", name, "activate"]) => self.activate_group(name),
+            ("POST", ["targets", id, "disable"]) => self.set_disabled(id, true),
+            ("POST", ["targets", id, "enable"]) => self.set_disabled(id, false),
+            ("POST", ["reload"]) => {
+                info!("admin api: reload requested");
+                request_reload();
+                Response::accepted("{\"reload\":\"requested\"}".to_string())
+            }
+            ("POST", ["drain"]) => {
+                if !self.shared.drain.is_draining() {
+                    info!("admin api: drain requested");
+                    self.shared.drain.start(self.drain_cycles);
+                }
+                Response::accepted("{\"drain\":\"started\"}".to_string())
+            }
+            _ => Response::error("404 Not Found", &format!("no resource {} {}", method, path)),
+        }
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        // skip the headers, requests have no body
+        let mut line = String::new();
+        while reader.read_line(&mut line)? > 0 && line.trim() != "" {
+            line.clear();
+        }
+        let words: Vec<&str> = request_line.split_whitespace().collect();
+        let response = if words.len() >= 2 {
+            self.handle(words[0], words[1])
+        } else {
+            Response::error("400 Bad Request", "invalid request line")
+        };
+        writer.write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response.status,
+                response.body.len(),
+                response.body
+            ).as_bytes(),
+        )
+    }
+}
+
+/// Starts the admin api: a small HTTP server, usually bound to the address of the KNI interface, with JSON responses.
+/// GET /targets, /connections, /stats, /latency, /protocols, /channels, /cores, /groups; POST /targets/<id>/disable,
+/// /targets/<id>/enable, /groups/<name>/activate, /reload, /drain.
+/// A drain terminates the engine like a SIGTERM, when all connections are closed or the drain timeout has passed.
+pub fn spawn_admin_server(
+    address: &str,
+    configuration: &Configuration,
+    shared: SharedState,
+    cpu_clock: u64,
+) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(address)?;
+    let admin = Admin {
+        shared,
+        max_per_target: configuration.engine.max_connections_per_target.map(|m| m as usize),
+        drain_cycles: configuration.engine.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT_MS) * cpu_clock / 1000,
+    };
+    info!("admin api listening on {}", address);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = admin.serve(stream) {
+                        debug!("admin api: {}", e);
+                    }
+                }
+                Err(e) => error!("admin api: accept failed: {}", e),
+            }
+        }
+    }))
+}
@@ -0,0 +1,67 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+
+/// number of independently locked shards of the table
+const SHARDS: usize = 64;
+const DEFAULT_TTL_SECONDS: u64 = 300;
+
+#[derive(Deserialize, Clone)]
+pub struct AffinityConfig {
+    /// seconds a client keeps its target after its last new connection, defaults to 300
+    pub ttl: Option<u64>,
+}
+
+impl AffinityConfig {
+    #[inline]
+    pub fn ttl_cycles(&self, cpu_clock: u64) -> u64 {
+        self.ttl.unwrap_or(DEFAULT_TTL_SECONDS) * cpu_clock
+    }
+}
+
+/// Client address (flow key) -> target index with expiry (tsc), shared by all pipelines.
+/// The table is sharded by the client address, so that pipelines rarely contend for a lock.
+#[derive(Clone)]
+pub struct AffinityTable(Arc<Vec<Mutex<HashMap<u128, (usize, u64)>>>>);
+
+impl AffinityTable {
+    pub fn new() -> AffinityTable {
+        AffinityTable(Arc::new((0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect()))
+    }
+
+    #[inline]
+    fn shard(&self, client: u128) -> &Mutex<HashMap<u128, (usize, u64)>> {
+        let mut hasher = FnvHasher::default();
+        hasher.write_u128(client);
+        &self.0[(hasher.finish() % SHARDS as u64) as usize]
+    }
+
+    /// the target of the client, if the entry has not expired
+    #[inline]
+    pub fn lookup(&self, client: u128, now: u64) -> Option<usize> {
+        match self.shard(client).lock().unwrap().get(&client) {
+            Some(&(target, expiry)) if expiry > now => Some(target),
+            _ => None,
+        }
+    }
+
+    /// binds the client to the target or refreshes the binding
+    #[inline]
+    pub fn bind(&self, client: u128, target: usize, expiry: u64) {
+        self.shard(client).lock().unwrap().insert(client, (target, expiry));
+    }
+
+    /// removes the expired entries of one shard, pipelines call this periodically for shard = 0, 1, ...
+    pub fn expire(&self, shard: usize, now: u64) -> usize {
+        let mut map = self.0[shard % SHARDS].lock().unwrap();
+        let before = map.len();
+        map.retain(|_, &mut (_, expiry)| expiry > now);
+        before - map.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|shard| shard.lock().unwrap().len()).sum()
+    }
+}
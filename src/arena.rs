@@ -0,0 +1,53 @@
+/// Boxes of per-connection state, which are allocated when the pipeline is set up and recycled, when a connection
+/// releases its state, so that connections do not allocate heap memory in the data path. When the arena is empty,
+/// a box is allocated and counted as a miss, it is kept by the arena after its release.
+pub struct BoxArena<T> {
+    free: Vec<Box<T>>,
+    misses: u64,
+}
+
+impl<T> BoxArena<T> {
+    pub fn new() -> BoxArena<T> {
+        BoxArena {
+            free: Vec::new(),
+            misses: 0,
+        }
+    }
+
+    /// pre-allocates capacity boxes, initialized by f
+    pub fn with_capacity<F: Fn() -> T>(capacity: usize, f: F) -> BoxArena<T> {
+        let mut free = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            free.push(Box::new(f()));
+        }
+        BoxArena { free, misses: 0 }
+    }
+
+    /// a recycled box with the state of its previous user, which must be initialized by the caller
+    #[inline]
+    pub fn alloc<F: FnOnce() -> T>(&mut self, f: F) -> Box<T> {
+        match self.free.pop() {
+            Some(b) => b,
+            None => {
+                self.misses += 1;
+                Box::new(f())
+            }
+        }
+    }
+
+    #[inline]
+    pub fn free(&mut self, b: Box<T>) {
+        self.free.push(b);
+    }
+
+    #[inline]
+    pub fn available(&self) -> usize {
+        self.free.len()
+    }
+
+    /// boxes allocated, because the arena was empty
+    #[inline]
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+}
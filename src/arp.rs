@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use eui48::MacAddress;
+
+pub const ARP_ETYPE: u16 = 0x0806;
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+/// size of an ARP message for Ethernet and IPv4
+pub const ARP_SIZE: usize = 28;
+
+const DEFAULT_REFRESH_MS: u64 = 60000;
+const DEFAULT_RETRY_MS: u64 = 1000;
+
+/// Resolution of the MAC addresses of targets, which have neither mac nor linux_if, and of the gateway (see GatewayConfig)
+/// by ARP requests of the engine.
+/// The pipeline of the first rx queue of a port sends the requests and learns the MAC addresses from the ARP messages
+/// of the targets, which are also passed to the KNI interface. Only IPv4 targets are resolved.
+#[derive(Deserialize, Clone)]
+pub struct ArpConfig {
+    /// milli-seconds until a resolved MAC address is requested again, defaults to 60000
+    pub refresh: Option<u64>,
+    /// milli-seconds until an unanswered request is repeated, defaults to 1000
+    pub retry: Option<u64>,
+}
+
+/// an ARP message for Ethernet and IPv4
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArpMessage {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: u32,
+    pub target_ip: u32,
+}
+
+#[inline]
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+#[inline]
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+/// parses the payload of an ARP frame, None if it is not an ARP message for Ethernet and IPv4
+pub fn parse_arp(payload: &[u8]) -> Option<ArpMessage> {
+    if payload.len() < ARP_SIZE
+        || be_u16(&payload[0..2]) != 1
+        || be_u16(&payload[2..4]) != 0x0800
+        || payload[4] != 6
+        || payload[5] != 4
+    {
+        return None;
+    }
+    Some(ArpMessage {
+        operation: be_u16(&payload[6..8]),
+        sender_mac: MacAddress::from_bytes(&payload[8..14]).ok()?,
+        sender_ip: be_u32(&payload[14..18]),
+        target_ip: be_u32(&payload[24..28]),
+    })
+}
+
+/// the payload of an ARP request of the engine for target_ip
+pub fn arp_request_bytes(sender_mac: &MacAddress, sender_ip: u32, target_ip: u32) -> [u8; ARP_SIZE] {
+    let mut bytes = [0u8; ARP_SIZE];
+    bytes[0..8].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, ARP_REQUEST as u8]);
+    bytes[8..14].copy_from_slice(sender_mac.as_bytes());
+    for i in 0..4 {
+        bytes[14 + i] = (sender_ip >> (24 - 8 * i)) as u8;
+        bytes[24 + i] = (target_ip >> (24 - 8 * i)) as u8;
+    }
+    bytes
+}
+
+/// the payload of the ARP reply of the engine with sender_mac to the request
+pub fn arp_reply_bytes(sender_mac: &MacAddress, request: &ArpMessage) -> [u8; ARP_SIZE] {
+    let mut bytes = [0u8; ARP_SIZE];
+    bytes[0..8].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, ARP_REPLY as u8]);
+    bytes[8..14].copy_from_slice(sender_mac.as_bytes());
+    bytes[18..24].copy_from_slice(request.sender_mac.as_bytes());
+    for i in 0..4 {
+        bytes[14 + i] = (request.target_ip >> (24 - 8 * i)) as u8;
+        bytes[24 + i] = (request.sender_ip >> (24 - 8 * i)) as u8;
+    }
+    bytes
+}
+
+/// ARP state of a pipeline: when the addresses have been requested and resolved
+pub struct ArpResolver {
+    refresh_cycles: u64,
+    retry_cycles: u64,
+    requested: HashMap<u32, u64>,
+    resolved: HashMap<u32, u64>,
+}
+
+impl ArpResolver {
+    pub fn new(config: &ArpConfig, cpu_clock: u64) -> ArpResolver {
+        ArpResolver {
+            refresh_cycles: config.refresh.unwrap_or(DEFAULT_REFRESH_MS) * cpu_clock / 1000,
+            retry_cycles: config.retry.unwrap_or(DEFAULT_RETRY_MS) * cpu_clock / 1000,
+            requested: HashMap::new(),
+            resolved: HashMap::new(),
+        }
+    }
+
+    /// the addresses of ips, which must be requested now, they are marked as requested
+    pub fn due(&mut self, ips: &[u32], now: u64) -> Vec<u32> {
+        let mut due = Vec::new();
+        for ip in ips {
+            let fresh = self.resolved.get(ip).map_or(false, |t| now - *t < self.refresh_cycles);
+            let pending = self.requested.get(ip).map_or(false, |t| now - *t < self.retry_cycles);
+            if !fresh && !pending {
+                self.requested.insert(*ip, now);
+                due.push(*ip);
+            }
+        }
+        due
+    }
+
+    /// Learns the MAC address of the sender of an ARP message, if its address is resolved by the engine.
+    /// Returns the resolved address, the caller updates the target table
+    pub fn received(&mut self, message: &ArpMessage, ips: &[u32], now: u64) -> Option<(u32, MacAddress)> {
+        if message.sender_ip != 0 && ips.contains(&message.sender_ip) {
+            self.resolved.insert(message.sender_ip, now);
+            self.requested.remove(&message.sender_ip);
+            Some((message.sender_ip, message.sender_mac))
+        } else {
+            None
+        }
+    }
+}
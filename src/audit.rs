@@ -0,0 +1,71 @@
+use std::fmt;
+
+use netfcts::tcp_common::TcpState;
+
+/// the leg of a connection, whose state changed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Leg {
+    Client,
+    Server,
+}
+
+/// A state transition of a connection, which is not in the transition table of its leg.
+#[derive(Clone, Copy, Debug)]
+pub struct StateViolation {
+    pub leg: Leg,
+    pub from: TcpState,
+    pub to: TcpState,
+}
+
+impl fmt::Display for StateViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} leg {:?} -> {:?}", self.leg, self.from, self.to)
+    }
+}
+
+/// The transitions of the client leg, as seen by the proxy, which answers the SYN of the client itself.
+/// A leg may go to Closed from any state (RST, timeouts, shutdown) and may stay in its state.
+fn client_transition(from: TcpState, to: TcpState) -> bool {
+    match (from, to) {
+        (TcpState::Closed, TcpState::SynSent) => true,
+        (TcpState::SynSent, TcpState::Established) | (TcpState::SynSent, TcpState::FinWait1) => true,
+        (TcpState::Established, TcpState::FinWait1)
+        | (TcpState::Established, TcpState::CloseWait)
+        | (TcpState::Established, TcpState::LastAck)
+        | (TcpState::Established, TcpState::Closing) => true,
+        (TcpState::FinWait1, TcpState::FinWait2) | (TcpState::FinWait1, TcpState::Closing) | (TcpState::FinWait1, TcpState::LastAck) => true,
+        (TcpState::CloseWait, TcpState::LastAck) | (TcpState::CloseWait, TcpState::Closing) => true,
+        _ => false,
+    }
+}
+
+/// The transitions of the server leg, which starts in Listen and is opened by the proxy after the server selection.
+/// The server leg goes to LastAck without being established, when the client closes before.
+fn server_transition(from: TcpState, to: TcpState) -> bool {
+    match (from, to) {
+        (TcpState::Listen, TcpState::SynReceived) | (TcpState::Listen, TcpState::LastAck) => true,
+        (TcpState::SynReceived, TcpState::Established) | (TcpState::SynReceived, TcpState::LastAck) => true,
+        (TcpState::Established, TcpState::FinWait1)
+        | (TcpState::Established, TcpState::LastAck)
+        | (TcpState::Established, TcpState::Closing) => true,
+        (TcpState::FinWait1, TcpState::FinWait2) | (TcpState::FinWait1, TcpState::Closing) | (TcpState::FinWait1, TcpState::LastAck) => true,
+        (TcpState::CloseWait, TcpState::LastAck) => true,
+        _ => false,
+    }
+}
+
+/// true, if the transition is in the table of the leg
+pub fn allowed_transition(leg: Leg, from: TcpState, to: TcpState) -> bool {
+    if from == to || to == TcpState::Closed {
+        return true;
+    }
+    match leg {
+        Leg::Client => client_transition(from, to),
+        Leg::Server => server_transition(from, to),
+    }
+}
+
+/// true, if the state transitions are audited, see EngineConfig.state_audit, by default in debug builds
+pub fn state_audit_enabled(state_audit: Option<bool>) -> bool {
+    state_audit.unwrap_or(cfg!(debug_assertions))
+}
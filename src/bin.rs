@@ -1,6 +1,4 @@
-extern crate ctrlc;
 extern crate e2d2;
-extern crate env_logger;
 // Logging
 #[macro_use]
 extern crate log;
@@ -14,40 +12,34 @@ extern crate netfcts;
 extern crate ipnet;
 extern crate separator;
 
-use e2d2::interface::PmdPort;
-use e2d2::scheduler::StandaloneScheduler;
-use e2d2::native::zcsi::mbuf_avail_count;
-
 use std::collections::{HashMap};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::RecvTimeoutError;
-use std::thread;
-use std::time::Duration;
 use std::convert::From;
 use std::io::{BufWriter, Write};
 use std::fs::File;
 use std::mem;
+use std::env;
+use std::process;
 
 use separator::Separatable;
 
-use netfcts::tcp_common::{ReleaseCause, CData, L234Data, TcpState};
-use netfcts::comm::{MessageFrom, MessageTo, PipelineId};
-use netfcts::io::{ print_tcp_counters };
-#[cfg(feature = "profiling")]
-use netfcts::io::print_rx_tx_counters;
-use netfcts::system::get_mac_from_ifname;
+use netfcts::tcp_common::{ReleaseCause, CData, TcpState};
+use netfcts::comm::PipelineId;
 use netfcts::recstore::Store64;
 use netfcts::conrecord::{HasTcpState, HasConData, ConRecord};
-use netfcts::RunTime;
 
-use tcp_proxy::setup_pipes_delayed_proxy;
-use tcp_proxy::{ProxyConnection, Extension, ProxyMode, Configuration};
+use tcp_proxy::{ProxyEngineBuilder, init_logger};
+#[cfg(feature = "alloc_audit")]
+use tcp_proxy::CountingAllocator;
+use tcp_proxy::{ProxyConnection, Extension};
+
+#[cfg(feature = "alloc_audit")]
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
-fn write_and_evaluate_records(con_records: &mut HashMap<PipelineId, Store64<Extension>>) {
+fn write_and_evaluate_records(con_records: &mut HashMap<PipelineId, Vec<Store64<Extension>>>) {
     let mut completed_count_c = 0;
     let mut completed_count_s = 0;
-    for con_recs in con_records.values() {
+    for con_recs in con_records.values().flat_map(|stores| stores.iter()) {
         for c in con_recs.iter_0() {
             if (c.release_cause() == ReleaseCause::PassiveClose || c.release_cause() == ReleaseCause::ActiveClose)
                 && c.last_state() == TcpState::Closed
@@ -73,7 +65,7 @@ fn write_and_evaluate_records(con_records: &mut HashMap<PipelineId, Store64<Exte
     };
     let mut f = BufWriter::new(file);
 
-    for (p, c_records) in con_records {
+    for (p, c_records) in con_records.iter_mut().flat_map(|(p, stores)| stores.iter_mut().map(move |s| (p.clone(), s))) {
         info!("Pipeline {}:", p);
         f.write_all(format!("Pipeline {}:\n", p).as_bytes())
             .expect("cannot write c_records");
@@ -116,45 +108,23 @@ fn write_and_evaluate_records(con_records: &mut HashMap<PipelineId, Store64<Exte
 }
 
 pub fn main() {
-    env_logger::init();
+    init_logger();
 
-    let mut run_time: RunTime<Configuration, Store64<Extension>> = match RunTime::init() {
-        Ok(run_time) => run_time,
-        Err(err) => panic!("failed to initialize RunTime {}", err),
+    let builder = match ProxyEngineBuilder::new() {
+        Ok(builder) => builder,
+        Err(err) => panic!("{}", err),
     };
+    if env::args().skip_while(|arg| arg != "--").any(|arg| arg == "--check") {
+        let report = builder.preflight();
+        println!("{}", report);
+        process::exit(if report.passed() { 0 } else { 1 });
+    }
     info!("Starting ProxyEngine ..");
+    let detailed_records = builder.configuration().engine.detailed_records.unwrap_or(false);
+    let load_test = builder.configuration().engine.load_test.is_some();
 
-    // setup flowdirector for physical ports:
-    run_time.setup_flowdirector().expect("failed to setup flowdirector");
-
-    let run_configuration = run_time.run_configuration.clone();
-    let configuration = &run_configuration.engine_configuration;
-
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        info!("received SIGINT or SIGTERM");
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("error setting Ctrl-C handler");
-
-    let l234data: Vec<L234Data> = run_configuration
-        .engine_configuration
-        .targets
-        .iter()
-        .enumerate()
-        .map(|(i, srv_cfg)| L234Data {
-            mac: srv_cfg
-                .mac
-                .unwrap_or_else(|| get_mac_from_ifname(srv_cfg.linux_if.as_ref().unwrap()).unwrap()),
-            ip: u32::from(srv_cfg.ip),
-            port: srv_cfg.port,
-            server_id: srv_cfg.id.clone(),
-            index: i,
-        })
-        .collect();
-
-    let l234data_clone = l234data.clone();
+    // the targets are looked up under the read lock of the table on each call, so that reloaded targets are selected
+    let targets = builder.shared().targets.clone();
     // this is the closure, which selects the target server to use for a new TCP connection
     let f_by_payload = move |c: &mut ProxyConnection| {
         //let cdata: CData = serde_json::from_slice(&c.payload).expect("cannot deserialize CData");
@@ -162,129 +132,50 @@ pub fn main() {
         let cdata: CData = bincode::deserialize::<CData>(c.payload_packet.as_ref().unwrap().get_payload(2))
             .expect("cannot deserialize CData");
         //inf   o!("cdata = {:?}", cdata);
-        for (i, l234) in l234data_clone.iter().enumerate() {
-            if l234.port == cdata.reply_socket.port() && l234.ip == u32::from(*cdata.reply_socket.ip()) {
-                c.set_server_index(i as u8);
-                break;
-            }
+        let reply_ip = u32::from(*cdata.reply_socket.ip());
+        let index = targets.with_targets(|targets| {
+            targets
+                .iter()
+                .position(|t| t.active && t.l234.port == cdata.reply_socket.port() && t.l234.ip == reply_ip)
+        });
+        if index.is_some() {
+            c.set_server_index(index.unwrap() as u8);
         }
     };
 
-    let no_servers = l234data.len();
-    let mut last_server: u8 = 0;
-    let _f_round_robbin = move |c: &mut ProxyConnection| {
-        if (last_server as usize) < no_servers - 1 {
-            last_server += 1;
-        } else {
-            last_server = 0;
-        }
-        c.set_server_index(last_server);
-    };
+    // note: omit with_selector to use the built-in selection policy, e.g. engine.selection = "round_robin"
 
     // this is the closure, which may modify the payload of client to server packets in a TCP connection
     let f_process_payload_c_s = |_c: &mut ProxyConnection, _payload: &mut [u8], _tailroom: usize| {};
 
-    run_time.start_schedulers().expect("cannot start schedulers");
-
-    if *run_configuration
-        .engine_configuration
-        .engine
-        .mode
-        .as_ref()
-        .unwrap_or(&ProxyMode::Delayed)
-        == ProxyMode::Delayed
-    {
-        let run_configuration_cloned = run_configuration.clone();
-        run_time
-            .install_pipeline_on_cores(Box::new(
-                move |core: i32, pmd_ports: HashMap<String, Arc<PmdPort>>, s: &mut StandaloneScheduler| {
-                    setup_pipes_delayed_proxy(
-                        core,
-                        pmd_ports,
-                        s,
-                        run_configuration_cloned.clone(),
-                        l234data.clone(),
-                        f_by_payload.clone(),
-                        f_process_payload_c_s.clone(),
-                    );
-                },
-            ))
-            .expect("cannot install pipelines");
-    } else {
-        // simple proxy
-        error!("simple proxy still not implemented");
-    }
-
-    let cores = run_time.context().unwrap().active_cores.clone();
-
-    // start the run_time receive thread
-    run_time.start();
-
-    let (mtx, reply_mrx) = run_time.get_main_channel().expect("cannot get main channel");
-    mtx.send(MessageFrom::StartEngine).unwrap();
-    thread::sleep(Duration::from_millis(2000 as u64));
-
     debug!(
         "Connection record sizes = {} + {} + {}",
         mem::size_of::<ProxyConnection>(),
         mem::size_of::<ConRecord>(),
         mem::size_of::<Extension>()
     );
-
-    //main loop
     println!("press ctrl-c to terminate proxy ...");
-    let mut loops: usize = 300;
-    while running.load(Ordering::SeqCst) {
-        if loops == 300 {
-            loops = 0;
-            info!("available mbufs in memory pool= {:6}", unsafe { mbuf_avail_count() });
-        }
-        thread::sleep(Duration::from_millis(200 as u64)); // Sleep for a bit
-        loops += 1;
-    }
-
-    println!("\nTask Performance Data:\n");
-    mtx.send(MessageFrom::PrintPerformance(cores)).unwrap();
-    thread::sleep(Duration::from_millis(1000 as u64));
 
-    mtx.send(MessageFrom::FetchCounter).unwrap();
-    if configuration.engine.detailed_records.unwrap_or(false) {
-        mtx.send(MessageFrom::FetchCRecords).unwrap();
-    }
-
-    let mut tcp_counters_c = HashMap::new();
-    let mut tcp_counters_s = HashMap::new();
-    let mut con_records = HashMap::new();
-
-    loop {
-        match reply_mrx.recv_timeout(Duration::from_millis(1000)) {
-            Ok(MessageTo::Counter(pipeline_id, tcp_counter_c, tcp_counter_s, _rx_tx_stats)) => {
-                print_tcp_counters(&pipeline_id, &tcp_counter_c, &tcp_counter_s);
-                //#[cfg(feature = "profiling")]
-                //print_rx_tx_counters(&pipeline_id, &_rx_tx_stats.unwrap());
-                tcp_counters_c.insert(pipeline_id.clone(), tcp_counter_c);
-                tcp_counters_s.insert(pipeline_id, tcp_counter_s);
-            }
-            Ok(MessageTo::CRecords(pipeline_id, Some(recv_con_records), _)) => {
-                debug!("{}: received {} CRecords", pipeline_id, recv_con_records.len(),);
-                con_records.insert(pipeline_id, recv_con_records);
-            }
-            Ok(_m) => error!("illegal MessageTo received from reply_to_main channel"),
-            Err(RecvTimeoutError::Timeout) => {
-                break;
-            }
-            Err(e) => {
-                error!("error receiving from reply_to_main channel (reply_mrx): {}", e);
-                break;
-            }
+    // the synthetic payload of a load test cannot be deserialized, the selection policy selects the targets
+    let result = if load_test {
+        builder.with_payload_hook(f_process_payload_c_s).run()
+    } else {
+        builder
+            .with_selector(f_by_payload)
+            .with_payload_hook(f_process_payload_c_s)
+            .run()
+    };
+    let mut summary = match result {
+        Ok(summary) => summary,
+        Err(err) => {
+            error!("{}", err);
+            std::process::exit(1);
         }
-    }
+    };
 
-    if configuration.engine.detailed_records.unwrap_or(false) {
-        write_and_evaluate_records(&mut con_records);
+    if detailed_records {
+        write_and_evaluate_records(&mut summary.con_records);
     }
-    mtx.send(MessageFrom::Exit).unwrap();
-    thread::sleep(Duration::from_millis(200 as u64)); // give threads some time to process Exit
     info!("terminating ProxyEngine ...");
     std::process::exit(0);
 }
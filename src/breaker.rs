@@ -0,0 +1,166 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicU32, AtomicU64, Ordering};
+use std::time::Instant;
+
+use health::MAX_TARGETS;
+
+const DEFAULT_MIN_CONNECTIONS: u32 = 20;
+const DEFAULT_WINDOW_MS: u64 = 10000;
+const DEFAULT_COOLDOWN_MS: u64 = 30000;
+const DEFAULT_PROBES: u32 = 1;
+
+const CLOSED: u8 = 0;
+const OPEN: u8 = 1;
+const HALF_OPEN: u8 = 2;
+
+/// A target trips out of rotation, when the ratio of failed connections in the current window exceeds failure_ratio.
+/// Failures are RSTs of the target and connections, which time out before the SYN-ACK of the target.
+/// After the cooldown the target is half-open: it gets probes connections, if they succeed it is closed again,
+/// otherwise it trips again.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CircuitBreakerConfig {
+    /// e.g. 0.5
+    pub failure_ratio: f64,
+    /// minimum number of connections in the window before the target may trip, defaults to 20
+    pub min_connections: Option<u32>,
+    /// milli-seconds, after which the counts of the window are reset, defaults to 10000
+    pub window: Option<u64>,
+    /// milli-seconds a tripped target stays out of rotation, defaults to 30000
+    pub cooldown: Option<u64>,
+    /// number of connections to a half-open target, defaults to 1
+    pub probes: Option<u32>,
+}
+
+/// state of the breaker of one target
+struct Breaker {
+    state: AtomicU8,
+    /// milli-seconds since the start of the breakers, when the window started or the breaker tripped
+    since: AtomicU64,
+    successes: AtomicU32,
+    failures: AtomicU32,
+    /// connections to the half-open target
+    probes: AtomicU32,
+}
+
+/// The circuit breakers of all targets, shared by all pipelines.
+#[derive(Clone)]
+pub struct CircuitBreakers {
+    start: Instant,
+    breakers: Arc<Vec<Breaker>>,
+}
+
+impl CircuitBreakers {
+    pub fn new() -> CircuitBreakers {
+        CircuitBreakers {
+            start: Instant::now(),
+            breakers: Arc::new(
+                (0..MAX_TARGETS)
+                    .map(|_| Breaker {
+                        state: AtomicU8::new(CLOSED),
+                        since: AtomicU64::new(0),
+                        successes: AtomicU32::new(0),
+                        failures: AtomicU32::new(0),
+                        probes: AtomicU32::new(0),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[inline]
+    fn now(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
+
+    /// true, if the breaker of the target is open, i.e. the target is out of rotation
+    #[inline]
+    pub fn is_open(&self, target: usize) -> bool {
+        self.breakers[target].state.load(Ordering::Relaxed) == OPEN
+    }
+
+    /// true, if the target may get a new connection
+    #[inline]
+    pub fn allows(&self, target: usize, config: &CircuitBreakerConfig) -> bool {
+        let b = &self.breakers[target];
+        match b.state.load(Ordering::Acquire) {
+            OPEN => self.now() >= b.since.load(Ordering::Relaxed) + config.cooldown.unwrap_or(DEFAULT_COOLDOWN_MS),
+            HALF_OPEN => b.probes.load(Ordering::Relaxed) < config.probes.unwrap_or(DEFAULT_PROBES),
+            _ => true,
+        }
+    }
+
+    /// the target got a connection, a tripped target becomes half-open after its cooldown
+    pub fn bound(&self, target: usize, config: &CircuitBreakerConfig) {
+        let b = &self.breakers[target];
+        if b.state.load(Ordering::Acquire) == OPEN && self.allows(target, config) {
+            if b.state.compare_exchange(OPEN, HALF_OPEN, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                b.probes.store(0, Ordering::Relaxed);
+                info!("circuit breaker of target {} is half-open", target);
+            }
+        }
+        if b.state.load(Ordering::Acquire) == HALF_OPEN {
+            b.probes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// resets the counts, if the window has passed
+    #[inline]
+    fn roll_window(&self, b: &Breaker, config: &CircuitBreakerConfig) {
+        let now = self.now();
+        if now >= b.since.load(Ordering::Relaxed) + config.window.unwrap_or(DEFAULT_WINDOW_MS) {
+            b.since.store(now, Ordering::Relaxed);
+            b.successes.store(0, Ordering::Relaxed);
+            b.failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// the target answered the SYN
+    pub fn success(&self, target: usize, config: &CircuitBreakerConfig) {
+        let b = &self.breakers[target];
+        match b.state.load(Ordering::Acquire) {
+            HALF_OPEN => {
+                if b.state.compare_exchange(HALF_OPEN, CLOSED, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    b.since.store(self.now(), Ordering::Relaxed);
+                    b.successes.store(0, Ordering::Relaxed);
+                    b.failures.store(0, Ordering::Relaxed);
+                    info!("circuit breaker of target {} is closed", target);
+                }
+            }
+            CLOSED => {
+                self.roll_window(b, config);
+                b.successes.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {}
+        }
+    }
+
+    /// the target reset a connection or did not answer its SYN
+    pub fn failure(&self, target: usize, config: &CircuitBreakerConfig) {
+        let b = &self.breakers[target];
+        match b.state.load(Ordering::Acquire) {
+            HALF_OPEN => {
+                if b.state.compare_exchange(HALF_OPEN, OPEN, Ordering::AcqRel, Ordering::Acquire).is_ok() {
+                    b.since.store(self.now(), Ordering::Relaxed);
+                    warn!("circuit breaker of target {} tripped again", target);
+                }
+            }
+            CLOSED => {
+                self.roll_window(b, config);
+                let failures = b.failures.fetch_add(1, Ordering::Relaxed) as u64 + 1;
+                let total = failures + b.successes.load(Ordering::Relaxed) as u64;
+                if total >= config.min_connections.unwrap_or(DEFAULT_MIN_CONNECTIONS) as u64
+                    && failures as f64 > config.failure_ratio * total as f64
+                    && b.state.compare_exchange(CLOSED, OPEN, Ordering::AcqRel, Ordering::Acquire).is_ok()
+                {
+                    b.since.store(self.now(), Ordering::Relaxed);
+                    warn!(
+                        "circuit breaker of target {} tripped, {} of {} connections failed",
+                        target, failures, total
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+}
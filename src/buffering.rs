@@ -0,0 +1,88 @@
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+
+use cmanager::ProxyConnection;
+use proxy_protocol::insert_into_payload;
+use sni::client_hello_incomplete;
+use http::http_header_incomplete;
+use h2::http2_request_incomplete;
+
+const DEFAULT_MAX_BYTES: usize = 4096;
+const DEFAULT_MAX_SEGMENTS: usize = 4;
+
+/// Buffering of the first client segments before the server is selected, so that a TLS ClientHello or a HTTP request
+/// header, which the client splits into several segments, can be routed by the sni_map, the alpn_map, the http_routes
+/// or the selection closure. The buffered payload is merged into the mbuf of the last segment, i.e. its size is also
+/// bounded by the tailroom of the mbuf; if it does not fit, the client connection is reset.
+#[derive(Deserialize, Clone)]
+pub struct PayloadBuffering {
+    /// defaults to 4096
+    pub max_bytes: Option<usize>,
+    /// defaults to 4
+    pub max_segments: Option<usize>,
+}
+
+impl PayloadBuffering {
+    /// true, if the payload is incomplete and the limits allow to wait for another segment
+    fn wait_for_more(&self, payload: &[u8], segments: usize) -> bool {
+        segments < self.max_segments.unwrap_or(DEFAULT_MAX_SEGMENTS)
+            && payload.len() < self.max_bytes.unwrap_or(DEFAULT_MAX_BYTES)
+            && (client_hello_incomplete(payload)
+                || http_header_incomplete(payload)
+                || http2_request_incomplete(payload))
+    }
+}
+
+/// payload of client segments, which has been received before the server is selected
+pub struct BufferedPayload {
+    /// seqn of the first byte
+    seqn: u32,
+    bytes: Vec<u8>,
+    segments: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferResult {
+    /// the segment has been buffered or is a retransmission, it must be dropped
+    Buffered,
+    /// the buffered payload has been merged into the segment, which can be used to select the server
+    Ready,
+    /// the buffered payload does not fit into the segment
+    Overflow,
+}
+
+/// Buffers the payload of a client segment, which is received before the server is selected.
+/// Segments, which are not in sequence, are dropped; the client retransmits them.
+pub fn buffer_payload(p: &mut Pdu, c: &mut ProxyConnection, config: &PayloadBuffering) -> BufferResult {
+    let seqn = p.headers().tcp(2).seq_num();
+    let payload_sz = tcp_payload_size(p);
+    if c.buffered_payload.is_none() {
+        if payload_sz == 0 || !config.wait_for_more(&p.get_payload(2)[..payload_sz], 1) {
+            return BufferResult::Ready;
+        }
+        c.buffered_payload = Some(Box::new(BufferedPayload {
+            seqn,
+            bytes: p.get_payload(2)[..payload_sz].to_vec(),
+            segments: 1,
+        }));
+        return BufferResult::Buffered;
+    }
+    let complete = {
+        let buffered = c.buffered_payload.as_mut().unwrap();
+        if payload_sz == 0 || seqn != buffered.seqn.wrapping_add(buffered.bytes.len() as u32) {
+            return BufferResult::Buffered;
+        }
+        buffered.bytes.extend_from_slice(&p.get_payload(2)[..payload_sz]);
+        buffered.segments += 1;
+        !config.wait_for_more(&buffered.bytes, buffered.segments)
+    };
+    if !complete {
+        return BufferResult::Buffered;
+    }
+    let buffered = c.buffered_payload.take().unwrap();
+    if !insert_into_payload(p, &buffered.bytes[..buffered.bytes.len() - payload_sz]) {
+        return BufferResult::Overflow;
+    }
+    p.headers_mut().tcp_mut(2).set_seq_num(buffered.seqn);
+    BufferResult::Ready
+}
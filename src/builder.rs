@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+use std::env;
+
+use e2d2::interface::PmdPort;
+use e2d2::scheduler::StandaloneScheduler;
+use e2d2::native::zcsi::mbuf_avail_count;
+
+use netfcts::comm::{MessageFrom, MessageTo, PipelineId};
+use netfcts::io::print_tcp_counters;
+use netfcts::recstore::Store64;
+use netfcts::tcp_common::TcpCounter;
+use netfcts::{RunTime, RunConfiguration};
+
+use cmanager::{ProxyConnection, Extension};
+use loadgen::{LoadGenerator, LoadReport};
+use overrides::{overrides_from_env, overrides_from_args};
+use preflight::{check_configuration, check_ports, check_targets, check_pipelines, CheckStatus, PreflightReport};
+use resolver::resolve_targets;
+use spans::init_tracing;
+use timer::calibrate_tsc;
+use tracing_appender::non_blocking::WorkerGuard;
+use reload::{install_sighup_handler, reload_requested, read_configuration_as, ConfigFormat};
+use {setup_pipes_delayed_proxy, Configuration, SharedState, ProxyMode};
+use {FnSelectServer, FnPayload, NoSelector, NoPayload};
+
+/// the measurement of the TSC frequency at startup
+const TSC_CALIBRATION_INTERVAL: Duration = Duration::from_millis(200);
+/// deviation in per mille of the calibrated TSC frequency, above which the configured frequency is replaced
+const TSC_DEVIATION_PER_MILLE: u64 = 10;
+
+fn ignore_payload(_c: &mut ProxyConnection, _payload: &mut [u8], _tailroom: usize) {}
+
+/// counters and connection records of all pipelines, collected when the engine terminates
+pub struct EngineSummary {
+    pub tcp_counters_c: HashMap<PipelineId, TcpCounter>,
+    pub tcp_counters_s: HashMap<PipelineId, TcpCounter>,
+    /// only with engine.detailed_records, with a record retention there may be more than one generation per pipeline
+    pub con_records: HashMap<PipelineId, Vec<Store64<Extension>>>,
+    /// only with engine.load_test
+    pub load_test: Option<LoadReport>,
+}
+
+/// Sets up and runs the proxy engine: the RunTime is initialized with the configuration of the command line
+/// (or passed in with from_run_time, the engine configuration may also come from a yaml or json file, see
+/// from_run_time_and_file), the flow director is set up and the engine services (health checks,
+/// control channel, admin api, event export, stats logger, resolver, group watcher, SIGHUP reload) are started when the builder is created.
+/// Overrides from the environment and the command line (see overrides_from_env and overrides_from_args) are applied
+/// and the configuration is validated first, failures of the RunTime during the setup panic.
+/// The closures are registered with the with_* methods, run installs the pipelines on the cores and runs the engine
+/// until SIGINT or SIGTERM, until a drain has completed or until the load test of engine.load_test has ended.
+pub struct ProxyEngineBuilder<F1 = NoSelector, F2 = NoPayload, F3 = NoPayload> {
+    run_time: RunTime<Configuration, Store64<Extension>>,
+    shared: SharedState,
+    /// the engine configuration, which is read on a reload
+    configuration_file: String,
+    format: ConfigFormat,
+    /// overrides of the environment and the command line, they are also applied on a reload
+    overrides: Vec<(String, String)>,
+    f_select_server: Option<F1>,
+    f_process_payload_c_s: F2,
+    f_process_payload_s_c: Option<F3>,
+    /// writes the pending tracing events, when the builder is dropped
+    tracing_guard: Option<WorkerGuard>,
+}
+
+impl ProxyEngineBuilder {
+    pub fn new() -> Result<ProxyEngineBuilder, String> {
+        match RunTime::init() {
+            Ok(run_time) => ProxyEngineBuilder::from_run_time(run_time),
+            Err(err) => Err(format!("failed to initialize RunTime {}", err)),
+        }
+    }
+
+    /// fails, if the configuration does not pass Configuration::validate, the problems are logged
+    pub fn from_run_time(run_time: RunTime<Configuration, Store64<Extension>>) -> Result<ProxyEngineBuilder, String> {
+        let configuration_file = run_time.toml_filename().to_string();
+        ProxyEngineBuilder::setup(run_time, configuration_file, ConfigFormat::Toml)
+    }
+
+    /// Uses the engine configuration of a toml, yaml or json file instead of the one in the toml file of the RunTime,
+    /// which still provides the netbricks configuration. Without format, it is detected by the extension of the file.
+    pub fn from_run_time_and_file(
+        mut run_time: RunTime<Configuration, Store64<Extension>>,
+        filename: &str,
+        format: Option<ConfigFormat>,
+    ) -> Result<ProxyEngineBuilder, String> {
+        let format = format.unwrap_or(ConfigFormat::from_filename(filename));
+        run_time.run_configuration.engine_configuration = read_configuration_as(filename, format)?;
+        ProxyEngineBuilder::setup(run_time, filename.to_string(), format)
+    }
+
+    fn setup(
+        mut run_time: RunTime<Configuration, Store64<Extension>>,
+        configuration_file: String,
+        format: ConfigFormat,
+    ) -> Result<ProxyEngineBuilder, String> {
+        let mut overrides = overrides_from_env();
+        overrides.extend(overrides_from_args(env::args()));
+        run_time
+            .run_configuration
+            .engine_configuration
+            .apply_overrides(&overrides)?;
+        {
+            let configuration = &mut run_time.run_configuration.engine_configuration;
+            let namespace = configuration.engine.dns.as_ref().and_then(|dns| dns.namespace.clone());
+            resolve_targets(configuration, namespace.as_ref())?;
+        }
+        {
+            let problems = run_time.run_configuration.engine_configuration.validate();
+            for problem in &problems {
+                error!("configuration: {}", problem);
+            }
+            if !problems.is_empty() {
+                return Err(format!("{} problems in the configuration", problems.len()));
+            }
+        }
+        if run_time.run_configuration.engine_configuration.engine.calibrate_tsc.unwrap_or(true) {
+            let cpu_clock = run_time.run_configuration.system_data.cpu_clock;
+            let calibrated = calibrate_tsc(TSC_CALIBRATION_INTERVAL);
+            let deviation = if calibrated > cpu_clock { calibrated - cpu_clock } else { cpu_clock - calibrated };
+            if deviation * 1000 > cpu_clock * TSC_DEVIATION_PER_MILLE {
+                warn!("TSC runs with {} Hz instead of {} Hz, using the calibrated frequency", calibrated, cpu_clock);
+                run_time.run_configuration.system_data.cpu_clock = calibrated;
+            } else {
+                info!("TSC frequency {} Hz, calibrated {} Hz", cpu_clock, calibrated);
+            }
+        }
+        let tracing_guard = match run_time.run_configuration.engine_configuration.engine.tracing.as_ref() {
+            Some(config) => Some(init_tracing(config)?),
+            None => None,
+        };
+        run_time.setup_flowdirector().expect("failed to setup flowdirector");
+        let shared = {
+            let configuration = &run_time.run_configuration.engine_configuration;
+            let cpu_clock = run_time.run_configuration.system_data.cpu_clock;
+            let shared = SharedState::new(configuration);
+            shared.load_sessions(configuration);
+            shared.start_health_checks(configuration);
+            shared.start_control_channel(configuration);
+            shared.start_admin_api(configuration, cpu_clock);
+            shared.start_event_export(configuration, cpu_clock);
+            shared.start_ipfix_export(configuration, cpu_clock);
+            shared.start_sflow_export(configuration);
+            shared.start_stats_logger(configuration);
+            shared.start_resolver(configuration);
+            shared.start_group_watcher(configuration);
+            shared.start_state_sync(configuration);
+            shared.start_watchdog(configuration, cpu_clock);
+            shared
+        };
+        install_sighup_handler();
+        Ok(ProxyEngineBuilder {
+            run_time,
+            shared,
+            configuration_file,
+            format,
+            overrides,
+            f_select_server: None,
+            f_process_payload_c_s: ignore_payload as NoPayload,
+            f_process_payload_s_c: None,
+            tracing_guard,
+        })
+    }
+}
+
+impl<F1, F2, F3> ProxyEngineBuilder<F1, F2, F3>
+where
+    F1: FnSelectServer,
+    F2: FnPayload,
+    F3: FnPayload,
+{
+    #[inline]
+    pub fn run_configuration(&self) -> &RunConfiguration<Configuration, Store64<Extension>> {
+        &self.run_time.run_configuration
+    }
+
+    #[inline]
+    pub fn configuration(&self) -> &Configuration {
+        &self.run_time.run_configuration.engine_configuration
+    }
+
+    /// the state shared by the pipelines, e.g. to get the targets for a selection closure
+    #[inline]
+    pub fn shared(&self) -> &SharedState {
+        &self.shared
+    }
+
+    /// Checks the engine before it accepts traffic: the configuration, the rx and tx queues and the NUMA nodes of the
+    /// ports of the active cores and the addresses of the targets. Packets through the pipelines cannot be checked
+    /// before run, the report marks this check as skipped.
+    pub fn preflight(&self) -> PreflightReport {
+        let configuration = self.configuration();
+        let mut report = PreflightReport::default();
+        check_configuration(configuration, &mut report);
+        match self.run_time.context() {
+            Some(context) => check_ports(&context.active_cores, &context.ports, &mut report),
+            None => report.add("ports", CheckStatus::Skipped, "the DPDK context is not initialized".to_string()),
+        }
+        check_targets(&self.shared.targets.targets(), configuration.engine.arp.is_some(), &mut report);
+        check_pipelines(&mut report);
+        report
+    }
+
+    /// the closure, which selects the target server, without it the selection policy of the configuration is used
+    pub fn with_selector<G: FnSelectServer>(self, f_select_server: G) -> ProxyEngineBuilder<G, F2, F3> {
+        ProxyEngineBuilder {
+            run_time: self.run_time,
+            shared: self.shared,
+            configuration_file: self.configuration_file,
+            format: self.format,
+            overrides: self.overrides,
+            f_select_server: Some(f_select_server),
+            f_process_payload_c_s: self.f_process_payload_c_s,
+            f_process_payload_s_c: self.f_process_payload_s_c,
+            tracing_guard: self.tracing_guard,
+        }
+    }
+
+    /// the closure, which is called with the payload of client segments
+    pub fn with_payload_hook<G: FnPayload>(self, f_process_payload_c_s: G) -> ProxyEngineBuilder<F1, G, F3> {
+        ProxyEngineBuilder {
+            run_time: self.run_time,
+            shared: self.shared,
+            configuration_file: self.configuration_file,
+            format: self.format,
+            overrides: self.overrides,
+            f_select_server: self.f_select_server,
+            f_process_payload_c_s,
+            f_process_payload_s_c: self.f_process_payload_s_c,
+            tracing_guard: self.tracing_guard,
+        }
+    }
+
+    /// the closure, which is called with the payload of server segments
+    pub fn with_server_payload_hook<G: FnPayload>(self, f_process_payload_s_c: G) -> ProxyEngineBuilder<F1, F2, G> {
+        ProxyEngineBuilder {
+            run_time: self.run_time,
+            shared: self.shared,
+            configuration_file: self.configuration_file,
+            format: self.format,
+            overrides: self.overrides,
+            f_select_server: self.f_select_server,
+            f_process_payload_c_s: self.f_process_payload_c_s,
+            f_process_payload_s_c: Some(f_process_payload_s_c),
+            tracing_guard: self.tracing_guard,
+        }
+    }
+
+    /// Installs the pipelines, starts the engine and runs it until SIGINT or SIGTERM or until a drain, e.g. one
+    /// requested by the admin api, has completed. A SIGHUP reloads the configuration file.
+    /// Returns the counters and, with engine.detailed_records, the connection records of all pipelines.
+    pub fn run(self) -> Result<EngineSummary, String> {
+        let mut run_time = self.run_time;
+        let shared = self.shared;
+        // kept until the engine has terminated
+        let _tracing_guard = self.tracing_guard;
+        let run_configuration = run_time.run_configuration.clone();
+        let configuration = &run_configuration.engine_configuration;
+        let cpu_clock = run_configuration.system_data.cpu_clock;
+
+        let running = Arc::new(AtomicBool::new(true));
+        let r = running.clone();
+        ctrlc::set_handler(move || {
+            info!("received SIGINT or SIGTERM");
+            r.store(false, Ordering::SeqCst);
+        }).map_err(|e| format!("error setting Ctrl-C handler: {}", e))?;
+        let configuration_file = self.configuration_file;
+        let format = self.format;
+        let overrides = self.overrides;
+
+        if *configuration.engine.mode.as_ref().unwrap_or(&ProxyMode::Delayed) != ProxyMode::Delayed {
+            return Err("simple proxy still not implemented".to_string());
+        }
+        run_time.start_schedulers().expect("cannot start schedulers");
+
+        let l234data = shared.targets.l234data();
+        let run_configuration_cloned = run_configuration.clone();
+        let shared_cloned = shared.clone();
+        let f_select_server = self.f_select_server;
+        let f_process_payload_c_s = self.f_process_payload_c_s;
+        let f_process_payload_s_c = self.f_process_payload_s_c;
+        run_time
+            .install_pipeline_on_cores(Box::new(
+                move |core: i32, pmd_ports: HashMap<String, Arc<PmdPort>>, s: &mut StandaloneScheduler| {
+                    setup_pipes_delayed_proxy(
+                        core,
+                        pmd_ports,
+                        s,
+                        run_configuration_cloned.clone(),
+                        l234data.clone(),
+                        shared_cloned.clone(),
+                        f_select_server.clone(),
+                        f_process_payload_c_s.clone(),
+                        f_process_payload_s_c.clone(),
+                    );
+                },
+            )).expect("cannot install pipelines");
+
+        let cores = run_time.context().unwrap().active_cores.clone();
+
+        // start the run_time receive thread
+        run_time.start();
+
+        let (mtx, reply_mrx) = run_time.get_main_channel().expect("cannot get main channel");
+        mtx.send(MessageFrom::StartEngine).unwrap();
+        thread::sleep(Duration::from_millis(2000 as u64));
+
+        let load_test = configuration
+            .engine
+            .load_test
+            .as_ref()
+            .map(|config| LoadGenerator::start(config, &configuration.targets));
+
+        let mut loops: usize = 300;
+        // a drain may also be started by the admin api
+        while running.load(Ordering::SeqCst)
+            && !shared.drain.is_draining()
+            && !load_test.as_ref().map_or(false, |load_test| load_test.finished())
+        {
+            if loops == 300 {
+                loops = 0;
+                info!("available mbufs in memory pool= {:6}", unsafe { mbuf_avail_count() });
+            }
+            if reload_requested() {
+                info!("reload requested, reloading {}", configuration_file);
+                let configuration = read_configuration_as(&configuration_file, format).and_then(|mut c| {
+                    c.apply_overrides(&overrides)?;
+                    let namespace = c.engine.dns.as_ref().and_then(|dns| dns.namespace.clone());
+                    resolve_targets(&mut c, namespace.as_ref())?;
+                    c.check()?;
+                    Ok(c)
+                });
+                match configuration.and_then(|c| shared.reload(&c)) {
+                    Ok(version) => info!("configuration version {} is active", version),
+                    Err(e) => error!("reload failed, keeping current configuration: {}", e),
+                }
+            }
+            thread::sleep(Duration::from_millis(200 as u64));
+            loops += 1;
+        }
+
+        // waits for the end of the test, if the engine is stopped before
+        let load_report = load_test.map(|load_test| load_test.join());
+        if load_report.is_some() {
+            info!("load test: {}", load_report.as_ref().unwrap());
+        }
+
+        // before a drain releases the connections
+        shared.save_sessions(configuration);
+        if configuration.engine.drain_timeout.is_some() || shared.drain.is_draining() {
+            if !shared.drain.is_draining() {
+                let drain_timeout = configuration.engine.drain_timeout.unwrap();
+                info!("draining connections, deadline in {} ms ...", drain_timeout);
+                shared.drain.start(drain_timeout * cpu_clock / 1000);
+            }
+            let pipelines = shared.drain.pipelines();
+            let remaining = shared
+                .drain
+                .wait_until_drained(pipelines, Duration::from_millis(100), Duration::from_millis(500));
+            if remaining > 0 {
+                warn!("{} connections still active after draining", remaining);
+            } else {
+                info!("all connections drained");
+            }
+        }
+
+        println!("\nTask Performance Data:\n");
+        mtx.send(MessageFrom::PrintPerformance(cores)).unwrap();
+        thread::sleep(Duration::from_millis(1000 as u64));
+
+        mtx.send(MessageFrom::FetchCounter).unwrap();
+        if configuration.engine.detailed_records.unwrap_or(false) {
+            mtx.send(MessageFrom::FetchCRecords).unwrap();
+        }
+
+        let mut summary = EngineSummary {
+            tcp_counters_c: HashMap::new(),
+            tcp_counters_s: HashMap::new(),
+            con_records: HashMap::new(),
+            load_test: load_report,
+        };
+        loop {
+            match reply_mrx.recv_timeout(Duration::from_millis(1000)) {
+                Ok(MessageTo::Counter(pipeline_id, tcp_counter_c, tcp_counter_s, _rx_tx_stats)) => {
+                    print_tcp_counters(&pipeline_id, &tcp_counter_c, &tcp_counter_s);
+                    summary.tcp_counters_c.insert(pipeline_id.clone(), tcp_counter_c);
+                    summary.tcp_counters_s.insert(pipeline_id, tcp_counter_s);
+                }
+                Ok(MessageTo::CRecords(pipeline_id, Some(recv_con_records), _)) => {
+                    debug!("{}: received {} CRecords", pipeline_id, recv_con_records.len(),);
+                    summary
+                        .con_records
+                        .entry(pipeline_id)
+                        .or_insert_with(Vec::new)
+                        .push(recv_con_records);
+                }
+                Ok(_m) => error!("illegal MessageTo received from reply_to_main channel"),
+                Err(RecvTimeoutError::Timeout) => {
+                    break;
+                }
+                Err(e) => {
+                    error!("error receiving from reply_to_main channel (reply_mrx): {}", e);
+                    break;
+                }
+            }
+        }
+
+        for (pipeline_id, counters) in shared.stats.snapshot() {
+            info!("{}: {}", pipeline_id, counters);
+        }
+        mtx.send(MessageFrom::Exit).unwrap();
+        thread::sleep(Duration::from_millis(200 as u64)); // give threads some time to process Exit
+        Ok(summary)
+    }
+}
@@ -0,0 +1,176 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::thread;
+
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::L234Data;
+
+use ipv6::v4_to_key;
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// selects the packets to capture
+#[derive(Clone, Debug)]
+pub enum CaptureFilter {
+    /// packets from and to the client address (flow key format)
+    Client(u128),
+    /// packets from and to the target with this id, i.e. the server side of the connections
+    Target(String),
+}
+
+/// the capture filter of a pipeline, resolved against its targets
+struct PacketMatcher {
+    client: Option<u128>,
+    target: Option<(u32, u16)>,
+}
+
+impl PacketMatcher {
+    fn new(filter: &CaptureFilter, servers: &Vec<L234Data>) -> PacketMatcher {
+        match filter {
+            CaptureFilter::Client(client) => PacketMatcher {
+                client: Some(*client),
+                target: None,
+            },
+            CaptureFilter::Target(id) => PacketMatcher {
+                client: None,
+                target: servers.iter().find(|s| &s.server_id == id).map(|s| (s.ip, s.port)),
+            },
+        }
+    }
+
+    #[inline]
+    fn matches(&self, p: &Pdu) -> bool {
+        let ip = p.headers().ip(1);
+        if self.client.is_some() {
+            let client = self.client.unwrap();
+            return v4_to_key(ip.src()) == client || v4_to_key(ip.dst()) == client;
+        }
+        if self.target.is_some() {
+            let target = self.target.unwrap();
+            let tcp = p.headers().tcp(2);
+            return (ip.src(), tcp.src_port()) == target || (ip.dst(), tcp.dst_port()) == target;
+        }
+        false
+    }
+}
+
+/// the packet capture of a pipeline
+pub struct Capture {
+    matcher: PacketMatcher,
+    tx: Sender<Vec<u8>>,
+}
+
+impl Capture {
+    /// p must be a TCP/IPv4 packet, a copy of matching packets is sent to the pcap writer
+    #[inline]
+    pub fn packet(&self, p: &Pdu) {
+        if self.matcher.matches(p) {
+            // the writer may have stopped because of an i/o error
+            let _ = self.tx.send(frame_bytes(p));
+        }
+    }
+}
+
+/// copies the ethernet frame of p
+pub fn frame_bytes(p: &Pdu) -> Vec<u8> {
+    let mac = p.headers().mac(0);
+    let payload = p.get_payload(0);
+    let mut frame = Vec::with_capacity(14 + payload.len());
+    frame.extend_from_slice(mac.dst.as_bytes());
+    frame.extend_from_slice(mac.src.as_bytes());
+    frame.push((mac.etype() >> 8) as u8);
+    frame.push(mac.etype() as u8);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn write_u32(w: &mut Write, v: u32) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8, (v >> 16) as u8, (v >> 24) as u8])
+}
+
+fn write_u16(w: &mut Write, v: u16) -> io::Result<()> {
+    w.write_all(&[v as u8, (v >> 8) as u8])
+}
+
+/// writes the frames received from the pipelines into a pcap file, until all senders are dropped
+fn spawn_pcap_writer(path: &str, rx: Receiver<Vec<u8>>) -> io::Result<thread::JoinHandle<()>> {
+    let mut f = BufWriter::new(File::create(path)?);
+    // global header, little endian
+    write_u32(&mut f, PCAP_MAGIC)?;
+    write_u16(&mut f, 2)?;
+    write_u16(&mut f, 4)?;
+    write_u32(&mut f, 0)?;
+    write_u32(&mut f, 0)?;
+    write_u32(&mut f, PCAP_SNAPLEN)?;
+    write_u32(&mut f, LINKTYPE_ETHERNET)?;
+    let path = path.to_string();
+    Ok(thread::spawn(move || {
+        let mut count = 0usize;
+        for frame in rx {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            let result = write_u32(&mut f, now.as_secs() as u32)
+                .and_then(|_| write_u32(&mut f, now.subsec_micros()))
+                .and_then(|_| write_u32(&mut f, frame.len() as u32))
+                .and_then(|_| write_u32(&mut f, frame.len() as u32))
+                .and_then(|_| f.write_all(&frame));
+            if let Err(e) = result {
+                error!("capture: cannot write {}: {}", path, e);
+                return;
+            }
+            count += 1;
+        }
+        f.flush().expect("cannot flush BufWriter");
+        info!("capture: wrote {} packets into {}", count, path);
+    }))
+}
+
+/// Enables and disables the packet capture of all pipelines. Pipelines compare the version on each timer tick.
+#[derive(Clone)]
+pub struct CaptureControl {
+    current: Arc<Mutex<Option<(CaptureFilter, Sender<Vec<u8>>)>>>,
+    version: Arc<AtomicUsize>,
+}
+
+impl CaptureControl {
+    pub fn new() -> CaptureControl {
+        CaptureControl {
+            current: Arc::new(Mutex::new(None)),
+            version: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// the current capture, resolved against the targets of the pipeline
+    pub fn capture(&self, servers: &Vec<L234Data>) -> Option<Capture> {
+        self.current.lock().unwrap().as_ref().map(|(filter, tx)| Capture {
+            matcher: PacketMatcher::new(filter, servers),
+            tx: tx.clone(),
+        })
+    }
+
+    /// starts capturing packets matching filter into the pcap file, a running capture is stopped
+    pub fn start(&self, filter: CaptureFilter, path: &str) -> io::Result<()> {
+        let (tx, rx) = channel();
+        spawn_pcap_writer(path, rx)?;
+        info!("capture: starting {:?} into {}", filter, path);
+        *self.current.lock().unwrap() = Some((filter, tx));
+        self.version.fetch_add(1, Ordering::AcqRel);
+        Ok(())
+    }
+
+    /// the pcap file is closed, when all pipelines have dropped their sender
+    pub fn stop(&self) {
+        *self.current.lock().unwrap() = None;
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+}
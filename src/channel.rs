@@ -0,0 +1,198 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvError, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the receiver sleeps for this time, when the queue is empty
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+pub const DEFAULT_QUEUE_SIZE: usize = 65536;
+
+struct Slot<T> {
+    /// the position of the value in this slot, plus 1 when the value has been written
+    sequence: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+}
+
+/// the bounded array queue of D. Vyukov, with a single consumer
+struct Queue<T> {
+    slots: Box<[Slot<T>]>,
+    mask: usize,
+    enqueue_pos: AtomicUsize,
+    dequeue_pos: AtomicUsize,
+    senders: AtomicUsize,
+    sent: AtomicU64,
+    overflows: AtomicU64,
+}
+
+unsafe impl<T: Send> Send for Queue<T> {}
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    fn push(&self, value: T) -> Result<(), T> {
+        let mut pos = self.enqueue_pos.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & self.mask];
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            let diff = sequence.wrapping_sub(pos) as isize;
+            if diff == 0 {
+                match self
+                    .enqueue_pos
+                    .compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed)
+                {
+                    Ok(_) => {
+                        unsafe { *slot.value.get() = Some(value) };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // the slot still holds the value of the previous round
+                return Err(value);
+            } else {
+                pos = self.enqueue_pos.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// only called by the receiver
+    fn pop(&self) -> Option<T> {
+        let pos = self.dequeue_pos.load(Ordering::Relaxed);
+        let slot = &self.slots[pos & self.mask];
+        if slot.sequence.load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return None;
+        }
+        self.dequeue_pos.store(pos.wrapping_add(1), Ordering::Relaxed);
+        let value = unsafe { (*slot.value.get()).take() };
+        slot.sequence.store(pos.wrapping_add(self.mask + 1), Ordering::Release);
+        value
+    }
+}
+
+/// the statistics of a channel
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ChannelStats {
+    pub capacity: usize,
+    pub sent: u64,
+    /// messages dropped, because the queue was full
+    pub overflows: u64,
+    /// messages in the queue
+    pub queued: usize,
+}
+
+/// A bounded channel from the pipelines to an exporter thread. Sending never blocks and never allocates: when the
+/// queue is full, the message is dropped and counted as overflow, so that a slow exporter cannot stall the pipelines
+/// nor let the queue grow without bounds. The receiver polls the queue, when it is empty.
+pub fn bounded<T>(capacity: usize) -> (BoundedSender<T>, BoundedReceiver<T>) {
+    let capacity = capacity.max(2).next_power_of_two();
+    let slots: Vec<Slot<T>> = (0..capacity)
+        .map(|i| Slot {
+            sequence: AtomicUsize::new(i),
+            value: UnsafeCell::new(None),
+        }).collect();
+    let queue = Arc::new(Queue {
+        slots: slots.into_boxed_slice(),
+        mask: capacity - 1,
+        enqueue_pos: AtomicUsize::new(0),
+        dequeue_pos: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        sent: AtomicU64::new(0),
+        overflows: AtomicU64::new(0),
+    });
+    (BoundedSender { queue: queue.clone() }, BoundedReceiver { queue })
+}
+
+pub struct BoundedSender<T> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T> BoundedSender<T> {
+    /// false, if the queue is full and the message has been dropped
+    #[inline]
+    pub fn send(&self, value: T) -> bool {
+        match self.queue.push(value) {
+            Ok(()) => {
+                self.queue.sent.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => {
+                self.queue.overflows.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        let enqueued = self.queue.enqueue_pos.load(Ordering::Relaxed);
+        let dequeued = self.queue.dequeue_pos.load(Ordering::Relaxed);
+        ChannelStats {
+            capacity: self.queue.mask + 1,
+            sent: self.queue.sent.load(Ordering::Relaxed),
+            overflows: self.queue.overflows.load(Ordering::Relaxed),
+            queued: enqueued.wrapping_sub(dequeued),
+        }
+    }
+}
+
+impl<T> Clone for BoundedSender<T> {
+    fn clone(&self) -> BoundedSender<T> {
+        self.queue.senders.fetch_add(1, Ordering::SeqCst);
+        BoundedSender {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl<T> Drop for BoundedSender<T> {
+    fn drop(&mut self) {
+        self.queue.senders.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// the receiving end of a bounded channel, there is only one
+pub struct BoundedReceiver<T> {
+    queue: Arc<Queue<T>>,
+}
+
+impl<T> BoundedReceiver<T> {
+    #[inline]
+    pub fn try_recv(&self) -> Option<T> {
+        self.queue.pop()
+    }
+
+    fn disconnected(&self) -> bool {
+        self.queue.senders.load(Ordering::SeqCst) == 0
+    }
+
+    /// waits for the next message, fails when all senders have been dropped and the queue is empty
+    pub fn recv(&self) -> Result<T, RecvError> {
+        loop {
+            if let Some(value) = self.queue.pop() {
+                return Ok(value);
+            }
+            if self.disconnected() {
+                // a message may have been sent before the last sender was dropped
+                return self.queue.pop().ok_or(RecvError);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(value) = self.queue.pop() {
+                return Ok(value);
+            }
+            if self.disconnected() {
+                return self.queue.pop().ok_or(RecvTimeoutError::Disconnected);
+            }
+            if Instant::now() >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
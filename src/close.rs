@@ -0,0 +1,114 @@
+use std::fmt;
+
+pub const CLOSE_REASONS: usize = 9;
+
+/// Why a connection was closed, recorded per connection and counted per pipeline.
+/// Unlike the ReleaseCause of netfcts, it tells which side closed and whether the proxy gave up on the connection.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum CloseReason {
+    Unknown = 0,
+    ClientFin = 1,
+    ServerFin = 2,
+    ClientRst = 3,
+    ServerRst = 4,
+    IdleTimeout = 5,
+    /// released when the drain deadline passed
+    Drain = 6,
+    /// killed by the operator, see ConnectionLookup::kill
+    OperatorKill = 7,
+    /// no server could be selected, or the selected server was at capacity
+    SelectionFailure = 8,
+}
+
+impl From<u8> for CloseReason {
+    fn from(reason: u8) -> CloseReason {
+        match reason {
+            1 => CloseReason::ClientFin,
+            2 => CloseReason::ServerFin,
+            3 => CloseReason::ClientRst,
+            4 => CloseReason::ServerRst,
+            5 => CloseReason::IdleTimeout,
+            6 => CloseReason::Drain,
+            7 => CloseReason::OperatorKill,
+            8 => CloseReason::SelectionFailure,
+            _ => CloseReason::Unknown,
+        }
+    }
+}
+
+impl CloseReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloseReason::Unknown => "unknown",
+            CloseReason::ClientFin => "client_fin",
+            CloseReason::ServerFin => "server_fin",
+            CloseReason::ClientRst => "client_rst",
+            CloseReason::ServerRst => "server_rst",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::Drain => "drain",
+            CloseReason::OperatorKill => "operator_kill",
+            CloseReason::SelectionFailure => "selection_failure",
+        }
+    }
+
+    /// the connection was reset, timed out or could not be bound to a server
+    #[inline]
+    pub fn is_failure(&self) -> bool {
+        match self {
+            CloseReason::ClientRst
+            | CloseReason::ServerRst
+            | CloseReason::IdleTimeout
+            | CloseReason::SelectionFailure => true,
+            _ => false,
+        }
+    }
+
+    /// the reason of a connection is set once, only the reason of a FIN is replaced by a later reason,
+    /// e.g. by a timeout of the half-closed connection
+    #[inline]
+    pub fn replaceable(&self) -> bool {
+        match self {
+            CloseReason::Unknown | CloseReason::ClientFin | CloseReason::ServerFin => true,
+            _ => false,
+        }
+    }
+}
+
+/// number of released connections per close reason
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CloseReasonCounts([u64; CLOSE_REASONS]);
+
+impl CloseReasonCounts {
+    #[inline]
+    pub fn count(&mut self, reason: CloseReason) {
+        self.0[reason as usize] += 1;
+    }
+
+    #[inline]
+    pub fn get(&self, reason: CloseReason) -> u64 {
+        self.0[reason as usize]
+    }
+
+    pub fn add(&mut self, other: &CloseReasonCounts) {
+        for i in 0..CLOSE_REASONS {
+            self.0[i] += other.0[i];
+        }
+    }
+
+    /// the reasons with their counts
+    pub fn counts(&self) -> Vec<(CloseReason, u64)> {
+        (0..CLOSE_REASONS).map(|i| (CloseReason::from(i as u8), self.0[i])).collect()
+    }
+}
+
+impl fmt::Display for CloseReasonCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counts: Vec<String> = self
+            .counts()
+            .iter()
+            .map(|(reason, count)| format!("{}= {}", reason.name(), count))
+            .collect();
+        write!(f, "{}", counts.join(", "))
+    }
+}
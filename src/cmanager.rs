@@ -1,31 +1,68 @@
-use std::net::Ipv4Addr;
-use std::collections::{VecDeque, BTreeMap};
+use std::net::{Ipv4Addr, IpAddr};
+use std::collections::{VecDeque, BTreeMap, HashMap};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fmt;
+use std::cmp;
 use std::mem;
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::any::Any;
 use std::arch::x86_64::_rdtsc;
 
 use e2d2::interface::{PortQueue, L4Flow, Pdu};
 
-//use uuid::Uuid;
-use netfcts::timer_wheel::TimerWheel;
+use uuid::Uuid;
 use netfcts::tcp_common::*;
 use netfcts::Store64;
 use netfcts::{Storable, SimpleStore};
 use netfcts::conrecord::HasTcpState;
 use netfcts::conrecord::TIME_STAMP_REDUCTION_FACTOR;
 use netfcts::utils::shuffle_ports;
+use ipv6::{key_to_v4, key_to_ip, ip_to_key};
+use audit::{Leg, StateViolation, allowed_transition};
+use close::CloseReason;
+use rtt::LegTiming;
+use selection::ServerLoad;
+use timer::{CancellableWheel, TimerToken};
+use arena::BoxArena;
+use http::HttpRequest;
+use socks5::Socks5State;
+use websocket::WebSocketState;
+use protocols::{Protocol, ProtocolCounters};
+use stats::PipelineCounters;
+use limits::ConnectionCounts;
+use events::EventSender;
+use ipfix::FlowSender;
+use tcp_options::WindowShifts;
+use buffering::BufferedPayload;
+use reorder::ReorderBuffers;
+use rewrite::SeqDeltas;
+use flowtable::{ConnectionTableConfig, SockTable};
+use ha::ConnectionState;
 //use netfcts::utils::Sock2Index;
 #[cfg(feature = "profiling")]
 use netfcts::utils::TimeAdder;
 
 use eui48::MacAddress;
 use separator::Separatable;
+use tracing::{Level, Span};
 
 pub type ProxyRecStore = Store64<Extension>;
 
+/// client socket used as flow key: 128-bit address and port, IPv4 addresses are IPv4-mapped (see ipv6::v4_to_key)
+pub type ClientSock = (u128, u16);
+
+/// identifies a connection of a connection manager: the index of its source address in the upper and its proxy port
+/// in the lower 16 bits, see SourcePoolConfig. Without a source pool the slot is the proxy port.
+pub type Slot = u32;
+
+/// maximum number of source addresses of a connection manager, including the address of the pipeline
+pub const MAX_SOURCE_IPS: usize = 64;
+
+const DEFAULT_TWO_MSL_MS: u64 = 60000;
+/// slots of the timer wheel of the TIME_WAIT, its resolution is 2MSL / TIME_WAIT_WHEEL_SLOTS
+const TIME_WAIT_WHEEL_SLOTS: usize = 1002;
+
 #[derive(Clone, Copy, Debug)]
 #[repr(align(32))]
 pub struct Extension {
@@ -33,6 +70,18 @@ pub struct Extension {
     s_state: [u8; 7],
     s_state_count: u8,
     s_release_cause: u8,
+    s_close_reason: u8,
+    /// handshake RTT and smoothed data RTT of client and server leg, in units of TIME_STAMP_REDUCTION_FACTOR cycles,
+    /// 0 if not measured
+    s_syn_rtt: [u32; 2],
+    s_srtt: [u32; 2],
+    /// retransmissions received from client and server
+    s_retransmissions: [u16; 2],
+    /// further targets the SYN was sent to, see SynRetryConfig
+    s_syn_retries: u8,
+    /// packets and their wire length received from client and server
+    s_packets: [u32; 2],
+    s_wire_bytes: [u64; 2],
 }
 
 impl Extension {
@@ -66,6 +115,90 @@ impl Extension {
     #[inline]
     fn init(&mut self) {
         self.s_state_count = 0;
+        self.s_close_reason = CloseReason::Unknown as u8;
+        self.s_syn_rtt = [0; 2];
+        self.s_srtt = [0; 2];
+        self.s_retransmissions = [0; 2];
+        self.s_syn_retries = 0;
+        self.s_packets = [0; 2];
+        self.s_wire_bytes = [0; 2];
+    }
+
+    /// number of further targets the SYN was sent to, see SynRetryConfig
+    #[inline]
+    pub fn syn_retries(&self) -> u8 {
+        self.s_syn_retries
+    }
+
+    #[inline]
+    fn reduced(cycles: Option<u64>) -> u32 {
+        cycles.map_or(0, |c| cmp::max(1, c / TIME_STAMP_REDUCTION_FACTOR) as u32)
+    }
+
+    #[inline]
+    fn cycles(reduced: u32) -> Option<u64> {
+        if reduced == 0 {
+            None
+        } else {
+            Some(reduced as u64 * TIME_STAMP_REDUCTION_FACTOR)
+        }
+    }
+
+    /// handshake RTT in cycles of the client leg (index 0) and the server leg (index 1)
+    #[inline]
+    pub fn syn_rtt(&self, leg: usize) -> Option<u64> {
+        Extension::cycles(self.s_syn_rtt[leg])
+    }
+
+    /// smoothed data RTT in cycles of the client leg (index 0) and the server leg (index 1)
+    #[inline]
+    pub fn srtt(&self, leg: usize) -> Option<u64> {
+        Extension::cycles(self.s_srtt[leg])
+    }
+
+    /// retransmissions received from the client (index 0) and the server (index 1)
+    #[inline]
+    pub fn retransmissions(&self, leg: usize) -> u16 {
+        self.s_retransmissions[leg]
+    }
+
+    /// packets received from the client (index 0) and the server (index 1)
+    #[inline]
+    pub fn packets(&self, leg: usize) -> u32 {
+        self.s_packets[leg]
+    }
+
+    /// bytes of the frames received from the client (index 0) and the server (index 1), including the L2 header
+    #[inline]
+    pub fn wire_bytes(&self, leg: usize) -> u64 {
+        self.s_wire_bytes[leg]
+    }
+
+    fn set_traffic(&mut self, packets: [u64; 2], wire_bytes: [u64; 2]) {
+        for i in 0..2 {
+            self.s_packets[i] = cmp::min(packets[i], u32::max_value() as u64) as u32;
+        }
+        self.s_wire_bytes = wire_bytes;
+    }
+
+    fn set_timing(&mut self, syn_rtt: [Option<u64>; 2], legs: [&LegTiming; 2], syn_retries: u8) {
+        self.s_syn_retries = syn_retries;
+        for i in 0..2 {
+            self.s_syn_rtt[i] = Extension::reduced(syn_rtt[i]);
+            self.s_srtt[i] = Extension::reduced(legs[i].srtt());
+            self.s_retransmissions[i] = cmp::min(legs[i].retransmissions(), u16::max_value() as u32) as u16;
+        }
+    }
+
+    /// the close reason of the connection, recorded with the server side, see CloseReason
+    #[inline]
+    pub fn close_reason(&self) -> CloseReason {
+        CloseReason::from(self.s_close_reason)
+    }
+
+    #[inline]
+    fn set_close_reason(&mut self, reason: CloseReason) {
+        self.s_close_reason = reason as u8;
     }
 
     #[inline]
@@ -110,6 +243,13 @@ impl Storable for Extension {
             s_stamps: [0u32; 7],
             s_release_cause: ReleaseCause::Unknown as u8,
             s_state_count: 0,
+            s_close_reason: CloseReason::Unknown as u8,
+            s_syn_rtt: [0; 2],
+            s_srtt: [0; 2],
+            s_retransmissions: [0; 2],
+            s_syn_retries: 0,
+            s_packets: [0; 2],
+            s_wire_bytes: [0; 2],
         }
     }
 }
@@ -118,9 +258,16 @@ impl fmt::Display for Extension {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "(Server, {:?}, {:?}, {:?})",
+            "(Server, {:?}, {:?}, {:?}, syn_rtt= {:?}, srtt= {:?}, retransmissions= {:?}, syn_retries= {}, packets= {:?}, wire_bytes= {:?}, {:?})",
             self.states(),
             self.release_cause(),
+            self.close_reason(),
+            self.s_syn_rtt,
+            self.s_srtt,
+            self.s_retransmissions,
+            self.s_syn_retries,
+            self.s_packets,
+            self.s_wire_bytes,
             self.deltas_to_base_stamp()
                 .iter()
                 .map(|u| u.separated_string())
@@ -142,7 +289,7 @@ pub struct ProxyConnection<'a> {
     detailed_c: Option<Box<DetailedConnection>>,
     pub client_mac: MacAddress,
     client_port: u16,
-    client_ip: u32,
+    client_ip: u128,
     /// seqn for connection to client,
     /// after the SYN-ACK from the target server it is the delta to be added to server seqn
     /// see 'server_synack_received'
@@ -158,12 +305,93 @@ pub struct ProxyConnection<'a> {
     pub seqn_fin_p2s: u32,
     /// egress proxy port assigned to this connection
     proxy_port: u16,
-    pub wheel_slot_and_index: (u16, u16),
+    /// the proxy port and the index of the source address, see Slot
+    slot: Slot,
+    /// source address of the proxy towards the server
+    source_ip: u32,
+    /// unique id of the connection, see ConnectionManager::find_by_uuid
+    uuid: u128,
+    /// the timeout event of this connection in the timer wheel
+    pub timer: TimerToken,
     /// current client and server state, we keep a copy here for performance reasons
     pub client_state: u8,
     pub server_state: u8,
+    /// true, while client and server side are established
+    spliced: bool,
     /// server assigned to this connection
     server_index: u8,
+    /// the frontend, to which the client connected, 0 for engine.port, see FrontendConfig
+    frontend: u8,
+    /// true, after the server has been selected and the SYN has been sent to it
+    server_bound: bool,
+    /// servers, which failed to answer the SYN, see SynRetryConfig
+    tried_servers: Vec<u8>,
+    /// server name of the TLS ClientHello, set before the server is selected, if the sni_map, the consistent hash or
+    /// a selection closure may use it
+    pub sni: Option<String>,
+    /// protocols of the ALPN extension of the TLS ClientHello in the order of the client, set before the server is
+    /// selected, if the alpn_map or a selection closure may use them
+    pub alpn: Option<Vec<String>>,
+    /// request line and host of the first HTTP/1.x or HTTP/2 request, set before the server is selected, if the http
+    /// routes, the consistent hash or a selection closure may use it
+    pub http_request: Option<Box<HttpRequest>>,
+    /// protocol of the first payload of the client, set before the server is selected
+    pub protocol: Option<Protocol>,
+    /// negotiation state, if the engine is a SOCKS5 front-end
+    pub socks5: Option<Socks5State>,
+    /// state of the HTTP upgrade and of the frames, see websocket_aware
+    pub websocket: Option<Box<WebSocketState>>,
+    /// seqn of the peer of the segment, whose payload is passed to the payload closure
+    pub payload_seqn: u32,
+    /// MSS option of the client SYN
+    pub client_mss: Option<u16>,
+    /// client payload received before the server is selected, see PayloadBuffering
+    pub buffered_payload: Option<Box<BufferedPayload>>,
+    /// segments held back on both legs, see ReorderConfig
+    pub reorder: Option<Box<ReorderBuffers<'a>>>,
+    /// seqn adjustments for segments from client and from server, which payload closures have rewritten
+    pub c2s_deltas: SeqDeltas,
+    pub s2c_deltas: SeqDeltas,
+    /// payload set by a payload closure, which replaces the payload of the current segment
+    payload_rewrite: Option<Vec<u8>>,
+    /// state of the closures, e.g. of a protocol parser, which is kept across the packets of the connection
+    user_data: Option<Box<Any>>,
+    /// the tracing span of the connection, see TracingConfig
+    span: Option<Span>,
+    /// the state transitions are checked against the transition tables, see EngineConfig.state_audit
+    audit: bool,
+    /// the first transition of the current packet, which is not in the tables
+    violation: Option<StateViolation>,
+    /// window scaling of client and server leg
+    pub window_shifts: WindowShifts,
+    /// SACK is permitted on the client leg
+    pub client_sack: bool,
+    /// SACK is permitted on the server leg
+    pub server_sack: bool,
+    /// tsc when the connection state was created
+    pub opened: u64,
+    /// tsc when the SYN was sent to the server
+    pub syn_sent: u64,
+    /// tsc of the latest packet of the connection, used for the idle timeouts
+    pub last_activity: u64,
+    /// tsc of the latest keepalive probe of the proxy
+    pub last_keepalive: u64,
+    /// cycles from the SYN towards the server until its SYN-ACK
+    pub server_rtt: Option<u64>,
+    /// cycles from the SYN-ACK towards the client until its ACK
+    pub client_rtt: Option<u64>,
+    /// data RTT and retransmissions of client and server leg
+    pub client_leg: LegTiming,
+    pub server_leg: LegTiming,
+    /// payload bytes received from the client and from the server
+    pub c2s_bytes: u64,
+    pub s2c_bytes: u64,
+    /// packets received from the client (index 0) and from the server (index 1) and the length of their frames
+    /// including the L2 header, but without FCS
+    pub packets: [u64; 2],
+    pub wire_bytes: [u64; 2],
+    release_cause: u8,
+    close_reason: u8,
 }
 
 impl<'a> ProxyConnection<'a> {
@@ -172,27 +400,77 @@ impl<'a> ProxyConnection<'a> {
             payload_packet: None,
             //payload: Box::new(Vec::with_capacity(1500)),
             detailed_c: None,
-            //userdata: None,
+            user_data: None,
+            span: None,
+            audit: false,
+            violation: None,
             client_mac: MacAddress::default(),
             c_seqn: 0,
             ackn_p2s: 0,
             ackn_p2c: 0,
             c2s_inserted_bytes: 0,
-            wheel_slot_and_index: (0, 0),
+            timer: TimerToken::none(),
             seqn: Seqn { f_seqn: 0 },
             seqn_fin_p2s: 0,
             client_ip: 0,
             client_port: 0,
             proxy_port: 0,
+            slot: 0,
+            source_ip: 0,
+            uuid: 0,
+            spliced: false,
             server_index: 0,
+            frontend: 0,
+            server_bound: false,
+            tried_servers: Vec::new(),
+            sni: None,
+            alpn: None,
+            http_request: None,
+            protocol: None,
+            socks5: None,
+            websocket: None,
+            payload_seqn: 0,
+            client_mss: None,
+            buffered_payload: None,
+            reorder: None,
+            c2s_deltas: SeqDeltas::new(),
+            s2c_deltas: SeqDeltas::new(),
+            payload_rewrite: None,
+            window_shifts: WindowShifts::default(),
+            client_sack: false,
+            server_sack: false,
+            opened: 0,
+            syn_sent: 0,
+            last_activity: 0,
+            last_keepalive: 0,
+            server_rtt: None,
+            client_rtt: None,
+            client_leg: LegTiming::new(),
+            server_leg: LegTiming::new(),
+            c2s_bytes: 0,
+            s2c_bytes: 0,
+            packets: [0; 2],
+            wire_bytes: [0; 2],
+            release_cause: ReleaseCause::Unknown as u8,
+            close_reason: CloseReason::Unknown as u8,
             client_state: TcpState::Closed as u8,
             server_state: TcpState::Listen as u8,
         }
     }
 
+    /// a connection, which is not managed by a ConnectionManager, to select the target of a udp flow of the client
+    pub fn for_selection(client_sock: ClientSock) -> ProxyConnection<'a> {
+        let mut c = ProxyConnection::new();
+        c.set_sock(client_sock);
+        c
+    }
+
     #[inline]
-    fn initialize(&mut self, client_sock: &(u32, u16), proxy_port: u16) {
-        //self.userdata = None;
+    fn initialize(&mut self, client_sock: &ClientSock, slot: Slot, source_ip: u32) {
+        self.user_data = None;
+        self.span = None;
+        self.audit = false;
+        self.violation = None;
         self.payload_packet = None;
         //self.payload.clear();
         self.client_mac = MacAddress::default();
@@ -202,24 +480,81 @@ impl<'a> ProxyConnection<'a> {
         self.ackn_p2c = 0;
         self.c2s_inserted_bytes = 0;
         self.seqn_fin_p2s = 0;
-        self.wheel_slot_and_index = (0, 0);
+        self.timer = TimerToken::none();
         self.client_ip = client_sock.0;
         self.client_port = client_sock.1;
-        self.proxy_port = proxy_port;
+        self.proxy_port = slot as u16;
+        self.slot = slot;
+        self.source_ip = source_ip;
+        self.spliced = false;
         self.server_index = 0;
+        self.frontend = 0;
+        self.server_bound = false;
+        self.tried_servers.clear();
+        self.sni = None;
+        self.alpn = None;
+        self.http_request = None;
+        self.protocol = None;
+        self.socks5 = None;
+        self.websocket = None;
+        self.client_mss = None;
+        self.buffered_payload = None;
+        self.reorder = None;
+        self.c2s_deltas.clear();
+        self.s2c_deltas.clear();
+        self.payload_rewrite = None;
+        self.window_shifts = WindowShifts::default();
+        self.client_sack = false;
+        self.server_sack = false;
+        self.opened = unsafe { _rdtsc() };
+        self.syn_sent = 0;
+        self.last_activity = self.opened;
+        self.last_keepalive = 0;
+        self.server_rtt = None;
+        self.client_rtt = None;
+        self.client_leg = LegTiming::new();
+        self.server_leg = LegTiming::new();
+        self.c2s_bytes = 0;
+        self.s2c_bytes = 0;
+        self.packets = [0; 2];
+        self.wire_bytes = [0; 2];
+        self.release_cause = ReleaseCause::Unknown as u8;
+        self.close_reason = CloseReason::Unknown as u8;
         self.client_state = TcpState::Closed as u8;
         self.server_state = TcpState::Listen as u8;
     }
 
     #[inline]
-    fn initialize_with_details(&mut self, client_sock: &(u32, u16), proxy_port: u16, store: &Rc<RefCell<ProxyRecStore>>) {
-        self.initialize(client_sock, proxy_port);
+    fn initialize_with_details(
+        &mut self,
+        client_sock: &ClientSock,
+        slot: Slot,
+        source_ip: u32,
+        store: &Rc<RefCell<ProxyRecStore>>,
+        details: &mut BoxArena<DetailedConnection>,
+    ) {
+        self.initialize(client_sock, slot, source_ip);
         if self.detailed_c.is_none() {
-            self.detailed_c = Some(Box::new(DetailedConnection::new(store)));
-        } else {
-            self.detailed_c.as_mut().unwrap().re_new(store)
+            self.detailed_c = Some(details.alloc(DetailedConnection::unused));
+        }
+        self.detailed_c.as_mut().unwrap().re_new(store);
+        self.detailed_c.as_mut().unwrap().initialize(client_sock, slot as u16)
+    }
+
+    /// creates the record of a connection, which has not been sampled, on its release, see RecordSampling.failures.
+    /// The record has the final states of the connection instead of the state history.
+    fn record_failure(&mut self, store: &Rc<RefCell<ProxyRecStore>>, details: &mut BoxArena<DetailedConnection>) {
+        let mut detailed_c = details.alloc(DetailedConnection::unused);
+        detailed_c.re_new(store);
+        detailed_c.initialize(&(self.client_ip, self.client_port), self.proxy_port);
+        detailed_c.c_push_state(self.client_state());
+        if self.server_bound {
+            detailed_c.s_init();
+            detailed_c.s_push_state(self.server_state());
         }
-        self.detailed_c.as_mut().unwrap().initialize(client_sock, proxy_port)
+        detailed_c.set_release_cause(self.release_cause());
+        detailed_c.set_close_reason(self.close_reason());
+        self.detailed_c = Some(detailed_c);
     }
 
     #[inline]
@@ -227,6 +562,78 @@ impl<'a> ProxyConnection<'a> {
         self.proxy_port
     }
 
+    /// identifies the connection in its connection manager, e.g. in the timer wheel
+    #[inline]
+    pub fn slot(&self) -> Slot {
+        self.slot
+    }
+
+    /// source address of the proxy towards the server
+    #[inline]
+    pub fn source_ip(&self) -> u32 {
+        self.source_ip
+    }
+
+    #[inline]
+    pub fn uuid(&self) -> Uuid {
+        Uuid::from_u128(self.uuid)
+    }
+
+    pub fn live_connection(&self) -> LiveConnection {
+        LiveConnection {
+            uuid: self.uuid(),
+            port: self.proxy_port,
+            source_ip: Ipv4Addr::from(self.source_ip),
+            client: self.client_addr(),
+            server_index: if self.server_bound() { Some(self.server_index()) } else { None },
+            client_state: self.client_state(),
+            server_state: self.server_state(),
+        }
+    }
+
+    /// the state which another engine needs to continue the connection, see HaConfig
+    pub fn connection_state(&self) -> ConnectionState {
+        let mut client_mac = [0u8; 6];
+        client_mac.copy_from_slice(self.client_mac.as_bytes());
+        ConnectionState {
+            client_ip: self.client_ip,
+            client_port: self.client_port,
+            client_mac,
+            slot: self.slot,
+            server_index: self.server_index,
+            frontend: self.frontend,
+            c_seqn: self.c_seqn,
+            ackn_p2s: self.ackn_p2s,
+            ackn_p2c: self.ackn_p2c,
+            c2s_inserted_bytes: self.c2s_inserted_bytes,
+            window_shifts: self.window_shifts,
+            client_sack: self.client_sack,
+            server_sack: self.server_sack,
+            c2s_deltas: self.c2s_deltas.entries(),
+            s2c_deltas: self.s2c_deltas.entries(),
+        }
+    }
+
+    /// takes over the sequence number state of a replicated connection, the connection is established on both legs
+    fn take_over(&mut self, state: &ConnectionState) {
+        self.client_mac = MacAddress::from_bytes(&state.client_mac).unwrap_or_default();
+        self.c_seqn = state.c_seqn;
+        self.ackn_p2s = state.ackn_p2s;
+        self.ackn_p2c = state.ackn_p2c;
+        self.c2s_inserted_bytes = state.c2s_inserted_bytes;
+        self.window_shifts = state.window_shifts;
+        self.client_sack = state.client_sack;
+        self.server_sack = state.server_sack;
+        self.c2s_deltas.set_entries(&state.c2s_deltas);
+        self.s2c_deltas.set_entries(&state.s2c_deltas);
+        if self.client_state() != TcpState::Established {
+            self.c_push_state(TcpState::Established);
+        }
+        if self.server_state() != TcpState::Established {
+            self.s_push_state(TcpState::Established);
+        }
+    }
+
     #[inline]
     fn in_use(&self) -> bool {
         self.proxy_port != 0
@@ -235,8 +642,100 @@ impl<'a> ProxyConnection<'a> {
     #[inline]
     fn release(&mut self) {
         self.proxy_port = 0;
+        // frees the mbufs of held back segments
+        self.reorder = None;
+        self.user_data = None;
+        if self.span.is_some() {
+            let cause = self.release_cause();
+            ::tracing::event!(parent: self.span.as_ref().unwrap(), Level::DEBUG, release_cause = ?cause, c2s_bytes = self.c2s_bytes, s2c_bytes = self.s2c_bytes, "released");
+            // closes the span
+            self.span = None;
+        }
         if self.detailed_c.is_some() {
-            self.detailed_c.as_mut().unwrap().release();
+            let detailed_c = self.detailed_c.as_mut().unwrap();
+            detailed_c.set_timing(
+                [self.client_rtt, self.server_rtt],
+                [&self.client_leg, &self.server_leg],
+                self.tried_servers.len() as u8,
+            );
+            detailed_c.set_traffic(self.packets, self.wire_bytes);
+            detailed_c.release();
+        }
+    }
+
+    #[inline]
+    pub fn set_span(&mut self, span: Span) {
+        self.span = Some(span);
+    }
+
+    #[inline]
+    pub fn span(&self) -> Option<&Span> {
+        self.span.as_ref()
+    }
+
+    #[inline]
+    pub fn set_audit(&mut self, audit: bool) {
+        self.audit = audit;
+    }
+
+    /// the transition violating the tables since the last call, see set_audit
+    #[inline]
+    pub fn take_violation(&mut self) -> Option<StateViolation> {
+        self.violation.take()
+    }
+
+    #[inline]
+    fn audit_transition(&mut self, leg: Leg, from: TcpState, to: TcpState) {
+        if self.audit && self.violation.is_none() && !allowed_transition(leg, from, to) {
+            self.violation = Some(StateViolation { leg, from, to });
+        }
+    }
+
+    /// records the selected server in the span of the connection
+    #[inline]
+    pub fn trace_server(&self, server_id: &str) {
+        if self.span.is_some() {
+            self.span.as_ref().unwrap().record("server", &server_id);
+        }
+    }
+
+    /// Called by payload closures to replace the payload of the current segment, which may change its length,
+    /// e.g. to inject a header. The sequence and ack numbers of the following segments of both sides are adjusted.
+    /// Retransmissions of the segment must be rewritten in the same way.
+    #[inline]
+    pub fn rewrite_payload(&mut self, payload: Vec<u8>) {
+        self.payload_rewrite = Some(payload);
+    }
+
+    #[inline]
+    pub fn take_payload_rewrite(&mut self) -> Option<Vec<u8>> {
+        self.payload_rewrite.take()
+    }
+
+    /// Stores data of the closures in the connection, replacing previous data of any type.
+    /// The data is dropped, when the connection is released.
+    #[inline]
+    pub fn set_user_data<T: Any>(&mut self, data: T) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// the data of the closures, None if there is none or if it has a different type
+    #[inline]
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref().and_then(|data| data.downcast_ref::<T>())
+    }
+
+    #[inline]
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut().and_then(|data| data.downcast_mut::<T>())
+    }
+
+    /// removes the data of the closures from the connection, if it has type T
+    pub fn take_user_data<T: Any>(&mut self) -> Option<T> {
+        if self.user_data.as_ref().map_or(false, |data| data.is::<T>()) {
+            self.user_data.take().unwrap().downcast::<T>().ok().map(|data| *data)
+        } else {
+            None
         }
     }
 
@@ -250,18 +749,88 @@ impl<'a> ProxyConnection<'a> {
         self.server_index = index;
     }
 
+    #[inline]
+    pub fn frontend(&self) -> usize {
+        self.frontend as usize
+    }
+
+    #[inline]
+    pub fn set_frontend(&mut self, frontend: u8) {
+        self.frontend = frontend;
+    }
+
+    #[inline]
+    pub fn server_bound(&self) -> bool {
+        self.server_bound
+    }
+
+    /// marks the connection as bound to the selected server, the connection then counts to the load of the server
+    #[inline]
+    pub fn bind_server(&mut self, load: &ServerLoad) {
+        if !self.server_bound {
+            load.inc(self.server_index as usize);
+            self.server_bound = true;
+        }
+    }
+
+    /// the SYN is sent to another server, after the current one failed
+    pub fn rebind_server(&mut self, index: u8, load: &ServerLoad) {
+        self.tried_servers.push(self.server_index);
+        self.unbind_server(load);
+        self.server_index = index;
+        self.bind_server(load);
+    }
+
+    /// true, if the server failed to answer the SYN of this connection
+    #[inline]
+    pub fn tried_server(&self, index: usize) -> bool {
+        self.tried_servers.iter().any(|i| *i as usize == index)
+    }
+
+    /// number of further servers the SYN was sent to
+    #[inline]
+    pub fn syn_retries(&self) -> u8 {
+        self.tried_servers.len() as u8
+    }
+
+    #[inline]
+    fn unbind_server(&mut self, load: &ServerLoad) {
+        if self.server_bound {
+            load.dec(self.server_index as usize);
+            self.server_bound = false;
+        }
+    }
+
+    /// the IPv4 client socket, None for IPv6 clients
     #[inline]
     pub fn sock(&self) -> Option<(u32, u16)> {
-        let s = (self.client_ip, self.client_port);
-        if s.0 != 0 {
-            Some(s)
+        match key_to_v4(self.client_ip) {
+            Some(ip) if ip != 0 => Some((ip, self.client_port)),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    pub fn client_sock(&self) -> Option<ClientSock> {
+        if self.client_ip != 0 {
+            Some((self.client_ip, self.client_port))
         } else {
             None
         }
     }
 
     #[inline]
-    pub fn set_sock(&mut self, s: (u32, u16)) {
+    pub fn client_addr(&self) -> Option<(IpAddr, u16)> {
+        self.client_sock().map(|s| (key_to_ip(s.0), s.1))
+    }
+
+    #[inline]
+    pub fn is_ipv6(&self) -> bool {
+        self.client_ip != 0 && key_to_v4(self.client_ip).is_none()
+    }
+
+    #[inline]
+    pub fn set_sock(&mut self, s: ClientSock) {
         self.client_ip = s.0;
         self.client_port = s.1;
     }
@@ -281,7 +850,13 @@ impl<'a> ProxyConnection<'a> {
         if self.detailed_c.is_some() {
             self.detailed_c.as_mut().unwrap().c_push_state(state)
         }
+        if self.span.is_some() {
+            ::tracing::event!(parent: self.span.as_ref().unwrap(), Level::TRACE, client_state = ?state);
+        }
+        let from = self.client_state();
+        self.audit_transition(Leg::Client, from, state);
         self.client_state = state as u8;
+        self.spliced = state == TcpState::Established && self.server_state() == TcpState::Established;
     }
 
     #[inline]
@@ -289,7 +864,19 @@ impl<'a> ProxyConnection<'a> {
         if self.detailed_c.is_some() {
             self.detailed_c.as_mut().unwrap().s_push_state(state)
         }
+        if self.span.is_some() {
+            ::tracing::event!(parent: self.span.as_ref().unwrap(), Level::TRACE, server_state = ?state);
+        }
+        let from = self.server_state();
+        self.audit_transition(Leg::Server, from, state);
         self.server_state = state as u8;
+        self.spliced = state == TcpState::Established && self.client_state() == TcpState::Established;
+    }
+
+    /// true, while client and server side are established, i.e. packets without SYN, FIN or RST only need to be translated
+    #[inline]
+    pub fn spliced(&self) -> bool {
+        self.spliced
     }
 
     #[inline]
@@ -311,6 +898,40 @@ impl<'a> ProxyConnection<'a> {
         if self.detailed_c.is_some() {
             self.detailed_c.as_mut().unwrap().set_release_cause(cause)
         }
+        self.release_cause = cause as u8;
+    }
+
+    #[inline]
+    pub fn release_cause(&self) -> ReleaseCause {
+        ReleaseCause::from(self.release_cause)
+    }
+
+    #[inline]
+    pub fn close_reason(&self) -> CloseReason {
+        CloseReason::from(self.close_reason)
+    }
+
+    /// sets the close reason, if the current one is replaceable, see CloseReason::replaceable
+    #[inline]
+    pub fn set_close_reason(&mut self, reason: CloseReason) {
+        if self.close_reason().replaceable() {
+            self.close_reason = reason as u8;
+            if self.detailed_c.is_some() {
+                self.detailed_c.as_mut().unwrap().set_close_reason(reason);
+            }
+        }
+    }
+
+    /// closes both legs as reset by the proxy and records that the operator killed the connection,
+    /// the caller sends the RSTs and releases the port
+    pub fn kill(&mut self) {
+        self.set_release_cause(ReleaseCause::ActiveRst);
+        self.s_set_release_cause(ReleaseCause::ActiveRst);
+        self.set_close_reason(CloseReason::OperatorKill);
+        self.c_push_state(TcpState::Closed);
+        if self.server_bound() {
+            self.s_push_state(TcpState::Closed);
+        }
     }
 
     #[inline]
@@ -341,19 +962,21 @@ const ERR_NO_CON_RECORD: &str = "connection has no ConRecord";
 
 impl DetailedConnection {
     #[inline]
-    fn initialize(&mut self, client_sock: &(u32, u16), proxy_sport: u16) {
+    fn initialize(&mut self, client_sock: &ClientSock, proxy_sport: u16) {
+        // the record store keeps IPv4 sockets only
+        let sock = key_to_v4(client_sock.0).map(|ip| (ip, client_sock.1));
         self.store()
             .borrow_mut()
             .get_mut(self.con_rec())
-            .init(TcpRole::Client, proxy_sport, Some(*client_sock));
+            .init(TcpRole::Client, proxy_sport, sock);
         // the server side record is initialized when SYN is sent to server
     }
 
-    fn new(store: &Rc<RefCell<ProxyRecStore>>) -> DetailedConnection {
-        let con_rec = store.borrow_mut().get_next_slot();
+    /// without a record, for the arena of a connection manager
+    fn unused() -> DetailedConnection {
         DetailedConnection {
-            con_rec: Some(con_rec),
-            store: Some(Rc::clone(store)),
+            con_rec: None,
+            store: None,
         }
     }
 
@@ -418,6 +1041,21 @@ impl DetailedConnection {
         self.store().borrow_mut().get_mut_1(self.con_rec()).set_release_cause(cause)
     }
 
+    #[inline]
+    fn set_close_reason(&mut self, reason: CloseReason) {
+        self.store().borrow_mut().get_mut_1(self.con_rec()).set_close_reason(reason)
+    }
+
+    #[inline]
+    fn set_timing(&mut self, syn_rtt: [Option<u64>; 2], legs: [&LegTiming; 2], syn_retries: u8) {
+        self.store().borrow_mut().get_mut_1(self.con_rec()).set_timing(syn_rtt, legs, syn_retries)
+    }
+
+    #[inline]
+    fn set_traffic(&mut self, packets: [u64; 2], wire_bytes: [u64; 2]) {
+        self.store().borrow_mut().get_mut_1(self.con_rec()).set_traffic(packets, wire_bytes)
+    }
+
     #[inline]
     fn release(&mut self) {
         //trace!("releasing con record on port {}", self.port());
@@ -437,29 +1075,247 @@ pub trait Connection {
     fn c_push_state(&mut self, state: TcpState);
 }
 
+#[derive(Deserialize, Clone)]
+pub struct RateLimitConfig {
+    /// new connections per second and client address
+    pub rate: u32,
+    /// number of connections a client may open at once, defaults to rate
+    pub burst: Option<u32>,
+    /// if true, SYNs above the rate are dropped silently, otherwise they are answered with a RST
+    pub drop: Option<bool>,
+}
+
+/// Token bucket per client address for new connections. It is implemented as GCRA (virtual scheduling),
+/// i.e. we only keep the theoretical arrival time of the next connection per client.
+pub struct RateLimiter {
+    /// cycles per token
+    interval: u64,
+    /// cycles the theoretical arrival time may be ahead of now, i.e. (burst - 1) * interval
+    tolerance: u64,
+    drop: bool,
+    tat: BTreeMap<u128, u64>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, cpu_clock: u64) -> RateLimiter {
+        let rate = if config.rate == 0 { 1 } else { config.rate as u64 };
+        let burst = config.burst.unwrap_or(config.rate) as u64;
+        let interval = cpu_clock / rate;
+        RateLimiter {
+            interval,
+            tolerance: if burst > 1 { (burst - 1) * interval } else { 0 },
+            drop: config.drop.unwrap_or(false),
+            tat: BTreeMap::new(),
+        }
+    }
+
+    /// consumes a token of the client, returns false if the bucket is empty
+    #[inline]
+    pub fn allow(&mut self, client: u128, now: u64) -> bool {
+        let tat = self.tat.entry(client).or_insert(now);
+        let t = if *tat > now { *tat } else { now };
+        if t - now > self.tolerance {
+            false
+        } else {
+            *tat = t + self.interval;
+            true
+        }
+    }
+
+    /// forgets clients with a full bucket
+    pub fn expire(&mut self, now: u64) {
+        let tolerance = self.tolerance;
+        let expired: Vec<u128> = self
+            .tat
+            .iter()
+            .filter(|(_, tat)| **tat + tolerance < now)
+            .map(|(client, _)| *client)
+            .collect();
+        for client in expired {
+            self.tat.remove(&client);
+        }
+    }
+
+    #[inline]
+    pub fn drops(&self) -> bool {
+        self.drop
+    }
+}
+
+/// summary of a connection in use, e.g. for listing the connections by the admin api
+#[derive(Clone, Debug)]
+pub struct LiveConnection {
+    pub uuid: Uuid,
+    pub port: u16,
+    /// source address of the proxy towards the server
+    pub source_ip: Ipv4Addr,
+    pub client: Option<(IpAddr, u16)>,
+    /// None, if no server is selected yet
+    pub server_index: Option<usize>,
+    pub client_state: TcpState,
+    pub server_state: TcpState,
+}
+
+/// identifies a connection in the queries of the admin api and the control channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionKey {
+    Uuid(Uuid),
+    /// address and port of the client
+    Client(IpAddr, u16),
+}
+
+/// Further source addresses of the proxy towards the targets. Each address is used with all proxy ports of a pipeline,
+/// so that a pipeline can have as many concurrent connections as it has ports times addresses. As the ports of the
+/// pipelines are disjoint, the pipelines share the addresses without collisions. The NIC must steer the packets
+/// towards the pool addresses by their destination port like those towards the address of the pipeline, and the
+/// addresses must be configured on the KNI interface, so that ARP requests are answered. Not used in transparent mode.
+#[derive(Deserialize, Clone)]
+pub struct SourcePoolConfig {
+    pub ips: Vec<Ipv4Addr>,
+    /// if present, only proxy ports from min_port are used, e.g. to leave lower ports to other services
+    pub min_port: Option<u16>,
+    /// if present, only proxy ports up to max_port are used
+    pub max_port: Option<u16>,
+}
+
+/// TIME_WAIT of the slots on the server leg: the slot of a released connection, which had a server leg, is only
+/// reused after 2MSL. Thus late segments of the previous connection are not taken for segments of a new connection
+/// with the same source, and the server has left the TIME_WAIT of the previous connection.
+#[derive(Deserialize, Clone)]
+pub struct TimeWaitConfig {
+    /// milli-seconds, defaults to 60000 like the TIME_WAIT of Linux
+    pub two_msl: Option<u64>,
+}
+
+impl TimeWaitConfig {
+    /// 2MSL in cycles
+    #[inline]
+    pub fn two_msl_cycles(&self, cpu_clock: u64) -> u64 {
+        self.two_msl.unwrap_or(DEFAULT_TWO_MSL_MS) * cpu_clock / 1000
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RecordRetention {
+    /// maximum number of connection records per generation
+    pub max_records: Option<usize>,
+    /// maximum age (seconds) of a generation of connection records
+    pub max_age: Option<u64>,
+}
+
+/// With detailed_records, only a sample of the connections gets a connection record.
+#[derive(Deserialize, Clone)]
+pub struct RecordSampling {
+    /// one in this number of connections is recorded, defaults to 1, i.e. all connections
+    pub one_in: Option<u32>,
+    /// pipeline id, as shown by the stats, -> one_in of the pipeline
+    pub pipelines: Option<HashMap<String, u32>>,
+    /// if true, failed connections, i.e. those reset, timed out or without server (see CloseReason::is_failure), which
+    /// are not in the sample, get a record with their final states on release, defaults to true
+    pub failures: Option<bool>,
+}
+
+impl RecordSampling {
+    /// one_in of the pipeline
+    pub fn one_in(&self, pipeline: &str) -> u32 {
+        self.pipelines
+            .as_ref()
+            .and_then(|pipelines| pipelines.get(pipeline).cloned())
+            .or(self.one_in)
+            .unwrap_or(1)
+    }
+
+    #[inline]
+    pub fn failures(&self) -> bool {
+        self.failures.unwrap_or(true)
+    }
+}
+
+/// slots of the connections, whose timer has expired
+pub fn expired_timers(now: &u64, wheel: &mut CancellableWheel<Slot>) -> Vec<Slot> {
+    let mut slots = Vec::new();
+    // cancelled timeouts are already skipped by the drain
+    wheel.tick_all(now, &mut |slot| slots.push(slot));
+    slots
+}
+
+/// true, if dst is the address of the proxy or one of the source addresses of the pipeline towards the servers,
+/// i.e. with a source pool the servers also reply to the addresses of the pool
+#[inline]
+pub fn addressed_to_proxy(dst: u32, proxy_ip: u32, source_ips: &[u32]) -> bool {
+    dst == proxy_ip || source_ips.contains(&dst)
+}
+
+/// the slots of all source addresses with the ports first..=last, the ports in random order
+fn shuffled_slots(sources: usize, first: u16, last: u16) -> VecDeque<Slot> {
+    let mut ports = if first < last { shuffle_ports(first, last - 1) } else { Vec::new() };
+    // need to add last port this way to avoid overflow with slice, when last == 65535
+    ports.push(last);
+    ports
+        .iter()
+        .flat_map(|port| (0..sources).map(move |source| (source as Slot) << 16 | *port as Slot))
+        .collect()
+}
+
 pub static GLOBAL_MANAGER_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 pub struct ConnectionManager<'a> {
     record_store: Rc<RefCell<ProxyRecStore>>,
     //    sock2port: Sock2Index,
-    sock2port: BTreeMap<(u32, u16), u16>,
+    sock2slot: SockTable,
     #[cfg(feature = "profiling")]
     time_adder: TimeAdder,
     //sock2port: HashMap<(u32, u16), u16>,
-    free_ports: VecDeque<u16>,
-    port2con: Vec<ProxyConnection<'a>>,
+    free_slots: VecDeque<Slot>,
+    slot2con: Vec<ProxyConnection<'a>>,
     pci: PortQueue,
     // the PortQueue for which connections are managed
     tcp_port_base: u16,
+    /// number of proxy ports of this manager
+    ports: usize,
     ip: u32,
     // ip address to use for connections of this manager/pipeline  towards the servers
+    /// ip and the addresses of the source pool, indexed by the upper bits of the slots
+    source_ips: Vec<u32>,
     detailed_records: bool,
+    server_load: ServerLoad,
+    rate_limiter: Option<RateLimiter>,
+    counters: PipelineCounters,
+    /// connections of all pipelines
+    counts: ConnectionCounts,
+    events: Option<EventSender>,
+    flows: Option<FlowSender>,
+    /// tsc when the current record store was created
+    store_created: u64,
+    /// record stores rotated out, but not yet fetched, live connections may still update their records
+    retired: VecDeque<Rc<RefCell<ProxyRecStore>>>,
+    /// (max_records, max_age in cycles)
+    retention: Option<(Option<usize>, Option<u64>)>,
+    /// random upper half of the uuids of the connections of this manager
+    uuid_base: u64,
+    /// number of connections opened, part of the uuids
+    opened: u64,
+    /// slots in TIME_WAIT and 2MSL in cycles, see TimeWaitConfig
+    time_wait: Option<(CancellableWheel<Slot>, u64)>,
+    /// see EngineConfig.protocol_counters
+    protocol_counters: Option<ProtocolCounters>,
+    /// (one_in, failures), see RecordSampling
+    sampling: Option<(u64, bool)>,
+    /// the boxes of the detailed connections, which are not used by a slot
+    details: BoxArena<DetailedConnection>,
 }
 
 const MAX_RECORDS: usize = 0x3FFFF as usize;
 
 impl<'a> ConnectionManager<'a> {
-    pub fn new(pci: PortQueue, l4flow: L4Flow, detailed_records: bool) -> ConnectionManager<'a> {
+    pub fn new(
+        pci: PortQueue,
+        l4flow: L4Flow,
+        detailed_records: bool,
+        server_load: ServerLoad,
+        rate_limiter: Option<RateLimiter>,
+        counts: ConnectionCounts,
+    ) -> ConnectionManager<'a> {
         let old_manager_count: u16 = GLOBAL_MANAGER_COUNT.fetch_add(1, Ordering::SeqCst) as u16;
         let (ip, tcp_port_base) = (l4flow.ip, l4flow.port);
         let port_mask = pci.port.get_tcp_dst_port_mask();
@@ -469,22 +1325,39 @@ impl<'a> ConnectionManager<'a> {
         let mut cm = ConnectionManager {
             record_store: store.clone(),
             //            sock2port: Sock2Index::new(),
-            sock2port: BTreeMap::new(),
+            sock2slot: SockTable::BTree(BTreeMap::new()),
             #[cfg(feature = "profiling")]
             time_adder: TimeAdder::new_with_warm_up("connection initialize", 100000, 100),
-            free_ports: {
-                let vec = shuffle_ports(if tcp_port_base == 0 { 1 } else { tcp_port_base }, max_tcp_port - 1);
-                VecDeque::<u16>::from(vec)
-            }, // port 0 is reserved and not usable for us
-            port2con: Vec::with_capacity(!port_mask as usize + 1),
+            // port 0 is reserved and not usable for us
+            free_slots: shuffled_slots(1, if tcp_port_base == 0 { 1 } else { tcp_port_base }, max_tcp_port),
+            slot2con: Vec::with_capacity(!port_mask as usize + 1),
             pci,
             tcp_port_base,
+            ports: !port_mask as usize + 1,
             ip,
+            source_ips: vec![ip],
             detailed_records,
+            server_load,
+            rate_limiter,
+            counters: PipelineCounters::default(),
+            counts,
+            events: None,
+            flows: None,
+            store_created: unsafe { _rdtsc() },
+            retired: VecDeque::new(),
+            retention: None,
+            uuid_base: Uuid::new_v4().as_u128() as u64,
+            opened: 0,
+            time_wait: None,
+            protocol_counters: None,
+            sampling: None,
+            details: if detailed_records {
+                BoxArena::with_capacity(!port_mask as usize + 1, DetailedConnection::unused)
+            } else {
+                BoxArena::new()
+            },
         };
-        cm.port2con = vec![ProxyConnection::new(); !port_mask as usize + 1];
-        // need to add last port this way to avoid overflow with slice, when max_tcp_port == 65535
-        cm.free_ports.push_back(max_tcp_port);
+        cm.slot2con = vec![ProxyConnection::new(); !port_mask as usize + 1];
         info!(
             "created ConnectionManager {} (detailed_records = {:?}) for port {}, rxq {}, ip= {}, tcp ports {} - {}",
             old_manager_count,
@@ -498,9 +1371,104 @@ impl<'a> ConnectionManager<'a> {
         cm
     }
 
+    /// close events of released connections are sent to the exporter
+    pub fn set_event_sender(&mut self, events: Option<EventSender>) {
+        self.events = events;
+    }
+
+    /// released connections are counted per target and protocol
+    pub fn enable_protocol_counters(&mut self) {
+        self.protocol_counters = Some(ProtocolCounters::new());
+    }
+
     #[inline]
-    fn get_mut_con(&mut self, p: &u16) -> &mut ProxyConnection<'a> {
-        &mut self.port2con[(p - self.tcp_port_base) as usize]
+    pub fn protocol_counters(&self) -> Option<&ProtocolCounters> {
+        self.protocol_counters.as_ref()
+    }
+
+    /// released connections are sent to the IPFIX exporter
+    pub fn set_flow_sender(&mut self, flows: Option<FlowSender>) {
+        self.flows = flows;
+    }
+
+    /// Records are kept in generations, a generation ends when it has max_records records or is older than max_age.
+    /// Only the current and the previous generation are retained, older ones are evicted if they have not been fetched.
+    pub fn set_record_retention(&mut self, retention: &RecordRetention, cpu_clock: u64) {
+        self.retention = Some((retention.max_records, retention.max_age.map(|a| a * cpu_clock)));
+    }
+
+    /// only a sample of the connections gets a record, must be called before the first connection is opened
+    pub fn set_record_sampling(&mut self, sampling: &RecordSampling, pipeline: &str) {
+        self.sampling = Some((sampling.one_in(pipeline) as u64, sampling.failures()));
+    }
+
+    /// Adds the addresses of the pool to the source addresses towards the servers, must be called before the first
+    /// connection is opened and before set_connection_table.
+    pub fn set_source_pool(&mut self, config: &SourcePoolConfig) {
+        assert_eq!(self.sock2slot.len(), 0);
+        for ip in &config.ips {
+            let ip = u32::from(*ip);
+            if !self.source_ips.contains(&ip) && self.source_ips.len() < MAX_SOURCE_IPS {
+                self.source_ips.push(ip);
+            }
+        }
+        let max_tcp_port = self.tcp_port_base + !self.pci.port.get_tcp_dst_port_mask();
+        let first = cmp::max(cmp::max(self.tcp_port_base, 1), config.min_port.unwrap_or(0));
+        let last = cmp::min(max_tcp_port, config.max_port.unwrap_or(max_tcp_port));
+        self.slot2con = vec![ProxyConnection::new(); self.ports * self.source_ips.len()];
+        self.free_slots = if first <= last {
+            shuffled_slots(self.source_ips.len(), first, last)
+        } else {
+            VecDeque::new()
+        };
+        info!(
+            "rxq={}: {} source addresses, tcp ports {} - {}, {} slots",
+            self.pci.rxq(),
+            self.source_ips.len(),
+            first,
+            last,
+            self.free_slots.len()
+        );
+    }
+
+    /// slots of released connections with a server leg are reused after the TIME_WAIT,
+    /// must be called before the first connection is opened
+    pub fn set_time_wait(&mut self, config: &TimeWaitConfig, cpu_clock: u64) {
+        let two_msl = config.two_msl_cycles(cpu_clock);
+        self.time_wait = Some((
+            CancellableWheel::new(
+                TIME_WAIT_WHEEL_SLOTS,
+                cmp::max(two_msl / (TIME_WAIT_WHEEL_SLOTS as u64 - 2), 1),
+                self.slot2con.len() / TIME_WAIT_WHEEL_SLOTS + 1,
+                cpu_clock,
+            ),
+            two_msl,
+        ));
+        info!("rxq={}: TIME_WAIT of {} ms", self.pci.rxq(), two_msl * 1000 / cpu_clock);
+    }
+
+    /// replaces the table of the client sockets, must be called before the first connection is opened
+    pub fn set_connection_table(&mut self, config: &ConnectionTableConfig) {
+        assert_eq!(self.sock2slot.len(), 0);
+        self.sock2slot = SockTable::new(config, self.slot2con.len());
+        info!(
+            "rxq={}: connection table {:?}, max_flows= {:?}, load_factor= {:?}",
+            self.pci.rxq(),
+            config.kind,
+            config.max_flows,
+            config.load_factor
+        );
+    }
+
+    #[inline]
+    fn index(&self, slot: Slot) -> usize {
+        (slot >> 16) as usize * self.ports + (slot as u16 - self.tcp_port_base) as usize
+    }
+
+    #[inline]
+    fn get_mut_con(&mut self, slot: Slot) -> &mut ProxyConnection<'a> {
+        let i = self.index(slot);
+        &mut self.slot2con[i]
     }
 
     #[inline]
@@ -508,6 +1476,11 @@ impl<'a> ConnectionManager<'a> {
         tcp_port & self.pci.port.get_tcp_dst_port_mask() == self.tcp_port_base
     }
 
+    #[inline]
+    fn owns_slot(&self, slot: Slot) -> bool {
+        self.owns_tcp_port(slot as u16) && ((slot >> 16) as usize) < self.source_ips.len()
+    }
+
     #[inline]
     pub fn tcp_port_base(&self) -> u16 {
         self.tcp_port_base
@@ -518,10 +1491,16 @@ impl<'a> ConnectionManager<'a> {
         self.ip
     }
 
+    /// the source addresses towards the servers, the address of the pipeline first
+    #[inline]
+    pub fn source_ips(&self) -> &Vec<u32> {
+        &self.source_ips
+    }
+
     #[inline]
-    pub fn get_mut_by_port(&mut self, port: u16) -> Option<&mut ProxyConnection<'a>> {
-        if self.owns_tcp_port(port) {
-            let c = self.get_mut_con(&port);
+    pub fn get_mut_by_slot(&mut self, slot: Slot) -> Option<&mut ProxyConnection<'a>> {
+        if self.owns_slot(slot) {
+            let c = self.get_mut_con(slot);
             // check if c is in use
             if c.in_use() {
                 Some(c)
@@ -533,158 +1512,430 @@ impl<'a> ConnectionManager<'a> {
         }
     }
 
-    pub fn get_mut_by_sock(&mut self, sock: &(u32, u16)) -> Option<&mut ProxyConnection<'a>> {
-        let port = self.sock2port.get(sock);
-        if port.is_some() {
-            Some(&mut self.port2con[(port.unwrap() - self.tcp_port_base) as usize])
+    /// the connection with the source address ip and the proxy port, e.g. of a server side packet towards ip and port;
+    /// without a source pool the address is not checked, as it is the client address in transparent mode
+    #[inline]
+    pub fn get_mut_by_source(&mut self, ip: u32, port: u16) -> Option<&mut ProxyConnection<'a>> {
+        let source = if self.source_ips.len() == 1 {
+            Some(0)
+        } else {
+            self.source_ips.iter().position(|s| *s == ip)
+        };
+        match source {
+            Some(source) => self.get_mut_by_slot((source as Slot) << 16 | port as Slot),
+            None => None,
+        }
+    }
+
+    pub fn get_mut_by_sock(&mut self, sock: &ClientSock) -> Option<&mut ProxyConnection<'a>> {
+        let slot = self.sock2slot.get(sock);
+        if slot.is_some() {
+            Some(self.get_mut_con(slot.unwrap()))
         } else {
             None
         }
     }
 
-    pub fn get_mut_or_insert(&mut self, sock: &(u32, u16)) -> Option<&mut ProxyConnection<'a>> {
+    /// the connection in use with this uuid, None, if it is released or owned by another manager
+    pub fn find_by_uuid(&mut self, uuid: &Uuid) -> Option<&mut ProxyConnection<'a>> {
+        let id = uuid.as_u128();
+        if (id >> 64) as u64 != self.uuid_base {
+            return None;
+        }
+        self.get_mut_by_slot(id as Slot).filter(|c| c.uuid == id)
+    }
+
+    pub fn find_by_client_tuple(&mut self, ip: &IpAddr, port: u16) -> Option<&mut ProxyConnection<'a>> {
+        self.get_mut_by_sock(&(ip_to_key(ip), port))
+    }
+
+    pub fn find(&mut self, key: &ConnectionKey) -> Option<&mut ProxyConnection<'a>> {
+        match key {
+            ConnectionKey::Uuid(uuid) => self.find_by_uuid(uuid),
+            ConnectionKey::Client(ip, port) => self.find_by_client_tuple(ip, *port),
+        }
+    }
+
+    pub fn get_mut_or_insert(&mut self, sock: &ClientSock) -> Option<&mut ProxyConnection<'a>> {
         {
-            // we borrow sock2port here !
-            let port = self.sock2port.get(sock);
-            if port.is_some() {
-                let cc = &mut self.port2con[(port.unwrap() - self.tcp_port_base) as usize];
+            // we borrow sock2slot here !
+            let slot = self.sock2slot.get(sock);
+            if slot.is_some() {
+                let cc = self.get_mut_con(slot.unwrap());
                 assert!(cc.in_use());
                 return Some(cc);
             }
         }
-        // now we are free to borrow sock2port mutably
-        let opt_port = self.free_ports.pop_front();
-        if opt_port.is_some() {
-            let port = opt_port.expect("something really weird has happened!");
-            let cc = &mut self.port2con[(port - self.tcp_port_base) as usize];
+        // now we are free to borrow sock2slot mutably
+        let opt_slot = self.free_slots.pop_front();
+        if opt_slot.is_some() {
+            let slot = opt_slot.expect("something really weird has happened!");
+            let port = slot as u16;
+            let source_ip = self.source_ips[(slot >> 16) as usize];
+            // the slot in the lower bits makes the lookup by uuid cheap
+            let uuid = (self.uuid_base as u128) << 64 | (self.opened as u32 as u128) << 32 | slot as u128;
+            self.opened += 1;
+            let i = self.index(slot);
+            let cc = &mut self.slot2con[i];
             assert!(!cc.in_use());
 
             #[cfg(feature = "profiling")]
             let timestamp_entry = utils::rdtscp_unsafe();
 
-            if self.detailed_records {
-                cc.initialize_with_details(sock, port, &self.record_store);
+            let sampled = self.sampling.map_or(true, |(one_in, _)| (self.opened - 1) % one_in == 0);
+            if self.detailed_records && sampled {
+                cc.initialize_with_details(sock, slot, source_ip, &self.record_store, &mut self.details);
             } else {
-                cc.initialize(sock, port);
+                cc.initialize(sock, slot, source_ip);
+                // the record store of the previous connection in this slot
+                if let Some(detailed_c) = cc.detailed_c.take() {
+                    self.details.free(detailed_c);
+                }
             }
 
+            cc.uuid = uuid;
+
             #[cfg(feature = "profiling")]
             self.time_adder.add_diff(utils::rdtscp_unsafe() - timestamp_entry);
 
             debug!(
                 "rxq={}: tcp flow for socket ({},{}) created on {}:{:?}",
                 self.pci.rxq(),
-                sock.0,
+                key_to_ip(sock.0),
                 sock.1,
-                Ipv4Addr::from(self.ip),
+                Ipv4Addr::from(source_ip),
                 port
             );
-            self.sock2port.insert(*sock, port);
+            self.sock2slot.insert(*sock, slot);
+            self.counts.opened();
 
             Some(cc)
         } else {
-            warn!("out of ports");
+            warn!("out of ports, {} ports in TIME_WAIT", self.time_wait_slots());
             None
         }
     }
 
-    pub fn release_port(&mut self, port: u16, wheel: &mut TimerWheel<u16>) {
-        let c = &mut self.port2con[(port - self.tcp_port_base) as usize];
+    pub fn release_slot(&mut self, slot: Slot, wheel: &mut CancellableWheel<Slot>) {
+        let i = self.index(slot);
+        let c = &mut self.slot2con[i];
         // only if it is in use, i.e. it has been not released already
         if c.in_use() {
-            self.free_ports.push_back(port);
-            assert_eq!(port, c.port());
-            //remove port from timer wheel by overwriting it
-            let old = wheel.replace(c.wheel_slot_and_index, 0);
+            let server_leg = c.server_state() >= TcpState::SynReceived;
+            assert_eq!(slot, c.slot());
+            // remove the timeout of the connection from the timer wheel
+            let old = wheel.cancel(&c.timer);
             if old.is_some() {
-                assert_eq!(old.unwrap(), port);
+                assert_eq!(old.unwrap(), slot);
             }
             {
-                let sock = c.sock();
+                let sock = c.client_sock();
                 if sock.is_some() {
-                    let port = self.sock2port.remove(&sock.unwrap());
-                    if port.is_some() {
-                        assert_eq!(port.unwrap(), c.port());
+                    let slot = self.sock2slot.remove(&sock.unwrap());
+                    if slot.is_some() {
+                        assert_eq!(slot.unwrap(), c.slot());
                     }
                 }
             }
+            if self.events.is_some() {
+                self.events.as_ref().unwrap().closed(c);
+            }
+            if self.flows.is_some() {
+                self.flows.as_ref().unwrap().closed(c);
+            }
+            self.counters.close_reasons.count(c.close_reason());
+            let record_failures = self.sampling.map_or(false, |(_, failures)| failures);
+            if record_failures && c.detailed_c.is_none() && c.close_reason().is_failure() {
+                c.record_failure(&self.record_store, &mut self.details);
+            }
+            if self.protocol_counters.is_some() {
+                self.protocol_counters.as_mut().unwrap().closed(c);
+            }
+            c.unbind_server(&self.server_load);
             c.release();
+            self.counts.closed();
+            self.recycle(slot, server_leg);
         }
     }
 
+    /// returns the slot to the free slots, or with a server leg of its connection, to the TIME_WAIT
+    #[inline]
+    fn recycle(&mut self, slot: Slot, server_leg: bool) {
+        if server_leg && self.time_wait.is_some() {
+            let (wheel, two_msl) = self.time_wait.as_mut().unwrap();
+            wheel.schedule(two_msl, slot);
+        } else {
+            self.free_slots.push_back(slot);
+        }
+    }
+
+    /// called on timer ticks, returns the slots, whose TIME_WAIT has passed, to the free slots
+    pub fn expire_time_wait(&mut self) {
+        if self.time_wait.is_some() {
+            let free_slots = &mut self.free_slots;
+            let wheel = &mut self.time_wait.as_mut().unwrap().0;
+            let now = wheel.now();
+            wheel.tick_all(&now, &mut |slot| free_slots.push_back(slot));
+        }
+    }
+
+    /// number of slots in TIME_WAIT
+    #[inline]
+    pub fn time_wait_slots(&self) -> usize {
+        self.time_wait.as_ref().map_or(0, |(wheel, _)| wheel.pending())
+    }
+
     //TODO allow for more precise time out conditions, currently whole TCP connections are timed out, also we should send a RST
-    pub fn release_timeouts(&mut self, now: &u64, wheel: &mut TimerWheel<u16>) {
-        loop {
-            match wheel.tick(now) {
-                (Some(mut drain), more) => {
-                    let mut port = drain.next();
-                    while port.is_some() {
-                        let p = port.unwrap();
-                        if p != 0 {
-                            self.timeout(p);
-                        }
-                        port = drain.next();
-                    }
-                    if !more {
-                        break;
-                    }
-                }
-                (None, more) => {
-                    if !more {
-                        break;
-                    }
-                }
+    /// returns the targets of the released connections, which did not answer the SYN
+    pub fn release_timeouts(&mut self, now: &u64, wheel: &mut CancellableWheel<Slot>) -> Vec<usize> {
+        let mut unanswered = Vec::new();
+        for slot in expired_timers(now, wheel) {
+            if let Some(target) = self.timeout(slot) {
+                unanswered.push(target);
             }
         }
+        unanswered
     }
 
+    /// the target of the connection, if it did not answer the SYN
     #[inline]
-    fn timeout(&mut self, port: u16) {
+    fn timeout(&mut self, slot: Slot) -> Option<usize> {
+        let mut unanswered = None;
         let mut release = false;
+        let mut server_leg = false;
         let mut sock = None;
+        let mut reason = CloseReason::Unknown;
+        let server_load = self.server_load.clone();
+        let events = self.events.clone();
+        let flows = self.flows.clone();
+        let mut protocol_counters = self.protocol_counters.take();
+        let record_failures = self.sampling.map_or(false, |(_, failures)| failures);
+        let record_store = self.record_store.clone();
+        let mut details = mem::replace(&mut self.details, BoxArena::new());
         {
-            let c = self.get_mut_by_port(port);
+            let c = self.get_mut_by_slot(slot);
             if c.is_some() {
                 let c = c.unwrap();
+                if c.server_bound() && c.server_state() == TcpState::SynReceived {
+                    unanswered = Some(c.server_index());
+                }
+                server_leg = c.server_state() >= TcpState::SynReceived;
                 c.set_release_cause(ReleaseCause::Timeout);
+                c.set_close_reason(CloseReason::IdleTimeout);
+                reason = c.close_reason();
                 c.c_push_state(TcpState::Closed);
                 warn!(
                     "timing out port {}, sock {:?} at {:?}",
-                    port,
-                    c.sock().unwrap_or((0, 0)),
-                    c.wheel_slot_and_index
+                    c.port(),
+                    c.client_addr(),
+                    c.timer
                 );
-                sock = c.sock();
+                sock = c.client_sock();
+                if record_failures && c.detailed_c.is_none() && reason.is_failure() {
+                    c.record_failure(&record_store, &mut details);
+                }
+                if events.is_some() {
+                    events.as_ref().unwrap().closed(c);
+                }
+                if flows.is_some() {
+                    flows.as_ref().unwrap().closed(c);
+                }
+                if protocol_counters.is_some() {
+                    protocol_counters.as_mut().unwrap().closed(c);
+                }
+                c.unbind_server(&server_load);
                 c.release();
                 release = true;
             }
         }
+        self.protocol_counters = protocol_counters;
+        self.details = details;
         if release {
-            self.free_ports.push_back(port);
+            self.counters.close_reasons.count(reason);
+            self.counts.closed();
             if sock.is_some() {
-                self.sock2port.remove(&sock.unwrap());
+                self.sock2slot.remove(&sock.unwrap());
             }
+            self.recycle(slot, server_leg);
         }
+        unanswered
+    }
+
+    /// boxes of detailed connections allocated in the data path, because the arena was empty
+    #[inline]
+    pub fn arena_misses(&self) -> u64 {
+        self.details.misses()
+    }
+
+    #[inline]
+    pub fn counters(&self) -> &PipelineCounters {
+        &self.counters
     }
 
-    pub fn fetch_c_records(&mut self) -> Option<ProxyRecStore> {
+    #[inline]
+    pub fn counters_mut(&mut self) -> &mut PipelineCounters {
+        &mut self.counters
+    }
+
+    /// checks the rate limit of the client for a new connection
+    #[inline]
+    pub fn admit_syn(&mut self, client: u128) -> bool {
+        if self.rate_limiter.is_none() {
+            return true;
+        }
+        let allowed = self.rate_limiter.as_mut().unwrap().allow(client, unsafe { _rdtsc() });
+        if !allowed {
+            self.counters.syn_rate_limited += 1;
+        }
+        allowed
+    }
+
+    /// true, if SYNs above the rate limit are dropped instead of answered with a RST
+    #[inline]
+    pub fn rate_limit_drops(&self) -> bool {
+        self.rate_limiter.as_ref().map_or(false, |r| r.drops())
+    }
+
+    pub fn expire_rate_limits(&mut self) {
+        if self.rate_limiter.is_some() {
+            self.rate_limiter.as_mut().unwrap().expire(unsafe { _rdtsc() });
+        }
+    }
+
+    /// number of connections currently in use
+    #[inline]
+    pub fn active_connections(&self) -> usize {
+        self.sock2slot.len()
+    }
+
+    pub fn live_connections(&self) -> Vec<LiveConnection> {
+        self.sock2slot
+            .slots()
+            .into_iter()
+            .map(|slot| self.slot2con[self.index(slot)].live_connection())
+            .collect()
+    }
+
+    /// the states of the spliced connections, see HaConfig
+    pub fn connection_states(&self) -> Vec<ConnectionState> {
+        self.sock2slot
+            .slots()
+            .into_iter()
+            .map(|slot| &self.slot2con[self.index(slot)])
+            .filter(|c| c.spliced())
+            .map(|c| c.connection_state())
+            .collect()
+    }
+
+    /// Creates or updates the connection of a state replicated by the active engine, with the slot of the active
+    /// engine. The timer of the connection is restarted with timeout. Returns false, if the slot is not free,
+    /// e.g. because it is in TIME_WAIT, or if the client socket has another slot.
+    pub fn restore(&mut self, state: &ConnectionState, timeout: u64, wheel: &mut CancellableWheel<Slot>) -> bool {
+        if !self.owns_slot(state.slot) {
+            return false;
+        }
+        let sock = (state.client_ip, state.client_port);
+        match self.sock2slot.get(&sock) {
+            Some(slot) if slot != state.slot => return false,
+            Some(_) => (),
+            None => {
+                let position = self.free_slots.iter().position(|slot| *slot == state.slot);
+                if position.is_none() {
+                    return false;
+                }
+                self.free_slots.remove(position.unwrap());
+                let source_ip = self.source_ips[(state.slot >> 16) as usize];
+                let uuid = (self.uuid_base as u128) << 64 | (self.opened as u32 as u128) << 32 | state.slot as u128;
+                self.opened += 1;
+                let i = self.index(state.slot);
+                let cc = &mut self.slot2con[i];
+                if self.detailed_records {
+                    cc.initialize_with_details(&sock, state.slot, source_ip, &self.record_store, &mut self.details);
+                } else {
+                    cc.initialize(&sock, state.slot, source_ip);
+                }
+                cc.uuid = uuid;
+                cc.set_server_index(state.server_index);
+                cc.set_frontend(state.frontend);
+                cc.bind_server(&self.server_load);
+                self.sock2slot.insert(sock, state.slot);
+                self.counts.opened();
+            }
+        }
+        let cc = self.get_mut_con(state.slot);
+        cc.take_over(state);
+        wheel.cancel(&cc.timer);
+        cc.timer = wheel.schedule(&timeout, state.slot);
+        cc.last_activity = unsafe { _rdtsc() };
+        true
+    }
+
+    /// releases all connections in use, e.g. when the drain deadline has passed
+    pub fn release_all(&mut self, cause: ReleaseCause, reason: CloseReason, wheel: &mut CancellableWheel<Slot>) {
+        let slots: Vec<Slot> = self.sock2slot.slots();
+        for slot in slots {
+            {
+                let c = self.get_mut_con(slot);
+                c.set_release_cause(cause);
+                c.set_close_reason(reason);
+                c.c_push_state(TcpState::Closed);
+            }
+            self.release_slot(slot, wheel);
+        }
+    }
+
+    fn rotate_records(&mut self, now: u64) {
         // we are "moving" the record_store out, and replace it with a new one
         debug!("records in record_store = {}", self.record_store.borrow().len());
         let new_store = Rc::new(RefCell::new(ProxyRecStore::with_capacity(MAX_RECORDS)));
         let old_store = mem::replace(&mut self.record_store, new_store);
-        let strong_count_c = Rc::strong_count(&old_store);
-        debug!("fetch_c_records: strong_count= {}", strong_count_c);
-        // we should have only one reference per store, if every connection was released
-        if strong_count_c > 1 {
-            for c in &mut self.port2con {
-                c.release();
+        self.retired.push_back(old_store);
+        self.store_created = now;
+    }
+
+    /// called on timer ticks, applies the retention policy
+    pub fn enforce_record_retention(&mut self, now: u64) {
+        if self.retention.is_none() {
+            return;
+        }
+        let (max_records, max_age) = self.retention.unwrap();
+        let len = self.record_store.borrow().len();
+        if max_records.map_or(false, |max| len >= max) || max_age.map_or(false, |max| now - self.store_created >= max) {
+            if len > 0 {
+                self.rotate_records(now);
+            } else {
+                self.store_created = now;
             }
         }
-        let unwrapped_c = Rc::try_unwrap(old_store);
-        if unwrapped_c.is_ok() {
-            Some(unwrapped_c.unwrap().into_inner())
-        } else {
-            None
+        while self.retired.len() > 1 {
+            let evicted = self.retired.pop_front().unwrap();
+            debug!("record retention: evicting {} records", evicted.borrow().len());
+        }
+    }
+
+    /// Returns the records of all generations and starts a new one. Generations with records of live connections
+    /// are kept, they are returned by a later call after the connections have been released.
+    pub fn snapshot_and_clear_records(&mut self) -> Vec<ProxyRecStore> {
+        self.rotate_records(unsafe { _rdtsc() });
+        let mut snapshot = Vec::new();
+        let mut still_referenced = VecDeque::new();
+        while let Some(store) = self.retired.pop_front() {
+            match Rc::try_unwrap(store) {
+                Ok(store) => snapshot.push(store.into_inner()),
+                Err(store) => still_referenced.push_back(store),
+            }
+        }
+        debug!("snapshot_and_clear_records: {} stores, {} still referenced", snapshot.len(), still_referenced.len());
+        self.retired = still_referenced;
+        snapshot
+    }
+
+    /// releases all connection states and returns all records
+    pub fn fetch_c_records(&mut self) -> Vec<ProxyRecStore> {
+        // we should have only one reference per store, if every connection was released
+        for c in &mut self.slot2con {
+            c.release();
         }
+        self.snapshot_and_clear_records()
     }
 }
@@ -0,0 +1,299 @@
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::io::{self, BufRead, BufReader, Write};
+use std::fs;
+use std::thread;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use log::LevelFilter;
+
+use uuid::Uuid;
+
+use {Configuration, SharedState, CaptureFilter};
+use cmanager::ConnectionKey;
+use drain::wait_until_target_quiesced;
+use ipv6::ip_to_key;
+use logcontrol::{set_log_level, reset_log_levels, log_overrides};
+
+const QUIESCE_POLL: Duration = Duration::from_millis(100);
+/// time the pipelines get to answer a connection lookup, they answer on their next timer tick
+const LOOKUP_WAIT: Duration = Duration::from_millis(100);
+
+fn quiesced(id: &str, removed: bool) -> String {
+    if removed {
+        format!("target {} removed while draining\n", id)
+    } else {
+        format!("target {} quiesced\n", id)
+    }
+}
+
+fn limit_to_string(limit: Option<usize>) -> String {
+    limit.map_or("-".to_string(), |l| l.to_string())
+}
+
+fn utilization(shared: &SharedState, max_connections: Option<usize>, max_per_target: Option<usize>) -> String {
+    let mut reply = format!(
+        "connections {}/{}\n",
+        shared.connections.total(),
+        limit_to_string(max_connections)
+    );
+    for (i, entry) in shared.targets.targets().iter().enumerate() {
+        if !entry.active {
+            continue;
+        }
+        reply.push_str(&format!(
+            "target {} {}/{}{}{}{}{}\n",
+            entry.config.id,
+            shared.connections.target(i),
+            limit_to_string(entry.config.max_connections.map(|m| m as usize).or(max_per_target)),
+            if shared.target_health.is_up(i) { "" } else { " down" },
+            if shared.target_health.is_disabled(i) { " disabled" } else { "" },
+            if shared.breakers.is_open(i) { " tripped" } else { "" },
+            if entry.config.is_fallback() { " fallback" } else { "" },
+        ));
+    }
+    reply
+}
+
+fn stats(shared: &SharedState) -> String {
+    let mut reply = String::new();
+    for (pipeline_id, counters) in shared.stats.snapshot() {
+        reply.push_str(&format!("{}: {}\n", pipeline_id, counters));
+    }
+    reply.push_str(&format!("total: {}\n", shared.stats.total()));
+    for (core, load) in shared.stats.cores() {
+        reply.push_str(&format!("core {}: {}\n", core, load));
+    }
+    reply
+}
+
+fn alerts(shared: &SharedState) -> String {
+    let alerts = shared.watchdog.alerts();
+    if alerts.is_empty() {
+        return "no alerts, see engine.watchdog\n".to_string();
+    }
+    alerts.iter().map(|alert| format!("{}\n", alert)).collect()
+}
+
+fn latency(shared: &SharedState) -> String {
+    let mut reply = String::new();
+    for (pipeline_id, latencies) in shared.latencies.snapshot() {
+        reply.push_str(&format!("{}: {}\n", pipeline_id, latencies));
+    }
+    match shared.latencies.total() {
+        Some(total) => reply.push_str(&format!("total: {}\n", total)),
+        None => reply.push_str("no latencies recorded, see engine.latency_histograms\n"),
+    }
+    reply
+}
+
+/// capture client <ip> <file> | capture target <id> <file> | capture stop
+fn capture(shared: &SharedState, args: &[&str]) -> String {
+    let usage = "usage: capture client <ip> <file> | capture target <id> <file> | capture stop\n".to_string();
+    if args.len() == 1 && args[0] == "stop" {
+        shared.capture.stop();
+        return "capture stopped\n".to_string();
+    }
+    if args.len() != 3 {
+        return usage;
+    }
+    let filter = match args[0] {
+        "client" => match args[1].parse::<IpAddr>() {
+            Ok(ip) => CaptureFilter::Client(ip_to_key(&ip)),
+            Err(e) => return format!("invalid client address {}: {}\n", args[1], e),
+        },
+        "target" => {
+            if !shared.targets.targets().iter().any(|t| t.active && t.config.id == args[1]) {
+                return format!("unknown target {}\n", args[1]);
+            }
+            CaptureFilter::Target(args[1].to_string())
+        }
+        _ => return usage,
+    };
+    match shared.capture.start(filter, args[2]) {
+        Ok(()) => format!("capturing into {}\n", args[2]),
+        Err(e) => format!("cannot create {}: {}\n", args[2], e),
+    }
+}
+
+/// key of the connection commands: uuid <uuid> | client <ip> <port>
+fn connection_key(command: &str, args: &[&str]) -> Result<ConnectionKey, String> {
+    match args {
+        ["uuid", uuid] => Uuid::parse_str(uuid)
+            .map(ConnectionKey::Uuid)
+            .map_err(|e| format!("invalid uuid {}: {}\n", uuid, e)),
+        ["client", ip, port] => {
+            let ip = ip.parse::<IpAddr>().map_err(|e| format!("invalid client address {}: {}\n", ip, e))?;
+            let port = port.parse::<u16>().map_err(|e| format!("invalid client port {}: {}\n", port, e))?;
+            Ok(ConnectionKey::Client(ip, port))
+        }
+        _ => Err(format!("usage: {0} uuid <uuid> | {0} client <ip> <port>\n", command)),
+    }
+}
+
+/// connection uuid <uuid> | connection client <ip> <port>, kill with the same arguments resets the connection on both legs
+fn connection(shared: &SharedState, args: &[&str], kill: bool) -> String {
+    let key = match connection_key(if kill { "kill" } else { "connection" }, args) {
+        Ok(key) => key,
+        Err(e) => return e,
+    };
+    let found = if kill {
+        shared.lookup.kill(key, LOOKUP_WAIT)
+    } else {
+        shared.lookup.find(key, LOOKUP_WAIT)
+    };
+    match found {
+        Some((pipeline_id, c)) => {
+            let targets = shared.targets.targets();
+            if kill {
+                info!("control channel: killed connection {} on {}", c.uuid, pipeline_id);
+            }
+            format!(
+                "{} {} on {} source {}:{} client {} target {} states {:?}/{:?}\n",
+                if kill { "killed connection" } else { "connection" },
+                c.uuid,
+                pipeline_id,
+                c.source_ip,
+                c.port,
+                c.client.map_or("-".to_string(), |(ip, port)| format!("{}:{}", ip, port)),
+                c.server_index
+                    .and_then(|i| targets.get(i))
+                    .map_or("-".to_string(), |entry| entry.config.id.clone()),
+                c.client_state,
+                c.server_state,
+            )
+        }
+        None => format!("no connection {}\n", args.join(" ")),
+    }
+}
+
+/// drain_target <id> [wait]: the target gets no new connections, its existing connections complete.
+/// With wait, the reply is sent when the target has no connections anymore, otherwise this is logged.
+/// The target is enabled again by the admin api, see POST /targets/<id>/enable.
+fn drain_target(shared: &SharedState, args: &[&str]) -> String {
+    if args.is_empty() || args.len() > 2 || args.len() == 2 && args[1] != "wait" {
+        return "usage: drain_target <id> [wait]\n".to_string();
+    }
+    let id = args[0].to_string();
+    let index = match shared.targets.targets().iter().position(|t| t.active && t.config.id == id) {
+        Some(i) => i,
+        None => return format!("unknown target {}\n", id),
+    };
+    shared.target_health.set_disabled(index, true);
+    info!("control channel: draining target {}, {} connections", id, shared.connections.target(index));
+    if args.len() == 2 {
+        let removed = !wait_until_target_quiesced(&shared.targets, &shared.connections, &id, QUIESCE_POLL);
+        quiesced(&id, removed)
+    } else {
+        let reply = format!("draining target {}, {} connections\n", id, shared.connections.target(index));
+        let shared = shared.clone();
+        thread::spawn(move || {
+            let removed = !wait_until_target_quiesced(&shared.targets, &shared.connections, &id, QUIESCE_POLL);
+            info!("{}", quiesced(&id, removed).trim_end());
+        });
+        reply
+    }
+}
+
+/// log | log reset | log <module> <level> [<seconds>] [core <core>]: the level replaces the filter of RUST_LOG for the
+/// module, e.g. "log nftcp debug 60 core 2" logs the debug messages of the pipelines on core 2 for one minute
+fn log_level(args: &[&str]) -> String {
+    let usage = "usage: log | log reset | log <module> <level> [<seconds>] [core <core>]\n".to_string();
+    let result = match args {
+        [] => log_overrides().map(|overrides| {
+            let now = Instant::now();
+            let mut reply = String::new();
+            for o in overrides {
+                reply.push_str(&format!(
+                    "{} {}{}{}\n",
+                    o.module,
+                    o.level,
+                    o.until
+                        .filter(|until| *until > now)
+                        .map_or(String::new(), |until| format!(" for {}s", (until - now).as_secs())),
+                    o.core.map_or(String::new(), |core| format!(" on core {}", core)),
+                ));
+            }
+            if reply.is_empty() {
+                reply.push_str("no log level overrides\n");
+            }
+            reply
+        }),
+        ["reset"] => reset_log_levels().map(|_| "log levels reset\n".to_string()),
+        _ if args.len() >= 2 => {
+            let level = match args[1].parse::<LevelFilter>() {
+                Ok(level) => level,
+                Err(_) => return format!("invalid level {}\n", args[1]),
+            };
+            let mut rest = &args[2..];
+            let mut duration = None;
+            if !rest.is_empty() && rest[0] != "core" {
+                match rest[0].parse::<u64>() {
+                    Ok(seconds) => duration = Some(Duration::from_secs(seconds)),
+                    Err(e) => return format!("invalid seconds {}: {}\n", rest[0], e),
+                }
+                rest = &rest[1..];
+            }
+            let core = match rest {
+                [] => None,
+                ["core", core] => match core.parse::<i32>() {
+                    Ok(core) => Some(core),
+                    Err(e) => return format!("invalid core {}: {}\n", core, e),
+                },
+                _ => return usage,
+            };
+            info!("control channel: log level of {} set to {}", args[0], level);
+            set_log_level(args[0], level, duration, core)
+                .map(|_| format!("log level of {} set to {}\n", args[0], level))
+        }
+        _ => return usage,
+    };
+    result.unwrap_or_else(|e| format!("{}\n", e))
+}
+
+fn serve(stream: UnixStream, shared: &SharedState, max_connections: Option<usize>, max_per_target: Option<usize>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let reply = match words.first() {
+            Some(&"utilization") => utilization(shared, max_connections, max_per_target),
+            Some(&"stats") => stats(shared),
+            Some(&"latency") => latency(shared),
+            Some(&"alerts") => alerts(shared),
+            Some(&"capture") => capture(shared, &words[1..]),
+            Some(&"drain_target") => drain_target(shared, &words[1..]),
+            Some(&"connection") => connection(shared, &words[1..], false),
+            Some(&"kill") => connection(shared, &words[1..], true),
+            Some(&"log") => log_level(&words[1..]),
+            None => continue,
+            Some(&"quit") => break,
+            Some(command) => format!("unknown command {}, commands: utilization, stats, latency, alerts, capture, drain_target, connection, kill, log, quit\n", command),
+        };
+        writer.write_all(reply.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Starts the control channel for operators: a unix domain socket, which accepts one text command per line,
+/// e.g. `echo utilization | socat - UNIX-CONNECT:/var/run/proxyengine.sock`
+pub fn spawn_control_server(path: &str, configuration: &Configuration, shared: SharedState) -> io::Result<thread::JoinHandle<()>> {
+    // remove a stale socket of a previous run
+    let _ = fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    let max_connections = configuration.engine.max_connections.map(|m| m as usize);
+    let max_per_target = configuration.engine.max_connections_per_target.map(|m| m as usize);
+    info!("control channel listening on {}", path);
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = serve(stream, &shared, max_connections, max_per_target) {
+                        debug!("control channel: {}", e);
+                    }
+                }
+                Err(e) => error!("control channel: accept failed: {}", e),
+            }
+        }
+    }))
+}
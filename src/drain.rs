@@ -0,0 +1,116 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+use std::arch::x86_64::_rdtsc;
+
+use netfcts::comm::PipelineId;
+
+use limits::ConnectionCounts;
+use reload::TargetTable;
+
+/// Controls the draining of the engine: when draining, pipelines refuse new connections and report the number of their active connections.
+/// Connections still active at the deadline are released by the pipelines.
+#[derive(Clone)]
+pub struct DrainControl {
+    draining: Arc<AtomicBool>,
+    /// tsc value at which remaining connections are released
+    deadline: Arc<AtomicU64>,
+    active: Arc<Mutex<HashMap<PipelineId, usize>>>,
+    /// number of pipelines set up, which report while draining
+    pipelines: Arc<AtomicUsize>,
+}
+
+impl DrainControl {
+    pub fn new() -> DrainControl {
+        DrainControl {
+            draining: Arc::new(AtomicBool::new(false)),
+            deadline: Arc::new(AtomicU64::new(u64::max_value())),
+            active: Arc::new(Mutex::new(HashMap::new())),
+            pipelines: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// starts draining, connections still open after drain_cycles are released
+    pub fn start(&self, drain_cycles: u64) {
+        self.deadline
+            .store(unsafe { _rdtsc() } + drain_cycles, Ordering::SeqCst);
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn deadline_passed(&self, now: u64) -> bool {
+        now > self.deadline.load(Ordering::Relaxed)
+    }
+
+    /// called by each pipeline on its setup
+    pub fn add_pipeline(&self) {
+        self.pipelines.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// number of pipelines set up
+    pub fn pipelines(&self) -> usize {
+        self.pipelines.load(Ordering::SeqCst)
+    }
+
+    /// called by the pipelines while draining
+    pub fn report_active(&self, pipeline_id: &PipelineId, active: usize) {
+        self.active.lock().unwrap().insert(pipeline_id.clone(), active);
+    }
+
+    /// sum of active connections reported by the pipelines, None if no pipeline has reported yet
+    pub fn active_connections(&self) -> Option<usize> {
+        let active = self.active.lock().unwrap();
+        if active.is_empty() {
+            None
+        } else {
+            Some(active.values().sum())
+        }
+    }
+
+    /// Blocks until all of the pipelines have reported and report zero active connections or until the deadline has passed
+    /// (plus some grace period, to let the pipelines release the remaining connections). Returns the number of connections
+    /// which were still active.
+    pub fn wait_until_drained(&self, pipelines: usize, poll: Duration, grace: Duration) -> usize {
+        loop {
+            let reported = self.active.lock().unwrap().len();
+            if reported >= pipelines && self.active_connections() == Some(0) {
+                return 0;
+            }
+            if self.deadline_passed(unsafe { _rdtsc() }) {
+                thread::sleep(grace);
+                return self.active_connections().unwrap_or(0);
+            }
+            thread::sleep(poll);
+        }
+    }
+}
+
+/// The index of the active target with this id, which has connections, None when it has no connections anymore.
+/// Err, if there is no such target, e.g. after a reload.
+fn target_connections(targets: &TargetTable, connections: &ConnectionCounts, id: &str) -> Result<usize, ()> {
+    targets
+        .targets()
+        .iter()
+        .position(|t| t.active && t.config.id == id)
+        .map(|i| connections.target(i))
+        .ok_or(())
+}
+
+/// Blocks until the target has no connections anymore, e.g. after it has been disabled for new connections.
+/// Returns false, if the target has been removed meanwhile.
+pub fn wait_until_target_quiesced(targets: &TargetTable, connections: &ConnectionCounts, id: &str, poll: Duration) -> bool {
+    loop {
+        match target_connections(targets, connections, id) {
+            Ok(0) => return true,
+            Ok(_) => thread::sleep(poll),
+            Err(()) => return false,
+        }
+    }
+}
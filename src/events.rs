@@ -0,0 +1,202 @@
+use std::sync::{Arc, Mutex};
+use std::net::IpAddr;
+use std::fs::OpenOptions;
+use std::os::unix::net::UnixStream;
+use std::io::{self, BufWriter, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::arch::x86_64::_rdtsc;
+
+use netfcts::comm::PipelineId;
+use netfcts::tcp_common::ReleaseCause;
+
+use channel::{bounded, BoundedReceiver, BoundedSender, ChannelStats, DEFAULT_QUEUE_SIZE};
+use cmanager::ProxyConnection;
+use close::CloseReason;
+use reload::TargetTable;
+
+/// a failed unix socket is connected again after this time
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize, Clone)]
+pub struct EventExportConfig {
+    /// file, the events are appended to
+    pub file: Option<String>,
+    /// unix domain socket (stream), the events are sent to, e.g. the socket source of a log shipper
+    pub unix_socket: Option<String>,
+    /// events queued for the exporter, further events are dropped, defaults to 65536
+    pub queue_size: Option<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EventKind {
+    /// the server has been selected and the SYN is sent to it
+    Open,
+    /// the connection has been released
+    Close,
+}
+
+/// a connection event as exported, one JSON object per line
+pub struct ConnectionEvent {
+    kind: EventKind,
+    pipeline_id: PipelineId,
+    client: Option<(IpAddr, u16)>,
+    port: u16,
+    server_index: Option<usize>,
+    /// cycles since the connection state was created
+    age: u64,
+    /// cycles from the SYN towards the server until the SYN-ACK
+    server_rtt: Option<u64>,
+    c2s_bytes: u64,
+    s2c_bytes: u64,
+    release_cause: Option<ReleaseCause>,
+    close_reason: Option<CloseReason>,
+}
+
+/// sends the events of a pipeline to the exporter
+#[derive(Clone)]
+pub struct EventSender {
+    tx: BoundedSender<ConnectionEvent>,
+    pipeline_id: PipelineId,
+}
+
+impl EventSender {
+    fn send(&self, kind: EventKind, c: &ProxyConnection) {
+        let event = ConnectionEvent {
+            kind,
+            pipeline_id: self.pipeline_id.clone(),
+            client: c.client_addr(),
+            port: c.port(),
+            server_index: if kind == EventKind::Open || c.server_bound() { Some(c.server_index()) } else { None },
+            age: unsafe { _rdtsc() }.wrapping_sub(c.opened),
+            server_rtt: c.server_rtt,
+            c2s_bytes: c.c2s_bytes,
+            s2c_bytes: c.s2c_bytes,
+            release_cause: if kind == EventKind::Close { Some(c.release_cause()) } else { None },
+            close_reason: if kind == EventKind::Close { Some(c.close_reason()) } else { None },
+        };
+        // dropped, while the exporter falls behind or after it has stopped because of an i/o error
+        self.tx.send(event);
+    }
+
+    #[inline]
+    pub fn opened(&self, c: &ProxyConnection) {
+        self.send(EventKind::Open, c)
+    }
+
+    /// must be called before the connection is released
+    #[inline]
+    pub fn closed(&self, c: &ProxyConnection) {
+        self.send(EventKind::Close, c)
+    }
+}
+
+fn json_option<T: ToString>(value: Option<T>) -> String {
+    value.map_or("null".to_string(), |v| v.to_string())
+}
+
+fn to_micros(cycles: u64, cpu_clock: u64) -> u64 {
+    cycles * 1000 / (cpu_clock / 1000)
+}
+
+fn to_json(event: &ConnectionEvent, targets: &TargetTable, cpu_clock: u64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let target = event
+        .server_index
+        .and_then(|i| targets.targets().get(i).map(|entry| entry.config.id.clone()));
+    format!(
+        "{{\"time\":{}.{:06},\"event\":\"{}\",\"pipeline\":\"{}\",\"client\":{},\"port\":{},\"target\":{},\"age_us\":{},\"server_rtt_us\":{},\"c2s_bytes\":{},\"s2c_bytes\":{},\"release_cause\":{},\"close_reason\":{}}}\n",
+        now.as_secs(),
+        now.subsec_micros(),
+        if event.kind == EventKind::Open { "open" } else { "close" },
+        event.pipeline_id,
+        json_option(event.client.map(|(ip, port)| format!("\"{}:{}\"", ip, port))),
+        event.port,
+        // target ids are plain identifiers from the configuration
+        json_option(target.map(|id| format!("\"{}\"", id.replace('"', "\\\"")))),
+        to_micros(event.age, cpu_clock),
+        json_option(event.server_rtt.map(|rtt| to_micros(rtt, cpu_clock))),
+        event.c2s_bytes,
+        event.s2c_bytes,
+        json_option(event.release_cause.map(|cause| format!("\"{:?}\"", cause))),
+        json_option(event.close_reason.map(|reason| format!("\"{}\"", reason.name()))),
+    )
+}
+
+fn run_exporter(
+    config: EventExportConfig,
+    rx: BoundedReceiver<ConnectionEvent>,
+    targets: TargetTable,
+    cpu_clock: u64,
+) -> io::Result<()> {
+    let mut file = match config.file {
+        Some(ref path) => Some(BufWriter::new(OpenOptions::new().create(true).append(true).open(path)?)),
+        None => None,
+    };
+    let mut socket: Option<UnixStream> = None;
+    let mut last_connect: Option<Instant> = None;
+    while let Ok(event) = rx.recv() {
+        let line = to_json(&event, &targets, cpu_clock);
+        if file.is_some() {
+            let f = file.as_mut().unwrap();
+            f.write_all(line.as_bytes())?;
+            f.flush()?;
+        }
+        if let Some(ref path) = config.unix_socket {
+            if socket.is_none() && last_connect.map_or(true, |t| t.elapsed() >= RECONNECT_INTERVAL) {
+                last_connect = Some(Instant::now());
+                match UnixStream::connect(path) {
+                    Ok(s) => socket = Some(s),
+                    Err(e) => warn!("event export: cannot connect to {}: {}", path, e),
+                }
+            }
+            // events are dropped, while the socket is not connected
+            if socket.is_some() {
+                if let Err(e) = socket.as_mut().unwrap().write_all(line.as_bytes()) {
+                    warn!("event export: {} failed: {}", path, e);
+                    socket = None;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Exports the open and close events of the connections of all pipelines as JSON lines (NDJSON).
+#[derive(Clone)]
+pub struct EventExporter {
+    tx: Arc<Mutex<Option<BoundedSender<ConnectionEvent>>>>,
+}
+
+impl EventExporter {
+    pub fn new() -> EventExporter {
+        EventExporter {
+            tx: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// starts the exporter thread, pipelines must be set up afterwards to get a sender
+    pub fn start(&self, config: &EventExportConfig, targets: TargetTable, cpu_clock: u64) -> thread::JoinHandle<()> {
+        let (tx, rx) = bounded(config.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE));
+        *self.tx.lock().unwrap() = Some(tx);
+        let config = config.clone();
+        thread::spawn(move || {
+            if let Err(e) = run_exporter(config, rx, targets, cpu_clock) {
+                error!("event export stopped: {}", e);
+            }
+        })
+    }
+
+    /// None, if events are not exported
+    pub fn sender(&self, pipeline_id: &PipelineId) -> Option<EventSender> {
+        self.tx.lock().unwrap().as_ref().map(|tx| EventSender {
+            tx: tx.clone(),
+            pipeline_id: pipeline_id.clone(),
+        })
+    }
+
+    /// None, if events are not exported
+    pub fn stats(&self) -> Option<ChannelStats> {
+        self.tx.lock().unwrap().as_ref().map(|tx| tx.stats())
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+
+use cmanager::{ClientSock, Slot};
+
+const DEFAULT_LOAD_FACTOR: f64 = 0.5;
+/// entries per cache line
+const BUCKET_ENTRIES: usize = 2;
+/// slot 0, i.e. port 0 of the first source address, is never used for connections, it marks an empty entry
+const EMPTY: Slot = 0;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionTableKind {
+    /// ordered map, the default
+    BTree,
+    /// open-addressed hash table with linear probing in cache-line aligned buckets
+    OpenAddressing,
+}
+
+/// The table of a pipeline, which maps the client sockets to the slots of their connections.
+#[derive(Deserialize, Clone)]
+pub struct ConnectionTableConfig {
+    pub kind: ConnectionTableKind,
+    /// expected maximum number of connections per pipeline, the open-addressed table is sized for it,
+    /// defaults to the number of slots of the pipeline, see SourcePoolConfig
+    pub max_flows: Option<usize>,
+    /// maximum ratio of used to all entries of the open-addressed table, before it is grown, defaults to 0.5
+    pub load_factor: Option<f64>,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    sock: ClientSock,
+    slot: Slot,
+}
+
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+struct Bucket([Entry; BUCKET_ENTRIES]);
+
+/// Open-addressed hash table with linear probing. Removal shifts the following entries back, so that no
+/// tombstones are needed and lookups stop at the first empty entry.
+pub struct FlowTable {
+    buckets: Vec<Bucket>,
+    /// number of entries minus one, the number of entries is a power of two
+    mask: usize,
+    len: usize,
+    load_factor: f64,
+}
+
+impl FlowTable {
+    pub fn new(max_flows: usize, load_factor: f64) -> FlowTable {
+        let entries = ((max_flows as f64 / load_factor).ceil() as usize).max(BUCKET_ENTRIES).next_power_of_two();
+        let empty = Entry {
+            sock: (0, 0),
+            slot: EMPTY,
+        };
+        FlowTable {
+            buckets: vec![Bucket([empty; BUCKET_ENTRIES]); entries / BUCKET_ENTRIES],
+            mask: entries - 1,
+            len: 0,
+            load_factor,
+        }
+    }
+
+    #[inline]
+    fn hash(&self, sock: &ClientSock) -> usize {
+        let folded = (sock.0 as u64) ^ (sock.0 >> 64) as u64 ^ (sock.1 as u64) << 48;
+        (folded.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize & self.mask
+    }
+
+    #[inline]
+    fn entry(&self, i: usize) -> &Entry {
+        &self.buckets[i / BUCKET_ENTRIES].0[i % BUCKET_ENTRIES]
+    }
+
+    #[inline]
+    fn entry_mut(&mut self, i: usize) -> &mut Entry {
+        &mut self.buckets[i / BUCKET_ENTRIES].0[i % BUCKET_ENTRIES]
+    }
+
+    /// the index of the entry of sock, or of the empty entry where it belongs
+    #[inline]
+    fn find(&self, sock: &ClientSock) -> usize {
+        let mut i = self.hash(sock);
+        loop {
+            let e = self.entry(i);
+            if e.slot == EMPTY || e.sock == *sock {
+                return i;
+            }
+            i = (i + 1) & self.mask;
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, sock: &ClientSock) -> Option<Slot> {
+        let e = self.entry(self.find(sock));
+        if e.slot == EMPTY {
+            None
+        } else {
+            Some(e.slot)
+        }
+    }
+
+    pub fn insert(&mut self, sock: ClientSock, slot: Slot) {
+        if (self.len + 1) as f64 > (self.mask + 1) as f64 * self.load_factor {
+            self.grow();
+        }
+        let i = self.find(&sock);
+        if self.entry(i).slot == EMPTY {
+            self.len += 1;
+        }
+        *self.entry_mut(i) = Entry { sock, slot };
+    }
+
+    pub fn remove(&mut self, sock: &ClientSock) -> Option<Slot> {
+        let mut i = self.find(sock);
+        let slot = self.entry(i).slot;
+        if slot == EMPTY {
+            return None;
+        }
+        self.len -= 1;
+        // shift back the following entries of the cluster, which may not stay behind the gap
+        let mut j = i;
+        loop {
+            j = (j + 1) & self.mask;
+            let e = *self.entry(j);
+            if e.slot == EMPTY {
+                break;
+            }
+            let home = self.hash(&e.sock);
+            let stays = if i <= j { i < home && home <= j } else { i < home || home <= j };
+            if !stays {
+                *self.entry_mut(i) = e;
+                i = j;
+            }
+        }
+        self.entry_mut(i).slot = EMPTY;
+        Some(slot)
+    }
+
+    fn grow(&mut self) {
+        warn!("connection table with {} entries is full, doubling its size", self.mask + 1);
+        let entries: Vec<Entry> = self.buckets.iter().flat_map(|b| b.0.iter().cloned()).filter(|e| e.slot != EMPTY).collect();
+        let mut table = FlowTable::new((self.mask + 1) * 2, 1.0);
+        table.load_factor = self.load_factor;
+        for e in entries {
+            table.insert(e.sock, e.slot);
+        }
+        *self = table;
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn slots(&self) -> Vec<Slot> {
+        self.buckets
+            .iter()
+            .flat_map(|b| b.0.iter())
+            .filter(|e| e.slot != EMPTY)
+            .map(|e| e.slot)
+            .collect()
+    }
+}
+
+/// the table of client sockets of a connection manager, see ConnectionTableConfig
+pub enum SockTable {
+    BTree(BTreeMap<ClientSock, Slot>),
+    OpenAddressing(FlowTable),
+}
+
+impl SockTable {
+    pub fn new(config: &ConnectionTableConfig, slots: usize) -> SockTable {
+        match config.kind {
+            ConnectionTableKind::BTree => SockTable::BTree(BTreeMap::new()),
+            ConnectionTableKind::OpenAddressing => SockTable::OpenAddressing(FlowTable::new(
+                config.max_flows.unwrap_or(slots),
+                config.load_factor.unwrap_or(DEFAULT_LOAD_FACTOR),
+            )),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, sock: &ClientSock) -> Option<Slot> {
+        match self {
+            SockTable::BTree(map) => map.get(sock).cloned(),
+            SockTable::OpenAddressing(table) => table.get(sock),
+        }
+    }
+
+    #[inline]
+    pub fn insert(&mut self, sock: ClientSock, slot: Slot) {
+        match self {
+            SockTable::BTree(map) => {
+                map.insert(sock, slot);
+            }
+            SockTable::OpenAddressing(table) => table.insert(sock, slot),
+        }
+    }
+
+    #[inline]
+    pub fn remove(&mut self, sock: &ClientSock) -> Option<Slot> {
+        match self {
+            SockTable::BTree(map) => map.remove(sock),
+            SockTable::OpenAddressing(table) => table.remove(sock),
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        match self {
+            SockTable::BTree(map) => map.len(),
+            SockTable::OpenAddressing(table) => table.len(),
+        }
+    }
+
+    /// the slots of the connections in the table
+    pub fn slots(&self) -> Vec<Slot> {
+        match self {
+            SockTable::BTree(map) => map.values().cloned().collect(),
+            SockTable::OpenAddressing(table) => table.slots(),
+        }
+    }
+}
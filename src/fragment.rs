@@ -0,0 +1,275 @@
+use std::cmp;
+use std::collections::HashMap;
+
+use e2d2::interface::Pdu;
+
+use icmp::internet_checksum;
+use timer::{CancellableWheel, TimerToken};
+
+const DEFAULT_MAX_DATAGRAMS: usize = 256;
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+const REASSEMBLY_WHEEL_SLOTS: usize = 102;
+const MAX_DATAGRAM_SIZE: usize = 65535;
+const DONT_FRAGMENT: u16 = 0x4000;
+const MORE_FRAGMENTS: u16 = 0x2000;
+const OFFSET_MASK: u16 = 0x1fff;
+const TCP_PROTOCOL: u8 = 6;
+
+/// Reassembly of fragmented tcp datagrams to the engine, before they are matched to the connections, and
+/// fragmentation of datagrams without DF bit, which exceed the MTU of the outgoing leg. The fragments must be
+/// steered to the pipeline of the connection, e.g. by flow steering on ip addresses, as the fragments after the
+/// first one carry no tcp header. A reassembled datagram is written into the mbuf of its last fragment, i.e.
+/// it is bounded by the tailroom of the mbuf, see MtuConfig.
+#[derive(Deserialize, Clone)]
+pub struct FragmentConfig {
+    /// incomplete datagrams in reassembly per pipeline, fragments of further datagrams are dropped, defaults to 256
+    pub max_datagrams: Option<usize>,
+    /// incomplete datagrams are dropped after this time (milli-seconds), defaults to 2000
+    pub timeout: Option<u64>,
+    /// larger datagrams towards the clients are fragmented
+    pub mtu_towards_clients: Option<u16>,
+    /// larger datagrams towards the targets are fragmented
+    pub mtu_towards_targets: Option<u16>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FragmentKey {
+    src: u32,
+    dst: u32,
+    id: u16,
+    protocol: u8,
+}
+
+/// the payload of an incomplete datagram
+struct Datagram {
+    data: Vec<u8>,
+    /// received byte ranges of data, ordered by offset
+    ranges: Vec<(usize, usize)>,
+    received: usize,
+    /// known with the last fragment
+    total: Option<usize>,
+    token: TimerToken,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FragmentResult {
+    /// the fragment has been stored, it must be dropped
+    Incomplete,
+    /// the payload of the reassembled datagram
+    Complete(Vec<u8>),
+    /// the fragment is malformed, overlaps another one or exceeds the limits, it must be dropped
+    Dropped,
+}
+
+#[inline]
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+#[inline]
+fn put_u16(bytes: &mut [u8], value: u16) {
+    bytes[0] = (value >> 8) as u8;
+    bytes[1] = value as u8;
+}
+
+#[inline]
+fn header_len(datagram: &[u8]) -> usize {
+    ((datagram[0] & 0x0f) as usize) * 4
+}
+
+/// true, if the ip datagram, starting with its header, is a fragment
+#[inline]
+pub fn is_fragment(datagram: &[u8]) -> bool {
+    datagram.len() >= 20 && be_u16(&datagram[6..8]) & (MORE_FRAGMENTS | OFFSET_MASK) != 0
+}
+
+fn update_ip_checksum(datagram: &mut [u8]) {
+    let ihl = header_len(datagram);
+    put_u16(&mut datagram[10..12], 0);
+    let checksum = internet_checksum(&datagram[..ihl]);
+    put_u16(&mut datagram[10..12], checksum);
+}
+
+/// computes the tcp checksum of the complete datagram in software, as the NIC cannot compute it for the fragments
+fn update_tcp_checksum(datagram: &mut [u8]) {
+    let ihl = header_len(datagram);
+    let segment_len = datagram.len() - ihl;
+    put_u16(&mut datagram[ihl + 16..ihl + 18], 0);
+    let mut bytes = Vec::with_capacity(12 + segment_len);
+    bytes.extend_from_slice(&datagram[12..20]);
+    bytes.extend_from_slice(&[0, TCP_PROTOCOL, (segment_len >> 8) as u8, segment_len as u8]);
+    bytes.extend_from_slice(&datagram[ihl..]);
+    let checksum = internet_checksum(&bytes);
+    put_u16(&mut datagram[ihl + 16..ihl + 18], checksum);
+}
+
+/// the incomplete datagrams of a pipeline
+pub struct Reassembly {
+    datagrams: HashMap<FragmentKey, Datagram>,
+    wheel: CancellableWheel<FragmentKey>,
+    max_datagrams: usize,
+    timeout: u64,
+}
+
+impl Reassembly {
+    pub fn new(config: &FragmentConfig, cpu_clock: u64) -> Reassembly {
+        let max_datagrams = config.max_datagrams.unwrap_or(DEFAULT_MAX_DATAGRAMS);
+        let timeout = config.timeout.unwrap_or(DEFAULT_TIMEOUT_MS) * cpu_clock / 1000;
+        Reassembly {
+            datagrams: HashMap::with_capacity(max_datagrams),
+            wheel: CancellableWheel::new(
+                REASSEMBLY_WHEEL_SLOTS,
+                cmp::max(timeout / (REASSEMBLY_WHEEL_SLOTS as u64 - 2), 1),
+                max_datagrams / REASSEMBLY_WHEEL_SLOTS + 1,
+                cpu_clock,
+            ),
+            max_datagrams,
+            timeout,
+        }
+    }
+
+    /// adds the fragment, i.e. the ip datagram starting with its header, to its datagram
+    pub fn add(&mut self, fragment: &[u8]) -> FragmentResult {
+        if fragment.len() < 20 {
+            return FragmentResult::Dropped;
+        }
+        let ihl = header_len(fragment);
+        let length = cmp::min(be_u16(&fragment[2..4]) as usize, fragment.len());
+        if ihl < 20 || length < ihl {
+            return FragmentResult::Dropped;
+        }
+        let flags_offset = be_u16(&fragment[6..8]);
+        let more = flags_offset & MORE_FRAGMENTS != 0;
+        let start = (flags_offset & OFFSET_MASK) as usize * 8;
+        let payload = &fragment[ihl..length];
+        let end = start + payload.len();
+        if end + ihl > MAX_DATAGRAM_SIZE || more && payload.len() % 8 != 0 || payload.is_empty() {
+            return FragmentResult::Dropped;
+        }
+        let key = FragmentKey {
+            src: (fragment[12] as u32) << 24 | (fragment[13] as u32) << 16 | (fragment[14] as u32) << 8 | fragment[15] as u32,
+            dst: (fragment[16] as u32) << 24 | (fragment[17] as u32) << 16 | (fragment[18] as u32) << 8 | fragment[19] as u32,
+            id: be_u16(&fragment[4..6]),
+            protocol: fragment[9],
+        };
+        if !self.datagrams.contains_key(&key) {
+            if self.datagrams.len() >= self.max_datagrams {
+                return FragmentResult::Dropped;
+            }
+            let token = self.wheel.schedule(&self.timeout, key);
+            self.datagrams.insert(
+                key,
+                Datagram {
+                    data: Vec::new(),
+                    ranges: Vec::new(),
+                    received: 0,
+                    total: None,
+                    token,
+                },
+            );
+        }
+        let consistent = {
+            let datagram = self.datagrams.get_mut(&key).unwrap();
+            if datagram.ranges.iter().any(|&range| range == (start, end)) {
+                // a retransmitted fragment
+                return FragmentResult::Incomplete;
+            }
+            let overlaps = datagram.ranges.iter().any(|&(s, e)| start < e && s < end);
+            let beyond_total = datagram.total.map_or(false, |total| end > total || !more && end != total);
+            let before_last = !more && datagram.ranges.last().map_or(false, |&(_, e)| e > end);
+            if overlaps || beyond_total || before_last {
+                false
+            } else {
+                if datagram.data.len() < end {
+                    datagram.data.resize(end, 0);
+                }
+                datagram.data[start..end].copy_from_slice(payload);
+                let position = datagram.ranges.iter().position(|&(s, _)| s > start).unwrap_or(datagram.ranges.len());
+                datagram.ranges.insert(position, (start, end));
+                datagram.received += payload.len();
+                if !more {
+                    datagram.total = Some(end);
+                }
+                true
+            }
+        };
+        let complete = consistent && {
+            let datagram = &self.datagrams[&key];
+            datagram.total == Some(datagram.received)
+        };
+        if !consistent || complete {
+            let datagram = self.datagrams.remove(&key).unwrap();
+            self.wheel.cancel(&datagram.token);
+            if complete {
+                return FragmentResult::Complete(datagram.data);
+            }
+            return FragmentResult::Dropped;
+        }
+        FragmentResult::Incomplete
+    }
+
+    /// called on timer ticks, drops the datagrams, whose timeout has passed, returns their number
+    pub fn expire(&mut self) -> usize {
+        let datagrams = &mut self.datagrams;
+        let now = self.wheel.now();
+        self.wheel.tick_all(&now, &mut |key| {
+            datagrams.remove(&key);
+        })
+    }
+
+    /// number of incomplete datagrams
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.datagrams.len()
+    }
+}
+
+/// Replaces the payload of the fragment in p by the reassembled payload and clears the fragment offset.
+/// Returns false, if the payload does not fit into the tailroom of p.
+pub fn set_reassembled_payload(p: &mut Pdu, payload: &[u8]) -> bool {
+    let in_mbuf = p.get_payload(1).len();
+    if payload.len() > in_mbuf {
+        if p.get_tailroom() < payload.len() - in_mbuf {
+            return false;
+        }
+        p.add_padding(payload.len() - in_mbuf);
+    }
+    p.get_payload_mut(1)[..payload.len()].copy_from_slice(payload);
+    let header = p.get_payload_mut(0);
+    let ihl = header_len(header);
+    put_u16(&mut header[2..4], (ihl + payload.len()) as u16);
+    put_u16(&mut header[6..8], 0);
+    true
+}
+
+/// The fragments of the ip datagram, starting with its header, which fit into the mtu. None, if the datagram
+/// fits, is a fragment itself or has the DF bit set. The fragments keep the options of the header.
+pub fn fragments(datagram: &[u8], mtu: u16) -> Option<Vec<Vec<u8>>> {
+    let length = cmp::min(be_u16(&datagram[2..4]) as usize, datagram.len());
+    let ihl = header_len(datagram);
+    if length <= mtu as usize || be_u16(&datagram[6..8]) & (DONT_FRAGMENT | MORE_FRAGMENTS | OFFSET_MASK) != 0 {
+        return None;
+    }
+    let chunk_size = (mtu as usize - ihl) / 8 * 8;
+    if chunk_size == 0 {
+        return None;
+    }
+    let mut whole = datagram[..length].to_vec();
+    if whole[9] == TCP_PROTOCOL {
+        update_tcp_checksum(&mut whole);
+    }
+    let payload = &whole[ihl..];
+    let mut fragments = Vec::with_capacity(payload.len() / chunk_size + 1);
+    for (i, chunk) in payload.chunks(chunk_size).enumerate() {
+        let mut fragment = Vec::with_capacity(ihl + chunk.len());
+        fragment.extend_from_slice(&whole[..ihl]);
+        fragment.extend_from_slice(chunk);
+        let last = (i + 1) * chunk_size >= payload.len();
+        let flags_offset = (i * chunk_size / 8) as u16 | if last { 0 } else { MORE_FRAGMENTS };
+        put_u16(&mut fragment[2..4], (ihl + chunk.len()) as u16);
+        put_u16(&mut fragment[6..8], flags_offset);
+        update_ip_checksum(&mut fragment);
+        fragments.push(fragment);
+    }
+    Some(fragments)
+}
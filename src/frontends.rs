@@ -0,0 +1,67 @@
+use reload::TargetEntry;
+use socks5::Socks5Config;
+use Configuration;
+
+/// A proxy service of the engine in addition to the one on engine.port, e.g. "api" on port 8443 with the targets of
+/// the backend "api". The frontends listen on the address of the engine, i.e. of the KNI interface. Their ports must
+/// be below the proxy ports of the server side, like engine.port. Connections of a frontend are only proxied to the
+/// targets of its backend. The sni_map, alpn_map and http_routes of the engine apply to all frontends.
+/// Frontends are set up at startup, a reload may only add targets with the ids listed in the backends.
+#[derive(Deserialize, Clone)]
+pub struct FrontendConfig {
+    pub name: String,
+    pub port: u16,
+    /// name of the backend in Configuration.backends
+    pub backend: String,
+    /// if present, clients of this frontend negotiate the target with SOCKS5, instead of engine.socks5
+    pub socks5: Option<Socks5Config>,
+}
+
+/// The listening ports by frontend, index 0 is engine.port, followed by the frontends in the configured order.
+pub fn frontend_ports(configuration: &Configuration) -> Vec<u16> {
+    let mut ports = vec![configuration.engine.port];
+    if configuration.frontends.is_some() {
+        ports.extend(configuration.frontends.as_ref().unwrap().iter().map(|f| f.port));
+    }
+    ports
+}
+
+/// The target ids of the backend of each frontend, None for engine.port, which proxies to all targets.
+pub fn backend_targets(configuration: &Configuration) -> Vec<Option<Vec<String>>> {
+    let mut targets = vec![None];
+    if configuration.frontends.is_some() {
+        targets.extend(configuration.frontends.as_ref().unwrap().iter().map(|f| {
+            Some(
+                configuration
+                    .backends
+                    .as_ref()
+                    .and_then(|backends| backends.get(&f.backend))
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+        }));
+    }
+    targets
+}
+
+/// The targets of a backend, see backend_targets. The other targets are marked inactive, so that the indices remain
+/// those of the target table.
+pub fn backend_entries(targets: &Vec<TargetEntry>, backend: &Option<Vec<String>>) -> Vec<TargetEntry> {
+    targets
+        .iter()
+        .map(|t| {
+            let mut t = t.clone();
+            t.active = t.active && backend.as_ref().map_or(true, |ids| ids.contains(&t.config.id));
+            t
+        })
+        .collect()
+}
+
+/// The SOCKS5 configuration of each frontend, engine.socks5 for engine.port.
+pub fn socks5_configs(configuration: &Configuration) -> Vec<Option<Socks5Config>> {
+    let mut configs = vec![configuration.engine.socks5.clone()];
+    if configuration.frontends.is_some() {
+        configs.extend(configuration.frontends.as_ref().unwrap().iter().map(|f| f.socks5.clone()));
+    }
+    configs
+}
@@ -0,0 +1,62 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use eui48::MacAddress;
+use ipnet::IpNet;
+
+use TargetConfig;
+
+/// Gateway towards targets, which are not on the local L2 segment of the engine: frames to these targets are sent
+/// to the MAC address of the gateway. Only targets without mac and linux_if are routed.
+#[derive(Deserialize, Clone)]
+pub struct GatewayConfig {
+    pub ip: Ipv4Addr,
+    /// if absent, the MAC address of the gateway is resolved by ARP, see ArpConfig
+    pub mac: Option<MacAddress>,
+    /// the local network of the engine, e.g. "192.168.222.0/24", targets outside are routed;
+    /// if absent, all targets without mac and linux_if are routed
+    pub local_network: Option<String>,
+}
+
+impl GatewayConfig {
+    #[inline]
+    pub fn ipv4(&self) -> u32 {
+        u32::from(self.ip)
+    }
+
+    /// true, if the target is reached via the gateway
+    pub fn routes(&self, target: &TargetConfig) -> bool {
+        if target.mac.is_some() || target.linux_if.is_some() {
+            return false;
+        }
+        match self.local_network.as_ref().map(|net| net.parse::<IpNet>()) {
+            Some(Ok(net)) => !net.contains(&target.ip),
+            Some(Err(_)) => {
+                error!("gateway: invalid local_network {}", self.local_network.as_ref().unwrap());
+                false
+            }
+            None => match target.ip {
+                IpAddr::V4(_) => true,
+                IpAddr::V6(_) => false,
+            },
+        }
+    }
+}
+
+/// The address, whose MAC address is resolved by ARP for the target, i.e. the address of the target or of the gateway.
+/// None, if the MAC address is configured, for the target or for its gateway.
+pub fn next_hop(target: &TargetConfig, gateway: &Option<GatewayConfig>) -> Option<u32> {
+    if target.mac.is_some() || target.linux_if.is_some() {
+        return None;
+    }
+    match gateway.as_ref() {
+        Some(gateway) if gateway.routes(target) => {
+            if gateway.mac.is_some() {
+                None
+            } else {
+                Some(gateway.ipv4())
+            }
+        }
+        _ if target.ipv4() != 0 => Some(target.ipv4()),
+        _ => None,
+    }
+}
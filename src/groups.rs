@@ -0,0 +1,153 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use health::TargetHealth;
+use reload::{TargetEntry, TargetTable};
+
+const NO_GROUP: usize = usize::max_value();
+const DEFAULT_ROLLBACK_WINDOW_MS: u64 = 30000;
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Named groups of targets, see TargetConfig.group, e.g. "blue" and "green": only the targets of the active group
+/// and the targets without group are selected for new connections. The active group is switched by the admin api,
+/// a reload keeps the active group.
+#[derive(Deserialize, Clone)]
+pub struct TargetGroupsConfig {
+    /// the group, which is active at startup
+    pub active: String,
+    /// milli-seconds after a switch, in which the switch is rolled back when no target of the new group is up,
+    /// defaults to 30000; requires health checks
+    pub rollback_window: Option<u64>,
+}
+
+/// The active target group, shared by the pipelines and the admin api.
+/// Groups are identified by their index in names, names are never removed, so that the switch is a single store.
+#[derive(Clone)]
+pub struct TargetGroups {
+    names: Arc<RwLock<Vec<String>>>,
+    active: Arc<AtomicUsize>,
+    previous: Arc<AtomicUsize>,
+    switched: Arc<Mutex<Option<Instant>>>,
+}
+
+impl TargetGroups {
+    pub fn new(config: &Option<TargetGroupsConfig>) -> TargetGroups {
+        let groups = TargetGroups {
+            names: Arc::new(RwLock::new(Vec::new())),
+            active: Arc::new(AtomicUsize::new(NO_GROUP)),
+            previous: Arc::new(AtomicUsize::new(NO_GROUP)),
+            switched: Arc::new(Mutex::new(None)),
+        };
+        if config.is_some() {
+            let id = groups.id(&config.as_ref().unwrap().active);
+            groups.active.store(id, Ordering::Release);
+        }
+        groups
+    }
+
+    /// the id of the group, a new group gets a new id
+    pub fn id(&self, name: &str) -> usize {
+        if let Some(id) = self.names.read().unwrap().iter().position(|n| n == name) {
+            return id;
+        }
+        let mut names = self.names.write().unwrap();
+        match names.iter().position(|n| n == name) {
+            Some(id) => id,
+            None => {
+                names.push(name.to_string());
+                names.len() - 1
+            }
+        }
+    }
+
+    fn name(&self, id: usize) -> Option<String> {
+        self.names.read().unwrap().get(id).cloned()
+    }
+
+    #[inline]
+    pub fn is_active(&self, id: usize) -> bool {
+        self.active.load(Ordering::Acquire) == id
+    }
+
+    pub fn active(&self) -> Option<String> {
+        self.name(self.active.load(Ordering::Acquire))
+    }
+
+    /// the names of the groups of the active targets
+    pub fn groups(&self, targets: &[TargetEntry]) -> Vec<String> {
+        let mut groups: Vec<String> = targets
+            .iter()
+            .filter(|t| t.active && t.config.group.is_some())
+            .map(|t| t.config.group.clone().unwrap())
+            .collect();
+        groups.sort();
+        groups.dedup();
+        groups
+    }
+
+    fn any_up(name: &str, targets: &[TargetEntry], health: &TargetHealth) -> bool {
+        targets
+            .iter()
+            .enumerate()
+            .any(|(i, t)| t.active && t.config.group.as_ref().map_or(false, |g| g == name) && health.is_up(i))
+    }
+
+    /// Makes the group active for new connections, existing connections keep their server.
+    /// A group without a target, which is up, is not activated.
+    pub fn switch(&self, name: &str, targets: &[TargetEntry], health: &TargetHealth) -> Result<(), String> {
+        if !self.groups(targets).iter().any(|g| g == name) {
+            return Err(format!("unknown target group {}", name));
+        }
+        if !TargetGroups::any_up(name, targets, health) {
+            return Err(format!("no target of group {} is up", name));
+        }
+        let id = self.id(name);
+        let previous = self.active.swap(id, Ordering::AcqRel);
+        if previous != id {
+            self.previous.store(previous, Ordering::Release);
+            *self.switched.lock().unwrap() = Some(Instant::now());
+            info!("target group {} is active, previous group {:?}", name, self.name(previous));
+        }
+        Ok(())
+    }
+
+    /// Switches back to the previous group, if the last switch is not older than window and no target of the
+    /// active group is up. Returns the name of the group switched back to.
+    pub fn rollback_if_down(&self, targets: &[TargetEntry], health: &TargetHealth, window: Duration) -> Option<String> {
+        let mut switched = self.switched.lock().unwrap();
+        if switched.is_none() || switched.unwrap().elapsed() > window {
+            return None;
+        }
+        let active = self.active()?;
+        if TargetGroups::any_up(&active, targets, health) {
+            return None;
+        }
+        let previous = self.previous.load(Ordering::Acquire);
+        self.active.store(previous, Ordering::Release);
+        *switched = None;
+        warn!("rollback of target group {}: no target is up", active);
+        self.name(previous)
+    }
+}
+
+/// Starts a thread, which rolls back a switch of the target group, when the health checks of the new group fail
+/// within the rollback window.
+pub fn spawn_group_watcher(
+    groups: TargetGroups,
+    target_table: TargetTable,
+    health: TargetHealth,
+    config: TargetGroupsConfig,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let window = Duration::from_millis(config.rollback_window.unwrap_or(DEFAULT_ROLLBACK_WINDOW_MS));
+        info!("target group watcher started, rollback window= {:?}", window);
+        loop {
+            thread::sleep(WATCH_INTERVAL);
+            if let Some(previous) = groups.rollback_if_down(&target_table.targets(), &health, window) {
+                info!("target group {} is active again", previous);
+            }
+        }
+    })
+}
@@ -0,0 +1,222 @@
+use http::{HttpRequest, host_without_port};
+
+/// the client connection preface of HTTP/2 over cleartext tcp (h2c with prior knowledge)
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_SIZE: usize = 9;
+const FRAME_HEADERS: u8 = 0x1;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+/// entries of the HPACK static table, RFC 7541 Appendix A
+const STATIC_AUTHORITY: usize = 1;
+const STATIC_METHOD_GET: usize = 2;
+const STATIC_METHOD_POST: usize = 3;
+const STATIC_PATH_ROOT: usize = 4;
+const STATIC_PATH_INDEX: usize = 5;
+const STATIC_HOST: usize = 38;
+const STATIC_TABLE_SIZE: usize = 61;
+
+/// code lengths 0 to 30: number of codes of this length
+const HUFFMAN_COUNTS: [u16; 31] = [
+    0, 0, 0, 0, 0, 10, 26, 32, 6, 0, 5, 3, 2, 6, 2, 3, 0, 0, 0, 3, 8, 13, 26, 29, 12, 4, 15, 19, 29, 0, 4,
+];
+
+/// the symbols ordered by code length and symbol, 256 is EOS
+const HUFFMAN_SYMBOLS: [u16; 257] = [
+    48, 49, 50, 97, 99, 101, 105, 111, 115, 116, 32, 37, 45, 46, 47, 51, 52, 53, 54, 55, 56, 57, 61, 65, 95, 98,
+    100, 102, 103, 104, 108, 109, 110, 112, 114, 117, 58, 66, 67, 68, 69, 70, 71, 72, 73, 74, 75, 76, 77, 78, 79,
+    80, 81, 82, 83, 84, 85, 86, 87, 89, 106, 107, 113, 118, 119, 120, 121, 122, 38, 42, 44, 59, 88, 90, 33, 34, 40,
+    41, 63, 39, 43, 124, 35, 62, 0, 36, 64, 91, 93, 126, 94, 125, 60, 96, 123, 92, 195, 208, 128, 130, 131, 162,
+    184, 194, 224, 226, 153, 161, 167, 172, 176, 177, 179, 209, 216, 217, 227, 229, 230, 129, 132, 133, 134, 136,
+    146, 154, 156, 160, 163, 164, 169, 170, 173, 178, 181, 185, 186, 187, 189, 190, 196, 198, 228, 232, 233, 1, 135,
+    137, 138, 139, 140, 141, 143, 147, 149, 150, 151, 152, 155, 157, 158, 165, 166, 168, 174, 175, 180, 182, 183,
+    188, 191, 197, 231, 239, 9, 142, 144, 145, 148, 159, 171, 206, 215, 225, 236, 237, 199, 207, 234, 235, 192, 193,
+    200, 201, 202, 205, 210, 213, 218, 219, 238, 240, 242, 243, 255, 203, 204, 211, 212, 214, 221, 222, 223, 241,
+    244, 245, 246, 247, 248, 250, 251, 252, 253, 254, 2, 3, 4, 5, 6, 7, 8, 11, 12, 14, 15, 16, 17, 18, 19, 20, 21,
+    23, 24, 25, 26, 27, 28, 29, 30, 31, 127, 220, 249, 10, 13, 22, 256,
+];
+
+/// decodes a Huffman coded string of HPACK, the code is canonical, i.e. the codes of a length are consecutive
+fn huffman_decode(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(bytes.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0;
+    // the first code of the current length and its position in HUFFMAN_SYMBOLS
+    let mut first = 0u32;
+    let mut offset = 0;
+    for byte in bytes {
+        for bit in (0..8).rev() {
+            code = code << 1 | ((byte >> bit) & 1) as u32;
+            first = (first + HUFFMAN_COUNTS[len] as u32) << 1;
+            offset += HUFFMAN_COUNTS[len] as usize;
+            len += 1;
+            if len >= HUFFMAN_COUNTS.len() {
+                return None;
+            }
+            if code < first + HUFFMAN_COUNTS[len] as u32 {
+                let symbol = HUFFMAN_SYMBOLS[offset + (code - first) as usize];
+                if symbol == 256 {
+                    // EOS must not be encoded
+                    return None;
+                }
+                decoded.push(symbol as u8);
+                code = 0;
+                len = 0;
+                first = 0;
+                offset = 0;
+            }
+        }
+    }
+    // the padding consists of less than 8 bits of the EOS code, i.e. ones
+    if len < 8 && code == (1 << len) - 1 {
+        Some(decoded)
+    } else {
+        None
+    }
+}
+
+/// an integer with a prefix of n bits, returns the integer and the position after it
+fn decode_integer(block: &[u8], pos: usize, n: u8) -> Option<(usize, usize)> {
+    let max_prefix = (1usize << n) - 1;
+    let mut value = *block.get(pos)? as usize & max_prefix;
+    let mut pos = pos + 1;
+    if value < max_prefix {
+        return Some((value, pos));
+    }
+    let mut shift = 0;
+    loop {
+        let byte = *block.get(pos)?;
+        pos += 1;
+        if shift > 28 {
+            return None;
+        }
+        value += ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some((value, pos));
+        }
+    }
+}
+
+/// a string literal, returns the string and the position after it
+fn decode_string(block: &[u8], pos: usize) -> Option<(Vec<u8>, usize)> {
+    let huffman = *block.get(pos)? & 0x80 != 0;
+    let (len, pos) = decode_integer(block, pos, 7)?;
+    let bytes = block.get(pos..pos + len)?;
+    let string = if huffman { huffman_decode(bytes)? } else { bytes.to_vec() };
+    Some((string, pos + len))
+}
+
+/// the method, path and authority of the header block of the first HEADERS frame, the dynamic table is still empty
+fn decode_header_block(block: &[u8]) -> Option<HttpRequest> {
+    let mut method = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut host = None;
+    let mut pos = 0;
+    while pos < block.len() {
+        let first = block[pos];
+        if first & 0x80 != 0 {
+            // indexed header field
+            let (index, next) = decode_integer(block, pos, 7)?;
+            match index {
+                STATIC_METHOD_GET => method = Some("GET".to_string()),
+                STATIC_METHOD_POST => method = Some("POST".to_string()),
+                STATIC_PATH_ROOT => path = Some("/".to_string()),
+                STATIC_PATH_INDEX => path = Some("/index.html".to_string()),
+                0 => return None,
+                i if i > STATIC_TABLE_SIZE => return None,
+                _ => (),
+            }
+            pos = next;
+        } else if first & 0xe0 == 0x20 {
+            // dynamic table size update
+            pos = decode_integer(block, pos, 5)?.1;
+        } else {
+            // literal header field with incremental indexing, without indexing or never indexed
+            let n = if first & 0x40 != 0 { 6 } else { 4 };
+            let (index, next) = decode_integer(block, pos, n)?;
+            if index > STATIC_TABLE_SIZE {
+                return None;
+            }
+            let (name, next) = if index == 0 {
+                let (name, next) = decode_string(block, next)?;
+                (Some(name), next)
+            } else {
+                (None, next)
+            };
+            let (value, next) = decode_string(block, next)?;
+            let value = String::from_utf8(value).ok()?;
+            let name = name.as_ref().map(|name| name.as_slice());
+            match (index, name) {
+                (STATIC_AUTHORITY, _) | (0, Some(b":authority")) => authority = Some(value),
+                (STATIC_METHOD_GET, _) | (STATIC_METHOD_POST, _) | (0, Some(b":method")) => method = Some(value),
+                (STATIC_PATH_ROOT, _) | (STATIC_PATH_INDEX, _) | (0, Some(b":path")) => path = Some(value),
+                (STATIC_HOST, _) | (0, Some(b"host")) => host = Some(value),
+                _ => (),
+            }
+            pos = next;
+        }
+    }
+    Some(HttpRequest {
+        method: method?,
+        path: path.unwrap_or_default(),
+        host: authority.or(host).map(|value| host_without_port(&value).to_lowercase()),
+    })
+}
+
+/// the header block of the frame, if it is a HEADERS frame, without padding and priority
+fn header_block(frame_type: u8, flags: u8, frame: &[u8]) -> Option<&[u8]> {
+    if frame_type != FRAME_HEADERS {
+        return None;
+    }
+    let mut block = frame;
+    if flags & FLAG_PADDED != 0 {
+        let padding = *block.get(0)? as usize;
+        block = block.get(1..block.len().checked_sub(padding)?)?;
+    }
+    if flags & FLAG_PRIORITY != 0 {
+        block = block.get(5..)?;
+    }
+    Some(block)
+}
+
+/// the frames after the preface: type, flags and payload of each complete frame
+fn frames(payload: &[u8]) -> Vec<(u8, u8, &[u8])> {
+    let mut frames = Vec::new();
+    let mut pos = PREFACE.len();
+    while pos + FRAME_HEADER_SIZE <= payload.len() {
+        let len = (payload[pos] as usize) << 16 | (payload[pos + 1] as usize) << 8 | payload[pos + 2] as usize;
+        let end = pos + FRAME_HEADER_SIZE + len;
+        if end > payload.len() {
+            break;
+        }
+        frames.push((payload[pos + 3], payload[pos + 4], &payload[pos + FRAME_HEADER_SIZE..end]));
+        pos = end;
+    }
+    frames
+}
+
+/// true, if payload starts with the HTTP/2 client preface, or with a part of it
+#[inline]
+pub fn is_http2_preface(payload: &[u8]) -> bool {
+    !payload.is_empty() && (payload.starts_with(PREFACE) || PREFACE.starts_with(payload))
+}
+
+/// true, if payload starts with the HTTP/2 client preface, but does not contain the first HEADERS frame
+pub fn http2_request_incomplete(payload: &[u8]) -> bool {
+    is_http2_preface(payload) && !frames(payload).iter().any(|(frame_type, _, _)| *frame_type == FRAME_HEADERS)
+}
+
+/// Parses the pseudo headers :method, :path and :authority, or the host header, of the first request, if payload
+/// starts with the HTTP/2 client preface, i.e. of a h2c client with prior knowledge. The HEADERS frame of the request
+/// must be contained in the payload, see PayloadBuffering; CONTINUATION frames are not considered.
+pub fn parse_http2_request(payload: &[u8]) -> Option<HttpRequest> {
+    if !payload.starts_with(PREFACE) {
+        return None;
+    }
+    frames(payload)
+        .into_iter()
+        .filter_map(|(frame_type, flags, frame)| header_block(frame_type, flags, frame))
+        .next()
+        .and_then(decode_header_block)
+}
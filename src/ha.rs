@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use bincode;
+use nix::sched::{setns, CloneFlags};
+
+use cmanager::Slot;
+use tcp_options::WindowShifts;
+
+const DEFAULT_INTERVAL_MS: u64 = 200;
+/// the active engine tries to reconnect to the standby after this time
+const RECONNECT_MS: u64 = 1000;
+/// upper bound of a frame, a snapshot of all pipelines
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum HaRole {
+    Active,
+    Standby,
+}
+
+/// Active-standby operation of two engines with the same configuration, i.e. the same pipelines, addresses and
+/// targets. The active engine sends snapshots of its established connections over TCP to the standby engine,
+/// which keeps them in the connection tables of the same pipelines. After a failover, e.g. by VRRP or by a
+/// routing change, the standby engine continues the connections with the same proxy ports and sequence numbers.
+/// Connections established after the latest snapshot are lost.
+#[derive(Deserialize, Clone)]
+pub struct HaConfig {
+    pub role: HaRole,
+    /// the active engine connects to this address of the standby engine, the standby engine listens on it
+    pub peer: SocketAddr,
+    /// milli-seconds between two snapshots, defaults to 200
+    pub interval: Option<u64>,
+    /// network namespace of the replication channel, e.g. the one of the KNI interface
+    pub namespace: Option<String>,
+}
+
+impl HaConfig {
+    #[inline]
+    pub fn interval_ms(&self) -> u64 {
+        self.interval.unwrap_or(DEFAULT_INTERVAL_MS)
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.interval_ms())
+    }
+}
+
+/// the state of an established connection, which the standby engine needs to continue it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectionState {
+    pub client_ip: u128,
+    pub client_port: u16,
+    pub client_mac: [u8; 6],
+    pub slot: Slot,
+    pub server_index: u8,
+    pub frontend: u8,
+    pub c_seqn: u32,
+    pub ackn_p2s: u32,
+    pub ackn_p2c: u32,
+    pub c2s_inserted_bytes: i32,
+    pub window_shifts: WindowShifts,
+    pub client_sack: bool,
+    pub server_sack: bool,
+    pub c2s_deltas: Vec<(u32, i32)>,
+    pub s2c_deltas: Vec<(u32, i32)>,
+}
+
+/// Snapshots of the connection states per pipeline. On the active engine the pipelines publish their snapshots on
+/// their timer ticks. On the standby engine the replication thread stores the received snapshots, the pipelines
+/// take them over on their next timer tick, when the version has changed.
+#[derive(Clone)]
+pub struct StateSync {
+    version: Arc<AtomicUsize>,
+    snapshots: Arc<Mutex<HashMap<String, Vec<ConnectionState>>>>,
+}
+
+impl StateSync {
+    pub fn new() -> StateSync {
+        StateSync {
+            version: Arc::new(AtomicUsize::new(0)),
+            snapshots: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn publish(&self, pipeline: &str, connections: Vec<ConnectionState>) {
+        self.snapshots.lock().unwrap().insert(pipeline.to_string(), connections);
+    }
+
+    /// the latest snapshot of the pipeline, None if the active engine did not send one
+    pub fn snapshot(&self, pipeline: &str) -> Option<Vec<ConnectionState>> {
+        self.snapshots.lock().unwrap().get(pipeline).cloned()
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let snapshots = self.snapshots.lock().unwrap();
+        bincode::serialize(&*snapshots).unwrap_or_else(|e| {
+            error!("cannot serialize connection states: {}", e);
+            Vec::new()
+        })
+    }
+
+    fn store(&self, snapshots: HashMap<String, Vec<ConnectionState>>) {
+        *self.snapshots.lock().unwrap() = snapshots;
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+fn enter_namespace(ns: &str) {
+    let path = format!("/var/run/netns/{}", ns);
+    match File::open(&path) {
+        Ok(f) => {
+            if let Err(e) = setns(f.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+                error!("replication cannot enter namespace {}: {}", ns, e);
+            }
+        }
+        Err(e) => error!("replication cannot open {}: {}", path, e),
+    }
+}
+
+/// a frame is the length of the snapshot as big endian u32 followed by the snapshot
+fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    let len = frame.len() as u32;
+    stream.write_all(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8])?;
+    stream.write_all(frame)
+}
+
+fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = (len[0] as usize) << 24 | (len[1] as usize) << 16 | (len[2] as usize) << 8 | len[3] as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes", len)));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+fn send_snapshots(config: &HaConfig, sync: &StateSync) {
+    let interval = config.interval();
+    loop {
+        match TcpStream::connect(&config.peer) {
+            Ok(mut stream) => {
+                info!("replication connected to standby {}", config.peer);
+                let _ = stream.set_nodelay(true);
+                loop {
+                    thread::sleep(interval);
+                    if let Err(e) = write_frame(&mut stream, &sync.encode()) {
+                        warn!("replication to {} failed: {}", config.peer, e);
+                        break;
+                    }
+                }
+            }
+            Err(e) => debug!("replication cannot connect to {}: {}", config.peer, e),
+        }
+        thread::sleep(Duration::from_millis(RECONNECT_MS));
+    }
+}
+
+fn receive_snapshots(config: &HaConfig, sync: &StateSync) {
+    let listener = match TcpListener::bind(&config.peer) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("replication cannot listen on {}: {}", config.peer, e);
+            return;
+        }
+    };
+    info!("replication listens on {}", config.peer);
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("replication accept failed: {}", e);
+                continue;
+            }
+        };
+        info!("replication from active engine {:?}", stream.peer_addr());
+        loop {
+            match read_frame(&mut stream) {
+                Ok(frame) => match bincode::deserialize::<HashMap<String, Vec<ConnectionState>>>(&frame) {
+                    Ok(snapshots) => sync.store(snapshots),
+                    Err(e) => {
+                        warn!("replication received an invalid snapshot: {}", e);
+                        break;
+                    }
+                },
+                Err(e) => {
+                    // the connection states are kept for a failover
+                    warn!("replication from active engine ended: {}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Starts the thread of the replication channel, which sends the snapshots to the standby engine or receives them.
+pub fn spawn_state_sync(config: HaConfig, sync: StateSync) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        if config.namespace.is_some() {
+            enter_namespace(config.namespace.as_ref().unwrap());
+        }
+        match config.role {
+            HaRole::Active => send_snapshots(&config, &sync),
+            HaRole::Standby => receive_snapshots(&config, &sync),
+        }
+    })
+}
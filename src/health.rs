@@ -0,0 +1,167 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::net::{SocketAddr, TcpStream};
+use std::io::{Read, Write};
+use std::time::Duration;
+use std::thread;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+use nix::sched::{setns, CloneFlags};
+
+use reload::TargetTable;
+
+/// server_index of a connection is an u8, therefore we never have more targets
+pub const MAX_TARGETS: usize = 256;
+
+const DEFAULT_INTERVAL_MS: u64 = 2000;
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_RISE: u32 = 2;
+const DEFAULT_FALL: u32 = 3;
+
+#[derive(Deserialize, Clone)]
+pub struct HealthCheckConfig {
+    /// milli-seconds between two probes of a target
+    pub interval: Option<u64>,
+    /// milli-seconds until a probe fails
+    pub timeout: Option<u64>,
+    /// number of successful probes required to mark a down target up
+    pub rise: Option<u32>,
+    /// number of failed probes required to mark an up target down
+    pub fall: Option<u32>,
+    /// if present, a HTTP GET with this path is sent and a 2xx or 3xx status is expected
+    pub http_path: Option<String>,
+    /// network namespace of the KNI interface from which the probes are sent, e.g. "nskni"
+    pub namespace: Option<String>,
+}
+
+/// up/down state of the targets, shared by the health checker and all pipelines.
+/// Targets are up until a health check marks them down.
+/// In addition operators may take targets out of rotation, independent of their health.
+#[derive(Clone)]
+pub struct TargetHealth {
+    up: Arc<Vec<AtomicBool>>,
+    disabled: Arc<Vec<AtomicBool>>,
+}
+
+impl TargetHealth {
+    pub fn new() -> TargetHealth {
+        TargetHealth {
+            up: Arc::new((0..MAX_TARGETS).map(|_| AtomicBool::new(true)).collect()),
+            disabled: Arc::new((0..MAX_TARGETS).map(|_| AtomicBool::new(false)).collect()),
+        }
+    }
+
+    #[inline]
+    pub fn is_up(&self, target: usize) -> bool {
+        self.up[target].load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_up(&self, target: usize, up: bool) {
+        self.up[target].store(up, Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn is_disabled(&self, target: usize) -> bool {
+        self.disabled[target].load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn set_disabled(&self, target: usize, disabled: bool) {
+        self.disabled[target].store(disabled, Ordering::Relaxed)
+    }
+
+    /// true, if the target is up and not disabled
+    #[inline]
+    pub fn in_rotation(&self, target: usize) -> bool {
+        self.is_up(target) && !self.is_disabled(target)
+    }
+
+    pub fn up_count(&self, no_targets: usize) -> usize {
+        (0..no_targets).filter(|i| self.is_up(*i)).count()
+    }
+}
+
+fn probe(addr: &SocketAddr, timeout: Duration, http_path: &Option<String>) -> bool {
+    match TcpStream::connect_timeout(addr, timeout) {
+        Ok(mut stream) => {
+            if http_path.is_none() {
+                return true;
+            }
+            stream.set_write_timeout(Some(timeout)).unwrap();
+            stream.set_read_timeout(Some(timeout)).unwrap();
+            let request = format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                http_path.as_ref().unwrap(),
+                addr.ip()
+            );
+            if stream.write_all(request.as_bytes()).is_err() {
+                return false;
+            }
+            let mut buf = [0u8; 16];
+            match stream.read(&mut buf[..]) {
+                // status line, e.g. "HTTP/1.1 200 OK"
+                Ok(n) if n >= 12 => &buf[0..5] == b"HTTP/" && (buf[9] == b'2' || buf[9] == b'3'),
+                _ => false,
+            }
+        }
+        Err(_) => false,
+    }
+}
+
+/// Starts a thread, which periodically probes all active targets and updates the shared TargetHealth.
+pub fn spawn_health_checker(
+    target_table: TargetTable,
+    config: HealthCheckConfig,
+    health: TargetHealth,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        if config.namespace.is_some() {
+            let ns = config.namespace.as_ref().unwrap();
+            let path = format!("/var/run/netns/{}", ns);
+            match File::open(&path) {
+                Ok(f) => {
+                    if let Err(e) = setns(f.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+                        error!("health checker cannot enter namespace {}: {}", ns, e);
+                    }
+                }
+                Err(e) => error!("health checker cannot open {}: {}", path, e),
+            }
+        }
+        let interval = Duration::from_millis(config.interval.unwrap_or(DEFAULT_INTERVAL_MS));
+        let timeout = Duration::from_millis(config.timeout.unwrap_or(DEFAULT_TIMEOUT_MS));
+        let rise = config.rise.unwrap_or(DEFAULT_RISE);
+        let fall = config.fall.unwrap_or(DEFAULT_FALL);
+        // number of consecutive probes with a result contradicting the current state
+        let mut contra = vec![0u32; MAX_TARGETS];
+        info!("health checker started, interval= {:?}, timeout= {:?}", interval, timeout);
+        loop {
+            // the table may change by a reload
+            for (i, entry) in target_table.targets().iter().enumerate() {
+                if !entry.active {
+                    continue;
+                }
+                let target = &entry.config;
+                let addr = SocketAddr::new(target.ip, target.port);
+                let ok = probe(&addr, timeout, &config.http_path);
+                let up = health.is_up(i);
+                if ok == up {
+                    contra[i] = 0;
+                } else {
+                    contra[i] += 1;
+                    if up && contra[i] >= fall {
+                        warn!("health check: target {} ({}) is down", target.id, addr);
+                        health.set_up(i, false);
+                        contra[i] = 0;
+                    } else if !up && contra[i] >= rise {
+                        info!("health check: target {} ({}) is up again", target.id, addr);
+                        health.set_up(i, true);
+                        contra[i] = 0;
+                    }
+                }
+            }
+            thread::sleep(interval);
+        }
+    })
+}
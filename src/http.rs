@@ -0,0 +1,127 @@
+use std::str;
+
+use reload::TargetEntry;
+
+/// an entry of the http routes, requests matching host and path prefix are routed to the target
+#[derive(Deserialize, Clone)]
+pub struct HttpRoute {
+    /// host of the Host header without port, if None any host matches
+    pub host: Option<String>,
+    /// if None any path matches
+    pub path_prefix: Option<String>,
+    /// target id
+    pub target: String,
+}
+
+/// request line and host of the first HTTP/1.x request of a connection, or the pseudo headers of the first HTTP/2
+/// request, see parse_http2_request
+#[derive(Clone, Debug)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    /// lower case, without port
+    pub host: Option<String>,
+}
+
+/// true, if payload starts like a HTTP/1.x request line, but does not contain the end of the request header
+pub fn http_header_incomplete(payload: &[u8]) -> bool {
+    let method_len = payload.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    if method_len == 0 || method_len < payload.len() && payload[method_len] != b' ' {
+        return false;
+    }
+    !payload.windows(4).any(|w| w == b"\r\n\r\n")
+}
+
+/// true, if payload starts with a HTTP/1.x request line, the request is not parsed
+pub fn is_http_request(payload: &[u8]) -> bool {
+    let method_len = payload.iter().take_while(|b| b.is_ascii_uppercase()).count();
+    if method_len == 0 || method_len == payload.len() || payload[method_len] != b' ' {
+        return false;
+    }
+    let line_len = payload.windows(2).position(|w| w == b"\r\n").unwrap_or(payload.len());
+    payload[..line_len].windows(8).any(|w| w == b" HTTP/1.")
+}
+
+/// the host of a Host header or an authority without the port
+pub fn host_without_port(value: &str) -> &str {
+    match value.rfind(':') {
+        // keep IPv6 literals like [::1]
+        Some(i) if !value[i..].contains(']') => &value[..i],
+        _ => value,
+    }
+}
+
+/// Parses the request line and the Host header, if payload starts with a HTTP/1.x request.
+/// Only the header lines contained in the payload, i.e. in the first segment of the client or in the
+/// segments buffered before the server is selected, are considered.
+pub fn parse_http_request(payload: &[u8]) -> Option<HttpRequest> {
+    // the header is ASCII, stop at the first invalid byte, e.g. the start of a binary body
+    let text = match str::from_utf8(payload) {
+        Ok(text) => text,
+        Err(e) => str::from_utf8(&payload[..e.valid_up_to()]).unwrap(),
+    };
+    let mut lines = text.split("\r\n");
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?;
+    let path = request_line.next()?;
+    if method.is_empty() || !method.bytes().all(|b| b.is_ascii_uppercase()) || !request_line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let mut host = None;
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        if line.get(..5).map_or(false, |name| name.eq_ignore_ascii_case("host:")) {
+            host = Some(host_without_port(line[5..].trim()).to_lowercase());
+            break;
+        }
+    }
+    Some(HttpRequest {
+        method: method.to_string(),
+        path: path.to_string(),
+        host,
+    })
+}
+
+/// The http routes of the engine configuration, resolved to target indices. The first matching route wins.
+#[derive(Clone)]
+pub struct HttpRouter {
+    routes: Vec<(Option<String>, Option<String>, usize)>,
+}
+
+impl HttpRouter {
+    pub fn new(http_routes: &Option<Vec<HttpRoute>>, targets: &Vec<TargetEntry>) -> HttpRouter {
+        let mut routes = Vec::new();
+        if http_routes.is_some() {
+            for route in http_routes.as_ref().unwrap() {
+                // only active targets are candidates, the table may contain inactive entries with the same id
+                match targets.iter().position(|t| t.active && t.config.id == route.target) {
+                    Some(index) => routes.push((
+                        route.host.as_ref().map(|h| h.to_lowercase()),
+                        route.path_prefix.clone(),
+                        index,
+                    )),
+                    None => warn!("http_routes: unknown target id {}", route.target),
+                }
+            }
+        }
+        HttpRouter { routes }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    /// returns the index of the target for the request
+    pub fn route(&self, request: &HttpRequest) -> Option<usize> {
+        self.routes
+            .iter()
+            .find(|(host, path_prefix, _)| {
+                (host.is_none() || host.as_ref() == request.host.as_ref())
+                    && (path_prefix.is_none() || request.path.starts_with(path_prefix.as_ref().unwrap().as_str()))
+            })
+            .map(|(_, _, index)| *index)
+    }
+}
@@ -0,0 +1,143 @@
+pub const ICMP_PROTOCOL: u8 = 1;
+pub const ECHO_REPLY: u8 = 0;
+pub const DESTINATION_UNREACHABLE: u8 = 3;
+pub const ECHO_REQUEST: u8 = 8;
+pub const PORT_UNREACHABLE: u8 = 3;
+pub const FRAGMENTATION_NEEDED: u8 = 4;
+/// the ICMP header before the embedded datagram of an error message
+const ICMP_HEADER_SIZE: usize = 8;
+/// an error message carries the IP header and the first 8 bytes of the datagram
+const EMBEDDED_DATA_SIZE: usize = 8;
+
+/// ICMP messages to the engine, which are handled by the pipelines instead of the KNI interface
+#[derive(Deserialize, Clone)]
+pub struct IcmpConfig {
+    /// answer echo requests to the addresses of the engine, defaults to true
+    pub echo: Option<bool>,
+    /// forward fragmentation-needed errors about a proxied connection to the other leg, defaults to true
+    pub forward_errors: Option<bool>,
+    /// answer udp datagrams to ports, which are not proxied, with port-unreachable, defaults to false
+    pub port_unreachable: Option<bool>,
+}
+
+impl IcmpConfig {
+    /// true, if a udp datagram to dst_port of the engine is answered with port-unreachable: the port is neither the
+    /// udp port of the engine nor, with a udp port, one of the proxy ports from port_base on, on which the targets
+    /// reply to the udp flows
+    pub fn answers_port_unreachable(&self, dst_port: u16, udp_port: Option<u16>, port_base: u16) -> bool {
+        self.port_unreachable.unwrap_or(false)
+            && (udp_port.is_none() || dst_port != udp_port.unwrap() && dst_port < port_base)
+    }
+}
+
+/// the TCP segment embedded in an ICMP error message, as sent by the engine, i.e. src is the address of the engine
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EmbeddedSegment {
+    pub src: (u32, u16),
+    pub dst: (u32, u16),
+    pub seqn: u32,
+}
+
+#[inline]
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+#[inline]
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+#[inline]
+fn put_u16(bytes: &mut [u8], value: u16) {
+    bytes[0] = (value >> 8) as u8;
+    bytes[1] = value as u8;
+}
+
+#[inline]
+fn put_u32(bytes: &mut [u8], value: u32) {
+    for i in 0..4 {
+        bytes[i] = (value >> (24 - 8 * i)) as u8;
+    }
+}
+
+/// RFC 1071
+pub fn internet_checksum(bytes: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in bytes.chunks(2) {
+        sum += if chunk.len() == 2 { be_u16(chunk) as u32 } else { (chunk[0] as u32) << 8 };
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// sets the checksum of the ICMP message
+pub fn update_icmp_checksum(icmp: &mut [u8]) {
+    put_u16(&mut icmp[2..4], 0);
+    let checksum = internet_checksum(icmp);
+    put_u16(&mut icmp[2..4], checksum);
+}
+
+/// turns an echo request into the echo reply with the same data, false if icmp is not an echo request
+pub fn echo_reply(icmp: &mut [u8]) -> bool {
+    if icmp.len() < ICMP_HEADER_SIZE || icmp[0] != ECHO_REQUEST || icmp[1] != 0 {
+        return false;
+    }
+    icmp[0] = ECHO_REPLY;
+    update_icmp_checksum(icmp);
+    true
+}
+
+#[inline]
+pub fn is_fragmentation_needed(icmp: &[u8]) -> bool {
+    icmp.len() >= ICMP_HEADER_SIZE && icmp[0] == DESTINATION_UNREACHABLE && icmp[1] == FRAGMENTATION_NEEDED
+}
+
+/// the TCP segment embedded in an error message, None if the message embeds something else or is truncated
+pub fn embedded_segment(icmp: &[u8]) -> Option<EmbeddedSegment> {
+    if icmp.len() < ICMP_HEADER_SIZE + 20 {
+        return None;
+    }
+    let ip = &icmp[ICMP_HEADER_SIZE..];
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    if ip[0] >> 4 != 4 || ip[9] != 6 || ip.len() < ihl + EMBEDDED_DATA_SIZE {
+        return None;
+    }
+    let tcp = &ip[ihl..];
+    Some(EmbeddedSegment {
+        src: (be_u32(&ip[12..16]), be_u16(&tcp[0..2])),
+        dst: (be_u32(&ip[16..20]), be_u16(&tcp[2..4])),
+        seqn: be_u32(&tcp[4..8]),
+    })
+}
+
+/// replaces the embedded TCP segment, as found by embedded_segment, and updates the checksums
+pub fn set_embedded_segment(icmp: &mut [u8], segment: &EmbeddedSegment) {
+    {
+        let ip = &mut icmp[ICMP_HEADER_SIZE..];
+        let ihl = (ip[0] & 0x0f) as usize * 4;
+        put_u32(&mut ip[12..16], segment.src.0);
+        put_u32(&mut ip[16..20], segment.dst.0);
+        put_u16(&mut ip[ihl..ihl + 2], segment.src.1);
+        put_u16(&mut ip[ihl + 2..ihl + 4], segment.dst.1);
+        put_u32(&mut ip[ihl + 4..ihl + 8], segment.seqn);
+        put_u16(&mut ip[10..12], 0);
+        let checksum = internet_checksum(&ip[..ihl]);
+        put_u16(&mut ip[10..12], checksum);
+    }
+    update_icmp_checksum(icmp);
+}
+
+/// the port-unreachable message for an IPv4 datagram, i.e. for its header and the first 8 bytes of its payload
+pub fn port_unreachable_bytes(datagram: &[u8]) -> Vec<u8> {
+    let ihl = (datagram[0] & 0x0f) as usize * 4;
+    let embedded = ::std::cmp::min(datagram.len(), ihl + EMBEDDED_DATA_SIZE);
+    let mut icmp = vec![0u8; ICMP_HEADER_SIZE];
+    icmp[0] = DESTINATION_UNREACHABLE;
+    icmp[1] = PORT_UNREACHABLE;
+    icmp.extend_from_slice(&datagram[..embedded]);
+    update_icmp_checksum(&mut icmp);
+    icmp
+}
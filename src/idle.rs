@@ -0,0 +1,71 @@
+use netfcts::tcp_common::TcpState;
+
+/// how idle connections are torn down
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Teardown {
+    /// a FIN is sent to client and server, the state is released without waiting for their FINs
+    Fin,
+    /// a RST is sent to client and server
+    Rst,
+}
+
+impl Default for Teardown {
+    fn default() -> Teardown {
+        Teardown::Rst
+    }
+}
+
+/// Idle timeouts (milli-seconds) per connection state, missing values default to timeouts.established.
+/// A connection is torn down, when it had no packet for the timeout of its current state.
+#[derive(Deserialize, Clone)]
+pub struct IdleTimeouts {
+    /// client side or server side handshake not completed
+    pub syn_received: Option<u64>,
+    /// both sides established
+    pub established: Option<u64>,
+    /// a FIN has been seen in one direction
+    pub half_closed: Option<u64>,
+    /// defaults to rst
+    pub teardown: Option<Teardown>,
+}
+
+impl IdleTimeouts {
+    /// the idle timeout in milli-seconds of a connection in the given client and server state
+    pub fn for_state(&self, c_state: TcpState, s_state: TcpState, default: u64) -> u64 {
+        let timeout = if c_state < TcpState::Established || s_state < TcpState::Established {
+            self.syn_received
+        } else if c_state == TcpState::Established && s_state == TcpState::Established {
+            self.established
+        } else {
+            self.half_closed
+        };
+        timeout.unwrap_or(default)
+    }
+}
+
+const DEFAULT_KEEPALIVE_IDLE_MS: u64 = 60000;
+const DEFAULT_KEEPALIVE_INTERVAL_MS: u64 = 15000;
+
+/// Keepalive probes of the proxy on established connections, towards client and server.
+/// A probe is an ACK with the sequence number of the last byte sent, which peers answer with an ACK,
+/// so that NAT and firewall state along both legs does not expire. The answers count as activity for the idle timeouts.
+#[derive(Deserialize, Clone)]
+pub struct KeepaliveConfig {
+    /// milli-seconds without packets before the first probe, defaults to 60000
+    pub idle: Option<u64>,
+    /// milli-seconds between probes, while the connection stays idle, defaults to 15000
+    pub interval: Option<u64>,
+}
+
+impl KeepaliveConfig {
+    #[inline]
+    pub fn idle_cycles(&self, cpu_clock: u64) -> u64 {
+        self.idle.unwrap_or(DEFAULT_KEEPALIVE_IDLE_MS) * cpu_clock / 1000
+    }
+
+    #[inline]
+    pub fn interval_cycles(&self, cpu_clock: u64) -> u64 {
+        self.interval.unwrap_or(DEFAULT_KEEPALIVE_INTERVAL_MS) * cpu_clock / 1000
+    }
+}
@@ -0,0 +1,14 @@
+/// Inline operation (bump in the wire) with a pair of ports: the clients are reached through the client port, the
+/// targets through the server port. A pipeline receives from and sends to the queues of both ports on its core;
+/// frames towards the targets leave with the MAC address of the server port. The client side identity is the one of
+/// the KNI interface of the client port (or of the KnilessConfig), the server side address is the one of the flow
+/// director of the server port, which must steer the replies of the targets by the proxy ports to the rx queue with
+/// the same index, i.e. both ports are served by the same cores. The engine answers ARP requests for its server side
+/// addresses, udp flows are not proxied in inline mode.
+#[derive(Deserialize, Clone)]
+pub struct InlineConfig {
+    /// name of the port towards the clients, e.g. "0000:03:00.0"
+    pub client_port: String,
+    /// name of the port towards the targets
+    pub server_port: String,
+}
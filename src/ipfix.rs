@@ -0,0 +1,348 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::thread;
+use std::arch::x86_64::_rdtsc;
+
+use nix::sched::{setns, CloneFlags};
+
+use channel::{bounded, BoundedReceiver, BoundedSender, ChannelStats, DEFAULT_QUEUE_SIZE};
+use cmanager::ProxyConnection;
+use reload::TargetTable;
+
+const IPFIX_VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const TEMPLATE_IPV4: u16 = 256;
+const TEMPLATE_IPV6: u16 = 257;
+const MESSAGE_HEADER_SIZE: usize = 16;
+const SET_HEADER_SIZE: usize = 4;
+/// fits into the MTU of the KNI interface with IP and UDP header
+const MAX_MESSAGE_SIZE: usize = 1400;
+/// private enterprise number of the reverse information elements of biflows, RFC 5103
+const REVERSE_PEN: u32 = 29305;
+/// the example enterprise number of RFC 5612
+const DEFAULT_PEN: u32 = 32473;
+/// element of the enterprise with the id of the target
+const TARGET_ID_ELEMENT: u16 = 1;
+const VARIABLE_LENGTH: u16 = 65535;
+const DEFAULT_TEMPLATE_INTERVAL_S: u64 = 60;
+/// pending records are sent after this time
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Export of the closed connections as IPFIX (RFC 7011) flow records over UDP. A record has the client socket, the
+/// socket of the target, the packets and bytes received from the client and, as reverse elements (RFC 5103), those
+/// received from the target, the start and end of the connection and the id of the target as an enterprise element.
+/// The counters are those of the frames including the L2 header, see ProxyConnection.wire_bytes.
+#[derive(Deserialize, Clone)]
+pub struct IpfixConfig {
+    /// address of the collector, e.g. "192.168.222.2:4739"
+    pub collector: SocketAddr,
+    /// network namespace of the exporter, e.g. the one of the KNI interface, defaults to the namespace of the engine
+    pub namespace: Option<String>,
+    pub observation_domain: Option<u32>,
+    /// private enterprise number of the target id element, defaults to 32473
+    pub enterprise_number: Option<u32>,
+    /// seconds between two transmissions of the templates, defaults to 60
+    pub template_interval: Option<u64>,
+    /// records queued for the exporter, further records are dropped, defaults to 65536
+    pub queue_size: Option<usize>,
+}
+
+impl IpfixConfig {
+    #[inline]
+    pub fn enterprise_number(&self) -> u32 {
+        self.enterprise_number.unwrap_or(DEFAULT_PEN)
+    }
+
+    #[inline]
+    pub fn template_interval(&self) -> Duration {
+        Duration::from_secs(self.template_interval.unwrap_or(DEFAULT_TEMPLATE_INTERVAL_S))
+    }
+}
+
+/// a closed connection as sent to the exporter
+pub struct FlowRecord {
+    client: Option<(IpAddr, u16)>,
+    server_index: Option<usize>,
+    /// cycles since the connection state was created
+    age: u64,
+    packets: [u64; 2],
+    wire_bytes: [u64; 2],
+}
+
+/// sends the closed connections of a pipeline to the exporter
+#[derive(Clone)]
+pub struct FlowSender {
+    tx: BoundedSender<FlowRecord>,
+}
+
+impl FlowSender {
+    /// must be called before the connection is released
+    #[inline]
+    pub fn closed(&self, c: &ProxyConnection) {
+        let record = FlowRecord {
+            client: c.client_addr(),
+            server_index: if c.server_bound() { Some(c.server_index()) } else { None },
+            age: unsafe { _rdtsc() }.wrapping_sub(c.opened),
+            packets: c.packets,
+            wire_bytes: c.wire_bytes,
+        };
+        // dropped, while the exporter falls behind
+        self.tx.send(record);
+    }
+}
+
+/// (element id, length, enterprise number) of the fields of the template
+fn template_fields(template_id: u16, pen: u32) -> Vec<(u16, u16, Option<u32>)> {
+    let source = if template_id == TEMPLATE_IPV4 { (8, 4, None) } else { (27, 16, None) };
+    vec![
+        // sourceIPv4Address or sourceIPv6Address, sourceTransportPort
+        source,
+        (7, 2, None),
+        // destinationIPv4Address, destinationTransportPort, protocolIdentifier, the socket of the target
+        (12, 4, None),
+        (11, 2, None),
+        (4, 1, None),
+        // octetDeltaCount, packetDeltaCount and their reverse elements
+        (1, 8, None),
+        (2, 8, None),
+        (1, 8, Some(REVERSE_PEN)),
+        (2, 8, Some(REVERSE_PEN)),
+        // flowStartMilliseconds, flowEndMilliseconds
+        (152, 8, None),
+        (153, 8, None),
+        (TARGET_ID_ELEMENT, VARIABLE_LENGTH, Some(pen)),
+    ]
+}
+
+#[inline]
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[inline]
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[inline]
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn template_set(pen: u32) -> Vec<u8> {
+    let mut set = Vec::new();
+    put_u16(&mut set, TEMPLATE_SET_ID);
+    put_u16(&mut set, 0);
+    for template_id in &[TEMPLATE_IPV4, TEMPLATE_IPV6] {
+        let fields = template_fields(*template_id, pen);
+        put_u16(&mut set, *template_id);
+        put_u16(&mut set, fields.len() as u16);
+        for (element, length, enterprise) in fields {
+            match enterprise {
+                Some(enterprise) => {
+                    put_u16(&mut set, element | 0x8000);
+                    put_u16(&mut set, length);
+                    put_u32(&mut set, enterprise);
+                }
+                None => {
+                    put_u16(&mut set, element);
+                    put_u16(&mut set, length);
+                }
+            }
+        }
+    }
+    let len = set.len() as u16;
+    set[2..4].copy_from_slice(&len.to_be_bytes());
+    set
+}
+
+fn millis(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since_epoch.as_secs() * 1000 + since_epoch.subsec_millis() as u64
+}
+
+/// the index of the set of its template and the data record of the flow, None for flows without client
+fn encode_record(record: &FlowRecord, targets: &TargetTable, cpu_clock: u64) -> Option<(usize, Vec<u8>)> {
+    let (ip, port) = record.client?;
+    let target = record
+        .server_index
+        .and_then(|i| targets.targets().get(i).map(|entry| (entry.config.id.clone(), entry.l234.ip, entry.l234.port)));
+    let end = millis(SystemTime::now());
+    let age_ms = record.age / (cpu_clock / 1000);
+    let mut buf = Vec::with_capacity(128);
+    let set = match ip {
+        IpAddr::V4(ip) => {
+            buf.extend_from_slice(&ip.octets());
+            0
+        }
+        IpAddr::V6(ip) => {
+            buf.extend_from_slice(&ip.octets());
+            1
+        }
+    };
+    put_u16(&mut buf, port);
+    put_u32(&mut buf, target.as_ref().map_or(0, |t| t.1));
+    put_u16(&mut buf, target.as_ref().map_or(0, |t| t.2));
+    // TCP
+    buf.push(6);
+    put_u64(&mut buf, record.wire_bytes[0]);
+    put_u64(&mut buf, record.packets[0]);
+    put_u64(&mut buf, record.wire_bytes[1]);
+    put_u64(&mut buf, record.packets[1]);
+    put_u64(&mut buf, end.saturating_sub(age_ms));
+    put_u64(&mut buf, end);
+    let id = target.map_or(String::new(), |t| t.0);
+    let id = &id.as_bytes()[..id.len().min(u16::max_value() as usize)];
+    if id.len() < 255 {
+        buf.push(id.len() as u8);
+    } else {
+        buf.push(255);
+        put_u16(&mut buf, id.len() as u16);
+    }
+    buf.extend_from_slice(id);
+    Some((set, buf))
+}
+
+struct Exporter {
+    socket: UdpSocket,
+    config: IpfixConfig,
+    /// data records sent so far
+    sequence: u32,
+    last_templates: Option<Instant>,
+}
+
+impl Exporter {
+    /// sends the pending data records, preceded by the templates, when they are due
+    fn flush(&mut self, sets: &mut [Vec<u8>; 2], records: &mut u32) {
+        let templates_due = self
+            .last_templates
+            .map_or(true, |t| t.elapsed() >= self.config.template_interval());
+        if *records == 0 && !templates_due {
+            return;
+        }
+        let mut message = Vec::with_capacity(MAX_MESSAGE_SIZE + MESSAGE_HEADER_SIZE);
+        put_u16(&mut message, IPFIX_VERSION);
+        put_u16(&mut message, 0);
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+        put_u32(&mut message, now.as_secs() as u32);
+        put_u32(&mut message, self.sequence);
+        put_u32(&mut message, self.config.observation_domain.unwrap_or(0));
+        if templates_due {
+            message.extend_from_slice(&template_set(self.config.enterprise_number()));
+            self.last_templates = Some(Instant::now());
+        }
+        for (set, template_id) in sets.iter_mut().zip([TEMPLATE_IPV4, TEMPLATE_IPV6].iter()) {
+            if !set.is_empty() {
+                put_u16(&mut message, *template_id);
+                put_u16(&mut message, (set.len() + SET_HEADER_SIZE) as u16);
+                message.extend_from_slice(set);
+                set.clear();
+            }
+        }
+        let len = message.len() as u16;
+        message[2..4].copy_from_slice(&len.to_be_bytes());
+        self.sequence = self.sequence.wrapping_add(*records);
+        *records = 0;
+        // flows are lost, while the collector is not reachable
+        if let Err(e) = self.socket.send_to(&message, &self.config.collector) {
+            warn!("ipfix export to {} failed: {}", self.config.collector, e);
+        }
+    }
+}
+
+fn enter_namespace(ns: &str) {
+    let path = format!("/var/run/netns/{}", ns);
+    match File::open(&path) {
+        Ok(f) => {
+            if let Err(e) = setns(f.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+                error!("ipfix export cannot enter namespace {}: {}", ns, e);
+            }
+        }
+        Err(e) => error!("ipfix export cannot open {}: {}", path, e),
+    }
+}
+
+fn run_exporter(config: IpfixConfig, rx: BoundedReceiver<FlowRecord>, targets: TargetTable, cpu_clock: u64) {
+    if config.namespace.is_some() {
+        enter_namespace(config.namespace.as_ref().unwrap());
+    }
+    let bind_address = if config.collector.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = match UdpSocket::bind(bind_address) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("ipfix export cannot bind a socket: {}", e);
+            return;
+        }
+    };
+    info!("ipfix export to {}", config.collector);
+    let mut exporter = Exporter {
+        socket,
+        config,
+        sequence: 0,
+        last_templates: None,
+    };
+    // a message may have the templates and a data set of each template
+    let overhead = MESSAGE_HEADER_SIZE + template_set(exporter.config.enterprise_number()).len() + 2 * SET_HEADER_SIZE;
+    let mut sets = [Vec::new(), Vec::new()];
+    let mut records = 0u32;
+    let mut last_flush = Instant::now();
+    loop {
+        match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok(record) => {
+                if let Some((set, data)) = encode_record(&record, &targets, cpu_clock) {
+                    if overhead + sets[0].len() + sets[1].len() + data.len() > MAX_MESSAGE_SIZE {
+                        exporter.flush(&mut sets, &mut records);
+                        last_flush = Instant::now();
+                    }
+                    sets[set].extend_from_slice(&data);
+                    records += 1;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                exporter.flush(&mut sets, &mut records);
+                return;
+            }
+        }
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            exporter.flush(&mut sets, &mut records);
+            last_flush = Instant::now();
+        }
+    }
+}
+
+/// Exports the closed connections of all pipelines as IPFIX flow records, see IpfixConfig.
+#[derive(Clone)]
+pub struct FlowExporter {
+    tx: Arc<Mutex<Option<BoundedSender<FlowRecord>>>>,
+}
+
+impl FlowExporter {
+    pub fn new() -> FlowExporter {
+        FlowExporter {
+            tx: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// starts the exporter thread, pipelines must be set up afterwards to get a sender
+    pub fn start(&self, config: &IpfixConfig, targets: TargetTable, cpu_clock: u64) -> thread::JoinHandle<()> {
+        let (tx, rx) = bounded(config.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE));
+        *self.tx.lock().unwrap() = Some(tx);
+        let config = config.clone();
+        thread::spawn(move || run_exporter(config, rx, targets, cpu_clock))
+    }
+
+    /// None, if flows are not exported
+    pub fn sender(&self) -> Option<FlowSender> {
+        self.tx.lock().unwrap().as_ref().map(|tx| FlowSender { tx: tx.clone() })
+    }
+
+    /// None, if flows are not exported
+    pub fn stats(&self) -> Option<ChannelStats> {
+        self.tx.lock().unwrap().as_ref().map(|tx| tx.stats())
+    }
+}
@@ -0,0 +1,34 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Converts an IPv4 address (host byte order) into the IPv4-mapped IPv6 address ::ffff:a.b.c.d,
+/// this is how IPv4 addresses are represented in 128-bit flow keys
+#[inline]
+pub fn v4_to_key(ip: u32) -> u128 {
+    0xffff_u128 << 32 | ip as u128
+}
+
+/// Returns the IPv4 address, if key is an IPv4-mapped address
+#[inline]
+pub fn key_to_v4(key: u128) -> Option<u32> {
+    if key >> 32 == 0xffff {
+        Some(key as u32)
+    } else {
+        None
+    }
+}
+
+#[inline]
+pub fn ip_to_key(ip: &IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(ip4) => v4_to_key(u32::from(*ip4)),
+        IpAddr::V6(ip6) => u128::from(*ip6),
+    }
+}
+
+#[inline]
+pub fn key_to_ip(key: u128) -> IpAddr {
+    match key_to_v4(key) {
+        Some(ip4) => IpAddr::V4(Ipv4Addr::from(ip4)),
+        None => IpAddr::V6(Ipv6Addr::from(key)),
+    }
+}
@@ -0,0 +1,30 @@
+use std::net::Ipv4Addr;
+
+use eui48::MacAddress;
+use netfcts::tcp_common::L234Data;
+
+/// Operation without KNI interface, e.g. on kernels or distributions without the rte_kni module. The control plane
+/// is served in the data path: the pipelines answer ARP requests for the addresses of the engine and, with
+/// IcmpConfig, ICMP echo requests; all other frames, which would go to KNI, are dropped and counted as KNI packets.
+/// The targets need a mac or are resolved by ARP (see ArpConfig). The health checks, the admin api and the
+/// control channel use the network stack of the host, i.e. a management interface instead of the KNI interface.
+#[derive(Deserialize, Clone)]
+pub struct KnilessConfig {
+    /// the address of the engine on the PCI port, which the clients connect to
+    pub ip: Ipv4Addr,
+    /// defaults to the MAC address of the PCI port
+    pub mac: Option<MacAddress>,
+}
+
+impl KnilessConfig {
+    /// the client side identity of the engine, which is otherwise taken from the KNI interface
+    pub fn l234(&self, port_mac: MacAddress) -> L234Data {
+        L234Data {
+            mac: self.mac.unwrap_or(port_mac),
+            ip: u32::from(self.ip),
+            port: 0,
+            server_id: String::new(),
+            index: 0,
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::fmt;
+
+use netfcts::comm::PipelineId;
+
+/// values below are counted exactly, above each power of two is split into SUB_BUCKETS buckets
+const SUB_BUCKETS: usize = 16;
+const SUB_BITS: u32 = 4;
+/// the largest power of two, which is resolved (about 18 minutes), larger values are counted with this power
+const MAX_EXPONENT: u32 = 40;
+const BUCKETS: usize = (MAX_EXPONENT - SUB_BITS + 2) as usize * SUB_BUCKETS;
+
+/// Histogram of latencies in nano-seconds with buckets of constant relative width (about 6%), as in HDR histograms.
+#[derive(Clone)]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: vec![0; BUCKETS],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    #[inline]
+    fn index(value: u64) -> usize {
+        if value < SUB_BUCKETS as u64 {
+            return value as usize;
+        }
+        let exponent = ::std::cmp::min(63 - value.leading_zeros(), MAX_EXPONENT);
+        let sub = (value >> (exponent - SUB_BITS)) as usize & (SUB_BUCKETS - 1);
+        (exponent - SUB_BITS + 1) as usize * SUB_BUCKETS + sub
+    }
+
+    /// the smallest value counted in the bucket
+    fn lower_bound(index: usize) -> u64 {
+        if index < SUB_BUCKETS {
+            return index as u64;
+        }
+        let exponent = (index / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+        ((SUB_BUCKETS + index % SUB_BUCKETS) as u64) << (exponent - SUB_BITS)
+    }
+
+    #[inline]
+    pub fn record(&mut self, value: u64) {
+        self.counts[LatencyHistogram::index(value)] += 1;
+        self.total += 1;
+        if value > self.max {
+            self.max = value;
+        }
+    }
+
+    pub fn add(&mut self, other: &LatencyHistogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += *other;
+        }
+        self.total += other.total;
+        if other.max > self.max {
+            self.max = other.max;
+        }
+    }
+
+    #[inline]
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    #[inline]
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// the value below which the fraction q (e.g. 0.99) of the values lies, 0 for an empty histogram
+    pub fn percentile(&self, q: f64) -> u64 {
+        let rank = (q * self.total as f64).ceil() as u64;
+        let mut seen = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            seen += *count;
+            if seen >= rank && *count > 0 {
+                return ::std::cmp::min(LatencyHistogram::lower_bound(i), self.max);
+            }
+        }
+        self.max
+    }
+}
+
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "n= {}, p50= {} ns, p90= {} ns, p99= {} ns, max= {} ns",
+            self.total,
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.max
+        )
+    }
+}
+
+/// latencies measured by a pipeline
+#[derive(Clone)]
+pub struct PipelineLatencies {
+    /// from the SYN of the client until its ACK of the SYN-ACK
+    pub handshake: LatencyHistogram,
+    /// the time of the server selection, i.e. of the delayed binding decision, including the inspection of the payload
+    pub binding: LatencyHistogram,
+    /// processing time of the pipeline for forwarded packets
+    pub forwarding: LatencyHistogram,
+    cycles_per_us: u64,
+}
+
+impl PipelineLatencies {
+    pub fn new(cpu_clock: u64) -> PipelineLatencies {
+        PipelineLatencies {
+            handshake: LatencyHistogram::new(),
+            binding: LatencyHistogram::new(),
+            forwarding: LatencyHistogram::new(),
+            cycles_per_us: ::std::cmp::max(cpu_clock / 1_000_000, 1),
+        }
+    }
+
+    /// cycles of the TSC in nano-seconds
+    #[inline]
+    pub fn nanos(&self, cycles: u64) -> u64 {
+        cycles * 1000 / self.cycles_per_us
+    }
+
+    pub fn add(&mut self, other: &PipelineLatencies) {
+        self.handshake.add(&other.handshake);
+        self.binding.add(&other.binding);
+        self.forwarding.add(&other.forwarding);
+    }
+}
+
+impl fmt::Display for PipelineLatencies {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "handshake: {}; binding: {}; forwarding: {}",
+            self.handshake, self.binding, self.forwarding
+        )
+    }
+}
+
+/// The latencies of all pipelines, each pipeline publishes its histograms periodically on a timer tick.
+#[derive(Clone)]
+pub struct EngineLatencies(Arc<Mutex<HashMap<PipelineId, PipelineLatencies>>>);
+
+impl EngineLatencies {
+    pub fn new() -> EngineLatencies {
+        EngineLatencies(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub fn publish(&self, pipeline_id: &PipelineId, latencies: &PipelineLatencies) {
+        self.0.lock().unwrap().insert(pipeline_id.clone(), latencies.clone());
+    }
+
+    pub fn snapshot(&self) -> HashMap<PipelineId, PipelineLatencies> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// merged histograms of all pipelines, None if no pipeline has published yet
+    pub fn total(&self) -> Option<PipelineLatencies> {
+        let latencies = self.0.lock().unwrap();
+        let mut values = latencies.values();
+        let mut total = values.next()?.clone();
+        for other in values {
+            total.add(other);
+        }
+        Some(total)
+    }
+}
@@ -9,6 +9,8 @@ extern crate e2d2;
 extern crate env_logger;
 extern crate fnv;
 extern crate toml;
+extern crate serde_yaml;
+extern crate serde_json;
 extern crate separator;
 #[macro_use]
 extern crate serde_derive;
@@ -17,11 +19,152 @@ extern crate ipnet;
 extern crate serde;
 extern crate uuid;
 extern crate netfcts;
+extern crate nix;
+extern crate ctrlc;
+extern crate bincode;
+extern crate tracing;
+extern crate tracing_appender;
+extern crate tracing_subscriber;
 
 mod nftcp;
+mod nfudp;
 mod cmanager;
+mod ipv6;
+mod selection;
+mod health;
+mod reload;
+mod timer;
+mod drain;
+mod sni;
+mod http;
+mod proxy_protocol;
+mod socks5;
+mod stats;
+mod syncookie;
+mod limits;
+mod control;
+mod acl;
+mod capture;
+mod admin;
+mod events;
+mod affinity;
+mod numa;
+mod idle;
+mod tcp_options;
+mod buffering;
+mod reorder;
+mod rewrite;
+mod builder;
+mod validate;
+mod overrides;
+mod resolver;
+mod arp;
+mod gateway;
+mod vlan;
+mod icmp;
+mod groups;
+mod pacing;
+mod latency;
+mod spans;
+mod audit;
+mod replay;
+mod flowtable;
+mod close;
+mod rtt;
+mod breaker;
+mod retry;
+mod offload;
+mod mtu;
+mod fragment;
+mod kniless;
+mod vhost;
+mod inline;
+mod ha;
+mod session;
+mod maglev;
+mod h2;
+mod websocket;
+mod protocols;
+mod ipfix;
+mod sflow;
+mod logcontrol;
+mod preflight;
+mod loadgen;
+mod arena;
+mod allocations;
+mod channel;
+mod polling;
+mod watchdog;
+mod frontends;
 
-pub use cmanager::{ProxyConnection, Extension, ProxyRecStore};
+pub use cmanager::{ProxyConnection, Extension, ProxyRecStore, ClientSock, RateLimitConfig, LiveConnection, RecordRetention, RecordSampling, ConnectionKey, SourcePoolConfig, Slot, MAX_SOURCE_IPS, TimeWaitConfig, addressed_to_proxy};
+pub use selection::{SelectionPolicy, PolicySelector, ServerLoad};
+pub use health::{HealthCheckConfig, TargetHealth, spawn_health_checker, MAX_TARGETS};
+pub use timer::{CancellableWheel, TimerToken, Clock, TscClock, MockClock, calibrate_tsc};
+pub use drain::{DrainControl, wait_until_target_quiesced};
+pub use sni::{SniMap, AlpnMap, parse_sni, parse_alpn, client_hello_incomplete};
+pub use http::{HttpRoute, HttpRequest, HttpRouter, parse_http_request, http_header_incomplete, host_without_port};
+pub use proxy_protocol::{ProxyProtocol, proxy_protocol_header};
+pub use socks5::{Socks5Config, Socks5State, Socks5Resolver, Destination};
+pub use stats::{EngineStats, PipelineCounters, CoreLoad, spawn_stats_logger};
+pub use limits::ConnectionCounts;
+pub use control::spawn_control_server;
+pub use acl::{Acl, AclConfig};
+pub use capture::{CaptureControl, CaptureFilter, Capture};
+pub use admin::{ConnectionListing, ConnectionLookup, ConnectionQuery, spawn_admin_server};
+pub use events::{EventExportConfig, EventExporter, EventSender};
+pub use affinity::{AffinityConfig, AffinityTable};
+pub use numa::{node_of_cpu, node_of_pci_device, core_is_local_to_port};
+pub use idle::{IdleTimeouts, Teardown, KeepaliveConfig};
+pub use reorder::{ReorderConfig, ReorderQueue, ReorderBuffers, SegmentOrder};
+pub use rewrite::{SeqDeltas, apply_payload_rewrite};
+pub use builder::{ProxyEngineBuilder, EngineSummary};
+pub use validate::ConfigProblem;
+pub use overrides::{overrides_from_env, overrides_from_args, ENV_PREFIX};
+pub use resolver::{DnsConfig, resolve_host, resolve_targets, spawn_resolver};
+pub use arp::{ArpConfig, ArpMessage, ArpResolver, parse_arp, arp_request_bytes};
+pub use gateway::{GatewayConfig, next_hop};
+pub use vlan::{VlanConfig, Vlans, tag_towards_destination, MAX_VLAN_ID};
+pub use icmp::{IcmpConfig, EmbeddedSegment, internet_checksum};
+pub use groups::{TargetGroupsConfig, TargetGroups, spawn_group_watcher};
+pub use pacing::{PacingConfig, Pacer};
+pub use latency::{LatencyHistogram, PipelineLatencies, EngineLatencies};
+pub use spans::{TracingConfig, init_tracing, connection_span};
+pub use audit::{Leg, StateViolation, allowed_transition};
+pub use replay::{ReplayConfig, ReplayReport, ReplayReports, Replayer, read_pcap};
+pub use flowtable::{ConnectionTableConfig, ConnectionTableKind, FlowTable, SockTable};
+pub use close::{CloseReason, CloseReasonCounts};
+pub use rtt::LegTiming;
+pub use breaker::{CircuitBreakerConfig, CircuitBreakers};
+pub use retry::SynRetryConfig;
+pub use offload::{ChecksumMode, OffloadConfig, OffloadCapabilities, Offloads};
+pub use mtu::{MtuConfig, MIN_MTU, MAX_MTU, mss_of_mtu};
+pub use fragment::{FragmentConfig, FragmentResult, Reassembly, is_fragment, fragments};
+pub use kniless::KnilessConfig;
+pub use vhost::VhostUserPort;
+pub use inline::InlineConfig;
+pub use ha::{HaConfig, HaRole, ConnectionState, StateSync, spawn_state_sync};
+pub use session::SessionTable;
+pub use maglev::{ConsistentHashConfig, HashKey, MaglevTable};
+pub use h2::{parse_http2_request, http2_request_incomplete, is_http2_preface};
+pub use websocket::{WebSocketFrame, WebSocketState, websocket_aware};
+pub use protocols::{Protocol, ProtocolCount, ProtocolCounters, EngineProtocols};
+pub use ipfix::{IpfixConfig, FlowExporter, FlowSender};
+pub use sflow::{SflowConfig, SflowExporter, PacketSampler};
+pub use logcontrol::{init_logger, set_log_level, reset_log_levels, log_overrides, LogOverride};
+pub use preflight::{PreflightReport, PreflightCheck, CheckStatus};
+pub use loadgen::{LoadTestConfig, LoadReport, LoadGenerator};
+pub use arena::BoxArena;
+pub use allocations::{CountingAllocator, thread_allocations};
+pub use channel::{bounded, BoundedSender, BoundedReceiver, ChannelStats};
+pub use polling::{PollingConfig, IdleStrategy, IdlePoller, CycleMeter, packet_received};
+pub use watchdog::{WatchdogConfig, Watchdog, WatchdogAlert};
+pub use frontends::FrontendConfig;
+pub use buffering::{PayloadBuffering, BufferedPayload, BufferResult, buffer_payload};
+pub use tcp_options::{TcpOptionsConfig, SynOptions, WindowShifts, mss_option, syn_options, add_options, add_mss_option, syn_option_bytes, translate_sack_blocks, mss_towards_clients};
+pub use reload::{TargetTable, TargetEntry, l234data_for_target, read_configuration, read_configuration_as, ConfigFormat, install_sighup_handler, reload_requested, request_reload};
+pub use ipv6::{v4_to_key, key_to_v4, ip_to_key, key_to_ip};
+pub use nfudp::{UdpFlow, FiveTuple};
 
 use netfcts::tasks::TaskType;
 use netfcts::tasks::KniHandleRequest;
@@ -32,24 +175,39 @@ use e2d2::scheduler::*;
 use e2d2::interface::PmdPort;
 
 use nftcp::setup_delayed_proxy;
+use logcontrol::set_thread_core;
 use netfcts::{new_port_queues_for_core, physical_ports_for_core, RunConfiguration};
 use netfcts::comm::{MessageFrom, MessageTo, PipelineId};
 use netfcts::tcp_common::L234Data;
 use netfcts::utils::Timeouts;
 use netfcts::recstore::Store64;
 
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::collections::{HashMap, };
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::path::Path;
+use std::fs;
 
 pub trait FnSelectServer = Fn(&mut ProxyConnection) + Sized + Send + Sync + Clone + 'static;
 pub trait FnPayload = Fn(&mut ProxyConnection, &mut [u8], usize) + Sized + Send + Sync + Clone + 'static;
 
+/// type to use for `f_select_server` when the selection policy of the configuration shall be used, i.e. `None::<NoSelector>`
+pub type NoSelector = for<'r, 'a> fn(&'r mut ProxyConnection<'a>);
+
+/// type to use for `f_process_payload_s_c` when the payload of the servers shall not be processed, i.e. `None::<NoPayload>`
+pub type NoPayload = for<'r, 'a, 'p> fn(&'r mut ProxyConnection<'a>, &'p mut [u8], usize);
+
 #[derive(Deserialize, Clone)]
 pub struct Configuration {
     pub targets: Vec<TargetConfig>,
     pub engine: EngineConfig,
     pub test_size: Option<usize>,
+    /// proxy services on further ports, each with the targets of a backend, see FrontendConfig
+    pub frontends: Option<Vec<FrontendConfig>>,
+    /// backend name -> target ids, e.g. "api" = ["server3", "server4"]
+    pub backends: Option<HashMap<String, Vec<String>>>,
 }
 
 #[derive(Deserialize, Clone, PartialEq)]
@@ -64,35 +222,464 @@ pub struct EngineConfig {
     pub port: u16,
     pub detailed_records: Option<bool>,
     pub mode: Option<ProxyMode>,
+    /// if present, udp flows are proxied in addition to tcp connections
+    pub udp: Option<UdpConfig>,
+    /// built-in server selection, used if no selection closure is supplied, defaults to round_robin
+    pub selection: Option<SelectionPolicy>,
+    /// if present, targets are actively probed and targets which are down are skipped by the selection policies
+    pub health_check: Option<HealthCheckConfig>,
+    /// if present, on SIGINT or SIGTERM new connections are refused and established connections may finish
+    /// within this time (milli-seconds), before the engine terminates
+    pub drain_timeout: Option<u64>,
+    /// server name (SNI of the TLS ClientHello) -> target id, e.g. "api.example.com" = "server1" or "*.example.com" = "server2",
+    /// used before the selection policy, if no selection closure is supplied
+    pub sni_map: Option<HashMap<String, String>>,
+    /// protocol of the ALPN extension of the TLS ClientHello -> target id, e.g. "h2" = "server1", the first protocol
+    /// of the client in the map wins, used after the sni_map, if no selection closure is supplied
+    pub alpn_map: Option<HashMap<String, String>>,
+    /// routes by Host header and path prefix of the first HTTP/1.x request, first match wins,
+    /// used after the sni_map, if no selection closure is supplied
+    pub http_routes: Option<Vec<HttpRoute>>,
+    /// if true, the client ip address (but not the port) is used as source address towards the targets.
+    /// The proxy must be the gateway of the targets for the client addresses, so that the replies reach the proxy;
+    /// as the proxy port is kept, replies are steered to the pipeline of the connection like in non transparent mode
+    pub transparent: Option<bool>,
+    /// if present, clients negotiate the target with SOCKS5 (CONNECT without authentication) before the connection is spliced
+    pub socks5: Option<Socks5Config>,
+    /// if present, new connections per client address are limited
+    pub rate_limit: Option<RateLimitConfig>,
+    /// if true, client SYNs are answered with SYN cookies and the connection state is allocated with the ACK of the client
+    pub syn_cookies: Option<bool>,
+    /// maximum number of concurrent connections of the engine, new connections above are answered with a RST
+    pub max_connections: Option<u32>,
+    /// default for the maximum number of concurrent connections of a target, see TargetConfig
+    pub max_connections_per_target: Option<u32>,
+    /// path of the unix domain socket of the control channel, e.g. "/var/run/proxyengine.sock"
+    pub control_socket: Option<String>,
+    /// if present, SYNs of clients which are not permitted are dropped
+    pub acl: Option<AclConfig>,
+    /// address of the admin api, usually on the KNI interface, e.g. "192.168.222.1:8080"
+    pub admin_address: Option<String>,
+    /// if present, open and close events of the connections are exported as JSON lines
+    pub event_export: Option<EventExportConfig>,
+    /// if present, connection records (see detailed_records) are evicted by this policy
+    pub record_retention: Option<RecordRetention>,
+    /// if present, new connections of a client use the target of its previous connection, if the target is eligible;
+    /// used after the sni_map and the http_routes, if no selection closure is supplied
+    pub affinity: Option<AffinityConfig>,
+    /// if true, once client and server side are established, packets without SYN, FIN or RST bypass the state machine
+    /// and the payload closure; only use it, if the payload closure does not modify established connections
+    pub splice: Option<bool>,
+    /// if true, pipelines of ports on a different NUMA node than their core are not set up, otherwise a warning is logged
+    pub numa_strict: Option<bool>,
+    /// if present, the packet rates and counters of all pipelines are logged every stats_interval milli-seconds
+    pub stats_interval: Option<u64>,
+    /// if present, connections without packets are torn down after the idle timeout of their state,
+    /// otherwise connections are released after timeouts.established regardless of their activity
+    pub idle_timeouts: Option<IdleTimeouts>,
+    /// if present, the proxy sends keepalive probes to client and server of established connections without packets
+    pub keepalive: Option<KeepaliveConfig>,
+    /// if present, the SYN is sent to another target, when the selected target resets it or does not answer it in time
+    pub syn_retry: Option<SynRetryConfig>,
+    /// if present, the MSS announced by the proxy to clients and targets is clamped to this value,
+    /// e.g. 1460 minus the overhead of a tunnel on the client side, see also TargetConfig.mss
+    pub mss: Option<u16>,
+    /// if present, window scaling and SACK are negotiated on both legs, otherwise all TCP options but the MSS are stripped
+    pub tcp_options: Option<TcpOptionsConfig>,
+    /// if present, client segments with an incomplete TLS ClientHello or HTTP request header are buffered,
+    /// before the server is selected
+    pub payload_buffering: Option<PayloadBuffering>,
+    /// if present, segments received out of order on established connections are held back, until the gap is filled
+    pub reorder: Option<ReorderConfig>,
+    /// if present, the host names of the targets are resolved periodically, otherwise only at startup and on a reload
+    pub dns: Option<DnsConfig>,
+    /// if present, the MAC addresses of targets without mac and linux_if are resolved by ARP requests of the engine
+    pub arp: Option<ArpConfig>,
+    /// if present, targets without mac and linux_if, which are not on the local network, are reached via this gateway
+    pub gateway: Option<GatewayConfig>,
+    /// if present, the frames of the physical ports are 802.1Q tagged, see also TargetConfig.vlan
+    pub vlan: Option<VlanConfig>,
+    /// if present, ICMP messages to the engine are handled by the pipelines, otherwise they are passed to KNI
+    pub icmp: Option<IcmpConfig>,
+    /// if present, only the targets of the active group (see TargetConfig.group) and those without group get new connections
+    pub target_groups: Option<TargetGroupsConfig>,
+    /// if present, the packets sent by each pipeline are paced, see PacingConfig
+    pub pacing: Option<PacingConfig>,
+    /// if true, the pipelines record histograms of the handshake, binding and forwarding latencies, defaults to false
+    pub latency_histograms: Option<bool>,
+    /// if present, each connection is traced by a span of the tracing crate
+    pub tracing: Option<TracingConfig>,
+    /// if true, the state transitions of the connections are checked against the transition tables of the legs and
+    /// violations are logged with the offending packet, defaults to true in debug builds
+    pub state_audit: Option<bool>,
+    /// if present, the pcap file is replayed into the pipeline of rx queue 0 and its packets are compared with the
+    /// expected packets of the pcap instead of being sent, see ReplayConfig
+    pub replay: Option<ReplayConfig>,
+    /// if true, the TSC frequency is measured at startup and replaces the frequency of the system data,
+    /// when they deviate by more than 1 %, defaults to true
+    pub calibrate_tsc: Option<bool>,
+    /// kind and sizing of the table, which maps the client sockets to the connections of a pipeline,
+    /// defaults to an ordered map
+    pub connection_table: Option<ConnectionTableConfig>,
+    /// if present, the pipelines also use these source addresses towards the targets, not in transparent mode
+    pub source_pool: Option<SourcePoolConfig>,
+    /// if present, the proxy ports of closed connections are reused after the TIME_WAIT, otherwise immediately
+    pub time_wait: Option<TimeWaitConfig>,
+    /// checksum and segmentation offload, by default the checksums are computed by the NIC, if the port is capable
+    pub offload: Option<OffloadConfig>,
+    /// if present, the MTU of the ports is set, e.g. to 9000 for jumbo frames, and the MSS is clamped to it
+    pub mtu: Option<MtuConfig>,
+    /// if present, fragmented tcp datagrams to the engine are reassembled and datagrams exceeding the MTU of
+    /// the outgoing leg are fragmented
+    pub fragments: Option<FragmentConfig>,
+    /// if present, ports without KNI interface are proxied and the control plane is served in the data path
+    pub kniless: Option<KnilessConfig>,
+    /// if present, the clients and the targets are reached through different ports, see InlineConfig
+    pub inline: Option<InlineConfig>,
+    /// if present, the established connections are replicated to a standby engine, see HaConfig
+    pub ha: Option<HaConfig>,
+    /// if present, the spliced connections are written to this file on shutdown and restored from it on startup,
+    /// see SessionTable
+    pub session_file: Option<String>,
+    /// key and table size of the maglev selection policy
+    pub consistent_hash: Option<ConsistentHashConfig>,
+    /// if true, the pipelines count the connections and payload bytes per target and protocol (TLS, HTTP/1.x, HTTP/2 or
+    /// other), see GET /protocols of the admin server, defaults to false
+    pub protocol_counters: Option<bool>,
+    /// if present, only a sample of the connections gets a connection record (see detailed_records)
+    pub record_sampling: Option<RecordSampling>,
+    /// if present, the closed connections are exported as IPFIX flow records
+    pub ipfix: Option<IpfixConfig>,
+    /// if present, a random sample of the received packets is exported to an sFlow collector
+    pub sflow: Option<SflowConfig>,
+    /// if present, synthetic clients connect to the engine, while it runs, and the engine terminates after the test
+    pub load_test: Option<LoadTestConfig>,
+    /// receive burst size and the idle strategy of the scheduler threads
+    pub polling: Option<PollingConfig>,
+    /// if present, a watchdog raises alerts, when the scheduler of a core stalls or its rounds take too long
+    pub watchdog: Option<WatchdogConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UdpConfig {
+    /// udp port the proxy listens on
+    pub port: u16,
+    /// udp flows without packets for this time (milli-seconds) are released
+    pub idle_timeout: Option<u64>,
+}
+
+fn unspecified_ip() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::UNSPECIFIED)
 }
 
 #[derive(Deserialize, Clone)]
 pub struct TargetConfig {
     pub id: String,
-    pub ip: Ipv4Addr,
+    /// may be omitted, if host is present; the data path is IPv4 only, IPv6 addresses are rejected by validate
+    #[serde(default = "unspecified_ip")]
+    pub ip: IpAddr,
+    /// if present, ip is the address of this host name, see DnsConfig
+    pub host: Option<String>,
+    /// if neither mac nor linux_if is present, the MAC address is resolved by ARP, see ArpConfig
     pub mac: Option<MacAddress>,
     pub linux_if: Option<String>,
     pub port: u16,
+    /// weight for the weighted, least_conn and least_response_time selection policies, defaults to 1
+    pub weight: Option<u32>,
+    /// if present, a PROXY protocol header ("v1" or "v2") with the client socket is sent to the target
+    pub proxy_protocol: Option<ProxyProtocol>,
+    /// maximum number of concurrent connections of this target, when all targets are at capacity, clients are reset
+    pub max_connections: Option<u32>,
+    /// if present, the MSS announced to this target is clamped to this value, e.g. when the target is behind a tunnel;
+    /// clients are announced the minimum of the MSS of all active targets
+    pub mss: Option<u16>,
+    /// VLAN id of the target, if it differs from the one of the port, see VlanConfig
+    pub vlan: Option<u16>,
+    /// target group, e.g. "blue" or "green", see TargetGroupsConfig
+    pub group: Option<String>,
+    /// if present, the target is taken out of rotation, when too many of its connections fail
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// if true, the target only gets new connections, when no other target is eligible, i.e. all are down, tripped or
+    /// at capacity, e.g. a sorry server with a maintenance page
+    pub fallback: Option<bool>,
+}
+
+impl TargetConfig {
+    /// IPv4 address of the target as used in L234Data, 0 for IPv6 targets, which do not pass validate
+    #[inline]
+    pub fn ipv4(&self) -> u32 {
+        match self.ip {
+            IpAddr::V4(ip) => u32::from(ip),
+            IpAddr::V6(_) => 0,
+        }
+    }
+
+    /// the address of the target in the 128-bit flow key format
+    #[inline]
+    pub fn ip_key(&self) -> u128 {
+        ip_to_key(&self.ip)
+    }
+
+    #[inline]
+    pub fn is_fallback(&self) -> bool {
+        self.fallback.unwrap_or(false)
+    }
+}
+
+/// State shared by the main thread and the pipelines on all cores, e.g. for use in selection closures.
+#[derive(Clone)]
+pub struct SharedState {
+    pub target_health: TargetHealth,
+    /// circuit breakers of the targets, see TargetConfig.circuit_breaker
+    pub breakers: CircuitBreakers,
+    pub targets: TargetTable,
+    pub drain: DrainControl,
+    pub stats: EngineStats,
+    /// key of the SYN cookies, the same for all pipelines
+    pub syn_cookie_secret: u64,
+    pub connections: ConnectionCounts,
+    pub capture: CaptureControl,
+    pub listing: ConnectionListing,
+    /// lookup of a connection by uuid or client socket in all pipelines
+    pub lookup: ConnectionLookup,
+    pub events: EventExporter,
+    /// client affinity, see EngineConfig.affinity
+    pub affinity: AffinityTable,
+    /// the active target group, see EngineConfig.target_groups
+    pub groups: TargetGroups,
+    /// latency histograms of the pipelines, see EngineConfig.latency_histograms
+    pub latencies: EngineLatencies,
+    /// results of the pcap replay, see EngineConfig.replay
+    pub replay: ReplayReports,
+    /// connection states of the active engine, see EngineConfig.ha
+    pub state_sync: StateSync,
+    /// connection table of the session file, see EngineConfig.session_file
+    pub sessions: SessionTable,
+    /// connections and bytes per target and protocol, see EngineConfig.protocol_counters
+    pub protocols: EngineProtocols,
+    /// IPFIX export of the closed connections, see EngineConfig.ipfix
+    pub flows: FlowExporter,
+    /// packet sampling of the pipelines, see EngineConfig.sflow
+    pub sflow: SflowExporter,
+    /// alerts of stalled cores, see EngineConfig.watchdog
+    pub watchdog: Watchdog,
+}
+
+impl SharedState {
+    pub fn new(configuration: &Configuration) -> SharedState {
+        if configuration.targets.len() > MAX_TARGETS {
+            error!("more than {} targets configured", MAX_TARGETS);
+        }
+        SharedState {
+            target_health: TargetHealth::new(),
+            breakers: CircuitBreakers::new(),
+            targets: TargetTable::new(configuration),
+            drain: DrainControl::new(),
+            stats: EngineStats::new(),
+            syn_cookie_secret: Uuid::new_v4().as_u128() as u64,
+            connections: ConnectionCounts::new(),
+            capture: CaptureControl::new(),
+            listing: ConnectionListing::new(),
+            lookup: ConnectionLookup::new(),
+            events: EventExporter::new(),
+            affinity: AffinityTable::new(),
+            groups: TargetGroups::new(&configuration.engine.target_groups),
+            latencies: EngineLatencies::new(),
+            replay: ReplayReports::new(),
+            state_sync: StateSync::new(),
+            sessions: SessionTable::new(),
+            protocols: EngineProtocols::new(),
+            flows: FlowExporter::new(),
+            sflow: SflowExporter::new(),
+            watchdog: Watchdog::new(),
+        }
+    }
+
+    /// starts the health checker thread, if health checks are configured
+    pub fn start_health_checks(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .health_check
+            .as_ref()
+            .map(|hc| spawn_health_checker(self.targets.clone(), hc.clone(), self.target_health.clone()))
+    }
+
+    /// starts the control channel, if a control socket is configured
+    pub fn start_control_channel(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .control_socket
+            .as_ref()
+            .and_then(|path| match spawn_control_server(path, configuration, self.clone()) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    error!("cannot start control channel on {}: {}", path, e);
+                    None
+                }
+            })
+    }
+
+    /// starts the admin api, if an admin address is configured
+    pub fn start_admin_api(&self, configuration: &Configuration, cpu_clock: u64) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .admin_address
+            .as_ref()
+            .and_then(|address| match spawn_admin_server(address, configuration, self.clone(), cpu_clock) {
+                Ok(handle) => Some(handle),
+                Err(e) => {
+                    error!("cannot start admin api on {}: {}", address, e);
+                    None
+                }
+            })
+    }
+
+    /// starts the periodic logging of the pipeline statistics, if a stats_interval is configured
+    pub fn start_stats_logger(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .stats_interval
+            .map(|interval| spawn_stats_logger(self.stats.clone(), Duration::from_millis(interval)))
+    }
+
+    /// starts the periodic resolution of the host names of the targets, if configured
+    pub fn start_resolver(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        if !configuration.targets.iter().any(|t| t.host.is_some()) {
+            return None;
+        }
+        configuration
+            .engine
+            .dns
+            .as_ref()
+            .map(|config| spawn_resolver(self.targets.clone(), config.clone()))
+    }
+
+    /// starts the rollback of target group switches, if target groups and health checks are configured
+    pub fn start_group_watcher(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        if configuration.engine.health_check.is_none() {
+            return None;
+        }
+        configuration
+            .engine
+            .target_groups
+            .as_ref()
+            .map(|config| spawn_group_watcher(self.groups.clone(), self.targets.clone(), self.target_health.clone(), config.clone()))
+    }
+
+    /// reads and removes the session file, if configured and present, so that the sessions are not restored again
+    /// after a crash, this must happen before the pipelines are installed
+    pub fn load_sessions(&self, configuration: &Configuration) {
+        if let Some(path) = configuration.engine.session_file.as_ref() {
+            if Path::new(path).exists() {
+                match self.sessions.load(path) {
+                    Ok(count) => info!("read {} connections from session file {}", count, path),
+                    Err(e) => error!("cannot restore sessions: {}", e),
+                }
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("cannot remove session file {}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    /// writes the connections of all pipelines to the session file, if configured
+    pub fn save_sessions(&self, configuration: &Configuration) {
+        if let Some(path) = configuration.engine.session_file.as_ref() {
+            match self.sessions.save(path) {
+                Ok(count) => info!("wrote {} connections to session file {}", count, path),
+                Err(e) => error!("cannot save sessions: {}", e),
+            }
+        }
+    }
+
+    /// starts the replication channel of the connection states, if configured
+    pub fn start_state_sync(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .ha
+            .as_ref()
+            .map(|config| spawn_state_sync(config.clone(), self.state_sync.clone()))
+    }
+
+    /// starts the event exporter, if configured, this must happen before the pipelines are installed
+    pub fn start_event_export(&self, configuration: &Configuration, cpu_clock: u64) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .event_export
+            .as_ref()
+            .map(|config| self.events.start(config, self.targets.clone(), cpu_clock))
+    }
+
+    /// starts the IPFIX exporter, if configured, this must happen before the pipelines are installed
+    pub fn start_ipfix_export(&self, configuration: &Configuration, cpu_clock: u64) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .ipfix
+            .as_ref()
+            .map(|config| self.flows.start(config, self.targets.clone(), cpu_clock))
+    }
+
+    /// starts the sFlow exporter, if configured, this must happen before the pipelines are installed
+    pub fn start_sflow_export(&self, configuration: &Configuration) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .sflow
+            .as_ref()
+            .map(|config| self.sflow.start(config))
+    }
+
+    /// starts the watchdog of the cores, if configured
+    pub fn start_watchdog(&self, configuration: &Configuration, cpu_clock: u64) -> Option<JoinHandle<()>> {
+        configuration
+            .engine
+            .watchdog
+            .as_ref()
+            .map(|config| self.watchdog.start(config, self.stats.clone(), cpu_clock))
+    }
+
+    /// new connections use the targets and timeouts of the new configuration, existing connections keep their server
+    pub fn reload(&self, configuration: &Configuration) -> Result<usize, String> {
+        self.targets.reload(configuration)
+    }
 }
 
 /// This function is called once by each scheduler running as an independent thread on each active core when the RunTime installs the pipelines.
 /// Currently it iterates through all physical ports which use the respective core and sets up the network function graph (NFG) of the proxy for that port and that core.
 /// This happens by adding Runnables to the scheduler. Each Runnable runs to completion. E.g. it takes a packet batch from an ingress queue, processes the packets
 /// following the NFG and puts the packets of the batch into egress queues. After this it returns to the scheduler.
-pub fn setup_pipes_delayed_proxy<F1, F2>(
+/// If f_select_server is None, the target server is selected by the selection policy of the engine configuration.
+/// f_process_payload_c_s is called with the payload of client segments, f_process_payload_s_c, if not None, with the payload
+/// of server segments; both are skipped on the spliced fast path.
+pub fn setup_pipes_delayed_proxy<F1, F2, F3>(
     core: i32,
     pmd_ports: HashMap<String, Arc<PmdPort>>,
     sched: &mut StandaloneScheduler,
     run_configuration: RunConfiguration<Configuration, Store64<Extension>>,
     servers: Vec<L234Data>,
-    f_select_server: F1,
+    shared: SharedState,
+    f_select_server: Option<F1>,
     f_process_payload_c_s: F2,
+    f_process_payload_s_c: Option<F3>,
 ) where
     F1: FnSelectServer,
     F2: FnPayload,
+    F3: FnPayload,
 {
+    set_thread_core(core);
+    let inline = run_configuration.engine_configuration.engine.inline.clone();
     for pmd_port in physical_ports_for_core(core, &pmd_ports) {
+        if inline.is_some() && pmd_port.name() == inline.as_ref().unwrap().server_port {
+            // the queues of the server port are set up together with those of the client port
+            continue;
+        }
         debug!("setup_pipelines for {} on core {}:", pmd_port.name(), core);
+        // the state of a pipeline is allocated on the node of its core by the scheduler thread of the core
+        if !core_is_local_to_port(core, pmd_port.name())
+            && run_configuration.engine_configuration.engine.numa_strict.unwrap_or(false)
+        {
+            error!("not setting up {} on core {}, as the port is on another NUMA node", pmd_port.name(), core);
+            continue;
+        }
         let mut kni_port = None;
         if pmd_port.kni_name().is_some() {
             kni_port = pmd_ports.get(pmd_port.kni_name().unwrap());
@@ -140,17 +727,41 @@ pub fn setup_pipes_delayed_proxy<F1, F2>(
             );
         }
 
-        if pci.is_some() && kni.is_some() {
+        let mut server_port = None;
+        if inline.is_some() && pmd_port.name() == inline.as_ref().unwrap().client_port {
+            let server_pmd_port = pmd_ports.get(&inline.as_ref().unwrap().server_port);
+            if server_pmd_port.is_some() {
+                server_port = new_port_queues_for_core(core, server_pmd_port.unwrap(), None).0;
+            }
+            if server_port.is_none() {
+                error!("not setting up {} on core {}, as the server port has no queue on this core", pmd_port.name(), core);
+                continue;
+            }
+        }
+
+        let kniless = run_configuration.engine_configuration.engine.kniless.is_some();
+        if pci.is_some() && (kni.is_some() || kniless) {
             setup_delayed_proxy(
                 core,
                 pci.unwrap(),
-                kni.unwrap(),
+                kni,
+                server_port,
                 sched,
                 run_configuration.clone(),
                 servers.clone(),
+                shared.clone(),
                 f_select_server.clone(),
                 f_process_payload_c_s.clone(),
+                f_process_payload_s_c.clone(),
             );
         }
     }
+    let polling = run_configuration.engine_configuration.engine.polling.as_ref();
+    if polling.map_or(false, |config| config.idle() != IdleStrategy::Spin) {
+        // one task for the pipelines of all ports of the core, as they share the scheduler
+        let poller = IdlePoller::new(polling.unwrap());
+        sched.add_runnable(Runnable::from_task(Uuid::new_v4(), String::from("IdlePoller"), poller).move_ready());
+    }
+    let meter = CycleMeter::new(core, shared.stats.clone(), run_configuration.system_data.cpu_clock);
+    sched.add_runnable(Runnable::from_task(Uuid::new_v4(), String::from("CycleMeter"), meter).move_ready());
 }
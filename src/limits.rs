@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use health::MAX_TARGETS;
+
+/// Number of connections of the engine and per target, shared by all pipelines.
+/// The caps are checked before a connection is counted, therefore they may be exceeded by one connection per pipeline.
+#[derive(Clone)]
+pub struct ConnectionCounts {
+    total: Arc<AtomicUsize>,
+    per_target: Arc<Vec<AtomicUsize>>,
+}
+
+impl ConnectionCounts {
+    pub fn new() -> ConnectionCounts {
+        ConnectionCounts {
+            total: Arc::new(AtomicUsize::new(0)),
+            per_target: Arc::new((0..MAX_TARGETS).map(|_| AtomicUsize::new(0)).collect()),
+        }
+    }
+
+    #[inline]
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn opened(&self) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn closed(&self) {
+        self.total.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn target(&self, target: usize) -> usize {
+        self.per_target[target].load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    pub fn target_bound(&self, target: usize) {
+        self.per_target[target].fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn target_unbound(&self, target: usize) {
+        self.per_target[target].fetch_sub(1, Ordering::Relaxed);
+    }
+}
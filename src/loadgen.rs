@@ -0,0 +1,348 @@
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sched::{sched_setaffinity, setns, CloneFlags, CpuSet};
+use nix::unistd::Pid;
+
+use latency::LatencyHistogram;
+use TargetConfig;
+
+const DEFAULT_RATE: u32 = 100;
+const DEFAULT_PAYLOAD_SIZE: usize = 64;
+const DEFAULT_DURATION: u64 = 10;
+const DEFAULT_TIMEOUT: u64 = 1;
+/// threads accepting connections per target
+const ACCEPTORS: usize = 4;
+
+/// A load test of the engine with synthetic clients, e.g. with the client and the server port of the engine
+/// connected to the ports of another NIC or a NIC pair assigned to the namespaces of the clients and the servers.
+/// Each generator thread opens its connections one after the other, sends the payload, waits for the reply and closes
+/// the connection. The rate is reached, as long as a connection takes less than the interval of its thread, use
+/// more cores for higher rates. The engine terminates after the test, the report is logged and returned by run.
+/// The first payload of the clients is synthetic, the targets must be selected by the selection policy.
+#[derive(Deserialize, Clone)]
+pub struct LoadTestConfig {
+    /// the address of the proxy, e.g. the address of the KNI interface and engine.port
+    pub proxy: SocketAddr,
+    /// new connections per second of all generator threads, defaults to 100
+    pub rate: Option<u32>,
+    /// bytes sent by the client on each connection, the servers of the generator reply with the same bytes,
+    /// defaults to 64
+    pub payload_size: Option<usize>,
+    /// seconds until the test ends, defaults to 10
+    pub duration: Option<u64>,
+    /// one generator thread is pinned to each of these cores, which should not run pipelines,
+    /// defaults to one thread without affinity
+    pub cores: Option<Vec<i32>>,
+    /// network namespace of the clients
+    pub namespace: Option<String>,
+    /// if true, the generator listens on the addresses of the targets and replies to the clients, defaults to true
+    pub serve_targets: Option<bool>,
+    /// network namespace of the servers of the generator
+    pub server_namespace: Option<String>,
+    /// seconds to wait for the connection and the reply, defaults to 1
+    pub timeout: Option<u64>,
+}
+
+impl LoadTestConfig {
+    #[inline]
+    pub fn rate(&self) -> u32 {
+        self.rate.unwrap_or(DEFAULT_RATE)
+    }
+
+    #[inline]
+    pub fn payload_size(&self) -> usize {
+        self.payload_size.unwrap_or(DEFAULT_PAYLOAD_SIZE)
+    }
+
+    #[inline]
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration.unwrap_or(DEFAULT_DURATION))
+    }
+
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(DEFAULT_TIMEOUT))
+    }
+}
+
+/// the result of a load test, latencies in nano-seconds
+#[derive(Clone)]
+pub struct LoadReport {
+    pub connections: u64,
+    /// connections, which could not be opened or did not get a reply
+    pub failures: u64,
+    pub sent_bytes: u64,
+    pub received_bytes: u64,
+    pub elapsed: Duration,
+    /// until the connection is established
+    pub connect: LatencyHistogram,
+    /// from the start of the connection until the first bytes of the reply
+    pub response: LatencyHistogram,
+}
+
+impl LoadReport {
+    fn new() -> LoadReport {
+        LoadReport {
+            connections: 0,
+            failures: 0,
+            sent_bytes: 0,
+            received_bytes: 0,
+            elapsed: Duration::from_secs(0),
+            connect: LatencyHistogram::new(),
+            response: LatencyHistogram::new(),
+        }
+    }
+
+    fn add(&mut self, other: &LoadReport) {
+        self.connections += other.connections;
+        self.failures += other.failures;
+        self.sent_bytes += other.sent_bytes;
+        self.received_bytes += other.received_bytes;
+        self.elapsed = cmp::max(self.elapsed, other.elapsed);
+        self.connect.add(&other.connect);
+        self.response.add(&other.response);
+    }
+
+    /// connections per second
+    pub fn cps(&self) -> f64 {
+        self.connections as f64 / seconds(self.elapsed)
+    }
+
+    /// payload bits per second of both directions
+    pub fn throughput(&self) -> f64 {
+        (self.sent_bytes + self.received_bytes) as f64 * 8.0 / seconds(self.elapsed)
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+    if seconds > 0.0 {
+        seconds
+    } else {
+        1.0
+    }
+}
+
+impl fmt::Display for LoadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} connections ({} failed) in {:.1} s: {:.0} cps, {:.1} Mbit/s, connect p50/p99 {}/{} us, \
+             response p50/p99 {}/{} us",
+            self.connections,
+            self.failures,
+            seconds(self.elapsed),
+            self.cps(),
+            self.throughput() / 1e6,
+            self.connect.percentile(0.5) / 1000,
+            self.connect.percentile(0.99) / 1000,
+            self.response.percentile(0.5) / 1000,
+            self.response.percentile(0.99) / 1000,
+        )
+    }
+}
+
+#[inline]
+fn nanos(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
+}
+
+fn enter_namespace(ns: &str) {
+    let path = format!("/var/run/netns/{}", ns);
+    match File::open(&path) {
+        Ok(f) => {
+            if let Err(e) = setns(f.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+                error!("load test cannot enter namespace {}: {}", ns, e);
+            }
+        }
+        Err(e) => error!("load test cannot open {}: {}", path, e),
+    }
+}
+
+fn pin_to_core(core: i32) {
+    let mut cpuset = CpuSet::new();
+    let _ = cpuset.set(core as usize);
+    if let Err(e) = sched_setaffinity(Pid::from_raw(0), &cpuset) {
+        warn!("load test cannot pin generator to core {}: {}", core, e);
+    }
+}
+
+/// replies to the client with the bytes it has sent
+fn serve(mut stream: TcpStream, payload_size: usize, timeout: Duration) {
+    let _ = stream.set_read_timeout(Some(timeout));
+    let mut buf = vec![0u8; payload_size];
+    let mut received = 0;
+    while received < payload_size {
+        match stream.read(&mut buf[received..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => received += n,
+        }
+    }
+    let _ = stream.write_all(&buf[..received]);
+}
+
+fn start_servers(config: &LoadTestConfig, targets: &[TargetConfig]) {
+    for target in targets {
+        let address = SocketAddr::new(target.ip, target.port);
+        let namespace = config.server_namespace.clone();
+        let payload_size = config.payload_size();
+        let timeout = config.timeout();
+        // the acceptor threads are left running, when the test ends
+        thread::spawn(move || {
+            if namespace.is_some() {
+                enter_namespace(namespace.as_ref().unwrap());
+            }
+            let listener = match TcpListener::bind(address) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!("load test cannot listen on {}: {}", address, e);
+                    return;
+                }
+            };
+            debug!("load test serves {}", address);
+            for _ in 1..ACCEPTORS {
+                if let Ok(listener) = listener.try_clone() {
+                    thread::spawn(move || {
+                        for stream in listener.incoming().filter_map(|s| s.ok()) {
+                            serve(stream, payload_size, timeout);
+                        }
+                    });
+                }
+            }
+            for stream in listener.incoming().filter_map(|s| s.ok()) {
+                serve(stream, payload_size, timeout);
+            }
+        });
+    }
+}
+
+/// opens a connection, sends the payload and waits for the reply
+fn connect(config: &LoadTestConfig, payload: &[u8], report: &mut LoadReport) {
+    let started = Instant::now();
+    report.connections += 1;
+    let mut stream = match TcpStream::connect_timeout(&config.proxy, config.timeout()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            debug!("load test cannot connect to {}: {}", config.proxy, e);
+            report.failures += 1;
+            return;
+        }
+    };
+    report.connect.record(nanos(started.elapsed()));
+    let _ = stream.set_read_timeout(Some(config.timeout()));
+    if stream.write_all(payload).is_err() {
+        report.failures += 1;
+        return;
+    }
+    report.sent_bytes += payload.len() as u64;
+    let mut buf = vec![0u8; payload.len()];
+    let mut received = 0;
+    while received < payload.len() {
+        match stream.read(&mut buf[received..]) {
+            Ok(0) | Err(_) => break,
+            Ok(n) => {
+                if received == 0 {
+                    report.response.record(nanos(started.elapsed()));
+                }
+                received += n;
+            }
+        }
+    }
+    if received == 0 {
+        report.failures += 1;
+    }
+    report.received_bytes += received as u64;
+}
+
+fn generate(config: LoadTestConfig, core: Option<i32>, rate: u32) -> LoadReport {
+    if core.is_some() {
+        pin_to_core(core.unwrap());
+    }
+    if config.namespace.is_some() {
+        enter_namespace(config.namespace.as_ref().unwrap());
+    }
+    let mut report = LoadReport::new();
+    let payload: Vec<u8> = (0..config.payload_size()).map(|i| b'a' + (i % 26) as u8).collect();
+    let interval = Duration::from_secs(1) / cmp::max(rate, 1);
+    let duration = config.duration();
+    let started = Instant::now();
+    let mut next = started;
+    while started.elapsed() < duration {
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+        }
+        next += interval;
+        connect(&config, &payload, &mut report);
+    }
+    report.elapsed = started.elapsed();
+    report
+}
+
+/// A running load test, see LoadTestConfig.
+pub struct LoadGenerator {
+    finished: Arc<AtomicBool>,
+    handle: thread::JoinHandle<LoadReport>,
+}
+
+impl LoadGenerator {
+    /// starts the servers and the generator threads, the engine must already run
+    pub fn start(config: &LoadTestConfig, targets: &[TargetConfig]) -> LoadGenerator {
+        if config.serve_targets.unwrap_or(true) {
+            start_servers(config, targets);
+            // until the servers listen
+            thread::sleep(Duration::from_millis(200));
+        }
+        let cores: Vec<Option<i32>> = match config.cores.as_ref() {
+            Some(cores) if !cores.is_empty() => cores.iter().map(|core| Some(*core)).collect(),
+            _ => vec![None],
+        };
+        let rate = config.rate() / cores.len() as u32;
+        info!(
+            "load test of {} for {} s with {} connections per second",
+            config.proxy,
+            config.duration().as_secs(),
+            config.rate()
+        );
+        let generators: Vec<thread::JoinHandle<LoadReport>> = cores
+            .into_iter()
+            .map(|core| {
+                let config = config.clone();
+                thread::spawn(move || generate(config, core, rate))
+            })
+            .collect();
+        let finished = Arc::new(AtomicBool::new(false));
+        let f = finished.clone();
+        let handle = thread::spawn(move || {
+            let mut report = LoadReport::new();
+            for generator in generators {
+                match generator.join() {
+                    Ok(r) => report.add(&r),
+                    Err(_) => error!("load test generator panicked"),
+                }
+            }
+            f.store(true, Ordering::SeqCst);
+            report
+        });
+        LoadGenerator { finished, handle }
+    }
+
+    #[inline]
+    pub fn finished(&self) -> bool {
+        self.finished.load(Ordering::SeqCst)
+    }
+
+    /// waits for the end of the test
+    pub fn join(self) -> LoadReport {
+        self.handle.join().unwrap_or_else(|_| LoadReport::new())
+    }
+}
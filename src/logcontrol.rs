@@ -0,0 +1,161 @@
+use std::cell::Cell;
+use std::cmp;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use env_logger;
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+
+thread_local! {
+    /// the core of a scheduler thread, see set_thread_core
+    static CORE: Cell<Option<i32>> = Cell::new(None);
+}
+
+/// called by the scheduler thread of a core, when it sets up its pipelines
+pub fn set_thread_core(core: i32) {
+    CORE.with(|c| c.set(Some(core)));
+}
+
+/// a log level, which replaces the filter of RUST_LOG for a module until it expires
+#[derive(Clone, Debug)]
+pub struct LogOverride {
+    /// a module path, e.g. "tcp_proxy::nftcp", or a segment of it, e.g. "nftcp"
+    pub module: String,
+    pub level: LevelFilter,
+    /// if present, only the pipelines of this core, as the pipelines of a core share its scheduler thread
+    pub core: Option<i32>,
+    pub until: Option<Instant>,
+}
+
+impl LogOverride {
+    #[inline]
+    fn matches(&self, target: &str) -> bool {
+        (target == self.module
+            || target.starts_with(&self.module) && target[self.module.len()..].starts_with("::")
+            || target.split("::").any(|segment| segment == self.module))
+            && (self.core.is_none() || CORE.with(|c| c.get()) == self.core)
+    }
+
+    #[inline]
+    fn expired(&self, now: Instant) -> bool {
+        self.until.map_or(false, |until| now >= until)
+    }
+}
+
+/// The logger of env_logger with overrides of the log level per module, which the control channel changes at runtime,
+/// see the log command.
+struct ControlledLogger {
+    inner: env_logger::Logger,
+    /// writes the records enabled by an override, which the filter of inner rejects
+    unfiltered: env_logger::Logger,
+    /// fast path, while there are no overrides
+    overridden: AtomicBool,
+    overrides: RwLock<Vec<LogOverride>>,
+}
+
+impl ControlledLogger {
+    /// the level of the latest matching override, which has not expired
+    fn override_level(&self, target: &str) -> Option<LevelFilter> {
+        let now = Instant::now();
+        self.overrides
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|o| !o.expired(now) && o.matches(target))
+            .map(|o| o.level)
+    }
+
+    fn enabled_for(&self, level: Level, target: &str) -> Option<bool> {
+        if !self.overridden.load(Ordering::Relaxed) {
+            return None;
+        }
+        self.override_level(target).map(|filter| level <= filter)
+    }
+}
+
+impl Log for ControlledLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.enabled_for(metadata.level(), metadata.target())
+            .unwrap_or_else(|| self.inner.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        match self.enabled_for(record.level(), record.target()) {
+            Some(false) => (),
+            Some(true) => self.unfiltered.log(record),
+            None => self.inner.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+static mut LOGGER: Option<&'static ControlledLogger> = None;
+
+fn logger() -> Option<&'static ControlledLogger> {
+    unsafe { LOGGER }
+}
+
+/// Installs env_logger, configured by RUST_LOG, with runtime control of the log levels, replaces env_logger::init.
+/// Without it the log command of the control channel is not available.
+pub fn init_logger() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter();
+    let controlled: &'static ControlledLogger = Box::leak(Box::new(ControlledLogger {
+        inner,
+        unfiltered: env_logger::Builder::new().filter_level(LevelFilter::Trace).build(),
+        overridden: AtomicBool::new(false),
+        overrides: RwLock::new(Vec::new()),
+    }));
+    match log::set_logger(controlled) {
+        Ok(()) => {
+            unsafe { LOGGER = Some(controlled) };
+            log::set_max_level(max_level);
+        }
+        Err(e) => eprintln!("cannot install the logger: {}", e),
+    }
+}
+
+/// removes the expired overrides and sets the maximum level of the log crate
+fn update(logger: &ControlledLogger, overrides: &mut Vec<LogOverride>) {
+    let now = Instant::now();
+    overrides.retain(|o| !o.expired(now));
+    let max_level = overrides.iter().fold(logger.inner.filter(), |max, o| cmp::max(max, o.level));
+    logger.overridden.store(!overrides.is_empty(), Ordering::Relaxed);
+    log::set_max_level(max_level);
+}
+
+/// adds an override, which expires after duration, if given
+pub fn set_log_level(module: &str, level: LevelFilter, duration: Option<Duration>, core: Option<i32>) -> Result<(), String> {
+    let logger = logger().ok_or("the logger is not installed by init_logger".to_string())?;
+    let mut overrides = logger.overrides.write().unwrap();
+    overrides.push(LogOverride {
+        module: module.to_string(),
+        level,
+        core,
+        until: duration.map(|d| Instant::now() + d),
+    });
+    update(logger, &mut overrides);
+    Ok(())
+}
+
+/// removes all overrides
+pub fn reset_log_levels() -> Result<(), String> {
+    let logger = logger().ok_or("the logger is not installed by init_logger".to_string())?;
+    let mut overrides = logger.overrides.write().unwrap();
+    overrides.clear();
+    update(logger, &mut overrides);
+    Ok(())
+}
+
+/// the overrides, which have not expired
+pub fn log_overrides() -> Result<Vec<LogOverride>, String> {
+    let logger = logger().ok_or("the logger is not installed by init_logger".to_string())?;
+    let mut overrides = logger.overrides.write().unwrap();
+    update(logger, &mut overrides);
+    Ok(overrides.clone())
+}
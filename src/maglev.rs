@@ -0,0 +1,144 @@
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+
+use cmanager::ProxyConnection;
+use reload::TargetEntry;
+
+const DEFAULT_TABLE_SIZE: usize = 65537;
+const EMPTY: u16 = u16::max_value();
+
+/// the part of the connection, which is hashed by SelectionPolicy::Maglev
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    ClientIp,
+    /// server name of the TLS ClientHello
+    Sni,
+    /// host of the first HTTP request
+    Host,
+}
+
+/// Consistent hashing of SelectionPolicy::Maglev. With sni or host, connections without server name or host are
+/// hashed by their client ip.
+#[derive(Deserialize, Clone)]
+pub struct ConsistentHashConfig {
+    /// defaults to client_ip
+    pub key: Option<HashKey>,
+    /// entries of the lookup table, a prime much larger than the number of targets, defaults to 65537
+    pub table_size: Option<usize>,
+}
+
+impl ConsistentHashConfig {
+    #[inline]
+    pub fn key(&self) -> HashKey {
+        self.key.unwrap_or(HashKey::ClientIp)
+    }
+
+    #[inline]
+    pub fn table_size(&self) -> usize {
+        self.table_size.unwrap_or(DEFAULT_TABLE_SIZE)
+    }
+}
+
+impl Default for ConsistentHashConfig {
+    fn default() -> ConsistentHashConfig {
+        ConsistentHashConfig {
+            key: None,
+            table_size: None,
+        }
+    }
+}
+
+pub fn is_prime(n: usize) -> bool {
+    n >= 2 && (2..).take_while(|d| d * d <= n).all(|d| n % d != 0)
+}
+
+#[inline]
+fn hash_bytes(bytes: &[u8], seed: u64) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u64(seed);
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// The lookup table of the Maglev hashing (Eisenbud et al., NSDI 2016). Each active target fills the entries of its
+/// permutation of the table in turns, with shares proportional to its weight. The permutation depends on the id of the
+/// target only, so that adding or removing a target remaps about 1/n of the keys.
+pub struct MaglevTable {
+    entries: Vec<u16>,
+}
+
+impl MaglevTable {
+    pub fn new(targets: &Vec<TargetEntry>, size: usize) -> MaglevTable {
+        let mut entries = vec![EMPTY; size];
+        let candidates: Vec<usize> = (0..targets.len()).filter(|i| targets[*i].active).collect();
+        if candidates.is_empty() || size == 0 {
+            return MaglevTable { entries };
+        }
+        let permutation: Vec<(usize, usize)> = candidates
+            .iter()
+            .map(|i| {
+                let id = targets[*i].config.id.as_bytes();
+                let offset = (hash_bytes(id, 0) % size as u64) as usize;
+                let skip = (hash_bytes(id, 1) % (size as u64 - 1).max(1)) as usize + 1;
+                (offset, skip)
+            })
+            .collect();
+        let mut next = vec![0usize; candidates.len()];
+        let mut filled = 0;
+        'fill: loop {
+            for (k, i) in candidates.iter().enumerate() {
+                let (offset, skip) = permutation[k];
+                for _ in 0..targets[*i].config.weight.unwrap_or(1).max(1) {
+                    loop {
+                        let entry = (offset + next[k] * skip) % size;
+                        next[k] += 1;
+                        if entries[entry] == EMPTY {
+                            entries[entry] = *i as u16;
+                            filled += 1;
+                            break;
+                        }
+                    }
+                    if filled == size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+        MaglevTable { entries }
+    }
+
+    /// The target of the hash, the k-th probe uses another entry, e.g. when the target of the previous probe is down.
+    /// None, if the table has no targets.
+    #[inline]
+    pub fn lookup(&self, hash: u64, k: u64) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let hash = if k == 0 { hash } else { hash_bytes(&hash.to_le_bytes(), k) };
+        let target = self.entries[(hash % self.entries.len() as u64) as usize];
+        if target == EMPTY {
+            None
+        } else {
+            Some(target as usize)
+        }
+    }
+}
+
+/// the hash of the key of the connection
+pub fn hash_of(c: &ProxyConnection, key: HashKey) -> u64 {
+    let name = match key {
+        HashKey::ClientIp => None,
+        HashKey::Sni => c.sni.as_ref().map(|sni| sni.as_bytes()),
+        HashKey::Host => c
+            .http_request
+            .as_ref()
+            .and_then(|r| r.host.as_ref())
+            .map(|host| host.as_bytes()),
+    };
+    match name {
+        Some(name) => hash_bytes(name, 0),
+        None => hash_bytes(&c.client_sock().map(|s| s.0).unwrap_or(0).to_le_bytes(), 0),
+    }
+}
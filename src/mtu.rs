@@ -0,0 +1,75 @@
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+
+/// RFC 791, the smallest MTU every host must accept
+pub const MIN_MTU: u16 = 576;
+/// jumbo frames
+pub const MAX_MTU: u16 = 9000;
+const DEFAULT_MTU: u16 = 1500;
+/// ethernet header and a VLAN tag, the fcs is not stored in the mbuf
+const L2_OVERHEAD: usize = 18;
+/// ip and tcp header without options
+const TCP_IP_OVERHEAD: u16 = 40;
+
+extern "C" {
+    fn rte_eth_dev_set_mtu(port_id: u16, mtu: u16) -> i32;
+}
+
+/// MTU of the ports, e.g. 9000 for targets which rely on jumbo frames. The mbufs of the mempool must hold a
+/// complete frame, i.e. the mbuf size of the [netbricks] section must be increased for MTUs above 1500;
+/// payloads spread over chained mbufs are forwarded without calling the payload closures.
+#[derive(Deserialize, Clone)]
+pub struct MtuConfig {
+    /// MTU of the PCI port, from 576 to 9000, defaults to 1500
+    pub pci: Option<u16>,
+    /// MTU of the associated KNI port, defaults to the MTU of the PCI port
+    pub kni: Option<u16>,
+}
+
+impl MtuConfig {
+    pub fn pci_mtu(&self) -> u16 {
+        self.pci.unwrap_or(DEFAULT_MTU)
+    }
+
+    pub fn kni_mtu(&self) -> u16 {
+        self.kni.unwrap_or(self.pci_mtu())
+    }
+}
+
+/// the largest MSS of a segment, which fits into the MTU
+pub fn mss_of_mtu(mtu: u16) -> u16 {
+    mtu - TCP_IP_OVERHEAD
+}
+
+/// the MSS of the engine clamped to the MSS of the MTU, None without MTU configuration and engine MSS
+pub fn clamp_mss(engine_mss: Option<u16>, mtu: &Option<MtuConfig>) -> Option<u16> {
+    match mtu {
+        Some(config) => {
+            let max_mss = mss_of_mtu(config.pci_mtu());
+            Some(engine_mss.map_or(max_mss, |mss| mss.min(max_mss)))
+        }
+        None => engine_mss,
+    }
+}
+
+/// true, if an empty mbuf, e.g. one of the pool of the packet allocator, holds a frame of this MTU
+pub fn mbuf_fits_mtu(empty: &Pdu, mtu: u16) -> bool {
+    empty.get_tailroom() >= mtu as usize + L2_OVERHEAD
+}
+
+/// sets the MTU of the port, returns false and logs the DPDK error, if the PMD refuses it
+pub fn set_port_mtu(port_name: &str, port_id: u16, mtu: u16) -> bool {
+    let result = unsafe { rte_eth_dev_set_mtu(port_id, mtu) };
+    if result != 0 {
+        warn!("cannot set MTU {} of port {}: error {}", mtu, port_name, result);
+        false
+    } else {
+        info!("MTU of port {} is {}", port_name, mtu);
+        true
+    }
+}
+
+/// false, if the tcp payload of p continues in a chained mbuf, i.e. the payload slice of p is incomplete
+pub fn payload_is_contiguous(p: &Pdu) -> bool {
+    p.get_payload(2).len() >= tcp_payload_size(p)
+}
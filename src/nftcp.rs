@@ -1,20 +1,60 @@
-use e2d2::operators::{ReceiveBatch, Batch, merge_auto, SchedulingPolicy};
+use e2d2::operators::{ReceiveBatch, Batch, PacketBatch, merge_auto, SchedulingPolicy};
 use e2d2::scheduler::{Runnable, Scheduler, StandaloneScheduler};
 use e2d2::allocators::CacheAligned;
-use e2d2::headers::Header;
+use e2d2::headers::{Header, MacHeader, IpHeader, TcpHeader};
 use e2d2::interface::*;
 use e2d2::queues::{new_mpsc_queue_pair, MpscProducer};
 
+use std::sync::{Arc, Mutex};
 #[cfg(feature = "profiling")]
 use std::sync::atomic::Ordering;
 use std::sync::mpsc::channel;
 use std::convert::TryFrom;
 use std::arch::x86_64::_rdtsc;
+use std::net::{Ipv4Addr, IpAddr};
+use std::cmp;
+use std::collections::HashSet;
 
 use uuid::Uuid;
-
-use cmanager::{ProxyConnection, ConnectionManager};
-use netfcts::timer_wheel::TimerWheel;
+use eui48::MacAddress;
+
+use cmanager::{ProxyConnection, ConnectionManager, RateLimiter, ClientSock, Slot, expired_timers, addressed_to_proxy};
+use nfudp::setup_udp_proxy;
+use ipv6::v4_to_key;
+use selection::{PolicySelector, ServerLoad};
+use maglev::HashKey;
+use sni::{SniMap, AlpnMap, parse_sni, parse_alpn};
+use http::{HttpRouter, parse_http_request};
+use h2::parse_http2_request;
+use proxy_protocol::{ProxyProtocol, proxy_protocol_header, insert_into_payload};
+use acl::Acl;
+use affinity::AffinityTable;
+use capture::{Capture, frame_bytes};
+use idle::{IdleTimeouts, Teardown};
+use buffering::{PayloadBuffering, BufferResult, buffer_payload};
+use reorder::{ReorderConfig, ReorderBuffers, SegmentOrder};
+use rewrite::{shift, apply_payload_rewrite};
+use arp::{ArpResolver, ArpMessage, ARP_ETYPE, ARP_REQUEST, ARP_SIZE, parse_arp, arp_request_bytes, arp_reply_bytes};
+use vlan::{Vlans, tag_towards_destination};
+use pacing::Pacer;
+use latency::PipelineLatencies;
+use protocols::Protocol;
+use spans::connection_span;
+use audit::state_audit_enabled;
+use close::CloseReason;
+use replay::Replayer;
+use offload::{Offloads, OffloadCapabilities};
+use mtu::{clamp_mss, mbuf_fits_mtu, set_port_mtu, payload_is_contiguous};
+use vhost::VhostUserPort;
+use ha::HaRole;
+use fragment::{FragmentResult, Reassembly, is_fragment, set_reassembled_payload, fragments};
+use icmp::{EmbeddedSegment, ICMP_PROTOCOL, echo_reply, is_fragmentation_needed, embedded_segment, set_embedded_segment, port_unreachable_bytes};
+use tcp_options::{TcpOptionsConfig, syn_options, add_options, add_mss_option, syn_option_bytes, translate_sack_blocks, mss_towards_clients};
+use syncookie::{syn_cookie, syn_cookie_valid, cookie_slot};
+use polling::packet_received;
+use frontends::{frontend_ports, backend_targets, backend_entries, socks5_configs};
+use socks5::{Socks5State, Socks5Resolver, parse_greeting, greeting_reply, parse_connect_request, connect_reply, replace_payload, REPLY_SUCCEEDED};
+use timer::CancellableWheel;
 use netfcts::tcp_common::*;
 use netfcts::tasks;
 use netfcts::tasks::private_etype;
@@ -26,8 +66,10 @@ use netfcts::recstore::Store64;
 
 #[cfg(feature = "profiling")]
 use netfcts::utils::TimeAdder;
+#[cfg(feature = "alloc_audit")]
+use allocations::thread_allocations;
 
-use ::{Configuration, FnSelectServer};
+use ::{Configuration, FnSelectServer, SharedState};
 use {PipelineId, MessageFrom, MessageTo, TaskType};
 use ::{Timeouts, FnPayload};
 use ::{ProxyRecStore, Extension};
@@ -42,36 +84,102 @@ const TIMER_WHEEL_SLOT_CAPACITY: usize = 2500;
 /// a port (@pci) and its associated kernel network port (@kni) which the current core (@core) serves.
 /// The kni port is used to utilize protocol stacks of the kernel, e.g. ARP, ICMP, etc.
 /// For this purpose Kni has been assigned one or more MAC and IP addresses. Kni may be either a native Kni or a Virtio port.
-pub fn setup_delayed_proxy<F1, F2>(
+/// Without kni, the engine is addressed by the KnilessConfig and the pipeline serves ARP itself.
+/// In inline mode (see InlineConfig) the targets are reached through the server port (@server_port) instead of @pci.
+pub fn setup_delayed_proxy<F1, F2, F3>(
     core: i32,
     pci: CacheAligned<PortQueueTxBuffered>,
-    kni: CacheAligned<PortQueue>,
+    kni: Option<CacheAligned<PortQueue>>,
+    server_port: Option<CacheAligned<PortQueueTxBuffered>>,
     sched: &mut StandaloneScheduler,
     run_configuration: RunConfiguration<Configuration,Store64<Extension>>,
     servers: Vec<L234Data>,
-    f_select_server: F1,
+    shared: SharedState,
+    f_select_server: Option<F1>,
     f_process_payload_c_s: F2,
+    f_process_payload_s_c: Option<F3>,
 ) where
     F1: FnSelectServer,
     F2: FnPayload,
+    F3: FnPayload,
 {
+    // the server side queue, the replies of the servers are steered to it by the proxy ports of its flow director
+    let server_side = server_port.as_ref().unwrap_or(&pci).port_queue.clone();
     let l4flow_for_this_core = run_configuration
         .flowdirector_map
-        .get(&pci.port_queue.port_id())
+        .get(&server_side.port_id())
         .unwrap()
-        .get_flow(pci.port_queue.rxq());
+        .get_flow(server_side.rxq());
 
     #[derive(Clone)]
     struct Me {
         // contains the client side ip address of the proxy
         l234: L234Data,
-        // server side ip address of the proxy to use in this pipeline
+        // server side ip address of the proxy to use in this pipeline, see also SourcePoolConfig
         ip_s: u32,
+        // server side mac address of the proxy, the one of the server port in inline mode
+        mac_s: MacAddress,
+        // in transparent mode the client ip address is used towards the servers
+        transparent: bool,
+        inline: bool,
+        /// the listening ports by frontend, index 0 is engine.port, see FrontendConfig
+        ports: Vec<u16>,
+    }
+
+    impl Me {
+        /// the frontend of a client side segment to this port, None if no frontend listens on the port
+        #[inline]
+        fn frontend(&self, port: u16) -> Option<u8> {
+            self.ports.iter().position(|p| *p == port).map(|i| i as u8)
+        }
+
+        /// the port of the frontend of the connection, the source port of the segments towards the client
+        #[inline]
+        fn port(&self, c: &ProxyConnection) -> u16 {
+            self.ports[c.frontend()]
+        }
+
+        #[inline]
+        fn src_ip_towards_server(&self, c: &ProxyConnection) -> u32 {
+            if self.transparent {
+                c.sock().unwrap().0
+            } else {
+                c.source_ip()
+            }
+        }
+
+        /// the mac address of the proxy, to which a frame with this destination was sent
+        #[inline]
+        fn own_mac(&self, dst: &MacAddress) -> MacAddress {
+            if self.inline && *dst == self.mac_s {
+                self.mac_s
+            } else {
+                self.l234.mac
+            }
+        }
+
+        /// the group of a frame sent by the proxy: 4 for the server port in inline mode, otherwise 1
+        #[inline]
+        fn tx_group(&self, p: &Pdu) -> usize {
+            if self.inline && p.headers().mac(0).src == self.mac_s {
+                4
+            } else {
+                1
+            }
+        }
     }
 
+    let l234: L234Data = match kni {
+        Some(ref kni) => TryFrom::try_from(kni.port.net_spec().as_ref().unwrap().clone()).unwrap(),
+        None => run_configuration.engine_configuration.engine.kniless.as_ref().unwrap().l234(pci.port_queue.port.mac_address()),
+    };
     let mut me = Me {
-        l234: TryFrom::try_from(kni.port.net_spec().as_ref().unwrap().clone()).unwrap(),
         ip_s: l4flow_for_this_core.ip,
+        mac_s: server_port.as_ref().map_or(l234.mac, |port| port.port_queue.port.mac_address()),
+        l234,
+        transparent: run_configuration.engine_configuration.engine.transparent.unwrap_or(false),
+        inline: server_port.is_some(),
+        ports: frontend_ports(&run_configuration.engine_configuration),
     };
 
     me.l234.port = run_configuration.engine_configuration.engine.port;
@@ -86,13 +194,85 @@ pub fn setup_delayed_proxy<F1, F2>(
     debug!("enter setup_forwarder {}", pipeline_id);
     let tx = run_configuration.remote_sender.clone();
     let detailed_records = engine_config.detailed_records.unwrap_or(false);
-    let mut cm: ConnectionManager = ConnectionManager::new(pci.port_queue.clone(), *l4flow_for_this_core, detailed_records);
+    let server_load = ServerLoad::new(shared.connections.clone());
+    let mut cm: ConnectionManager = ConnectionManager::new(
+        server_side,
+        *l4flow_for_this_core,
+        detailed_records,
+        server_load.clone(),
+        engine_config
+            .rate_limit
+            .as_ref()
+            .map(|config| RateLimiter::new(config, system_data.cpu_clock)),
+        shared.connections.clone(),
+    );
+    // per frontend the target ids of its backend, see FrontendConfig
+    let backends = backend_targets(&run_configuration.engine_configuration);
+    let mut policy_selector = PolicySelector::new(
+        engine_config.selection.unwrap_or_default(),
+        &shared.targets.targets(),
+        server_load.clone(),
+        shared.target_health.clone(),
+        shared.groups.clone(),
+        shared.breakers.clone(),
+        engine_config.max_connections_per_target.map(|m| m as usize),
+        engine_config.consistent_hash.clone(),
+        backends.clone(),
+    );
+    if f_select_server.is_none() {
+        info!("{} using selection policy {:?}", pipeline_id, policy_selector.policy());
+    }
+    shared.drain.add_pipeline();
+    let events = shared.events.sender(&pipeline_id);
+    cm.set_event_sender(events.clone());
+    cm.set_flow_sender(shared.flows.sender());
+    if engine_config.record_retention.is_some() {
+        cm.set_record_retention(engine_config.record_retention.as_ref().unwrap(), system_data.cpu_clock);
+    }
+    if engine_config.record_sampling.is_some() {
+        cm.set_record_sampling(engine_config.record_sampling.as_ref().unwrap(), &pipeline_id.to_string());
+    }
+    if engine_config.source_pool.is_some() && !me.transparent {
+        cm.set_source_pool(engine_config.source_pool.as_ref().unwrap());
+    }
+    if engine_config.protocol_counters.unwrap_or(false) {
+        cm.enable_protocol_counters();
+    }
+    if engine_config.time_wait.is_some() {
+        cm.set_time_wait(engine_config.time_wait.as_ref().unwrap(), system_data.cpu_clock);
+    }
+    if engine_config.connection_table.is_some() {
+        cm.set_connection_table(engine_config.connection_table.as_ref().unwrap());
+    }
+    let mut sni_map = SniMap::new(&shared.targets.sni_map(), &shared.targets.targets());
+    let mut alpn_map = AlpnMap::new(&shared.targets.alpn_map(), &shared.targets.targets());
+    let mut http_router = HttpRouter::new(&shared.targets.http_routes(), &shared.targets.targets());
+    let mut proxy_protocols: Vec<Option<ProxyProtocol>> =
+        shared.targets.targets().iter().map(|t| t.config.proxy_protocol).collect();
+    let mut target_mss: Vec<Option<u16>> = shared.targets.targets().iter().map(|t| t.config.mss).collect();
+    let engine_mss = clamp_mss(engine_config.mss, &engine_config.mtu);
+    let tcp_options = engine_config.tcp_options.clone();
+    let payload_buffering: Option<PayloadBuffering> = engine_config.payload_buffering.clone();
+    let reorder: Option<ReorderConfig> = engine_config.reorder.clone();
+    // the MSS announced to the clients
+    let mut mss_to_clients = mss_towards_clients(engine_mss, &shared.targets.targets());
+    // per frontend, the destinations are resolved to the targets of its backend
+    let mut socks5_resolvers: Vec<Option<Socks5Resolver>> = socks5_configs(&run_configuration.engine_configuration)
+        .iter()
+        .zip(backends.iter())
+        .map(|(config, backend)| {
+            config
+                .as_ref()
+                .map(|config| Socks5Resolver::new(config, &backend_entries(&shared.targets.targets(), backend)))
+        })
+        .collect();
 
     let mut timeouts = Timeouts::default_or_some(&engine_config.timeouts);
-    let mut wheel = TimerWheel::new(
+    let mut wheel = CancellableWheel::new(
         TIMER_WHEEL_SLOTS,
         system_data.cpu_clock * TIMER_WHEEL_RESOLUTION_MS / 1000,
         TIMER_WHEEL_SLOT_CAPACITY,
+        system_data.cpu_clock,
     );
 
     // check that we do not overflow the wheel:
@@ -107,6 +287,14 @@ pub fn setup_delayed_proxy<F1, F2>(
         }
     }
 
+    // the key of the pipeline in the connection state snapshots and in the session file
+    let pipeline_key = pipeline_id.to_string();
+    if let Some(states) = shared.sessions.take(&pipeline_key) {
+        let timeout = cmp::min(timeouts.established.unwrap() * system_data.cpu_clock / 1000, wheel.get_max_timeout_cycles());
+        let restored = states.iter().filter(|state| cm.restore(state, timeout, &mut wheel)).count();
+        info!("{}: restored {} of {} connections of the session file", pipeline_id, restored, states.len());
+    }
+
     // we need this queue pair for the delayed bindrequest
     let (mut producer, consumer) = new_mpsc_queue_pair();
 
@@ -117,10 +305,12 @@ pub fn setup_delayed_proxy<F1, F2>(
     tx.send(MessageFrom::Channel(pipeline_id.clone(), remote_tx)).unwrap();
 
     // forwarding frames coming from KNI to PCI
-    let forward2pci = ReceiveBatch::new(kni.clone()).send(pci.clone());
-    let uuid = Uuid::new_v4();
-    let name = String::from("Kni2Pci");
-    sched.add_runnable(Runnable::from_task(uuid, name, forward2pci).move_ready());
+    if kni.is_some() {
+        let forward2pci = ReceiveBatch::new(kni.clone().unwrap()).send(pci.clone());
+        let uuid = Uuid::new_v4();
+        let name = String::from("Kni2Pci");
+        sched.add_runnable(Runnable::from_task(uuid, name, forward2pci).move_ready());
+    }
 
     struct PduAllocator<'a> {
         pdu_batch: Option<Vec<Pdu<'a>>>,
@@ -152,9 +342,118 @@ pub fn setup_delayed_proxy<F1, F2>(
     }
 
     let mut packet_allocator = PduAllocator::new();
+    let vhost = VhostUserPort::of_port(pci.port_queue.port.name());
+    if vhost.is_some() && pci.port_queue.rxq() == 0 {
+        let vhost = vhost.as_ref().unwrap();
+        info!(
+            "{}: vhost-user port on {} in {} mode with {} queues",
+            pipeline_id,
+            vhost.iface,
+            if vhost.client { "client" } else { "server" },
+            vhost.queues
+        );
+    }
+    // the MTU is set once per port, by the pipeline of rx queue 0, if the mbufs hold the frames
+    if engine_config.mtu.is_some() && pci.port_queue.rxq() == 0 {
+        let mtu = engine_config.mtu.as_ref().unwrap();
+        match packet_allocator.get_pdu() {
+            Some(ref empty) if mbuf_fits_mtu(empty, cmp::max(mtu.pci_mtu(), mtu.kni_mtu())) => {
+                if vhost.is_some() {
+                    warn!("{}: the MTU of vhost-user port {} is negotiated with the virtio frontend", pipeline_id, vhost.as_ref().unwrap().iface);
+                } else {
+                    set_port_mtu(pci.port_queue.port.name(), pci.port_queue.port_id() as u16, mtu.pci_mtu());
+                }
+                if kni.is_some() {
+                    let kni = kni.as_ref().unwrap();
+                    set_port_mtu(kni.port.name(), kni.port_id() as u16, mtu.kni_mtu());
+                }
+            }
+            Some(_) => error!(
+                "{}: the mbufs are too small for MTU {}, the MTU of the ports is not changed",
+                pipeline_id,
+                cmp::max(mtu.pci_mtu(), mtu.kni_mtu())
+            ),
+            None => error!("{}: no mbuf to check the MTU, the MTU of the ports is not changed", pipeline_id),
+        }
+    }
+    let mut targets_version = shared.targets.version();
+    let mut servers = servers;
+    let mut capture_version = shared.capture.version();
+    let mut listing_request = shared.listing.requested();
+    let mut lookup_request = shared.lookup.requested();
+    let mut session_request = shared.sessions.requested();
+    // role and ticks between two snapshots of the connection states, see HaConfig
+    let ha = engine_config.ha.as_ref().map(|config| (config.role, cmp::max(config.interval_ms() / 10, 1)));
+    let mut sync_version = shared.state_sync.version();
+    // slots of the connections replicated by the active engine
+    let mut replicated: HashSet<Slot> = HashSet::new();
+    let mut capture: Option<Capture> = shared.capture.capture(&servers);
+    let mut sflow = shared.sflow.sampler(pipeline_id.port_id, pipeline_id.rxq);
     let thread_id = format!("<c{}, rx{}>: ", core, pci.port_queue.rxq());
     let tcp_min_port = cm.tcp_port_base();
+    let udp_port = engine_config.udp.as_ref().map(|u| u.port);
+    let syn_cookies = engine_config.syn_cookies.unwrap_or(false);
+    let splice = engine_config.splice.unwrap_or(false);
+    let idle_timeouts = engine_config.idle_timeouts.clone();
+    // a server selected by the closure is not replaced by another one
+    let syn_retry = if f_select_server.is_none() { engine_config.syn_retry.clone() } else { None };
+    let syn_timeout = syn_retry.as_ref().map(|config| config.timeout(system_data.cpu_clock)).unwrap_or(0);
+    let keepalive = engine_config
+        .keepalive
+        .as_ref()
+        .map(|config| (config.idle_cycles(system_data.cpu_clock), config.interval_cycles(system_data.cpu_clock)));
+    let max_connections = engine_config.max_connections.map(|m| m as usize);
+    // ARP requests are sent by the pipeline of the first rx queue, which also receives the replies
+    let mut arp = if pci.port_queue.rxq() == 0 {
+        engine_config
+            .arp
+            .as_ref()
+            .map(|config| ArpResolver::new(config, system_data.cpu_clock))
+    } else {
+        None
+    };
+    let mut arp_targets = shared.targets.arp_targets();
+    let mut vlans = engine_config
+        .vlan
+        .as_ref()
+        .map(|config| Vlans::new(config, pci.port_queue.port.name(), &shared.targets.targets()));
+    let acl = engine_config.acl.as_ref().map(|config| Acl::new(config));
+    let icmp_config = engine_config.icmp.clone();
+    let mut pacer = engine_config.pacing.as_ref().map(|config| Pacer::new(config));
+    let kniless = kni.is_none();
+    let mut reassembly = engine_config.fragments.as_ref().map(|config| Reassembly::new(config, system_data.cpu_clock));
+    let mtu_towards_clients = engine_config.fragments.as_ref().and_then(|config| config.mtu_towards_clients);
+    let mtu_towards_targets = engine_config.fragments.as_ref().and_then(|config| config.mtu_towards_targets);
+    let tracing_spans = engine_config.tracing.is_some();
+    let state_audit = state_audit_enabled(engine_config.state_audit);
+    if state_audit {
+        info!("{}: auditing the connection state transitions", pipeline_id);
+    }
+    // the pcap is replayed by a single pipeline, which opens all connections of the replay
+    let replayer = if engine_config.replay.is_some() && pipeline_id.rxq == 0 {
+        match Replayer::new(engine_config.replay.as_ref().unwrap(), &pipeline_id, me.l234.mac, shared.replay.clone()) {
+            Ok(replayer) => Some(Arc::new(Mutex::new(replayer))),
+            Err(e) => {
+                error!("{}: cannot replay: {}", pipeline_id, e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let replayer_clone = replayer.clone();
+    let mut latencies = if engine_config.latency_histograms.unwrap_or(false) {
+        Some(PipelineLatencies::new(system_data.cpu_clock))
+    } else {
+        None
+    };
+    let affinity = engine_config
+        .affinity
+        .as_ref()
+        .map(|config| (shared.affinity.clone(), config.ttl_cycles(system_data.cpu_clock)));
     let me_clone = me.clone();
+    let me_clone2 = me.clone();
+    let shared_clone = shared.clone();
     let tx_clone = tx.clone();
     let pipeline_ip = cm.ip();
     let pipeline_id_clone = pipeline_id.clone();
@@ -177,16 +476,45 @@ pub fn setup_delayed_proxy<F1, F2>(
     ))
         .unwrap();
 
-    let receive_pci = ReceiveBatch::new(pci.clone());
-    let l2_input_stream = merge_auto(
-        vec![box consumer_timerticks.set_urgent(), box receive_pci],
-        SchedulingPolicy::LongestQueue,
-    );
+    // the frames of a replay enter the pipeline through this queue, see ReplayConfig
+    let (mut producer_replay, consumer_replay) = new_mpsc_queue_pair();
+
+    let rx_burst = engine_config.polling.as_ref().and_then(|config| config.rx_burst);
+    let receive = |queue: CacheAligned<PortQueueTxBuffered>| match rx_burst {
+        Some(burst) => ReceiveBatch::new_with_parent(PacketBatch::new(burst as i32), queue),
+        None => ReceiveBatch::new(queue),
+    };
+    let receive_pci = receive(pci.clone());
+    let l2_input_stream = if server_port.is_some() {
+        let receive_server_port = receive(server_port.clone().unwrap());
+        merge_auto(
+            vec![box consumer_timerticks.set_urgent(), box consumer_replay, box receive_pci, box receive_server_port],
+            SchedulingPolicy::LongestQueue,
+        )
+    } else {
+        merge_auto(
+            vec![box consumer_timerticks.set_urgent(), box consumer_replay, box receive_pci],
+            SchedulingPolicy::LongestQueue,
+        )
+    };
 
     // group 0 -> dump packets
     // group 1 -> send to PCI
     // group 2 -> send to KNI
-    let csum_offload = pci.port_queue.port.csum_offload();
+    // group 3 -> udp pipeline
+    // group 4 -> send to the server port, in inline mode
+    let offloads = Offloads::negotiate(
+        &engine_config.offload,
+        &OffloadCapabilities::of_port(pci.port_queue.port.name(), pci.port_queue.port.csum_offload()),
+        pci.port_queue.port.name(),
+    );
+    info!(
+        "{}: checksums computed {}, TSO {}",
+        pipeline_id,
+        if offloads.checksum { "by the NIC" } else { "in software" },
+        if offloads.tso { "on" } else { "off" },
+    );
+    let csum_offload = offloads.checksum;
     let uuid_l4groupby = Uuid::new_v4();
 
     #[cfg(feature = "profiling")]
@@ -222,8 +550,19 @@ pub fn setup_delayed_proxy<F1, F2>(
             // this is the major closure for TCP processing
 
             #[inline]
-            fn client_syn_received(p: &mut Pdu, c: &mut ProxyConnection) {
+            fn client_syn_received(p: &mut Pdu, c: &mut ProxyConnection, mss: Option<u16>, tcp_options: &Option<TcpOptionsConfig>) {
                 c.client_mac = p.headers().mac(0).src;
+                let client_options = syn_options(p);
+                c.client_mss = client_options.mss;
+                let mut window_scale = None;
+                if tcp_options.is_some() {
+                    let config = tcp_options.as_ref().unwrap();
+                    if config.window_scale.is_some() && client_options.window_scale.is_some() {
+                        c.window_shifts.client_syn(client_options.window_scale.unwrap(), config.window_scale.unwrap());
+                        window_scale = Some(c.window_shifts.to_client);
+                    }
+                    c.client_sack = config.sack.unwrap_or(false) && client_options.sack_permitted;
+                }
                 //c.set_sock((h.ip.src(), h.tcp.src_port())); this is redundant, as sock is set when c is allocated
                 remove_tcp_options(p);
                 make_reply_packet(p, 1);
@@ -231,54 +570,512 @@ pub fn setup_delayed_proxy<F1, F2>(
                 c.c_seqn = (unsafe { _rdtsc() } << 8) as u32;
                 p.headers_mut().tcp_mut(2).set_seq_num(c.c_seqn);
                 c.ackn_p2c = p.headers().tcp(2).ack_num();
+                if !add_options(p, &syn_option_bytes(mss, window_scale, c.client_sack)) {
+                    warn!("cannot add TCP options to SYN-ACK towards client");
+                }
+                prepare_checksum_and_ttl(p);
+            }
+
+            /// a SYN of a new connection, which is not accepted, e.g. while draining, is answered with a RST
+            #[inline]
+            fn client_syn_refused(p: &mut Pdu) {
+                remove_tcp_options(p);
+                make_reply_packet(p, 1);
+                {
+                    let tcp = p.headers_mut().tcp_mut(2);
+                    tcp.unset_syn_flag();
+                    tcp.set_rst_flag();
+                    tcp.set_seq_num(0);
+                }
+                prepare_checksum_and_ttl(p);
+            }
+
+            /// replies with a SYN-ACK carrying the SYN cookie as seqn, no connection state is allocated
+            #[inline]
+            fn client_syn_cookie_reply(p: &mut Pdu, secret: u64, client: &ClientSock, slot: u8, mss: Option<u16>) {
+                let client_isn = p.headers().tcp(2).seq_num();
+                remove_tcp_options(p);
+                make_reply_packet(p, 1);
+                p.headers_mut().tcp_mut(2).set_seq_num(syn_cookie(secret, client, client_isn, slot));
+                if mss.is_some() && !add_mss_option(p, mss.unwrap()) {
+                    warn!("cannot add MSS option to SYN-ACK towards client");
+                }
                 prepare_checksum_and_ttl(p);
             }
 
+            /// takes over the state of the handshake from the ACK carrying a valid cookie
+            /// cycles until the timer of a new connection expires
+            #[inline]
+            fn initial_timeout(timeouts: &Timeouts, idle_timeouts: &Option<IdleTimeouts>, cpu_clock: u64, wheel: &CancellableWheel<Slot>) -> u64 {
+                let established = timeouts.established.unwrap();
+                let millis = idle_timeouts.as_ref().map_or(established, |t| t.syn_received.unwrap_or(established));
+                cmp::min(millis * cpu_clock / 1000, wheel.get_max_timeout_cycles())
+            }
+
+            /// the frame of a replay as packet, with the header stack of a received packet,
+            /// None if no mbuf is available
+            fn replayed_pdu(packet_allocator: &mut PduAllocator<'static>, frame: &[u8]) -> Option<Pdu<'static>> {
+                let mut p = packet_allocator.get_pdu()?;
+                let mut mac = MacHeader::new();
+                mac.set_dmac(&MacAddress::from_bytes(&frame[0..6]).unwrap());
+                mac.set_smac(&MacAddress::from_bytes(&frame[6..12]).unwrap());
+                mac.set_etype(0x0800);
+                let mut ip = IpHeader::new();
+                ip.set_version(4);
+                ip.set_ihl(5);
+                if !p.push_header(&mac) || !p.push_header(&ip) {
+                    return None;
+                }
+                // the protocol of the ip header
+                if frame[23] == 6 {
+                    let mut tcp = TcpHeader::new();
+                    tcp.set_data_offset(5);
+                    if !p.push_header(&tcp) {
+                        return None;
+                    }
+                }
+                let n_padding_bytes = cmp::max(frame.len(), MIN_FRAME_SIZE).saturating_sub(p.data_len());
+                p.add_padding(n_padding_bytes);
+                // the headers are overwritten by those of the frame
+                p.get_payload_mut(0)[..frame.len() - 14].copy_from_slice(&frame[14..]);
+                Some(p)
+            }
+
+            /// a fragment of a datagram of the pipeline with the mac header of the datagram, None if no mbuf is available
+            fn fragment_pdu(packet_allocator: &mut PduAllocator<'static>, original: &MacHeader, fragment: &[u8]) -> Option<Pdu<'static>> {
+                let mut p = packet_allocator.get_pdu()?;
+                let mut mac = MacHeader::new();
+                mac.set_dmac(&original.dst);
+                mac.set_smac(&original.src);
+                mac.set_etype(0x0800);
+                let mut ip = IpHeader::new();
+                ip.set_version(4);
+                ip.set_ihl(5);
+                if !p.push_header(&mac) || !p.push_header(&ip) {
+                    return None;
+                }
+                let n_padding_bytes = cmp::max(fragment.len() + 14, MIN_FRAME_SIZE).saturating_sub(p.data_len());
+                p.add_padding(n_padding_bytes);
+                // the ip header is overwritten by the one of the fragment
+                p.get_payload_mut(0)[..fragment.len()].copy_from_slice(fragment);
+                Some(p)
+            }
+
+            /// a segment generated by the proxy with ACK and FIN or RST flag, or a pure ACK for teardown None,
+            /// None if no mbuf is available
+            fn proxy_segment(
+                packet_allocator: &mut PduAllocator<'static>,
+                smac: &MacAddress,
+                dmac: &MacAddress,
+                src: (u32, u16),
+                dst: (u32, u16),
+                seqn: u32,
+                ackn: u32,
+                teardown: Option<Teardown>,
+            ) -> Option<Pdu<'static>> {
+                let mut p = packet_allocator.get_pdu()?;
+                let mut mac = MacHeader::new();
+                mac.set_smac(smac);
+                mac.set_dmac(dmac);
+                mac.set_etype(0x0800);
+                let mut ip = IpHeader::new();
+                ip.set_version(4);
+                ip.set_ihl(5);
+                ip.set_protocol(6);
+                ip.set_length(40);
+                ip.set_src(src.0);
+                ip.set_dst(dst.0);
+                let mut tcp = TcpHeader::new();
+                tcp.set_src_port(src.1);
+                tcp.set_dst_port(dst.1);
+                tcp.set_seq_num(seqn);
+                tcp.set_ack_num(ackn);
+                tcp.set_data_offset(5);
+                tcp.set_window_size(0);
+                tcp.set_ack_flag();
+                match teardown {
+                    Some(Teardown::Fin) => tcp.set_fin_flag(),
+                    Some(Teardown::Rst) => tcp.set_rst_flag(),
+                    None => {}
+                }
+                if !p.push_header(&mac) || !p.push_header(&ip) || !p.push_header(&tcp) {
+                    return None;
+                }
+                let n_padding_bytes = MIN_FRAME_SIZE - p.data_len();
+                p.add_padding(n_padding_bytes);
+                prepare_checksum_and_ttl(&mut p);
+                Some(p)
+            }
+
+            /// the ARP reply of the proxy to the request, None if no mbuf is available
+            fn arp_reply(packet_allocator: &mut PduAllocator<'static>, smac: &MacAddress, request: &ArpMessage) -> Option<Pdu<'static>> {
+                let mut p = packet_allocator.get_pdu()?;
+                let mut mac = MacHeader::new();
+                mac.set_smac(smac);
+                mac.set_dmac(&request.sender_mac);
+                mac.set_etype(ARP_ETYPE);
+                if !p.push_header(&mac) {
+                    return None;
+                }
+                let n_padding_bytes = MIN_FRAME_SIZE - p.data_len();
+                p.add_padding(n_padding_bytes);
+                p.get_payload_mut(0)[..ARP_SIZE].copy_from_slice(&arp_reply_bytes(smac, request));
+                Some(p)
+            }
+
+            /// an ARP request of the proxy for target_ip, None if no mbuf is available
+            fn arp_request(
+                packet_allocator: &mut PduAllocator<'static>,
+                smac: &MacAddress,
+                sender_ip: u32,
+                target_ip: u32,
+            ) -> Option<Pdu<'static>> {
+                let mut p = packet_allocator.get_pdu()?;
+                let mut mac = MacHeader::new();
+                mac.set_smac(smac);
+                mac.set_dmac(&MacAddress::broadcast());
+                mac.set_etype(ARP_ETYPE);
+                if !p.push_header(&mac) {
+                    return None;
+                }
+                let n_padding_bytes = MIN_FRAME_SIZE - p.data_len();
+                p.add_padding(n_padding_bytes);
+                p.get_payload_mut(0)[..ARP_SIZE].copy_from_slice(&arp_request_bytes(smac, sender_ip, target_ip));
+                Some(p)
+            }
+
+            /// the size of the ICMP message in p
+            #[inline]
+            fn icmp_length(p: &Pdu) -> usize {
+                let ip = p.headers().ip(1);
+                let length = (ip.length() as usize).saturating_sub(ip.ihl() as usize * 4);
+                cmp::min(length, p.get_payload(1).len())
+            }
+
+            /// turns an echo request to the proxy into the echo reply, false if p is not an echo request
+            fn icmp_echo_reply(p: &mut Pdu, me: &Me) -> bool {
+                let length = icmp_length(p);
+                if !echo_reply(&mut p.get_payload_mut(1)[..length]) {
+                    return false;
+                }
+                let h = p.headers_mut();
+                {
+                    let ip = h.ip_mut(1);
+                    let (src, dst) = (ip.src(), ip.dst());
+                    ip.set_src(dst);
+                    ip.set_dst(src);
+                    ip.set_ttl(64);
+                    ip.update_checksum();
+                }
+                let client_mac = h.mac(0).src;
+                let own_mac = me.own_mac(&h.mac(0).dst);
+                h.mac_mut(0).set_dmac(&client_mac);
+                h.mac_mut(0).set_smac(&own_mac);
+                true
+            }
+
+            /// Forwards a fragmentation-needed error about a segment of the proxy to the sender of the segment on the
+            /// other leg of the connection, which must send smaller segments. The embedded segment is translated into the
+            /// addresses and sequence numbers of that leg. False if the error does not concern a connection of this pipeline.
+            fn icmp_forward_error(p: &mut Pdu, cm: &mut ConnectionManager, me: &Me, servers: &Vec<L234Data>) -> bool {
+                let length = icmp_length(p);
+                let segment = {
+                    let icmp = &p.get_payload(1)[..length];
+                    if !is_fragmentation_needed(icmp) {
+                        return false;
+                    }
+                    match embedded_segment(icmp) {
+                        Some(segment) => segment,
+                        None => return false,
+                    }
+                };
+                let (embedded, src, dst, dmac, smac) = if segment.src.0 == me.l234.ip && me.frontend(segment.src.1).is_some() {
+                    // a segment towards the client, the server sends smaller segments
+                    let c = match cm.get_mut_by_sock(&(v4_to_key(segment.dst.0), segment.dst.1)) {
+                        Some(c) if c.server_bound() => c,
+                        _ => return false,
+                    };
+                    let server = &servers[c.server_index()];
+                    let src = me.src_ip_towards_server(c);
+                    let embedded = EmbeddedSegment {
+                        src: (server.ip, server.port),
+                        dst: (src, c.port()),
+                        seqn: c.s2c_deltas.original(segment.seqn.wrapping_sub(c.c_seqn)),
+                    };
+                    (embedded, src, server.ip, server.mac, me.mac_s)
+                } else {
+                    // a segment towards the server, the client sends smaller segments
+                    let c = match cm.get_mut_by_source(segment.src.0, segment.src.1) {
+                        Some(c) if c.server_bound() => c,
+                        _ => return false,
+                    };
+                    let client = c.sock().unwrap();
+                    let embedded = EmbeddedSegment {
+                        src: client,
+                        dst: (me.l234.ip, me.port(c)),
+                        seqn: c.c2s_deltas.original(shift(segment.seqn, -c.c2s_inserted_bytes)),
+                    };
+                    (embedded, me.l234.ip, client.0, c.client_mac, me.l234.mac)
+                };
+                set_embedded_segment(&mut p.get_payload_mut(1)[..length], &embedded);
+                let h = p.headers_mut();
+                {
+                    let ip = h.ip_mut(1);
+                    ip.set_src(src);
+                    ip.set_dst(dst);
+                    ip.set_ttl(64);
+                    ip.update_checksum();
+                }
+                h.mac_mut(0).set_dmac(&dmac);
+                h.mac_mut(0).set_smac(&smac);
+                true
+            }
+
+            /// the port-unreachable message of the proxy for the udp datagram in p, None if no mbuf is available
+            fn icmp_port_unreachable(packet_allocator: &mut PduAllocator<'static>, p: &Pdu, me: &Me) -> Option<Pdu<'static>> {
+                let (src, dst, length) = {
+                    let ip = p.headers().ip(1);
+                    (ip.src(), ip.dst(), ip.length() as usize)
+                };
+                let icmp = {
+                    let datagram = p.get_payload(0);
+                    port_unreachable_bytes(&datagram[..cmp::min(length, datagram.len())])
+                };
+                let mut reply = packet_allocator.get_pdu()?;
+                let mut mac = MacHeader::new();
+                mac.set_smac(&me.own_mac(&p.headers().mac(0).dst));
+                mac.set_dmac(&p.headers().mac(0).src);
+                mac.set_etype(0x0800);
+                let mut ip = IpHeader::new();
+                ip.set_version(4);
+                ip.set_ihl(5);
+                ip.set_protocol(ICMP_PROTOCOL);
+                ip.set_length(20 + icmp.len() as u16);
+                ip.set_ttl(64);
+                ip.set_src(dst);
+                ip.set_dst(src);
+                ip.update_checksum();
+                if !reply.push_header(&mac) || !reply.push_header(&ip) {
+                    return None;
+                }
+                let n_padding_bytes = cmp::max(icmp.len(), MIN_FRAME_SIZE.saturating_sub(reply.data_len()));
+                reply.add_padding(n_padding_bytes);
+                reply.get_payload_mut(1)[..icmp.len()].copy_from_slice(&icmp);
+                Some(reply)
+            }
+
+            /// sends FIN or RST segments, which continue the proxied sequence numbers, to client and server of an idle connection.
+            /// Without teardown keepalive probes are sent, i.e. ACKs with the sequence number of the last byte sent
+            fn segments_to_both_legs(
+                c: &ProxyConnection,
+                me: &Me,
+                servers: &Vec<L234Data>,
+                vlans: &Option<Vlans>,
+                teardown: Option<Teardown>,
+                packet_allocator: &mut PduAllocator<'static>,
+                producer: &mut MpscProducer,
+            ) {
+                let client = c.sock().unwrap();
+                let server = &servers[c.server_index()];
+                let probe = if teardown.is_none() { 1 } else { 0 };
+                let seqn_to_server = shift(c.ackn_p2c, c.c2s_inserted_bytes + c.c2s_deltas.at(c.ackn_p2c)).wrapping_sub(probe);
+                let seqn_to_client = shift(c.ackn_p2s.wrapping_add(c.c_seqn), c.s2c_deltas.at(c.ackn_p2s)).wrapping_sub(probe);
+                let to_client = proxy_segment(
+                    packet_allocator,
+                    &me.l234.mac,
+                    &c.client_mac,
+                    (me.l234.ip, me.port(c)),
+                    client,
+                    seqn_to_client,
+                    c.ackn_p2c,
+                    teardown,
+                );
+                let to_server = proxy_segment(
+                    packet_allocator,
+                    &me.mac_s,
+                    &server.mac,
+                    (me.src_ip_towards_server(c), c.port()),
+                    (server.ip, server.port),
+                    seqn_to_server,
+                    c.ackn_p2s,
+                    teardown,
+                );
+                for mut p in to_client.into_iter().chain(to_server) {
+                    tag_towards_destination(&mut p, vlans, servers);
+                    producer.enqueue_one(p);
+                }
+            }
+
+            /// sends a RST to the established client, before the SYN-ACK of the server
+            fn reset_client_leg(
+                c: &ProxyConnection,
+                me: &Me,
+                servers: &Vec<L234Data>,
+                vlans: &Option<Vlans>,
+                packet_allocator: &mut PduAllocator<'static>,
+                producer: &mut MpscProducer,
+            ) {
+                let to_client = proxy_segment(
+                    packet_allocator,
+                    &me.l234.mac,
+                    &c.client_mac,
+                    (me.l234.ip, me.port(c)),
+                    c.sock().unwrap(),
+                    c.c_seqn.wrapping_add(1),
+                    c.ackn_p2c,
+                    Some(Teardown::Rst),
+                );
+                for mut p in to_client {
+                    tag_towards_destination(&mut p, vlans, servers);
+                    producer.enqueue_one(p);
+                }
+            }
+
+            /// Resets the legs of the connection and marks it as killed by the operator. Before the SYN-ACK of the
+            /// server, the seqn towards the server is not known and only the client gets a RST.
+            fn kill_connection(
+                c: &mut ProxyConnection,
+                me: &Me,
+                servers: &Vec<L234Data>,
+                vlans: &Option<Vlans>,
+                packet_allocator: &mut PduAllocator<'static>,
+                producer: &mut MpscProducer,
+            ) {
+                if c.sock().is_some() {
+                    if c.server_bound() && c.server_state() >= TcpState::Established {
+                        segments_to_both_legs(c, me, servers, vlans, Some(Teardown::Rst), packet_allocator, producer);
+                    } else if c.client_state() >= TcpState::Established {
+                        reset_client_leg(c, me, servers, vlans, packet_allocator, producer);
+                    }
+                }
+                c.kill();
+            }
+
+            #[inline]
+            fn client_syn_cookie_validated(p: &Pdu, c: &mut ProxyConnection) {
+                c.client_mac = p.headers().mac(0).src;
+                c.c_seqn = p.headers().tcp(2).ack_num().wrapping_sub(1);
+                c.ackn_p2c = p.headers().tcp(2).seq_num();
+            }
+
+            /// logs a transition of the connection, which is not in the transition tables, with the packet causing it
+            fn report_violation(p: &Pdu, c: &mut ProxyConnection, thread_id: &str) -> bool {
+                let violation = c.take_violation();
+                if violation.is_some() {
+                    warn!(
+                        "{} state violation on port {}: {}, client/server state {:?}/{:?}, L3: {}, L4: {}",
+                        thread_id,
+                        c.port(),
+                        violation.unwrap(),
+                        c.client_state(),
+                        c.server_state(),
+                        p.headers().ip(1),
+                        p.headers().tcp(2),
+                    );
+                    true
+                } else {
+                    false
+                }
+            }
+
             fn client_to_server<F>(
                 p: &mut Pdu,
                 c: &mut ProxyConnection,
                 me: &Me,
                 servers: &Vec<L234Data>,
                 f_process_payload: F,
+                process_payload: bool,
             ) where
                 F: Fn(&mut ProxyConnection, &mut [u8], usize),
             {
-                if tcp_payload_size(p) > 0 {
+                let now = unsafe { _rdtsc() };
+                let length = tcp_payload_size(p) as u32;
+                {
+                    let tcp = p.headers().tcp(2);
+                    c.client_leg.acked(tcp.ack_num(), now);
+                    if c.client_leg.received(tcp.seq_num(), length) {
+                        c.server_leg.discard_probe();
+                    }
+                }
+                c.c2s_bytes += tcp_payload_size(p) as u64;
+                if process_payload && tcp_payload_size(p) > 0 && payload_is_contiguous(p) {
                     let tailroom = p.get_tailroom();
+                    c.payload_seqn = p.headers().tcp(2).seq_num();
                     f_process_payload(c, p.get_payload_mut(2), tailroom);
+                    let rewrite = c.take_payload_rewrite();
+                    if rewrite.is_some() && !apply_payload_rewrite(p, &rewrite.unwrap(), &mut c.c2s_deltas) {
+                        warn!("rewritten client payload exceeds the tailroom, forwarding the original payload");
+                    }
                 }
+                // the payload may have been rewritten
+                let sent_length = tcp_payload_size(p) as u32;
 
                 let server = &servers[c.server_index()];
-                set_header(server, c.port(), p, &me.l234.mac, me.ip_s);
+                set_header(server, c.port(), p, &me.mac_s, me.src_ip_towards_server(c));
 
                 {
                     let tcp = p.headers_mut().tcp_mut(2);
                     // adapt ackn of client packet
                     let oldackn = tcp.ack_num();
-                    let newackn = oldackn.wrapping_sub(c.c_seqn);
+                    let newackn = c.s2c_deltas.original(oldackn.wrapping_sub(c.c_seqn));
                     let oldseqn = tcp.seq_num();
-                    let newseqn = if c.c2s_inserted_bytes >= 0 {
-                        oldseqn.wrapping_add(c.c2s_inserted_bytes as u32)
-                    } else {
-                        oldseqn.wrapping_sub((-c.c2s_inserted_bytes) as u32)
-                    };
-                    if c.c2s_inserted_bytes != 0 {
+                    let delta = c.c2s_inserted_bytes + c.c2s_deltas.at(oldseqn);
+                    let newseqn = shift(oldseqn, delta);
+                    if delta != 0 {
                         tcp.set_seq_num(newseqn);
                     }
                     tcp.set_ack_num(newackn);
                     c.ackn_p2s = newackn;
                     if tcp.fin_flag() { c.seqn_fin_p2s = newseqn; }
+                    if sent_length > 0 {
+                        c.server_leg.sent(newseqn.wrapping_add(sent_length), now);
+                    }
+                    if c.window_shifts.rescales_c2s() {
+                        let window = tcp.window_size();
+                        tcp.set_window_size(c.window_shifts.c2s(window));
+                    }
+                }
+                if c.client_sack {
+                    // the SACK blocks acknowledge server bytes, like the ackn
+                    let c_seqn = c.c_seqn;
+                    let s2c_deltas = &c.s2c_deltas;
+                    translate_sack_blocks(p, |edge| s2c_deltas.original(edge.wrapping_sub(c_seqn)));
                 }
 
                 prepare_checksum_and_ttl(p);
             }
 
-            fn server_to_client(
+            fn server_to_client<F>(
                 p: &mut Pdu,
                 c: &mut ProxyConnection,
                 me: &Me,
-            ) {
+                f_process_payload: &Option<F>,
+                process_payload: bool,
+            ) where
+                F: Fn(&mut ProxyConnection, &mut [u8], usize),
+            {
                 let newseqn;
+                let now = unsafe { _rdtsc() };
+                let length = tcp_payload_size(p) as u32;
+                {
+                    let tcp = p.headers().tcp(2);
+                    c.server_leg.acked(tcp.ack_num(), now);
+                    if c.server_leg.received(tcp.seq_num(), length) {
+                        c.client_leg.discard_probe();
+                    }
+                }
+                c.s2c_bytes += tcp_payload_size(p) as u64;
+                if process_payload && f_process_payload.is_some() && tcp_payload_size(p) > 0 && payload_is_contiguous(p) {
+                    let tailroom = p.get_tailroom();
+                    c.payload_seqn = p.headers().tcp(2).seq_num();
+                    (f_process_payload.as_ref().unwrap())(c, p.get_payload_mut(2), tailroom);
+                    let rewrite = c.take_payload_rewrite();
+                    if rewrite.is_some() && !apply_payload_rewrite(p, &rewrite.unwrap(), &mut c.s2c_deltas) {
+                        warn!("rewritten server payload exceeds the tailroom, forwarding the original payload");
+                    }
+                }
+                // the payload may have been rewritten
+                let sent_length = tcp_payload_size(p) as u32;
                 {
                     // this is the s->c part of the stable two-way connection state
                     // translate packets and forward to client
@@ -289,54 +1086,222 @@ pub fn setup_delayed_proxy<F1, F2>(
                     h.ip_mut(1).set_dst(sock.0);
                     h.ip_mut(1).set_src(me.l234.ip);
                     let tcp = h.tcp_mut(2);
-                    tcp.set_src_port(me.l234.port);
+                    tcp.set_src_port(me.port(c));
                     tcp.set_dst_port(sock.1);
 
                     // adapt seqn and ackn from server packet
                     let oldseqn = tcp.seq_num();
-                    newseqn = oldseqn.wrapping_add(c.c_seqn);
+                    newseqn = shift(oldseqn.wrapping_add(c.c_seqn), c.s2c_deltas.at(oldseqn));
                     let oldackn = tcp.ack_num();
-                    let newackn = if c.c2s_inserted_bytes >= 0 {
-                        oldackn.wrapping_sub(c.c2s_inserted_bytes as u32)
-                    } else {
-                        oldackn.wrapping_add((-c.c2s_inserted_bytes) as u32)
-                    };
-                    if c.c2s_inserted_bytes != 0 {
+                    let newackn = c.c2s_deltas.original(shift(oldackn, -c.c2s_inserted_bytes));
+                    if newackn != oldackn {
                         tcp.set_ack_num(newackn);
                     }
                     tcp.set_seq_num(newseqn);
                     c.ackn_p2c = newackn;
+                    if sent_length > 0 {
+                        c.client_leg.sent(newseqn.wrapping_add(sent_length), now);
+                    }
+                    if c.window_shifts.rescales_s2c() {
+                        let window = tcp.window_size();
+                        tcp.set_window_size(c.window_shifts.s2c(window));
+                    }
+                }
+                if c.server_sack {
+                    // the SACK blocks acknowledge client bytes, like the ackn
+                    let inserted = c.c2s_inserted_bytes;
+                    let c2s_deltas = &c.c2s_deltas;
+                    translate_sack_blocks(p, |edge| c2s_deltas.original(shift(edge, -inserted)));
                 }
                 if p.headers().tcp(2).fin_flag() { c.seqn.ack_for_fin_p2c = newseqn.wrapping_add(tcp_payload_size(p) as u32 + 1); }
 
                 prepare_checksum_and_ttl(p);
             }
 
+            /// the MSS announced to the server in the SYN
+            #[inline]
+            fn syn_mss(c: &ProxyConnection, engine_mss: Option<u16>, target_mss: &Vec<Option<u16>>) -> Option<u16> {
+                if engine_mss.is_some() || target_mss[c.server_index()].is_some() {
+                    // the server must not send segments which are larger than what the client leg can carry
+                    [c.client_mss, engine_mss, target_mss[c.server_index()]].iter().filter_map(|m| *m).min()
+                } else {
+                    None
+                }
+            }
+
+            /// Sends the SYN of c to another server, after the current one reset it or did not answer it.
+            /// The payload packet is re-addressed to the new server, it already contains the PROXY protocol header,
+            /// if the servers use one. Returns false, if there is no further server or no mbuf is available
+            fn retry_syn(
+                c: &mut ProxyConnection,
+                me: &Me,
+                servers: &Vec<L234Data>,
+                vlans: &Option<Vlans>,
+                policy_selector: &mut PolicySelector,
+                proxy_protocols: &Vec<Option<ProxyProtocol>>,
+                engine_mss: Option<u16>,
+                target_mss: &Vec<Option<u16>>,
+                server_load: &ServerLoad,
+                packet_allocator: &mut PduAllocator<'static>,
+                producer: &mut MpscProducer,
+            ) -> bool {
+                let failed = c.server_index();
+                let index = match policy_selector.alternate(c, |i| proxy_protocols[i] == proxy_protocols[failed]) {
+                    Some(index) => index,
+                    None => return false,
+                };
+                c.rebind_server(index as u8, server_load);
+                policy_selector.bound(index);
+                let server = &servers[index];
+                let src_ip = me.src_ip_towards_server(c);
+                let port = c.port();
+                let window = c
+                    .payload_packet
+                    .as_ref()
+                    .map(|p| p.headers().tcp(2).window_size())
+                    .unwrap_or(0xffff);
+                if c.payload_packet.is_some() {
+                    set_header(server, port, c.payload_packet.as_mut().unwrap(), &me.mac_s, src_ip);
+                }
+                let syn = proxy_segment(
+                    packet_allocator,
+                    &me.mac_s,
+                    &server.mac,
+                    (src_ip, port),
+                    (server.ip, server.port),
+                    unsafe { c.seqn.f_seqn },
+                    0,
+                    None,
+                );
+                if syn.is_none() {
+                    return false;
+                }
+                let mut p = syn.unwrap();
+                {
+                    let tcp = p.headers_mut().tcp_mut(2);
+                    tcp.unset_ack_flag();
+                    tcp.set_syn_flag();
+                    tcp.set_window_size(window);
+                }
+                if !add_options(&mut p, &syn_option_bytes(syn_mss(c, engine_mss, target_mss), c.window_shifts.announced_to_server(), c.client_sack)) {
+                    warn!("cannot add TCP options to SYN towards server {}", server.server_id);
+                }
+                prepare_checksum_and_ttl(&mut p);
+                tag_towards_destination(&mut p, vlans, servers);
+                producer.enqueue_one(p);
+                c.trace_server(&server.server_id);
+                c.syn_sent = unsafe { _rdtsc() };
+                true
+            }
+
             /// attention: after calling select_server, p points to a different mbuf and has different headers
-            /// selects the server by calling the closure, sends SYN to server
+            /// selects the server by calling the closure or, if there is no closure, by the selection policy, sends SYN to server.
+            /// Returns false without changing p, if the selected server is at capacity
             fn select_server<F>(
                 p: &mut Pdu,
                 c: &mut ProxyConnection,
                 me: &Me,
                 servers: &Vec<L234Data>,
-                f_select_server: &F,
+                f_select_server: &Option<F>,
+                policy_selector: &mut PolicySelector,
+                sni_map: &SniMap,
+                alpn_map: &AlpnMap,
+                http_router: &HttpRouter,
+                proxy_protocols: &Vec<Option<ProxyProtocol>>,
+                engine_mss: Option<u16>,
+                target_mss: &Vec<Option<u16>>,
+                affinity: &Option<(AffinityTable, u64)>,
+                server_load: &ServerLoad,
                 mut syn: Pdu<'static>,
-            ) where
+            ) -> bool
+            where
                 F: Fn(&mut ProxyConnection),
             {
                 let ip;
                 let tcp;
                 let payload_sz;
+                let bound_payload_sz;
+                // true, if the server is selected by the policy or by the client affinity
+                let mut by_policy = false;
                 {
                     // save clone of payload packet to connection state
                     let p_clone = Box::new(p.clone()); // creates reference to the mbuf in p
                     payload_sz = tcp_payload_size(&p_clone);
+                    c.c2s_bytes += payload_sz as u64;
                     c.payload_packet = Some(p_clone);
-                    f_select_server(c);
-                    c.c2s_inserted_bytes = tcp_payload_size(c.payload_packet.as_ref().unwrap()) as i32 - payload_sz as i32;
+                    // the ClientHello is only parsed, if the server name or the protocols are used for the selection
+                    let by_closure = f_select_server.is_some();
+                    if by_closure || !sni_map.is_empty() || policy_selector.hash_key() == Some(HashKey::Sni) {
+                        c.sni = parse_sni(p.get_payload(2));
+                    }
+                    if by_closure || !alpn_map.is_empty() {
+                        c.alpn = parse_alpn(p.get_payload(2));
+                    }
+                    // the request is only parsed for the http routes, a Maglev hash of the host or a selection closure
+                    let by_request =
+                        by_closure || !http_router.is_empty() || policy_selector.hash_key() == Some(HashKey::Host);
+                    if by_request && c.sni.is_none() && c.alpn.is_none() {
+                        c.http_request = parse_http_request(p.get_payload(2))
+                            .or_else(|| parse_http2_request(p.get_payload(2)))
+                            .map(|r| Box::new(r));
+                    }
+                    c.protocol = Some(Protocol::classify(c, p.get_payload(2)));
+                    if c.socks5 == Some(Socks5State::Connected) {
+                        // the server has been selected by the SOCKS5 CONNECT request
+                    } else if f_select_server.is_some() {
+                        (f_select_server.as_ref().unwrap())(c);
+                    } else {
+                        // a server name in the sni_map, a protocol in the alpn_map or a http route take precedence over
+                        // the selection policy
+                        let routed = c
+                            .sni
+                            .as_ref()
+                            .and_then(|name| sni_map.lookup(name))
+                            .or_else(|| c.alpn.as_ref().and_then(|protocols| alpn_map.lookup(protocols)))
+                            .or_else(|| c.http_request.as_ref().and_then(|r| http_router.route(r)))
+                            // a routed target, which is down, drained, tripped or at capacity, is not selected
+                            .filter(|i| policy_selector.eligible_for(c, *i));
+                        let index = match routed {
+                            Some(index) => index,
+                            None => {
+                                by_policy = true;
+                                // a client keeps its previous target as long as it is eligible
+                                affinity
+                                    .as_ref()
+                                    .and_then(|(table, _)| table.lookup(c.client_sock().unwrap().0, unsafe { _rdtsc() }))
+                                    .filter(|i| policy_selector.eligible_for(c, *i))
+                                    .unwrap_or_else(|| policy_selector.select(c))
+                            }
+                        };
+                        c.set_server_index(index as u8);
+                    }
+                    if policy_selector.at_capacity(c.server_index()) {
+                        c.payload_packet = None;
+                        return false;
+                    }
+                    c.bind_server(server_load);
+                    policy_selector.bound(c.server_index());
+                    // clients of a fallback target return to the other targets, when they are eligible again
+                    if by_policy && affinity.is_some() && !policy_selector.is_fallback(c.server_index()) {
+                        let (table, ttl) = affinity.as_ref().unwrap();
+                        table.bind(c.client_sock().unwrap().0, c.server_index(), unsafe { _rdtsc() } + ttl);
+                    }
+                    if proxy_protocols[c.server_index()].is_some() {
+                        // the PROXY protocol header precedes the first payload towards the server
+                        let header = proxy_protocol_header(
+                            proxy_protocols[c.server_index()].unwrap(),
+                            c.client_addr().unwrap(),
+                            (IpAddr::V4(Ipv4Addr::from(me.l234.ip)), me.port(c)),
+                        );
+                        if !insert_into_payload(c.payload_packet.as_mut().unwrap(), &header) {
+                            warn!("no tailroom for PROXY protocol header towards server {}", servers[c.server_index()].server_id);
+                        }
+                    }
+                    bound_payload_sz = tcp_payload_size(c.payload_packet.as_ref().unwrap());
+                    c.c2s_inserted_bytes = bound_payload_sz as i32 - payload_sz as i32;
 
                     // set the header for the selected server in the payload packet p and its clone p_clone
-                    set_header(&servers[c.server_index()], c.port(), p, &me.l234.mac, me.ip_s);
+                    set_header(&servers[c.server_index()], c.port(), p, &me.mac_s, me.src_ip_towards_server(c));
                     let ok = syn.push_header(p.headers().mac(0));
                     assert!(ok);
                     // this is a little bit tricky: we replace the borrowed packet of the closure, with the syn packet
@@ -357,7 +1322,8 @@ pub fn setup_delayed_proxy<F1, F2>(
                 assert!(ok);
                 {
                     let hs = p.headers_mut();
-                    hs.ip_mut(1).trim_length_by(payload_sz as u16);
+                    // the ip header is cloned from the payload packet, which may have been extended
+                    hs.ip_mut(1).trim_length_by(bound_payload_sz as u16);
                     let tcp = hs.tcp_mut(2);
                     c.seqn.f_seqn = tcp.seq_num().wrapping_sub(1);
                     unsafe { tcp.set_seq_num(c.seqn.f_seqn); }
@@ -366,20 +1332,104 @@ pub fn setup_delayed_proxy<F1, F2>(
                     tcp.unset_ack_flag();
                     tcp.unset_psh_flag();
                 }
+                if !add_options(p, &syn_option_bytes(syn_mss(c, engine_mss, target_mss), c.window_shifts.announced_to_server(), c.client_sack)) {
+                    warn!("cannot add TCP options to SYN towards server {}", servers[c.server_index()].server_id);
+                }
 
                 prepare_checksum_and_ttl(p);
+                c.syn_sent = unsafe { _rdtsc() };
+                true
+            }
+
+            /// resets the established client connection, e.g. when no server is available, the RST replaces the client packet p
+            fn client_reset(p: &mut Pdu, c: &mut ProxyConnection) {
+                let payload_sz = tcp_payload_size(p);
+                let ackn = p.headers().tcp(2).seq_num().wrapping_add(payload_sz as u32);
+                remove_tcp_options(p);
+                make_reply_packet(p, 1);
+                {
+                    let hs = p.headers_mut();
+                    hs.ip_mut(1).trim_length_by(payload_sz as u16);
+                    let tcp = hs.tcp_mut(2);
+                    tcp.unset_psh_flag();
+                    tcp.set_rst_flag();
+                    tcp.set_ack_flag();
+                    tcp.set_ack_num(ackn);
+                    tcp.set_seq_num(c.c_seqn.wrapping_add(1));
+                }
+                prepare_checksum_and_ttl(p);
+            }
+
+            /// the proxy answers the SOCKS5 greeting and request itself, the reply replaces the client packet p.
+            /// Returns the group index for p
+            fn socks5_negotiate(
+                p: &mut Pdu,
+                c: &mut ProxyConnection,
+                resolver: &Socks5Resolver,
+                me: &Me,
+            ) -> usize {
+                let payload_sz = tcp_payload_size(p);
+                if payload_sz == 0 {
+                    return 0;
+                }
+                let reply = match c.socks5.unwrap() {
+                    Socks5State::Greeting => {
+                        let accepted = parse_greeting(p.get_payload(2)) == Some(true);
+                        if accepted {
+                            c.socks5 = Some(Socks5State::Request);
+                        }
+                        greeting_reply(accepted)
+                    }
+                    _ => {
+                        match parse_connect_request(p.get_payload(2)).and_then(|d| resolver.resolve(&d)) {
+                            Ok(index) => {
+                                c.set_server_index(index as u8);
+                                c.socks5 = Some(Socks5State::Connected);
+                                connect_reply(REPLY_SUCCEEDED, (Ipv4Addr::from(me.l234.ip), me.port(c)))
+                            }
+                            Err(code) => {
+                                debug!("socks5: CONNECT request of {:?} failed with {}", c.client_addr(), code);
+                                connect_reply(code, (Ipv4Addr::from(me.l234.ip), me.port(c)))
+                            }
+                        }
+                    }
+                };
+                let ackn = p.headers().tcp(2).seq_num().wrapping_add(payload_sz as u32);
+                make_reply_packet(p, 1);
+                if !replace_payload(p, &reply) {
+                    warn!("socks5: no tailroom for reply");
+                    return 0;
+                }
+                {
+                    let tcp = p.headers_mut().tcp_mut(2);
+                    tcp.set_ack_flag();
+                    tcp.set_psh_flag();
+                    tcp.set_ack_num(ackn);
+                    tcp.set_seq_num(c.c_seqn.wrapping_add(1));
+                }
+                // bytes sent by the proxy shift the seqn of the server towards the client, see server_synack_received
+                c.c_seqn = c.c_seqn.wrapping_add(reply.len() as u32);
+                c.ackn_p2c = ackn;
+                prepare_checksum_and_ttl(p);
+                1
             }
 
             ///returns ACK for SYN to server, and sends payload packet to server
             fn server_synack_received(
                 p: &mut Pdu,
                 c: &mut ProxyConnection,
+                servers: &Vec<L234Data>,
+                vlans: &Option<Vlans>,
                 producer: &mut MpscProducer,
             ) {
                 trace!("syn_ack_recv: p.refcnt= {}", p.refcnt());
+                c.server_rtt = Some(unsafe { _rdtsc() }.wrapping_sub(c.syn_sent));
                 // correction for server side seq numbers
                 let delta = c.c_seqn.wrapping_sub(p.headers().tcp(2).seq_num());
                 c.c_seqn = delta;
+                let server_options = syn_options(p);
+                c.window_shifts.server_synack(server_options.window_scale);
+                c.server_sack = c.client_sack && server_options.sack_permitted;
                 remove_tcp_options(p);
                 make_reply_packet(p, 1);
                 p.headers_mut().tcp_mut(2).unset_syn_flag();
@@ -389,6 +1439,7 @@ pub fn setup_delayed_proxy<F1, F2>(
                 }
                 //debug!("data_len= { }, p= { }",p.data_len(), p);
                 prepare_checksum_and_ttl(p);
+                tag_towards_destination(p, vlans, servers);
                 // we clone the packet and send it via the extra queue, the original p gets discarded
                 let p_clone = p.clone();
                 trace!("syn_ack_recv: p_clone/p.refcnt= {}/{}", p_clone.refcnt(), p.refcnt());
@@ -413,6 +1464,7 @@ pub fn setup_delayed_proxy<F1, F2>(
                     c.ackn_p2s = p.headers().tcp(2).ack_num();
                     trace!("delayed packet: { }", payload_packet.headers());
                     assert_eq!(payload_packet.refcnt(), 1);
+                    tag_towards_destination(&mut payload_packet, vlans, servers);
                     producer.enqueue_one_boxed(payload_packet);
                 }
             }
@@ -421,45 +1473,175 @@ pub fn setup_delayed_proxy<F1, F2>(
 
             #[cfg(feature = "profiling")]
                 let timestamp_entry = _rdtsc();
+            let entry_tsc = if latencies.is_some() { unsafe { _rdtsc() } } else { 0 };
+            #[cfg(feature = "alloc_audit")]
+            let entry_allocations = thread_allocations();
 
             let b_private_etype;
             {
                 let mac_header = pdu.headers().mac(0);
                 b_private_etype = private_etype(&mac_header.etype());
                 if !b_private_etype {
-                    if mac_header.dst != me.l234.mac && !mac_header.dst.is_multicast() && !mac_header.dst.is_broadcast() {
+                    cm.counters_mut().rx_packets += 1;
+                    packet_received();
+                    if sflow.is_some() {
+                        sflow.as_mut().unwrap().packet(pdu);
+                    }
+                    if mac_header.dst != me.l234.mac && mac_header.dst != me.mac_s && !mac_header.dst.is_multicast() && !mac_header.dst.is_broadcast() {
                         debug!("{} from pci: discarding because mac unknown: {} ", thread_id, mac_header);
+                        cm.counters_mut().dropped_packets += 1;
+                        return 0;
+                    }
+                    if vlans.is_some() && !vlans.as_ref().unwrap().accepts(pdu.vlan_tci()) {
+                        debug!("{} from pci: discarding frame of unknown vlan, tci= {:?}", thread_id, pdu.vlan_tci());
+                        cm.counters_mut().dropped_packets += 1;
                         return 0;
                     }
+                    if mac_header.etype() == ARP_ETYPE && arp.is_some() {
+                        // the ARP messages also go to KNI
+                        let message = parse_arp(pdu.get_payload(0));
+                        if message.is_some() {
+                            let now = unsafe { _rdtsc() };
+                            let resolved = arp.as_mut().unwrap().received(&message.unwrap(), &arp_targets, now);
+                            if resolved.is_some() {
+                                let (ip, mac) = resolved.unwrap();
+                                shared.targets.update_mac(ip, mac);
+                            }
+                        }
+                    }
+                    if mac_header.etype() == ARP_ETYPE && (kniless || me.inline) {
+                        // without KNI the pipeline answers the ARP requests for the addresses of the engine,
+                        // in inline mode those for the server side addresses, which are not known to KNI
+                        let message = parse_arp(pdu.get_payload(0));
+                        if message.is_some() {
+                            let message = message.unwrap();
+                            let client_side = message.target_ip == me.l234.ip;
+                            if message.operation == ARP_REQUEST
+                                && (kniless && client_side || !client_side && cm.source_ips().contains(&message.target_ip))
+                            {
+                                let smac = if client_side { me.l234.mac } else { me.mac_s };
+                                match arp_reply(&mut packet_allocator, &smac, &message) {
+                                    Some(mut p) => {
+                                        tag_towards_destination(&mut p, &vlans, &servers);
+                                        producer.enqueue_one(p);
+                                    }
+                                    None => warn!("{}: no mbuf for ARP reply", pipeline_id_clone),
+                                }
+                            }
+                        }
+                    }
                     if mac_header.etype() != 0x0800 && !b_private_etype {
                         // everything other than Ipv4 or our own packets we send to KNI, i.e. group 2
+                        // note: the state machine works on the IPv4 header stack of e2d2, IPv6 frames also go to KNI
+                        cm.counters_mut().kni_packets += 1;
                         return 2;
                     }
                 }
             }
 
+            if !b_private_etype && reassembly.is_some() && is_fragment(pdu.get_payload(0)) {
+                // fragments of tcp datagrams to the proxy are reassembled, all others still go to KNI
+                let (protocol, src, dst) = {
+                    let ip_header = pdu.headers().ip(1);
+                    (ip_header.protocol(), ip_header.src(), ip_header.dst())
+                };
+                if protocol == 6
+                    && (addressed_to_proxy(dst, me.l234.ip, cm.source_ips())
+                        || me.transparent && servers.iter().any(|s| s.ip == src))
+                {
+                    match reassembly.as_mut().unwrap().add(pdu.get_payload(0)) {
+                        FragmentResult::Complete(payload) => {
+                            if !set_reassembled_payload(pdu, &payload) {
+                                debug!("{}: reassembled datagram of {} bytes exceeds the tailroom", thread_id, payload.len());
+                                cm.counters_mut().fragment_drops += 1;
+                                cm.counters_mut().dropped_packets += 1;
+                                return 0;
+                            }
+                            cm.counters_mut().reassembled_datagrams += 1;
+                        }
+                        FragmentResult::Incomplete => {
+                            cm.counters_mut().dropped_packets += 1;
+                            return 0;
+                        }
+                        FragmentResult::Dropped => {
+                            cm.counters_mut().fragment_drops += 1;
+                            cm.counters_mut().dropped_packets += 1;
+                            return 0;
+                        }
+                    }
+                }
+            }
+
+            if !b_private_etype && icmp_config.is_some() {
+                // ICMP to the proxy is handled here, everything else still goes to KNI
+                let (protocol, dst) = {
+                    let ip_header = pdu.headers().ip(1);
+                    (ip_header.protocol(), ip_header.dst())
+                };
+                if addressed_to_proxy(dst, me.l234.ip, cm.source_ips()) {
+                    let config = icmp_config.as_ref().unwrap();
+                    if protocol == ICMP_PROTOCOL {
+                        if config.echo.unwrap_or(true) && icmp_echo_reply(pdu, &me)
+                            || config.forward_errors.unwrap_or(true) && icmp_forward_error(pdu, &mut cm, &me, &servers)
+                        {
+                            tag_towards_destination(pdu, &vlans, &servers);
+                            cm.counters_mut().tx_packets += 1;
+                            return me.tx_group(pdu);
+                        }
+                    } else if protocol == 17
+                        && config.answers_port_unreachable(pdu.headers().udp(2).dst_port(), udp_port, tcp_min_port)
+                    {
+                        match icmp_port_unreachable(&mut packet_allocator, pdu, &me) {
+                            Some(mut p) => {
+                                tag_towards_destination(&mut p, &vlans, &servers);
+                                producer.enqueue_one(p);
+                            }
+                            None => warn!("{}: no mbuf for ICMP port unreachable", pipeline_id_clone),
+                        }
+                        cm.counters_mut().dropped_packets += 1;
+                        return 0;
+                    }
+                }
+            }
+
             {
                 let ip_header = pdu.headers().ip(1);
                 if !b_private_etype {
+                    if ip_header.protocol() == 17 && udp_port.is_some() && (ip_header.dst() == pipeline_ip || ip_header.dst() == me.l234.ip) {
+                        // udp flows are handled by the udp pipeline, i.e. group 3
+                        cm.counters_mut().kni_packets += 1;
+                        return 3;
+                    }
                     // everything other than TCP, and everything not addressed to us we send to KNI, i.e. group 2
-                    if ip_header.protocol() != 6 || ip_header.dst() != pipeline_ip && ip_header.dst() != me.l234.ip {
+                    // in transparent mode, the servers reply to the client ip address
+                    if ip_header.protocol() != 6
+                        || !addressed_to_proxy(ip_header.dst(), me.l234.ip, cm.source_ips())
+                            && !(me.transparent && servers.iter().any(|s| s.ip == ip_header.src()))
+                    {
+                        cm.counters_mut().kni_packets += 1;
                         return 2;
                     }
                 }
             }
 
             if csum_offload {
+                // a VLAN tag is inserted by the NIC behind the checksummed headers, see VlanConfig
                 pdu.set_tcp_ipv4_checksum_tx_offload();
             }
             let mut group_index = 0usize; // the index of the group to be returned, default 0: dump packet
 
 
             //check ports
-            if !b_private_etype && pdu.headers().tcp(2).dst_port() != me_clone.l234.port && pdu.headers().tcp(2).dst_port() < tcp_min_port {
+            if !b_private_etype && me_clone.frontend(pdu.headers().tcp(2).dst_port()).is_none() && pdu.headers().tcp(2).dst_port() < tcp_min_port {
+                cm.counters_mut().kni_packets += 1;
                 return 2;
             }
 
 
+            if !b_private_etype && capture.is_some() {
+                capture.as_ref().unwrap().packet(pdu);
+            }
+
             let ethertype = pdu.headers().mac(0).etype();
 
 
@@ -467,11 +1649,110 @@ pub fn setup_delayed_proxy<F1, F2>(
             // the port/connection becomes released afterwards
             // this is cumbersome, but we must make the  borrow checker happy
             let mut release_connection = None;
+            // set, if a connection violated the state transition tables, counted afterwards for the same reason
+            let mut state_violation = false;
+            // set, if the SYN of a connection was sent to another server, counted afterwards
+            let mut syn_retried = false;
             // check if we got a packet from generator
             match ethertype {
                 tasks::PRIVATE_ETYPE_PACKET => {}
                 tasks::PRIVATE_ETYPE_TIMER => {
                     ticks += 1;
+                    if pacer.is_some() {
+                        for p in pacer.as_mut().unwrap().tick() {
+                            producer.enqueue_one_boxed(p);
+                        }
+                    }
+                    if replayer_clone.is_some() {
+                        let frames = replayer_clone.as_ref().unwrap().lock().unwrap().due();
+                        for frame in frames {
+                            match replayed_pdu(&mut packet_allocator, &frame) {
+                                Some(p) => producer_replay.enqueue_one(p),
+                                None => warn!("{}: replay: no mbuf available, frame is skipped", pipeline_id_clone),
+                            }
+                        }
+                    }
+                    if shared.targets.version() != targets_version {
+                        // configuration was reloaded, new connections use the new targets and timeouts
+                        targets_version = shared.targets.version();
+                        servers = shared.targets.l234data();
+                        policy_selector.update_targets(&shared.targets.targets());
+                        sni_map = SniMap::new(&shared.targets.sni_map(), &shared.targets.targets());
+                        alpn_map = AlpnMap::new(&shared.targets.alpn_map(), &shared.targets.targets());
+                        http_router = HttpRouter::new(&shared.targets.http_routes(), &shared.targets.targets());
+                        proxy_protocols = shared.targets.targets().iter().map(|t| t.config.proxy_protocol).collect();
+                        target_mss = shared.targets.targets().iter().map(|t| t.config.mss).collect();
+                        mss_to_clients = mss_towards_clients(engine_mss, &shared.targets.targets());
+                        arp_targets = shared.targets.arp_targets();
+                        if vlans.is_some() {
+                            vlans.as_mut().unwrap().update_targets(&shared.targets.targets());
+                        }
+                        for (resolver, backend) in socks5_resolvers.iter_mut().zip(backends.iter()) {
+                            if resolver.is_some() {
+                                resolver.as_mut().unwrap().update_targets(&backend_entries(&shared.targets.targets(), backend));
+                            }
+                        }
+                        timeouts = Timeouts::default_or_some(&shared.targets.timeouts());
+                        if timeouts.established.is_some() && timeouts.established.unwrap() > wheel.get_max_timeout_cycles() {
+                            timeouts.established = Some(wheel.get_max_timeout_cycles());
+                        }
+                        info!("{}: took over configuration version {}, {} targets", pipeline_id_clone, targets_version, servers.len());
+                        // a target filter must be resolved again
+                        capture = shared.capture.capture(&servers);
+                    }
+                    if arp.is_some() && ticks % wheel_tick_reduction_factor == 0 {
+                        let now = unsafe { _rdtsc() };
+                        for ip in arp.as_mut().unwrap().due(&arp_targets, now) {
+                            let (smac, sender_ip) = if me.inline && servers.iter().any(|s| s.ip == ip) {
+                                (me.mac_s, me.ip_s)
+                            } else {
+                                (me.l234.mac, me.l234.ip)
+                            };
+                            match arp_request(&mut packet_allocator, &smac, sender_ip, ip) {
+                                Some(mut p) => {
+                                    if vlans.is_some() {
+                                        let vlan = vlans.as_ref().unwrap().towards(ip, &servers);
+                                        if vlan.is_some() {
+                                            p.set_vlan_tx_offload(vlan.unwrap());
+                                        }
+                                    }
+                                    producer.enqueue_one(p)
+                                }
+                                None => warn!("{}: no mbuf for ARP request", pipeline_id_clone),
+                            }
+                        }
+                    }
+                    if shared.listing.requested() != listing_request {
+                        listing_request = shared.listing.requested();
+                        shared.listing.report(&pipeline_id_clone, listing_request, cm.live_connections());
+                    }
+                    if shared.sessions.requested() != session_request {
+                        session_request = shared.sessions.requested();
+                        shared.sessions.report(&pipeline_key, session_request, cm.connection_states());
+                    }
+                    if shared.lookup.requested() != lookup_request {
+                        lookup_request = shared.lookup.requested();
+                        if let Some((request, query)) = shared.lookup.query() {
+                            let mut killed = None;
+                            let found = cm.find(&query.key).map(|c| {
+                                let live = c.live_connection();
+                                if query.kill {
+                                    kill_connection(c, &me, &servers, &vlans, &mut packet_allocator, &mut producer);
+                                    killed = Some(c.slot());
+                                }
+                                live
+                            });
+                            if killed.is_some() {
+                                info!("{}: killed connection {} on port {}", pipeline_id_clone, found.as_ref().unwrap().uuid, killed.unwrap() as u16);
+                                cm.release_slot(killed.unwrap(), &mut wheel);
+                            }
+                            shared.lookup.answer(&pipeline_id_clone, request, found);
+                        }
+                    }
+                    if shared.capture.version() != capture_version {
+                        capture_version = shared.capture.version();
+                        capture = shared.capture.capture(&servers);
+                    }
                     match rx.try_recv() {
                         Ok(MessageTo::FetchCounter) => {
                             debug!("{}: received FetchCounter", pipeline_id_clone);
@@ -493,18 +1774,168 @@ pub fn setup_delayed_proxy<F1, F2>(
                                 )).unwrap();
                         }
                         Ok(MessageTo::FetchCRecords) => {
-                            let c_recs = cm.fetch_c_records();
-                            debug!("{}: received FetchCRecords, returning {} records", pipeline_id_clone, if c_recs.is_some() { c_recs.as_ref().unwrap().len() } else { 0 });
-                            tx_clone
-                                .send(MessageFrom::CRecords(pipeline_id_clone.clone(), c_recs, None))
-                                .unwrap();
+                            // one message per generation of records
+                            for c_recs in cm.fetch_c_records() {
+                                debug!("{}: received FetchCRecords, returning {} records", pipeline_id_clone, c_recs.len());
+                                tx_clone
+                                    .send(MessageFrom::CRecords(pipeline_id_clone.clone(), Some(c_recs), None))
+                                    .unwrap();
+                            }
                         }
                         _ => {}
                     }
                     // check for timeouts
                     // debug!("ticks = {}", ticks);
                     if ticks % wheel_tick_reduction_factor == 0 {
-                        cm.release_timeouts(unsafe { &_rdtsc() }, &mut wheel);
+                        if idle_timeouts.is_some() || keepalive.is_some() || syn_retry.is_some() {
+                            // timers are re-armed until the connection is idle for its timeout, or with keepalive,
+                            // until the timeouts.established has passed since the connection was opened
+                            let now = wheel.now();
+                            for slot in expired_timers(&now, &mut wheel) {
+                                let mut expired = false;
+                                let mut retried = false;
+                                if let Some(c) = cm.get_mut_by_slot(slot) {
+                                    if syn_retry.is_some()
+                                        && c.server_bound()
+                                        && c.server_state() == TcpState::SynReceived
+                                        && now >= c.syn_sent.wrapping_add(syn_timeout) {
+                                        // the server did not answer the SYN in time
+                                        policy_selector.record_failure(c.server_index());
+                                        if c.syn_retries() < syn_retry.as_ref().unwrap().attempts()
+                                            && retry_syn(c, &me, &servers, &vlans, &mut policy_selector, &proxy_protocols, engine_mss, &target_mss, &server_load, &mut packet_allocator, &mut producer) {
+                                            debug!("{} SYN on port {} not answered, SYN sent to server {}", thread_id, c.port(), c.server_index());
+                                            c.timer = wheel.schedule(&syn_timeout, slot);
+                                            retried = true;
+                                        } else {
+                                            debug!("{} no server answered the SYN on port {}, resetting client connection", thread_id, c.port());
+                                            if c.sock().is_some() {
+                                                reset_client_leg(c, &me, &servers, &vlans, &mut packet_allocator, &mut producer);
+                                            }
+                                            c.set_release_cause(ReleaseCause::ActiveRst);
+                                            c.set_close_reason(CloseReason::IdleTimeout);
+                                            c.c_push_state(TcpState::Closed);
+                                            expired = true;
+                                        }
+                                    } else {
+                                        let both_established = c.server_bound() && c.sock().is_some()
+                                            && c.client_state() >= TcpState::Established
+                                            && c.server_state() >= TcpState::Established;
+                                        let deadline = if idle_timeouts.is_some() {
+                                            let millis = idle_timeouts.as_ref().unwrap().for_state(c.client_state(), c.server_state(), timeouts.established.unwrap());
+                                            c.last_activity + millis * system_data.cpu_clock / 1000
+                                        } else {
+                                            c.opened + timeouts.established.unwrap() * system_data.cpu_clock / 1000
+                                        };
+                                        if now >= deadline {
+                                            // after a FIN we do not wait for the FINs of client and server, like after a RST
+                                            if idle_timeouts.is_some() && both_established {
+                                                let teardown = idle_timeouts.as_ref().unwrap().teardown.unwrap_or_default();
+                                                segments_to_both_legs(c, &me, &servers, &vlans, Some(teardown), &mut packet_allocator, &mut producer);
+                                            }
+                                            debug!("{} timeout on port {} in client/server state {:?}/{:?}", thread_id, c.port(), c.client_state(), c.server_state());
+                                            if c.server_bound() && c.server_state() == TcpState::SynReceived {
+                                                // the target did not answer the SYN
+                                                policy_selector.record_failure(c.server_index());
+                                            }
+                                            c.set_release_cause(ReleaseCause::Timeout);
+                                            c.set_close_reason(CloseReason::IdleTimeout);
+                                            c.c_push_state(TcpState::Closed);
+                                            expired = true;
+                                        } else {
+                                            let mut next = deadline;
+                                            if keepalive.is_some()
+                                                && both_established
+                                                && c.client_state() == TcpState::Established
+                                                && c.server_state() == TcpState::Established {
+                                                let (idle, interval) = keepalive.unwrap();
+                                                let probe_at = cmp::max(c.last_activity + idle, c.last_keepalive + interval);
+                                                if now >= probe_at {
+                                                    segments_to_both_legs(c, &me, &servers, &vlans, None, &mut packet_allocator, &mut producer);
+                                                    c.last_keepalive = now;
+                                                    next = cmp::min(next, now + interval);
+                                                } else {
+                                                    next = cmp::min(next, probe_at);
+                                                }
+                                            }
+                                            c.timer = wheel.schedule(&cmp::min(next - now, wheel.get_max_timeout_cycles()), slot);
+                                        }
+                                    }
+                                }
+                                if retried {
+                                    cm.counters_mut().syn_retries += 1;
+                                }
+                                if expired {
+                                    cm.release_slot(slot, &mut wheel);
+                                }
+                            }
+                        } else {
+                            let now = wheel.now();
+                            for target in cm.release_timeouts(&now, &mut wheel) {
+                                policy_selector.record_failure(target);
+                            }
+                        }
+                        cm.enforce_record_retention(unsafe { _rdtsc() });
+                        cm.expire_time_wait();
+                        if reassembly.is_some() {
+                            let expired = reassembly.as_mut().unwrap().expire();
+                            cm.counters_mut().fragment_drops += expired as u64;
+                            cm.counters_mut().reassembly_pending = reassembly.as_ref().unwrap().pending() as u64;
+                        }
+                        cm.counters_mut().time_wait_slots = cm.time_wait_slots() as u64;
+                        cm.counters_mut().active_connections = cm.active_connections() as u64;
+                        cm.counters_mut().wheel_occupancy = wheel.pending() as u64;
+                        cm.counters_mut().wheel_overflows = wheel.overflowed();
+                        cm.counters_mut().checksum_offload = csum_offload as u64;
+                        cm.counters_mut().arena_misses = cm.arena_misses();
+                        shared.stats.publish(&pipeline_id_clone, cm.counters());
+                        if shared.drain.is_draining() {
+                            if shared.drain.deadline_passed(unsafe { _rdtsc() }) && cm.active_connections() > 0 {
+                                warn!("{}: drain deadline passed, releasing {} connections", pipeline_id_clone, cm.active_connections());
+                                cm.release_all(ReleaseCause::Timeout, CloseReason::Drain, &mut wheel);
+                            }
+                            shared.drain.report_active(&pipeline_id_clone, cm.active_connections());
+                        }
+                    }
+                    if ticks % 100 == 0 {
+                        // once per second
+                        cm.expire_rate_limits();
+                        policy_selector.decay_response_times();
+                        if affinity.is_some() {
+                            affinity.as_ref().unwrap().0.expire(ticks / 100, unsafe { _rdtsc() });
+                        }
+                        if latencies.is_some() {
+                            shared.latencies.publish(&pipeline_id_clone, latencies.as_ref().unwrap());
+                        }
+                        if cm.protocol_counters().is_some() {
+                            shared.protocols.publish(&pipeline_id_clone, cm.protocol_counters().unwrap());
+                        }
+                    }
+                    if ha.is_some() && ticks % ha.as_ref().unwrap().1 == 0 {
+                        match ha.as_ref().unwrap().0 {
+                            HaRole::Active => shared.state_sync.publish(&pipeline_key, cm.connection_states()),
+                            HaRole::Standby => {
+                                if shared.state_sync.version() != sync_version {
+                                    sync_version = shared.state_sync.version();
+                                    if let Some(states) = shared.state_sync.snapshot(&pipeline_key) {
+                                        let timeout = cmp::min(timeouts.established.unwrap() * system_data.cpu_clock / 1000, wheel.get_max_timeout_cycles());
+                                        let mut restored = HashSet::with_capacity(states.len());
+                                        for state in &states {
+                                            if cm.restore(state, timeout, &mut wheel) {
+                                                restored.insert(state.slot);
+                                            } else {
+                                                debug!("{}: cannot restore connection on port {}", pipeline_id_clone, state.slot as u16);
+                                            }
+                                        }
+                                        // these connections have been closed by the active engine
+                                        for slot in replicated.difference(&restored) {
+                                            cm.release_slot(*slot, &mut wheel);
+                                        }
+                                        cm.counters_mut().replicated_connections = restored.len() as u64;
+                                        replicated = restored;
+                                    }
+                                }
+                            }
+                        }
                     }
                     #[cfg(feature = "profiling")]
                         {   //save stats
@@ -519,11 +1950,60 @@ pub fn setup_delayed_proxy<F1, F2>(
                 _ => {
                     // we use a clone for reading tcp header to avoid immutable borrow. But attention, this clone does not update, when we change the original header!
                     let tcp = pdu.headers().tcp(2).clone();
-                    let src_sock = (pdu.headers().ip(1).src(), tcp.src_port());
+                    let src_sock = (v4_to_key(pdu.headers().ip(1).src()), tcp.src_port());
 
-                    if tcp.dst_port() == me.l234.port {
+                    let frontend = me.frontend(tcp.dst_port());
+                    if frontend.is_some() && tcp.syn_flag() && acl.is_some() && !acl.as_ref().unwrap().permits(src_sock.0) {
+                        // not permitted by the access control list, dump the packet
+                        cm.counters_mut().syn_acl_denied += 1;
+                    } else if frontend.is_some() {
                         //trace!("client to server");
-                        let opt_c = if tcp.syn_flag() {
+                        // while draining or above the rate limit of the client we do not accept new connections
+                        let mut refuse = false;
+                        let mut refuse_with_rst = true;
+                        // with syn cookies, we allocate the connection state, when the ACK of the client validates
+                        let mut cookie_reply = false;
+                        if tcp.syn_flag() && cm.get_mut_by_sock(&src_sock).is_none() {
+                            if shared.drain.is_draining() {
+                                refuse = true;
+                            } else if max_connections.is_some() && shared.connections.total() >= max_connections.unwrap() {
+                                refuse = true;
+                            } else if !cm.admit_syn(src_sock.0) {
+                                refuse = true;
+                                refuse_with_rst = !cm.rate_limit_drops();
+                            } else if syn_cookies {
+                                cookie_reply = true;
+                            }
+                        }
+                        let opt_c = if refuse || cookie_reply {
+                            None
+                        } else if syn_cookies
+                            && tcp.ack_flag()
+                            && !tcp.syn_flag()
+                            && !tcp.rst_flag()
+                            && cm.get_mut_by_sock(&src_sock).is_none()
+                            && syn_cookie_valid(
+                                shared.syn_cookie_secret,
+                                &src_sock,
+                                tcp.seq_num().wrapping_sub(1),
+                                tcp.ack_num().wrapping_sub(1),
+                                cookie_slot(unsafe { _rdtsc() }, system_data.cpu_clock),
+                            ) {
+                            let mut c = cm.get_mut_or_insert(&src_sock);
+                            if c.is_some() {
+                                let c = c.as_mut().unwrap();
+                                c.set_audit(state_audit);
+                                client_syn_cookie_validated(pdu, c);
+                                c.set_frontend(frontend.unwrap());
+                                if socks5_resolvers[c.frontend()].is_some() {
+                                    c.socks5 = Some(Socks5State::Greeting);
+                                }
+                                c.c_push_state(TcpState::SynSent);
+                                let timeout = initial_timeout(&timeouts, &idle_timeouts, system_data.cpu_clock, &wheel);
+                                c.timer = wheel.schedule(&timeout, c.slot());
+                            }
+                            c
+                        } else if tcp.syn_flag() {
                             let c = cm.get_mut_or_insert(&src_sock);
                             #[cfg(feature = "profiling")]
                                 time_adders[0].add_diff(_rdtsc() - timestamp_entry);
@@ -536,29 +2016,58 @@ pub fn setup_delayed_proxy<F1, F2>(
                         };
 
 
-                        if opt_c.is_none() {
-                            warn!("{} unexpected client side packet: no state for socket ({}, {}), tcp= {}, discarding", thread_id, src_sock.0, src_sock.1, tcp);
+                        if cookie_reply {
+                            client_syn_cookie_reply(pdu, shared.syn_cookie_secret, &src_sock, cookie_slot(unsafe { _rdtsc() }, system_data.cpu_clock), mss_to_clients);
+                            counter_c[TcpStatistics::RecvSyn] += 1;
+                            counter_c[TcpStatistics::SentSynAck] += 1;
+                            group_index = 1;
+                        } else if refuse {
+                            debug!("{} refusing SYN from ({}, {})", thread_id, Ipv4Addr::from(pdu.headers().ip(1).src()), src_sock.1);
+                            if refuse_with_rst {
+                                client_syn_refused(pdu);
+                                group_index = 1;
+                            }
+                        } else if opt_c.is_none() {
+                            warn!("{} unexpected client side packet: no state for socket ({}, {}), tcp= {}, discarding", thread_id, Ipv4Addr::from(pdu.headers().ip(1).src()), src_sock.1, tcp);
                         } else {
                             let mut c = opt_c.unwrap();
+                            c.packets[0] += 1;
+                            c.wire_bytes[0] += pdu.data_len() as u64;
 
                             let old_s_state = c.server_state().clone();
                             let old_c_state = c.client_state().clone();
+                            if idle_timeouts.is_some() || keepalive.is_some() {
+                                c.last_activity = unsafe { _rdtsc() };
+                            }
+                            let fast = splice && c.spliced() && !tcp.syn_flag() && !tcp.fin_flag() && !tcp.rst_flag();
 
-                            //check seqn
-                            if old_c_state != TcpState::Closed && tcp.seq_num() < c.ackn_p2c {
+                            if fast {
+                                // spliced fast path: the packet is translated below, without passing the state machine
+                            } else if old_c_state != TcpState::Closed && tcp.seq_num() < c.ackn_p2c {
+                                //check seqn
                                 let diff = tcp.seq_num() as i64 - c.ackn_p2c as i64;
                                 //  a re-sent packet ?
                                 debug!("{} state= {:?}, diff= {}, tcp= {}", thread_id, old_s_state, diff, tcp);
                             } else if tcp.syn_flag() {
                                 if old_c_state == TcpState::Closed {
                                     // replies with a SYN-ACK to client:
-                                    client_syn_received(pdu, &mut c);
+                                    c.set_audit(state_audit);
+                                    c.set_frontend(frontend.unwrap());
+                                    client_syn_received(pdu, &mut c, mss_to_clients, &tcp_options);
+                                    if tracing_spans {
+                                        let span = connection_span(&pipeline_id_clone, c.client_addr(), c.port());
+                                        c.set_span(span);
+                                    }
+                                    if socks5_resolvers[c.frontend()].is_some() {
+                                        c.socks5 = Some(Socks5State::Greeting);
+                                    }
                                     c.c_push_state(TcpState::SynSent);
                                     trace!("{} (SYN-)ACK to client, L3: { }, L4: { }", thread_id, pdu.headers().ip(1), pdu.headers().tcp(2));
                                     counter_c[TcpStatistics::RecvSyn] += 1;
                                     counter_c[TcpStatistics::SentSynAck] += 1;
 
-                                    c.wheel_slot_and_index = wheel.schedule(&(timeouts.established.unwrap() * system_data.cpu_clock / 1000), c.port());
+                                    let timeout = initial_timeout(&timeouts, &idle_timeouts, system_data.cpu_clock, &wheel);
+                                    c.timer = wheel.schedule(&timeout, c.slot());
                                     group_index = 1;
                                 } else {
                                     warn!("received client SYN in state {:?}/{:?}, {:?}/{:?}, {}", old_c_state, old_s_state, c.c_states(), c.s_states(), tcp);
@@ -567,10 +2076,17 @@ pub fn setup_delayed_proxy<F1, F2>(
                                     time_adders[2].add_diff(_rdtsc() - timestamp_entry);
                             } else if tcp.ack_flag() && old_c_state == TcpState::SynSent {
                                 c.c_push_state(TcpState::Established);
+                                c.client_rtt = Some(entry_tsc.wrapping_sub(c.opened));
+                                if latencies.is_some() {
+                                    let latencies = latencies.as_mut().unwrap();
+                                    let nanos = latencies.nanos(entry_tsc.wrapping_sub(c.opened));
+                                    latencies.handshake.record(nanos);
+                                }
                                 counter_c[TcpStatistics::RecvSynAck2] += 1;
                                 #[cfg(feature = "profiling")]
                                     time_adders[4].add_diff(_rdtsc() - timestamp_entry);
                             } else if tcp.fin_flag() {
+                                c.set_close_reason(CloseReason::ClientFin);
                                 if old_s_state >= TcpState::FinWait1 { // server in active close, client in passive or also active close
                                     if tcp.ack_flag() && tcp.ack_num() == unsafe { c.seqn.ack_for_fin_p2c } {
                                         counter_c[TcpStatistics::RecvFinPssv] += 1;
@@ -620,9 +2136,10 @@ pub fn setup_delayed_proxy<F1, F2>(
                             } else if tcp.rst_flag() {
                                 trace!("received RST");
                                 counter_c[TcpStatistics::RecvRst] += 1;
+                                c.set_close_reason(CloseReason::ClientRst);
                                 c.c_push_state(TcpState::Closed);
                                 c.set_release_cause(ReleaseCause::ActiveRst);
-                                release_connection = Some(c.port());
+                                release_connection = Some(c.slot());
                             } else if tcp.ack_flag() && tcp.ack_num() == unsafe { c.seqn.ack_for_fin_p2c } && old_s_state >= TcpState::FinWait1 {
                                 // ACK from client for FIN of Server
                                 trace!(
@@ -656,20 +2173,69 @@ pub fn setup_delayed_proxy<F1, F2>(
                                 c.c_push_state(TcpState::Closed);
                                 counter_c[TcpStatistics::RecvAck4Fin] += 1;
                                 counter_s[TcpStatistics::SentAck4Fin] += 1;
+                            } else if old_c_state == TcpState::Established
+                                && old_s_state == TcpState::Listen
+                                && c.socks5.is_some() && c.socks5 != Some(Socks5State::Connected) {
+                                let resolver = socks5_resolvers[c.frontend()].as_ref().unwrap();
+                                group_index = socks5_negotiate(pdu, &mut c, resolver, &me);
                             } else if old_c_state == TcpState::Established
                                 && old_s_state == TcpState::Listen {
-                                // should be the first payload packet from client
-                                let syn = packet_allocator.get_pdu().unwrap();
-                                select_server(pdu, &mut c, &me, &servers, &f_select_server, syn);
-                                //trace!("after  select_server: p.refcnt = {}, packet_in.refcnt() = {}", pdu.refcnt(), pdu_in.refcnt());
-                                debug!("{} SYN packet to server - L3: {}, L4: {}", thread_id, pdu.headers().ip(1), pdu.headers().tcp(2));
-                                c.s_init();
-                                c.s_push_state(TcpState::SynReceived);
-                                counter_c[TcpStatistics::RecvPayload] += 1;
-                                counter_s[TcpStatistics::SentSyn] += 1;
-                                group_index = 1;
-                                #[cfg(feature = "profiling")]
-                                    time_adders[5].add_diff(_rdtsc() - timestamp_entry);
+                                // should be the first payload packet from client, or the segment completing the buffered payload
+                                let buffering = if payload_buffering.is_some() {
+                                    buffer_payload(pdu, &mut c, payload_buffering.as_ref().unwrap())
+                                } else {
+                                    BufferResult::Ready
+                                };
+                                if buffering == BufferResult::Buffered {
+                                    // the segment is dropped, its payload waits for the next segment
+                                    group_index = 0;
+                                } else {
+                                    // the seqn following the payload, which is sent to the server after the SYN-ACK
+                                    let next_c2s = pdu.headers().tcp(2).seq_num().wrapping_add(tcp_payload_size(pdu) as u32);
+                                    let syn = packet_allocator.get_pdu().unwrap();
+                                    let selected = buffering == BufferResult::Ready && select_server(pdu, &mut c, &me, &servers, &f_select_server, &mut policy_selector, &sni_map, &alpn_map, &http_router, &proxy_protocols, engine_mss, &target_mss, &affinity, &server_load, syn);
+                                    if latencies.is_some() && buffering == BufferResult::Ready {
+                                        let latencies = latencies.as_mut().unwrap();
+                                        let nanos = latencies.nanos(unsafe { _rdtsc() }.wrapping_sub(entry_tsc));
+                                        latencies.binding.record(nanos);
+                                    }
+                                    if selected {
+                                        c.trace_server(&servers[c.server_index()].server_id);
+                                        //trace!("after  select_server: p.refcnt = {}, packet_in.refcnt() = {}", pdu.refcnt(), pdu_in.refcnt());
+                                        debug!("{} SYN packet to server - L3: {}, L4: {}", thread_id, pdu.headers().ip(1), pdu.headers().tcp(2));
+                                        c.s_init();
+                                        c.s_push_state(TcpState::SynReceived);
+                                        if syn_retry.is_some() {
+                                            // the timer fires, when the server does not answer the SYN in time
+                                            wheel.cancel(&c.timer);
+                                            c.timer = wheel.schedule(&syn_timeout, c.slot());
+                                        }
+                                        if reorder.is_some() {
+                                            let mut buffers = ReorderBuffers::new();
+                                            buffers.c2s.start(next_c2s);
+                                            c.reorder = Some(Box::new(buffers));
+                                        }
+                                        if events.is_some() {
+                                            events.as_ref().unwrap().opened(&c);
+                                        }
+                                        counter_c[TcpStatistics::RecvPayload] += 1;
+                                        counter_s[TcpStatistics::SentSyn] += 1;
+                                    } else {
+                                        if buffering == BufferResult::Overflow {
+                                            debug!("{} buffered payload does not fit into the segment, resetting client connection", thread_id);
+                                        } else {
+                                            debug!("{} server {} at capacity, resetting client connection", thread_id, c.server_index());
+                                        }
+                                        client_reset(pdu, &mut c);
+                                        c.set_close_reason(CloseReason::SelectionFailure);
+                                        c.c_push_state(TcpState::Closed);
+                                        c.set_release_cause(ReleaseCause::PassiveRst);
+                                        release_connection = Some(c.slot());
+                                    }
+                                    group_index = 1;
+                                    #[cfg(feature = "profiling")]
+                                        time_adders[5].add_diff(_rdtsc() - timestamp_entry);
+                                }
                             } else if old_s_state < TcpState::SynReceived || old_c_state < TcpState::Established {
                                 warn!(
                                     "{} unexpected client-side TCP packet on port {}/{} in client/server state {:?}/{:?}, sending to KNI i/f",
@@ -685,15 +2251,45 @@ pub fn setup_delayed_proxy<F1, F2>(
                                 trace! {"c2s: nothing to do?, tcp= {}, tcp_payload_size={}, expected ackn_for_fin ={}", tcp, tcp_payload_size(pdu), unsafe { c.seqn.ack_for_fin_p2c }};
                             }
 
+                            if state_audit && report_violation(pdu, &mut c, &thread_id) {
+                                state_violation = true;
+                            }
+
                             if c.client_state() == TcpState::Closed && c.server_state() == TcpState::Closed {
-                                release_connection = Some(c.port());
+                                release_connection = Some(c.slot());
                             }
 
                             // once we established a two-way e2e-connection, we always forward the packets
                             if old_s_state >= TcpState::Established && old_s_state < TcpState::Closed
                                 && old_c_state >= TcpState::Established {
-                                client_to_server(pdu, &mut c, &me, &servers, &f_process_payload_c_s);
-                                group_index = 1;
+                                let order = if c.reorder.is_some() {
+                                    c.reorder.as_mut().unwrap().c2s.receive(pdu, reorder.as_ref().unwrap())
+                                } else {
+                                    SegmentOrder::InOrder
+                                };
+                                if order == SegmentOrder::InOrder {
+                                    client_to_server(pdu, &mut c, &me, &servers, &f_process_payload_c_s, !fast);
+                                    group_index = 1;
+                                    let released = if c.reorder.is_some() {
+                                        c.reorder.as_mut().unwrap().c2s.release_in_order()
+                                    } else {
+                                        Vec::new()
+                                    };
+                                    if !released.is_empty() {
+                                        // the segment and the released ones are sent via the extra queue, to keep them in order
+                                        tag_towards_destination(pdu, &vlans, &servers);
+                                        producer.enqueue_one(pdu.clone());
+                                        group_index = 0;
+                                        for mut p in released {
+                                            client_to_server(&mut p, &mut c, &me, &servers, &f_process_payload_c_s, !fast);
+                                            tag_towards_destination(&mut p, &vlans, &servers);
+                                            producer.enqueue_one_boxed(p);
+                                        }
+                                    }
+                                } else {
+                                    // held back until the gap is filled, or dropped
+                                    group_index = 0;
+                                }
                                 #[cfg(feature = "profiling")]
                                     time_adders[6].add_diff(_rdtsc() - timestamp_entry);
                             }
@@ -702,22 +2298,34 @@ pub fn setup_delayed_proxy<F1, F2>(
                         // server to client
                         {
                             //debug!("looking up state for server side port { }", tcp.dst_port());
-                            let mut c = cm.get_mut_by_port(tcp.dst_port());
+                            let mut c = cm.get_mut_by_source(pdu.headers().ip(1).dst(), tcp.dst_port());
                             #[cfg(feature = "profiling")]
                                 time_adders[1].add_diff(_rdtsc() - timestamp_entry);
 
                             if c.is_some() {
                                 let mut c = c.as_mut().unwrap();
+                                c.packets[1] += 1;
+                                c.wire_bytes[1] += pdu.data_len() as u64;
                                 let mut b_unexpected = false;
                                 let old_s_state = c.server_state();
                                 let old_c_state = c.client_state();
+                                if idle_timeouts.is_some() || keepalive.is_some() {
+                                    c.last_activity = unsafe { _rdtsc() };
+                                }
+                                let fast = splice && c.spliced() && !tcp.syn_flag() && !tcp.fin_flag() && !tcp.rst_flag();
 
-                                if tcp.ack_flag() && tcp.syn_flag() {
+                                if fast {
+                                    // spliced fast path: the packet is translated below, without passing the state machine
+                                } else if tcp.ack_flag() && tcp.syn_flag() {
                                     counter_s[TcpStatistics::RecvSynAck] += 1;
                                     if old_s_state == TcpState::SynReceived {
                                         c.s_push_state(TcpState::Established);
                                         debug!("{} established two-way client server connection, SYN-ACK received: L3: {}, L4: {}", thread_id, pdu.headers().ip(1), tcp);
-                                        server_synack_received(pdu, &mut c, &mut producer);
+                                        server_synack_received(pdu, &mut c, &servers, &vlans, &mut producer);
+                                        if c.reorder.is_some() {
+                                            c.reorder.as_mut().unwrap().s2c.start(tcp.seq_num().wrapping_add(1));
+                                        }
+                                        policy_selector.record_syn_ack(c.server_index(), c.server_rtt.unwrap());
                                         counter_s[TcpStatistics::SentSynAck2] += 1;
                                         counter_s[TcpStatistics::SentPayload] += 1;
                                         group_index = 0; // delayed payload packets are sent via extra queue
@@ -728,6 +2336,7 @@ pub fn setup_delayed_proxy<F1, F2>(
                                     #[cfg(feature = "profiling")]
                                         time_adders[3].add_diff(_rdtsc() - timestamp_entry);
                                 } else if tcp.fin_flag() {
+                                    c.set_close_reason(CloseReason::ServerFin);
                                     if old_c_state >= TcpState::FinWait1 {
                                         if tcp.ack_flag() && tcp.ack_num() == c.seqn_fin_p2s.wrapping_add(1) {
                                             counter_s[TcpStatistics::RecvFinPssv] += 1;
@@ -786,12 +2395,31 @@ pub fn setup_delayed_proxy<F1, F2>(
                                         trace!("{} on proxy port {} transition to client/server state {:?}/{:?}", thread_id, c.port(), c.c_states(), c.s_states());
                                     }
                                 } else {
+                                    if tcp.rst_flag() {
+                                        policy_selector.record_failure(c.server_index());
+                                        if old_s_state == TcpState::SynReceived
+                                            && syn_retry.is_some()
+                                            && c.syn_retries() < syn_retry.as_ref().unwrap().attempts()
+                                            && retry_syn(&mut c, &me, &servers, &vlans, &mut policy_selector, &proxy_protocols, engine_mss, &target_mss, &server_load, &mut packet_allocator, &mut producer) {
+                                            // the RST is dropped, the client waits for the SYN-ACK of the next server
+                                            debug!("{} server reset the SYN on port {}, SYN sent to server {}", thread_id, c.port(), c.server_index());
+                                            syn_retried = true;
+                                        } else {
+                                            // before the connection is established, the RST is not forwarded but sent to KNI as unexpected below,
+                                            // the connection is released by its timeout
+                                            c.set_close_reason(CloseReason::ServerRst);
+                                        }
+                                    }
                                     // debug!("received from server { } in c/s state {:?}/{:?} ", tcp, c.con_rec.c_state, c.con_rec.s_state);
-                                    b_unexpected = true; //  may still be revised, see below
+                                    b_unexpected = !syn_retried; //  may still be revised, see below
+                                }
+
+                                if state_audit && report_violation(pdu, &mut c, &thread_id) {
+                                    state_violation = true;
                                 }
 
                                 if c.client_state() == TcpState::Closed && c.server_state() == TcpState::Closed {
-                                    release_connection = Some(c.port());
+                                    release_connection = Some(c.slot());
                                 }
 
                                 // once we established a two-way e-2-e connection, we always forward server side packets
@@ -799,8 +2427,39 @@ pub fn setup_delayed_proxy<F1, F2>(
                                     && old_c_state >= TcpState::Established
                                     && old_c_state < TcpState::Closed {
                                     // translate packets and forward to client
-                                    server_to_client(pdu, &mut c, &me);
-                                    group_index = 1;
+                                    if c.s2c_bytes == 0 && c.server_rtt.is_some() && tcp_payload_size(pdu) > 0 {
+                                        // first payload of the server, the first client payload was forwarded with the SYN-ACK
+                                        let forwarded = c.syn_sent.wrapping_add(c.server_rtt.unwrap());
+                                        policy_selector.record_first_byte(c.server_index(), unsafe { _rdtsc() }.wrapping_sub(forwarded));
+                                    }
+                                    let order = if c.reorder.is_some() {
+                                        c.reorder.as_mut().unwrap().s2c.receive(pdu, reorder.as_ref().unwrap())
+                                    } else {
+                                        SegmentOrder::InOrder
+                                    };
+                                    if order == SegmentOrder::InOrder {
+                                        server_to_client(pdu, &mut c, &me, &f_process_payload_s_c, !fast);
+                                        group_index = 1;
+                                        let released = if c.reorder.is_some() {
+                                            c.reorder.as_mut().unwrap().s2c.release_in_order()
+                                        } else {
+                                            Vec::new()
+                                        };
+                                        if !released.is_empty() {
+                                            // the segment and the released ones are sent via the extra queue, to keep them in order
+                                            tag_towards_destination(pdu, &vlans, &servers);
+                                            producer.enqueue_one(pdu.clone());
+                                            group_index = 0;
+                                            for mut p in released {
+                                                server_to_client(&mut p, &mut c, &me, &f_process_payload_s_c, !fast);
+                                                tag_towards_destination(&mut p, &vlans, &servers);
+                                                producer.enqueue_one_boxed(p);
+                                            }
+                                        }
+                                    } else {
+                                        // held back until the gap is filled, or dropped
+                                        group_index = 0;
+                                    }
                                     b_unexpected = false;
                                     #[cfg(feature = "profiling")]
                                         time_adders[7].add_diff(_rdtsc() - timestamp_entry);
@@ -828,35 +2487,176 @@ pub fn setup_delayed_proxy<F1, F2>(
             }
             // here we check if we shall release the connection state,
             // required because of borrow checker for the state manager sm
-            if let Some(sport) = release_connection {
-                trace!("releasing connection on port {}", sport);
-                cm.release_port(sport, &mut wheel);
+            if let Some(slot) = release_connection {
+                trace!("releasing connection on port {}", slot as u16);
+                cm.release_slot(slot, &mut wheel);
+            }
+            if state_violation {
+                cm.counters_mut().state_violations += 1;
+            }
+            if syn_retried {
+                cm.counters_mut().syn_retries += 1;
+            }
+            if group_index == 1 {
+                tag_towards_destination(pdu, &vlans, &servers);
+                if capture.is_some() {
+                    capture.as_ref().unwrap().packet(pdu);
+                }
+                if pacer.is_some() && !pacer.as_mut().unwrap().admit() {
+                    // a copy is sent on one of the next ticks, the original gets discarded
+                    if pacer.as_mut().unwrap().hold(pdu) {
+                        cm.counters_mut().tx_packets += 1;
+                        cm.counters_mut().paced_packets += 1;
+                        return 0;
+                    }
+                    cm.counters_mut().pacing_drops += 1;
+                    group_index = 0;
+                }
+            }
+            if group_index == 1 && !b_private_etype && (mtu_towards_clients.is_some() || mtu_towards_targets.is_some()) {
+                let dst = pdu.headers().ip(1).dst();
+                let mtu = if servers.iter().any(|s| s.ip == dst) { mtu_towards_targets } else { mtu_towards_clients };
+                let fragments = mtu.and_then(|mtu| fragments(pdu.get_payload(0), mtu));
+                if fragments.is_some() {
+                    for fragment in fragments.unwrap() {
+                        match fragment_pdu(&mut packet_allocator, pdu.headers().mac(0), &fragment) {
+                            Some(mut p) => {
+                                tag_towards_destination(&mut p, &vlans, &servers);
+                                producer.enqueue_one(p);
+                            }
+                            None => warn!("{}: no mbuf for a fragment", pipeline_id_clone),
+                        }
+                    }
+                    cm.counters_mut().fragmented_datagrams += 1;
+                    return 0;
+                }
+            }
+            if group_index == 1 && latencies.is_some() {
+                let latencies = latencies.as_mut().unwrap();
+                let nanos = latencies.nanos(unsafe { _rdtsc() }.wrapping_sub(entry_tsc));
+                latencies.forwarding.record(nanos);
+            }
+            #[cfg(feature = "alloc_audit")]
+            {
+                // SYN, FIN and RST may allocate, e.g. the state of a connection or the record of its release
+                if group_index == 1 && !b_private_etype && thread_allocations() != entry_allocations {
+                    let tcp = pdu.headers().tcp(2);
+                    if !tcp.syn_flag() && !tcp.fin_flag() && !tcp.rst_flag() {
+                        cm.counters_mut().allocating_packets += 1;
+                        debug_assert!(false, "{}: forwarded packet allocated heap memory", pipeline_id_clone);
+                    }
+                }
+            }
+            if group_index == 1 {
+                group_index = me.tx_group(pdu);
+            }
+            if !b_private_etype {
+                match group_index {
+                    1 | 4 => cm.counters_mut().tx_packets += 1,
+                    2 => cm.counters_mut().kni_packets += 1,
+                    _ => cm.counters_mut().dropped_packets += 1,
+                }
             }
             group_index
         };
 
     let mut l4groups = l2_input_stream.group_by(
-        3,
+        5,
         delayed_binding_closure,
         sched,
         "L4-Groups".to_string(),
         uuid_l4groupby,
     );
 
-    let pipe2kni = l4groups.get_group(2).unwrap().send(kni.clone());
+    if kni.is_some() {
+        let pipe2kni = l4groups.get_group(2).unwrap().send(kni.clone().unwrap());
+        let uuid_pipe2kni = tasks::install_task(sched, "Pipe2Kni", pipe2kni);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_pipe2kni, TaskType::Pipe2Kni))
+            .unwrap();
+    } else {
+        // without KNI group 2 is dropped
+        let kni_drop = l4groups.get_group(2).unwrap().drop().send(pci.clone());
+        let uuid_kni_drop = tasks::install_task(sched, "KniDrop", kni_drop);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_kni_drop, TaskType::Pipe2Kni))
+            .unwrap();
+    }
+    if server_port.is_some() {
+        let pipe2server_port = l4groups.get_group(4).unwrap().send(server_port.clone().unwrap());
+        let uuid_pipe2server_port = tasks::install_task(sched, "Pipe2ServerPort", pipe2server_port);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_pipe2server_port, TaskType::Pipe2Pci))
+            .unwrap();
+    } else {
+        // without inline mode group 4 is never selected
+        let server_port_drop = l4groups.get_group(4).unwrap().drop().send(pci.clone());
+        let uuid_server_port_drop = tasks::install_task(sched, "ServerPortDrop", server_port_drop);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_server_port_drop, TaskType::Pipe2Pci))
+            .unwrap();
+    }
     let l4pciflow = l4groups.get_group(1).unwrap();
     let l4dumpflow = l4groups.get_group(0).unwrap().drop();
-    let pipe2pci = merge_auto(vec![box l4pciflow, box l4dumpflow], SchedulingPolicy::LongestQueue).send(pci.clone());
-
-    let uuid_pipe2kni = tasks::install_task(sched, "Pipe2Kni", pipe2kni);
-    tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_pipe2kni, TaskType::Pipe2Kni))
-        .unwrap();
 
-    let uuid_pipe2pic = tasks::install_task(sched, "Pipe2Pci", pipe2pci);
-    tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_pipe2pic, TaskType::Pipe2Pci))
-        .unwrap();
+    if replayer.is_some() {
+        // packets towards the NIC, including those of the bypass queue, are compared with the pcap and dropped
+        let replayer = replayer.unwrap();
+        let replay_closure = box move |pdu: &mut Pdu| {
+            replayer.lock().unwrap().sent(&frame_bytes(pdu));
+            0
+        };
+        let mut replay_groups = merge_auto(vec![box l4pciflow, box consumer], SchedulingPolicy::LongestQueue).group_by(
+            1,
+            replay_closure,
+            sched,
+            "Replay-Groups".to_string(),
+            Uuid::new_v4(),
+        );
+        let replay_dumpflow = replay_groups.get_group(0).unwrap().drop();
+        let pipe2pci = merge_auto(vec![box replay_dumpflow, box l4dumpflow], SchedulingPolicy::LongestQueue).send(pci.clone());
+        let uuid_pipe2pic = tasks::install_task(sched, "Pipe2Pci", pipe2pci);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_pipe2pic, TaskType::Pipe2Pci))
+            .unwrap();
+    } else {
+        let pipe2pci = merge_auto(vec![box l4pciflow, box l4dumpflow], SchedulingPolicy::LongestQueue).send(pci.clone());
+        let uuid_pipe2pic = tasks::install_task(sched, "Pipe2Pci", pipe2pci);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_pipe2pic, TaskType::Pipe2Pci))
+            .unwrap();
+
+        if server_port.is_some() {
+            // the segments generated by the pipeline towards the servers leave through the server port
+            let mac_s = me_clone2.mac_s;
+            let bypass_closure = box move |pdu: &mut Pdu| if pdu.headers().mac(0).src == mac_s { 1 } else { 0 };
+            let mut bypass_groups = consumer.group_by(2, bypass_closure, sched, "Bypass-Groups".to_string(), Uuid::new_v4());
+            let uuid_consumer = tasks::install_task(sched, "BypassPipe", bypass_groups.get_group(0).unwrap().send(pci.clone()));
+            tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_consumer, TaskType::BypassPipe))
+                .unwrap();
+            let bypass2server_port = bypass_groups.get_group(1).unwrap().send(server_port.clone().unwrap());
+            let uuid_bypass2server_port = tasks::install_task(sched, "BypassServerPort", bypass2server_port);
+            tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_bypass2server_port, TaskType::BypassPipe))
+                .unwrap();
+        } else {
+            let uuid_consumer = tasks::install_task(sched, "BypassPipe", consumer.send(pci.clone()));
+            tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_consumer, TaskType::BypassPipe))
+                .unwrap();
+        }
+    }
 
-    let uuid_consumer = tasks::install_task(sched, "BypassPipe", consumer.send(pci.clone()));
-    tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_consumer, TaskType::BypassPipe))
-        .unwrap();
+    let udp_stream = l4groups.get_group(3).unwrap();
+    if udp_port.is_some() {
+        setup_udp_proxy(
+            core,
+            udp_stream,
+            pci.clone(),
+            kni.clone(),
+            sched,
+            run_configuration.clone(),
+            shared_clone,
+            me_clone2.l234,
+            me_clone2.ip_s,
+            tcp_min_port,
+        );
+    } else {
+        // without udp configuration group 3 is never selected
+        let uuid_udp_drop = tasks::install_task(sched, "UdpDrop", udp_stream.drop().send(pci.clone()));
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_udp_drop, TaskType::Pipe2Pci))
+            .unwrap();
+    }
 }
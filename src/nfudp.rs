@@ -0,0 +1,447 @@
+use e2d2::operators::{Batch, merge_auto, SchedulingPolicy};
+use e2d2::scheduler::StandaloneScheduler;
+use e2d2::allocators::CacheAligned;
+use e2d2::interface::*;
+
+use std::collections::{VecDeque, BTreeMap};
+use std::net::Ipv4Addr;
+use std::arch::x86_64::_rdtsc;
+
+use uuid::Uuid;
+use eui48::MacAddress;
+
+use netfcts::timer_wheel::TimerWheel;
+use netfcts::tasks;
+use netfcts::utils::shuffle_ports;
+use netfcts::RunConfiguration;
+use netfcts::recstore::Store64;
+
+use cmanager::ProxyConnection;
+use ipv6::v4_to_key;
+use selection::{PolicySelector, ServerLoad};
+use {PipelineId, MessageFrom, TaskType, SharedState};
+use ::{Configuration, Extension};
+
+const TIMER_WHEEL_RESOLUTION_MS: u64 = 100;
+const TIMER_WHEEL_SLOTS: usize = 1002;
+const TIMER_WHEEL_SLOT_CAPACITY: usize = 2500;
+/// default idle timeout of udp flows in milli-seconds
+pub const DEFAULT_UDP_IDLE_TIMEOUT: u64 = 30000;
+
+/// (src ip, src port, dst ip, dst port, protocol) of the client side of a udp flow
+pub type FiveTuple = (u32, u16, u32, u16, u8);
+
+pub struct UdpFlow {
+    pub client_mac: MacAddress,
+    /// client side 5-tuple of the flow
+    pub tuple: FiveTuple,
+    /// server side port of the proxy assigned to this flow, 0 if flow is not in use
+    proxy_port: u16,
+    server_index: usize,
+    /// tsc of the last packet seen in either direction
+    last_seen: u64,
+    pub c2s_packets: u64,
+    pub s2c_packets: u64,
+    /// datagrams to the proxy port of the flow, which were not sent by its target, they are discarded
+    pub s2c_drops: u64,
+}
+
+impl UdpFlow {
+    fn new() -> UdpFlow {
+        UdpFlow {
+            client_mac: MacAddress::default(),
+            tuple: (0, 0, 0, 0, 17),
+            proxy_port: 0,
+            server_index: 0,
+            last_seen: 0,
+            c2s_packets: 0,
+            s2c_packets: 0,
+            s2c_drops: 0,
+        }
+    }
+
+    #[inline]
+    fn initialize(&mut self, tuple: &FiveTuple, proxy_port: u16, server_index: usize, now: u64) {
+        self.tuple = *tuple;
+        self.proxy_port = proxy_port;
+        self.server_index = server_index;
+        self.last_seen = now;
+        self.c2s_packets = 0;
+        self.s2c_packets = 0;
+        self.s2c_drops = 0;
+    }
+
+    #[inline]
+    fn in_use(&self) -> bool {
+        self.proxy_port != 0
+    }
+
+    #[inline]
+    pub fn port(&self) -> u16 {
+        self.proxy_port
+    }
+
+    #[inline]
+    pub fn server_index(&self) -> usize {
+        self.server_index
+    }
+}
+
+/// Tracks udp flows of one pipeline. Client side flows are keyed on their 5-tuple,
+/// server side packets are mapped to flows by the destination port, which is a proxy port owned by this pipeline.
+pub struct UdpFlowManager {
+    tuple2port: BTreeMap<FiveTuple, u16>,
+    free_ports: VecDeque<u16>,
+    port2flow: Vec<UdpFlow>,
+    port_base: u16,
+    /// the flows count as connections of their target, see PolicySelector
+    load: ServerLoad,
+    idle_timeout: u64,
+    wheel: TimerWheel<u16>,
+}
+
+impl UdpFlowManager {
+    pub fn new(pci: &PortQueue, port_base: u16, load: ServerLoad, idle_timeout: u64, cpu_clock: u64) -> UdpFlowManager {
+        let port_mask = pci.port.get_tcp_dst_port_mask();
+        let max_port = port_base + !port_mask;
+        let mut fm = UdpFlowManager {
+            tuple2port: BTreeMap::new(),
+            free_ports: VecDeque::<u16>::from(shuffle_ports(if port_base == 0 { 1 } else { port_base }, max_port - 1)),
+            port2flow: Vec::with_capacity(!port_mask as usize + 1),
+            port_base,
+            load,
+            idle_timeout,
+            wheel: TimerWheel::new(
+                TIMER_WHEEL_SLOTS,
+                cpu_clock * TIMER_WHEEL_RESOLUTION_MS / 1000,
+                TIMER_WHEEL_SLOT_CAPACITY,
+            ),
+        };
+        if fm.idle_timeout > fm.wheel.get_max_timeout_cycles() {
+            warn!(
+                "udp idle timeout overflows timer wheel: reset to {} millis",
+                fm.wheel.get_max_timeout_cycles() * 1000 / cpu_clock
+            );
+            fm.idle_timeout = fm.wheel.get_max_timeout_cycles();
+        }
+        fm.port2flow = (0..!port_mask as usize + 1).map(|_| UdpFlow::new()).collect();
+        // need to add last port this way to avoid overflow with slice, when max_port == 65535
+        fm.free_ports.push_back(max_port);
+        fm
+    }
+
+    #[inline]
+    fn owns_port(&self, port: u16) -> bool {
+        port >= self.port_base && ((port - self.port_base) as usize) < self.port2flow.len()
+    }
+
+    /// the existing flow of the tuple, e.g. while draining, when no new flows are accepted
+    pub fn get_mut(&mut self, tuple: &FiveTuple, now: u64) -> Option<&mut UdpFlow> {
+        match self.tuple2port.get(tuple) {
+            Some(port) => {
+                let f = &mut self.port2flow[(port - self.port_base) as usize];
+                f.last_seen = now;
+                Some(f)
+            }
+            None => None,
+        }
+    }
+
+    /// a new flow is bound to the target returned by select
+    pub fn get_mut_or_insert<F>(&mut self, tuple: &FiveTuple, now: u64, select: F) -> Option<&mut UdpFlow>
+    where
+        F: FnOnce() -> usize,
+    {
+        {
+            let port = self.tuple2port.get(tuple);
+            if port.is_some() {
+                let f = &mut self.port2flow[(port.unwrap() - self.port_base) as usize];
+                f.last_seen = now;
+                return Some(f);
+            }
+        }
+        let opt_port = self.free_ports.pop_front();
+        if opt_port.is_some() {
+            let port = opt_port.unwrap();
+            let server_index = select();
+            self.load.inc(server_index);
+            self.wheel.schedule(&self.idle_timeout, port);
+            self.tuple2port.insert(*tuple, port);
+            let f = &mut self.port2flow[(port - self.port_base) as usize];
+            f.initialize(tuple, port, server_index, now);
+            debug!(
+                "udp flow for ({}, {}) created on proxy port {}",
+                Ipv4Addr::from(tuple.0),
+                tuple.1,
+                port
+            );
+            Some(f)
+        } else {
+            warn!("udp: out of ports");
+            None
+        }
+    }
+
+    /// the flow on the proxy port, the caller refreshes it after checking the source of the packet
+    pub fn get_mut_by_port(&mut self, port: u16) -> Option<&mut UdpFlow> {
+        if self.owns_port(port) {
+            let f = &mut self.port2flow[(port - self.port_base) as usize];
+            if f.in_use() {
+                Some(f)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    /// releases flows which have been idle longer than the idle timeout,
+    /// flows with recent activity are re-scheduled for their remaining idle time
+    pub fn release_timeouts(&mut self, now: &u64) {
+        let mut expired = Vec::new();
+        loop {
+            match self.wheel.tick(now) {
+                (Some(mut drain), more) => {
+                    let mut port = drain.next();
+                    while port.is_some() {
+                        let p = port.unwrap();
+                        if p != 0 {
+                            expired.push(p);
+                        }
+                        port = drain.next();
+                    }
+                    if !more {
+                        break;
+                    }
+                }
+                (None, more) => {
+                    if !more {
+                        break;
+                    }
+                }
+            }
+        }
+        for p in expired {
+            if !self.port2flow[(p - self.port_base) as usize].in_use() {
+                continue;
+            }
+            let idle = now - self.port2flow[(p - self.port_base) as usize].last_seen;
+            if idle >= self.idle_timeout {
+                self.release_port(p);
+            } else {
+                self.wheel.schedule(&(self.idle_timeout - idle), p);
+            }
+        }
+    }
+
+    fn release_port(&mut self, port: u16) {
+        let f = &mut self.port2flow[(port - self.port_base) as usize];
+        if f.in_use() {
+            debug!(
+                "releasing idle udp flow ({}, {}) on proxy port {}, packets c2s/s2c = {}/{}, discarded {}",
+                Ipv4Addr::from(f.tuple.0),
+                f.tuple.1,
+                port,
+                f.c2s_packets,
+                f.s2c_packets,
+                f.s2c_drops
+            );
+            f.proxy_port = 0;
+            self.load.dec(f.server_index);
+            self.free_ports.push_back(port);
+            self.tuple2port.remove(&f.tuple);
+        }
+    }
+
+    pub fn active_flows(&self) -> usize {
+        self.tuple2port.len()
+    }
+}
+
+#[inline]
+fn prepare_udp_checksum_and_ttl(p: &mut Pdu) {
+    let h = p.headers_mut();
+    {
+        let ip = h.ip_mut(1);
+        let ttl = ip.ttl();
+        ip.set_ttl(ttl - 1);
+        ip.update_checksum();
+    }
+    // udp checksum is optional for IPv4
+    h.udp_mut(2).set_checksum(0);
+}
+
+/// This function sets up the network function graph for udp flows, which are split off by the tcp pipeline
+/// (see setup_delayed_proxy). Udp flows are tracked by their client side 5-tuple and are released after an idle timeout.
+/// Targets are selected by the selection policy of the engine when the first packet of a flow is received, as for tcp
+/// connections. While the engine drains, no new flows are accepted.
+pub fn setup_udp_proxy<B>(
+    core: i32,
+    udp_stream: B,
+    pci: CacheAligned<PortQueueTxBuffered>,
+    kni: Option<CacheAligned<PortQueue>>,
+    sched: &mut StandaloneScheduler,
+    run_configuration: RunConfiguration<Configuration, Store64<Extension>>,
+    shared: SharedState,
+    me_l234: L234Data,
+    ip_s: u32,
+    port_base: u16,
+) where
+    B: Batch + 'static,
+{
+    let engine_config = &run_configuration.engine_configuration.engine;
+    let udp_config = engine_config.udp.as_ref().unwrap();
+    let cpu_clock = run_configuration.system_data.cpu_clock;
+    let pipeline_id = PipelineId {
+        core: core as u16,
+        port_id: pci.port_queue.port_id() as u16,
+        rxq: pci.port_queue.rxq(),
+    };
+    let tx = run_configuration.remote_sender.clone();
+    let idle_timeout = udp_config.idle_timeout.unwrap_or(DEFAULT_UDP_IDLE_TIMEOUT) * cpu_clock / 1000;
+    let server_load = ServerLoad::new(shared.connections.clone());
+    // udp flows arrive on udp.port, which proxies to all targets
+    let mut policy_selector = PolicySelector::new(
+        engine_config.selection.unwrap_or_default(),
+        &shared.targets.targets(),
+        server_load.clone(),
+        shared.target_health.clone(),
+        shared.groups.clone(),
+        shared.breakers.clone(),
+        engine_config.max_connections_per_target.map(|m| m as usize),
+        engine_config.consistent_hash.clone(),
+        vec![None],
+    );
+    let mut servers = shared.targets.l234data();
+    let mut targets_version = shared.targets.version();
+    let mut fm = UdpFlowManager::new(&pci.port_queue, port_base, server_load, idle_timeout, cpu_clock);
+    let udp_port = udp_config.port;
+    let thread_id = format!("<c{}, rx{}>: ", core, pci.port_queue.rxq());
+    let check_interval = cpu_clock * TIMER_WHEEL_RESOLUTION_MS / 1000;
+    let mut last_check = unsafe { _rdtsc() };
+
+    info!(
+        "{} udp proxy on port {}, idle timeout {} cycles, active flows {}",
+        thread_id,
+        udp_port,
+        idle_timeout,
+        fm.active_flows(),
+    );
+
+    // group 0 -> dump packets
+    // group 1 -> send to PCI
+    // group 2 -> send to KNI
+    let udp_closure = box move |pdu: &mut Pdu| {
+        let now = unsafe { _rdtsc() };
+        if now - last_check > check_interval {
+            fm.release_timeouts(&now);
+            last_check = now;
+            if shared.targets.version() != targets_version {
+                // configuration was reloaded, new flows use the new targets
+                targets_version = shared.targets.version();
+                servers = shared.targets.l234data();
+                policy_selector.update_targets(&shared.targets.targets());
+            }
+        }
+
+        let src_ip = pdu.headers().ip(1).src();
+        let dst_ip = pdu.headers().ip(1).dst();
+        let (src_port, dst_port) = {
+            let udp = pdu.headers().udp(2);
+            (udp.src_port(), udp.dst_port())
+        };
+
+        if dst_port == udp_port && dst_ip == me_l234.ip {
+            // client to server
+            let client_mac = pdu.headers().mac(0).src;
+            let tuple = (src_ip, src_port, dst_ip, dst_port, 17u8);
+            let selector = &mut policy_selector;
+            let flow = if shared.drain.is_draining() {
+                fm.get_mut(&tuple, now)
+            } else {
+                fm.get_mut_or_insert(&tuple, now, || selector.select(&ProxyConnection::for_selection((v4_to_key(src_ip), src_port))))
+            };
+            match flow {
+                Some(f) => {
+                    f.client_mac = client_mac;
+                    f.c2s_packets += 1;
+                    let server = &servers[f.server_index()];
+                    {
+                        let h = pdu.headers_mut();
+                        h.mac_mut(0).set_dmac(&server.mac);
+                        h.mac_mut(0).set_smac(&me_l234.mac);
+                        h.ip_mut(1).set_dst(server.ip);
+                        h.ip_mut(1).set_src(ip_s);
+                        let udp = h.udp_mut(2);
+                        udp.set_src_port(f.port());
+                        udp.set_dst_port(server.port);
+                    }
+                    prepare_udp_checksum_and_ttl(pdu);
+                    1
+                }
+                None => 0,
+            }
+        } else if dst_ip == ip_s && dst_port >= port_base {
+            // server to client
+            match fm.get_mut_by_port(dst_port) {
+                Some(f) => {
+                    let server = &servers[f.server_index()];
+                    if server.ip != src_ip || server.port != src_port {
+                        // only the target of the flow may send to its proxy port, the flow is not refreshed
+                        f.s2c_drops += 1;
+                        debug!(
+                            "{} udp packet from ({}, {}) to proxy port {} is not from the target of the flow, discarding",
+                            thread_id,
+                            Ipv4Addr::from(src_ip),
+                            src_port,
+                            dst_port
+                        );
+                        0
+                    } else {
+                        f.last_seen = now;
+                        f.s2c_packets += 1;
+                        {
+                            let h = pdu.headers_mut();
+                            h.mac_mut(0).set_dmac(&f.client_mac);
+                            h.mac_mut(0).set_smac(&me_l234.mac);
+                            h.ip_mut(1).set_dst(f.tuple.0);
+                            h.ip_mut(1).set_src(f.tuple.2);
+                            let udp = h.udp_mut(2);
+                            udp.set_src_port(f.tuple.3);
+                            udp.set_dst_port(f.tuple.1);
+                        }
+                        prepare_udp_checksum_and_ttl(pdu);
+                        1
+                    }
+                }
+                None => {
+                    debug!("{} unexpected server side udp packet: no flow on port {}, discarding", thread_id, dst_port);
+                    0
+                }
+            }
+        } else {
+            2
+        }
+    };
+
+    let mut udp_groups = udp_stream.group_by(3, udp_closure, sched, "UDP-Groups".to_string(), Uuid::new_v4());
+    if kni.is_some() {
+        let udp2kni = udp_groups.get_group(2).unwrap().send(kni.unwrap());
+        let uuid_udp2kni = tasks::install_task(sched, "Udp2Kni", udp2kni);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_udp2kni, TaskType::Pipe2Kni))
+            .unwrap();
+    } else {
+        let udp_kni_drop = udp_groups.get_group(2).unwrap().drop().send(pci.clone());
+        let uuid_udp_kni_drop = tasks::install_task(sched, "UdpKniDrop", udp_kni_drop);
+        tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_udp_kni_drop, TaskType::Pipe2Kni))
+            .unwrap();
+    }
+    let udp2pci_flow = udp_groups.get_group(1).unwrap();
+    let udp_dumpflow = udp_groups.get_group(0).unwrap().drop();
+    let udp2pci = merge_auto(vec![box udp2pci_flow, box udp_dumpflow], SchedulingPolicy::LongestQueue).send(pci.clone());
+
+    let uuid_udp2pci = tasks::install_task(sched, "Udp2Pci", udp2pci);
+    tx.send(MessageFrom::Task(pipeline_id.clone(), uuid_udp2pci, TaskType::Pipe2Pci))
+        .unwrap();
+}
@@ -0,0 +1,38 @@
+use std::fs;
+
+/// NUMA node of the cpu, from the node<n> link in /sys/devices/system/cpu/cpu<core>
+pub fn node_of_cpu(core: i32) -> Option<u32> {
+    let entries = fs::read_dir(format!("/sys/devices/system/cpu/cpu{}", core)).ok()?;
+    for entry in entries {
+        let name = entry.ok()?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("node") {
+            if let Ok(node) = name[4..].parse::<u32>() {
+                return Some(node);
+            }
+        }
+    }
+    None
+}
+
+/// NUMA node of a PCI device, e.g. "0000:03:00.0", None if unknown or if the system has a single node
+pub fn node_of_pci_device(pci_address: &str) -> Option<u32> {
+    let node = fs::read_to_string(format!("/sys/bus/pci/devices/{}/numa_node", pci_address)).ok()?;
+    // -1 means no NUMA affinity
+    node.trim().parse::<i32>().ok().filter(|n| *n >= 0).map(|n| n as u32)
+}
+
+/// Checks that the core is on the NUMA node of the port, whose name is its PCI address.
+/// Returns false, if both nodes are known and differ.
+pub fn core_is_local_to_port(core: i32, port_name: &str) -> bool {
+    match (node_of_cpu(core), node_of_pci_device(port_name)) {
+        (Some(core_node), Some(port_node)) if core_node != port_node => {
+            warn!(
+                "core {} is on NUMA node {}, but port {} is on node {}: packets and connection state cross the interconnect",
+                core, core_node, port_name, port_node
+            );
+            false
+        }
+        _ => true,
+    }
+}
@@ -0,0 +1,77 @@
+use std::path::Path;
+
+/// how the IPv4 and TCP checksums of the transmitted segments are computed
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumMode {
+    /// by the NIC, if the port is capable, otherwise in software, the default
+    Auto,
+    /// by the NIC, falls back to software with a warning, if the port is not capable
+    Hardware,
+    Software,
+}
+
+impl Default for ChecksumMode {
+    fn default() -> ChecksumMode {
+        ChecksumMode::Auto
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct OffloadConfig {
+    pub checksum: Option<ChecksumMode>,
+    /// if true, TCP segmentation of payloads, which grew beyond the MSS by a rewrite, is offloaded to the NIC
+    pub tso: Option<bool>,
+}
+
+/// what the port can offload
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OffloadCapabilities {
+    pub checksum: bool,
+    pub tso: bool,
+}
+
+impl OffloadCapabilities {
+    /// The capabilities of the port with this name. Checksums are offloaded, if the port was initialized with
+    /// checksum offload and it is a PCI device, virtual devices like rings, pcap, af_packet or vhost-user ports do not
+    /// compute checksums. TSO is not available, as the tx path does not set the segment size in the mbufs.
+    pub fn of_port(port_name: &str, csum_offload: bool) -> OffloadCapabilities {
+        let pci_device = Path::new(&format!("/sys/bus/pci/devices/{}", port_name)).exists();
+        OffloadCapabilities {
+            checksum: csum_offload && pci_device,
+            tso: false,
+        }
+    }
+}
+
+/// the offloads used by a pipeline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Offloads {
+    pub checksum: bool,
+    pub tso: bool,
+}
+
+impl Offloads {
+    /// the requested offloads, which the port is capable of
+    pub fn negotiate(config: &Option<OffloadConfig>, capabilities: &OffloadCapabilities, port_name: &str) -> Offloads {
+        let mode = config.as_ref().and_then(|c| c.checksum).unwrap_or_default();
+        let checksum = match mode {
+            ChecksumMode::Auto => capabilities.checksum,
+            ChecksumMode::Hardware => {
+                if !capabilities.checksum {
+                    warn!("port {} is not capable of checksum offload, computing checksums in software", port_name);
+                }
+                capabilities.checksum
+            }
+            ChecksumMode::Software => false,
+        };
+        let tso_requested = config.as_ref().and_then(|c| c.tso).unwrap_or(false);
+        if tso_requested && !capabilities.tso {
+            warn!("port {} is not capable of TSO, rewritten payloads beyond the MSS are sent unsegmented", port_name);
+        }
+        Offloads {
+            checksum,
+            tso: tso_requested && capabilities.tso,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use std::env;
+use std::net::IpAddr;
+
+use eui48::MacAddress;
+
+use Configuration;
+
+/// prefix of the environment variables, which override the configuration, e.g. PROXYENGINE_ENGINE__PORT=999
+pub const ENV_PREFIX: &str = "PROXYENGINE_";
+
+/// Overrides of the configuration from environment variables: the name after ENV_PREFIX is the path,
+/// with "__" separating its parts, e.g. PROXYENGINE_TARGETS__0__IP=192.168.222.3
+pub fn overrides_from_env() -> Vec<(String, String)> {
+    let mut overrides: Vec<(String, String)> = env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .map(|(name, value)| (name[ENV_PREFIX.len()..].to_lowercase().replace("__", "."), value))
+        .collect();
+    overrides.sort();
+    overrides
+}
+
+/// Overrides of the configuration from the command line arguments after "--", which are not seen by the RunTime,
+/// e.g. "-- --set engine.port=999 --set targets.server1.ip=10.0.0.1"
+pub fn overrides_from_args<I: Iterator<Item = String>>(args: I) -> Vec<(String, String)> {
+    let mut overrides = Vec::new();
+    let mut args = args.skip_while(|arg| arg != "--").skip(1);
+    while let Some(arg) = args.next() {
+        let assignment = if arg == "--set" {
+            args.next()
+        } else if arg.starts_with("--set=") {
+            Some(arg["--set=".len()..].to_string())
+        } else if arg == "--check" {
+            // see ProxyEngineBuilder::preflight
+            None
+        } else {
+            warn!("ignoring argument {}", arg);
+            None
+        };
+        if assignment.is_some() {
+            let assignment = assignment.unwrap();
+            match assignment.find('=') {
+                Some(i) => overrides.push((assignment[..i].to_string(), assignment[i + 1..].to_string())),
+                None => warn!("ignoring override {} without value", assignment),
+            }
+        }
+    }
+    overrides
+}
+
+fn parse<T: ::std::str::FromStr>(path: &str, value: &str) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("invalid value {} for {}", value, path))
+}
+
+impl Configuration {
+    /// Overrides a setting, e.g. in a container without regenerating the configuration file.
+    /// Supported paths are engine.port, engine.admin_address, engine.control_socket, engine.max_connections,
+    /// engine.drain_timeout, engine.mss and targets.<index or id>.ip, .host, .port, .mac, .linux_if.
+    /// The netbricks section, e.g. the cores, is evaluated by the RunTime before and is not covered.
+    pub fn apply_override(&mut self, path: &str, value: &str) -> Result<(), String> {
+        let parts: Vec<&str> = path.split('.').collect();
+        match parts.as_slice() {
+            ["engine", "port"] => self.engine.port = parse(path, value)?,
+            ["engine", "admin_address"] => self.engine.admin_address = Some(value.to_string()),
+            ["engine", "control_socket"] => self.engine.control_socket = Some(value.to_string()),
+            ["engine", "max_connections"] => self.engine.max_connections = Some(parse(path, value)?),
+            ["engine", "drain_timeout"] => self.engine.drain_timeout = Some(parse(path, value)?),
+            ["engine", "mss"] => self.engine.mss = Some(parse(path, value)?),
+            ["targets", target, field] => {
+                let index = match target.parse::<usize>() {
+                    Ok(index) if index < self.targets.len() => index,
+                    _ => self
+                        .targets
+                        .iter()
+                        .position(|t| t.id == *target)
+                        .ok_or(format!("unknown target {} in {}", target, path))?,
+                };
+                let target = &mut self.targets[index];
+                match *field {
+                    "ip" => target.ip = parse::<IpAddr>(path, value)?,
+                    "port" => target.port = parse(path, value)?,
+                    "mac" => {
+                        target.mac = Some(MacAddress::parse_str(value).map_err(|_| format!("invalid value {} for {}", value, path))?)
+                    }
+                    "linux_if" => target.linux_if = Some(value.to_string()),
+                    "host" => target.host = Some(value.to_string()),
+                    _ => return Err(format!("{} cannot be overridden", path)),
+                }
+            }
+            _ => return Err(format!("{} cannot be overridden", path)),
+        }
+        info!("configuration override {} = {}", path, value);
+        Ok(())
+    }
+
+    /// applies the overrides in their order, i.e. later ones win
+    pub fn apply_overrides(&mut self, overrides: &[(String, String)]) -> Result<(), String> {
+        for (path, value) in overrides {
+            self.apply_override(path, value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+
+use e2d2::interface::Pdu;
+
+const DEFAULT_MAX_QUEUED: usize = 4096;
+
+/// Pacing of the packets a pipeline sends to the physical port, to avoid microbursts on shallow-buffered switches:
+/// per timer tick (10 ms) a budget of packets is sent at once, further packets are held back in a software queue
+/// and released with the budget of the next ticks. Packets generated by the pipeline, e.g. ACKs of the handshake
+/// towards the server, are not paced.
+#[derive(Deserialize, Clone)]
+pub struct PacingConfig {
+    /// packets per timer tick and tx queue
+    pub packets_per_tick: usize,
+    /// maximum number of held back packets per tx queue, further packets are dropped, defaults to 4096
+    pub max_queued: Option<usize>,
+}
+
+pub struct Pacer<'a> {
+    budget: usize,
+    max_queued: usize,
+    /// packets sent in the current tick
+    sent: usize,
+    queue: VecDeque<Box<Pdu<'a>>>,
+}
+
+impl<'a> Pacer<'a> {
+    pub fn new(config: &PacingConfig) -> Pacer<'a> {
+        let max_queued = config.max_queued.unwrap_or(DEFAULT_MAX_QUEUED);
+        Pacer {
+            budget: config.packets_per_tick,
+            max_queued,
+            sent: 0,
+            // the queue does not grow in the data path
+            queue: VecDeque::with_capacity(max_queued),
+        }
+    }
+
+    /// true, if a packet may be sent now, as the budget of the tick is not used up and no packets are held back
+    #[inline]
+    pub fn admit(&mut self) -> bool {
+        if self.queue.is_empty() && self.sent < self.budget {
+            self.sent += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// holds back a copy of p, false if the queue is full
+    pub fn hold(&mut self, p: &Pdu<'a>) -> bool {
+        if self.queue.len() >= self.max_queued {
+            return false;
+        }
+        self.queue.push_back(Box::new(p.clone()));
+        true
+    }
+
+    /// starts a new tick and returns the held back packets, which are sent with its budget
+    pub fn tick(&mut self) -> Vec<Box<Pdu<'a>>> {
+        let n = ::std::cmp::min(self.budget, self.queue.len());
+        self.sent = n;
+        self.queue.drain(..n).collect()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+}
@@ -0,0 +1,174 @@
+use std::arch::x86_64::_rdtsc;
+use std::cell::Cell;
+use std::sync::atomic::spin_loop_hint;
+use std::thread;
+use std::time::Duration;
+
+use e2d2::scheduler::Executable;
+
+use stats::{CoreLoad, EngineStats};
+
+const DEFAULT_IDLE_AFTER: u32 = 1000;
+/// the thread sleeps after this multiple of idle_after empty rounds
+const SLEEP_FACTOR: u32 = 10;
+const DEFAULT_SLEEP_US: u64 = 10;
+const PAUSES: usize = 32;
+pub const MAX_RX_BURST: usize = 512;
+
+/// what the scheduler thread of a core does, when the queues of its pipelines had no packets
+#[derive(Deserialize, Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleStrategy {
+    /// busy polling, lowest latency
+    Spin,
+    /// the pause instruction between the polls
+    Pause,
+    /// pause, and after a longer idle time a short sleep between the polls
+    Sleep,
+}
+
+/// Receive burst size and polling strategy of the pipelines, to trade latency for power on lightly loaded engines.
+/// The strategy adapts to the load: it starts with spinning, pauses after idle_after empty scheduler rounds and,
+/// with sleep, sleeps after ten times as many rounds. The first received packet returns to spinning.
+#[derive(Deserialize, Clone)]
+pub struct PollingConfig {
+    /// maximum number of packets received from a queue per poll, defaults to the burst size of the receive operator
+    pub rx_burst: Option<usize>,
+    /// defaults to spin
+    pub idle: Option<IdleStrategy>,
+    /// empty scheduler rounds, before the thread pauses, defaults to 1000
+    pub idle_after: Option<u32>,
+    /// micro-seconds the thread sleeps per round, defaults to 10
+    pub sleep_us: Option<u64>,
+}
+
+impl PollingConfig {
+    #[inline]
+    pub fn idle(&self) -> IdleStrategy {
+        self.idle.unwrap_or(IdleStrategy::Spin)
+    }
+}
+
+thread_local! {
+    /// packets received by the pipelines of the scheduler thread
+    static RECEIVED: Cell<u64> = Cell::new(0);
+}
+
+/// called by the pipelines for each received packet
+#[inline]
+pub fn packet_received() {
+    RECEIVED.with(|received| received.set(received.get().wrapping_add(1)));
+}
+
+/// The task of a core, which applies the idle strategy, it runs once per round of the scheduler of the core.
+pub struct IdlePoller {
+    strategy: IdleStrategy,
+    idle_after: u32,
+    sleep: Duration,
+    last_received: u64,
+    empty_rounds: u32,
+}
+
+impl IdlePoller {
+    pub fn new(config: &PollingConfig) -> IdlePoller {
+        IdlePoller {
+            strategy: config.idle(),
+            idle_after: config.idle_after.unwrap_or(DEFAULT_IDLE_AFTER),
+            sleep: Duration::from_micros(config.sleep_us.unwrap_or(DEFAULT_SLEEP_US)),
+            last_received: 0,
+            empty_rounds: 0,
+        }
+    }
+}
+
+impl Executable for IdlePoller {
+    fn execute(&mut self) -> (u32, i32) {
+        let received = RECEIVED.with(|received| received.get());
+        if received != self.last_received {
+            self.last_received = received;
+            self.empty_rounds = 0;
+            return (0, 0);
+        }
+        self.empty_rounds = self.empty_rounds.saturating_add(1);
+        if self.strategy == IdleStrategy::Sleep && self.empty_rounds >= self.idle_after.saturating_mul(SLEEP_FACTOR) {
+            thread::sleep(self.sleep);
+        } else if self.strategy >= IdleStrategy::Pause && self.empty_rounds >= self.idle_after {
+            for _ in 0..PAUSES {
+                spin_loop_hint();
+            }
+        }
+        (0, 0)
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        Vec::new()
+    }
+}
+
+/// The task of a core, which measures the busy and the idle cycles of the scheduler thread. The cycles between two
+/// runs of the task are one round of the scheduler, the round is busy, if the pipelines received packets in it.
+/// The load is published to EngineStats once per second.
+pub struct CycleMeter {
+    core: i32,
+    stats: EngineStats,
+    /// cycles between two publications
+    period: u64,
+    load: CoreLoad,
+    last_tsc: u64,
+    last_received: u64,
+    last_published: u64,
+    /// busy cycles at the last publication
+    published_busy: u64,
+    /// longest round since the last publication
+    max_round: u64,
+}
+
+impl CycleMeter {
+    pub fn new(core: i32, stats: EngineStats, cpu_clock: u64) -> CycleMeter {
+        let now = unsafe { _rdtsc() };
+        CycleMeter {
+            core,
+            stats,
+            period: cpu_clock,
+            load: CoreLoad::default(),
+            last_tsc: now,
+            last_received: 0,
+            last_published: now,
+            published_busy: 0,
+            max_round: 0,
+        }
+    }
+}
+
+impl Executable for CycleMeter {
+    fn execute(&mut self) -> (u32, i32) {
+        let now = unsafe { _rdtsc() };
+        let round = now.wrapping_sub(self.last_tsc);
+        self.last_tsc = now;
+        let received = RECEIVED.with(|received| received.get());
+        if received != self.last_received {
+            self.last_received = received;
+            self.load.busy_cycles += round;
+        } else {
+            self.load.idle_cycles += round;
+        }
+        self.load.rounds += 1;
+        if round > self.max_round {
+            self.max_round = round;
+        }
+        let elapsed = now.wrapping_sub(self.last_published);
+        if elapsed >= self.period {
+            self.load.utilization = (self.load.busy_cycles - self.published_busy) as f64 * 100.0 / elapsed as f64;
+            self.load.max_round_cycles = self.max_round;
+            self.stats.publish_core(self.core, &self.load);
+            self.last_published = now;
+            self.published_busy = self.load.busy_cycles;
+            self.max_round = 0;
+        }
+        (0, 0)
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        Vec::new()
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use e2d2::interface::PmdPort;
+use eui48::MacAddress;
+use netfcts::physical_ports_for_core;
+use serde_json;
+
+use numa::core_is_local_to_port;
+use reload::TargetEntry;
+use Configuration;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Passed,
+    /// the engine may run, but e.g. with degraded performance
+    Warning,
+    Failed,
+    /// the check cannot be done before the engine runs
+    Skipped,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct PreflightCheck {
+    /// e.g. "configuration", "port 0000:01:00.0" or "target server1"
+    pub name: String,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+/// The result of ProxyEngineBuilder::preflight, one check per subject.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    pub fn add(&mut self, name: &str, status: CheckStatus, detail: String) {
+        self.checks.push(PreflightCheck {
+            name: name.to_string(),
+            status,
+            detail,
+        });
+    }
+
+    /// true, if no check failed
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.status != CheckStatus::Failed)
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_else(|e| format!("{{\"error\":\"{}\"}}", e))
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            writeln!(f, "{:?} {}: {}", check.status, check.name, check.detail)?;
+        }
+        write!(f, "preflight {}", if self.passed() { "passed" } else { "failed" })
+    }
+}
+
+pub fn check_configuration(configuration: &Configuration, report: &mut PreflightReport) {
+    let problems = configuration.validate();
+    if problems.is_empty() {
+        report.add("configuration", CheckStatus::Passed, "valid".to_string());
+    } else {
+        let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+        report.add("configuration", CheckStatus::Failed, problems.join("; "));
+    }
+}
+
+/// Each port needs an rx and a tx queue per core of its pipelines, the cores should be on the NUMA node of the port.
+pub fn check_ports(cores: &[i32], ports: &HashMap<String, Arc<PmdPort>>, report: &mut PreflightReport) {
+    let mut cores_of_port: HashMap<String, Vec<i32>> = HashMap::new();
+    for core in cores {
+        for port in physical_ports_for_core(*core, ports) {
+            cores_of_port.entry(port.name().to_string()).or_insert_with(Vec::new).push(*core);
+        }
+    }
+    let mut names: Vec<&String> = cores_of_port.keys().collect();
+    names.sort();
+    for name in names {
+        let port = &ports[name];
+        let cores = &cores_of_port[name];
+        let name = format!("port {}", name);
+        if (port.rxqs() as usize) < cores.len() || (port.txqs() as usize) < cores.len() {
+            report.add(
+                &name,
+                CheckStatus::Failed,
+                format!("{} rx and {} tx queues for {} cores", port.rxqs(), port.txqs(), cores.len()),
+            );
+            continue;
+        }
+        let remote: Vec<String> = cores
+            .iter()
+            .filter(|core| !core_is_local_to_port(**core, port.name()))
+            .map(|core| core.to_string())
+            .collect();
+        if remote.is_empty() {
+            report.add(&name, CheckStatus::Passed, format!("{} queues on cores {:?}", cores.len(), cores));
+        } else {
+            report.add(&name, CheckStatus::Warning, format!("cores {} are on another NUMA node", remote.join(", ")));
+        }
+    }
+}
+
+/// Targets need an address and a MAC address, unresolved MAC addresses are only acceptable with ARP.
+pub fn check_targets(targets: &Vec<TargetEntry>, arp: bool, report: &mut PreflightReport) {
+    for entry in targets.iter().filter(|entry| entry.active) {
+        let name = format!("target {}", entry.config.id);
+        if entry.config.ip.is_unspecified() {
+            report.add(&name, CheckStatus::Failed, "no address, the host is not resolved".to_string());
+        } else if entry.l234.mac != MacAddress::nil() {
+            let detail = format!("{}:{} at {}", entry.config.ip, entry.config.port, entry.l234.mac);
+            report.add(&name, CheckStatus::Passed, detail);
+        } else if arp {
+            report.add(&name, CheckStatus::Warning, format!("MAC address of {} is resolved by ARP", entry.config.ip));
+        } else {
+            report.add(&name, CheckStatus::Failed, "no MAC address, configure mac, linux_if or engine.arp".to_string());
+        }
+    }
+}
+
+/// a packet through the pipelines needs the running schedulers, see ReplayConfig for a pcap through a pipeline
+pub fn check_pipelines(report: &mut PreflightReport) {
+    report.add(
+        "pipelines",
+        CheckStatus::Skipped,
+        "packets are processed only after the engine has started, use engine.replay for a test run".to_string(),
+    );
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use netfcts::comm::PipelineId;
+
+use cmanager::ProxyConnection;
+use h2::is_http2_preface;
+use http::is_http_request;
+use health::MAX_TARGETS;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const PROTOCOLS: usize = 4;
+
+/// the protocol of a connection, classified by the first client payload, when the server is selected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Tls = 0,
+    Http = 1,
+    Http2 = 2,
+    Other = 3,
+}
+
+impl Protocol {
+    /// classifies the first payload of the client, after c.sni, c.alpn and c.http_request have been parsed from it,
+    /// if they are used for the selection
+    pub fn classify(c: &ProxyConnection, payload: &[u8]) -> Protocol {
+        let tls_record = payload.len() >= 2 && payload[0] == TLS_HANDSHAKE && payload[1] == 0x03;
+        if c.sni.is_some() || c.alpn.is_some() || tls_record {
+            Protocol::Tls
+        } else if is_http2_preface(payload) {
+            Protocol::Http2
+        } else if c.http_request.is_some() || is_http_request(payload) {
+            Protocol::Http
+        } else {
+            Protocol::Other
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Protocol::Tls => "tls",
+            Protocol::Http => "http",
+            Protocol::Http2 => "http2",
+            Protocol::Other => "other",
+        }
+    }
+
+    pub fn all() -> [Protocol; PROTOCOLS] {
+        [Protocol::Tls, Protocol::Http, Protocol::Http2, Protocol::Other]
+    }
+}
+
+/// released connections and their payload bytes of a protocol and target
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ProtocolCount {
+    pub connections: u64,
+    pub c2s_bytes: u64,
+    pub s2c_bytes: u64,
+}
+
+/// Counters of a pipeline per target and protocol, see EngineConfig.protocol_counters. A connection is counted on
+/// its release with its payload bytes, if it has been bound to a target.
+#[derive(Clone)]
+pub struct ProtocolCounters(Vec<[ProtocolCount; PROTOCOLS]>);
+
+impl ProtocolCounters {
+    pub fn new() -> ProtocolCounters {
+        ProtocolCounters(vec![[ProtocolCount::default(); PROTOCOLS]; MAX_TARGETS])
+    }
+
+    #[inline]
+    pub fn closed(&mut self, c: &ProxyConnection) {
+        if c.server_bound() {
+            let count = &mut self.0[c.server_index()][c.protocol.unwrap_or(Protocol::Other) as usize];
+            count.connections += 1;
+            count.c2s_bytes += c.c2s_bytes;
+            count.s2c_bytes += c.s2c_bytes;
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, target: usize, protocol: Protocol) -> &ProtocolCount {
+        &self.0[target][protocol as usize]
+    }
+
+    pub fn add(&mut self, other: &ProtocolCounters) {
+        for (counts, other_counts) in self.0.iter_mut().zip(other.0.iter()) {
+            for (count, other_count) in counts.iter_mut().zip(other_counts.iter()) {
+                count.connections += other_count.connections;
+                count.c2s_bytes += other_count.c2s_bytes;
+                count.s2c_bytes += other_count.s2c_bytes;
+            }
+        }
+    }
+}
+
+/// The protocol counters of all pipelines, each pipeline publishes its counters once per second.
+#[derive(Clone)]
+pub struct EngineProtocols(Arc<Mutex<HashMap<PipelineId, ProtocolCounters>>>);
+
+impl EngineProtocols {
+    pub fn new() -> EngineProtocols {
+        EngineProtocols(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub fn publish(&self, pipeline_id: &PipelineId, counters: &ProtocolCounters) {
+        self.0.lock().unwrap().insert(pipeline_id.clone(), counters.clone());
+    }
+
+    /// the sum of the counters of all pipelines
+    pub fn total(&self) -> ProtocolCounters {
+        let mut total = ProtocolCounters::new();
+        for counters in self.0.lock().unwrap().values() {
+            total.add(counters);
+        }
+        total
+    }
+}
@@ -0,0 +1,83 @@
+use std::net::{IpAddr, Ipv6Addr};
+
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+
+/// version of the HAProxy PROXY protocol header, which is sent to a target before the first payload of the client
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+/// version 2, command PROXY
+const V2_VERSION_COMMAND: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+fn to_ipv6(ip: &IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => *ip,
+    }
+}
+
+/// builds the PROXY protocol header for the connection from the client socket src to the proxy socket dst
+pub fn proxy_protocol_header(version: ProxyProtocol, src: (IpAddr, u16), dst: (IpAddr, u16)) -> Vec<u8> {
+    match version {
+        ProxyProtocol::V1 => match (src.0, dst.0) {
+            (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                format!("PROXY TCP4 {} {} {} {}\r\n", src_ip, dst_ip, src.1, dst.1).into_bytes()
+            }
+            _ => format!("PROXY TCP6 {} {} {} {}\r\n", to_ipv6(&src.0), to_ipv6(&dst.0), src.1, dst.1).into_bytes(),
+        },
+        ProxyProtocol::V2 => {
+            let mut header = Vec::with_capacity(16 + 36);
+            header.extend_from_slice(&V2_SIGNATURE);
+            header.push(V2_VERSION_COMMAND);
+            match (src.0, dst.0) {
+                (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+                    header.push(V2_TCP4);
+                    header.extend_from_slice(&[0, 12]);
+                    header.extend_from_slice(&src_ip.octets());
+                    header.extend_from_slice(&dst_ip.octets());
+                }
+                _ => {
+                    header.push(V2_TCP6);
+                    header.extend_from_slice(&[0, 36]);
+                    header.extend_from_slice(&to_ipv6(&src.0).octets());
+                    header.extend_from_slice(&to_ipv6(&dst.0).octets());
+                }
+            }
+            header.push((src.1 >> 8) as u8);
+            header.push(src.1 as u8);
+            header.push((dst.1 >> 8) as u8);
+            header.push(dst.1 as u8);
+            header
+        }
+    }
+}
+
+/// inserts data in front of the tcp payload of p and adapts the ip length, returns false if the tailroom of p is too small.
+/// checksums must be recalculated afterwards
+pub fn insert_into_payload(p: &mut Pdu, data: &[u8]) -> bool {
+    let n = data.len();
+    if p.get_tailroom() < n {
+        return false;
+    }
+    let payload_sz = tcp_payload_size(p);
+    p.add_padding(n);
+    {
+        let ip = p.headers_mut().ip_mut(1);
+        let length = ip.length();
+        ip.set_length(length + n as u16);
+    }
+    let payload = p.get_payload_mut(2);
+    for i in (0..payload_sz).rev() {
+        payload[i + n] = payload[i];
+    }
+    payload[..n].copy_from_slice(data);
+    true
+}
@@ -0,0 +1,386 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::fs::File;
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::IpAddr;
+
+use nix::sys::signal::{sigaction, SigAction, SigHandler, SaFlags, SigSet, Signal};
+
+use toml;
+use serde_yaml;
+use serde_json;
+
+use eui48::MacAddress;
+
+use netfcts::tcp_common::L234Data;
+use netfcts::system::get_mac_from_ifname;
+use netfcts::utils::Timeouts;
+
+use health::MAX_TARGETS;
+use gateway::{GatewayConfig, next_hop};
+use http::HttpRoute;
+use {Configuration, TargetConfig};
+
+/// A target of the target table. Entries are never removed from the table, as connections refer to targets by their index.
+/// Targets which are removed by a reload, or which change their address, become inactive.
+#[derive(Clone)]
+pub struct TargetEntry {
+    pub config: TargetConfig,
+    pub l234: L234Data,
+    /// inactive targets are not selected for new connections
+    pub active: bool,
+}
+
+pub fn l234data_for_target(index: usize, target: &TargetConfig) -> Result<L234Data, String> {
+    let mac = match target.mac {
+        Some(mac) => mac,
+        None => match target.linux_if.as_ref() {
+            Some(linux_if) => match get_mac_from_ifname(linux_if) {
+                Ok(mac) => mac,
+                Err(e) => return Err(format!("cannot get mac of {} for target {}: {}", linux_if, target.id, e)),
+            },
+            // resolved by ARP, see ArpConfig
+            None => MacAddress::nil(),
+        },
+    };
+    Ok(L234Data {
+        mac,
+        ip: target.ipv4(),
+        port: target.port,
+        server_id: target.id.clone(),
+        index,
+    })
+}
+
+/// the MAC address of a target without mac and linux_if: the one of its gateway or the one learned by ARP
+fn resolved_mac(target: &TargetConfig, gateway: &Option<GatewayConfig>, arp_cache: &HashMap<u32, MacAddress>) -> Option<MacAddress> {
+    match gateway.as_ref() {
+        Some(gateway) if gateway.routes(target) && gateway.mac.is_some() => gateway.mac,
+        _ => next_hop(target, gateway).and_then(|ip| arp_cache.get(&ip).cloned()),
+    }
+}
+
+fn new_entry(
+    index: usize,
+    target: &TargetConfig,
+    gateway: &Option<GatewayConfig>,
+    arp_cache: &HashMap<u32, MacAddress>,
+) -> Result<TargetEntry, String> {
+    let mut l234 = l234data_for_target(index, target)?;
+    if let Some(mac) = resolved_mac(target, gateway, arp_cache) {
+        l234.mac = mac;
+    }
+    Ok(TargetEntry {
+        config: target.clone(),
+        l234,
+        active: true,
+    })
+}
+
+struct Generation {
+    targets: Vec<TargetEntry>,
+    timeouts: Option<Timeouts>,
+    sni_map: Option<HashMap<String, String>>,
+    alpn_map: Option<HashMap<String, String>>,
+    http_routes: Option<Vec<HttpRoute>>,
+    gateway: Option<GatewayConfig>,
+    /// MAC addresses learned by ARP
+    arp_cache: HashMap<u32, MacAddress>,
+}
+
+/// The reloadable part of the configuration, shared by all pipelines.
+/// Pipelines compare the version on each timer tick and take a copy, when it has changed.
+#[derive(Clone)]
+pub struct TargetTable {
+    generation: Arc<RwLock<Generation>>,
+    version: Arc<AtomicUsize>,
+}
+
+impl TargetTable {
+    pub fn new(configuration: &Configuration) -> TargetTable {
+        let arp_cache = HashMap::new();
+        let targets = configuration
+            .targets
+            .iter()
+            .enumerate()
+            .map(|(i, t)| new_entry(i, t, &configuration.engine.gateway, &arp_cache).unwrap())
+            .collect();
+        TargetTable {
+            generation: Arc::new(RwLock::new(Generation {
+                targets,
+                timeouts: configuration.engine.timeouts.clone(),
+                sni_map: configuration.engine.sni_map.clone(),
+                alpn_map: configuration.engine.alpn_map.clone(),
+                http_routes: configuration.engine.http_routes.clone(),
+                gateway: configuration.engine.gateway.clone(),
+                arp_cache,
+            })),
+            version: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    #[inline]
+    pub fn version(&self) -> usize {
+        self.version.load(Ordering::Acquire)
+    }
+
+    pub fn targets(&self) -> Vec<TargetEntry> {
+        self.generation.read().unwrap().targets.clone()
+    }
+
+    /// calls f with the targets under the read lock, e.g. to look up a target per connection without a copy of the table
+    pub fn with_targets<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce(&Vec<TargetEntry>) -> T,
+    {
+        f(&self.generation.read().unwrap().targets)
+    }
+
+    pub fn timeouts(&self) -> Option<Timeouts> {
+        self.generation.read().unwrap().timeouts.clone()
+    }
+
+    pub fn sni_map(&self) -> Option<HashMap<String, String>> {
+        self.generation.read().unwrap().sni_map.clone()
+    }
+
+    pub fn alpn_map(&self) -> Option<HashMap<String, String>> {
+        self.generation.read().unwrap().alpn_map.clone()
+    }
+
+    pub fn http_routes(&self) -> Option<Vec<HttpRoute>> {
+        self.generation.read().unwrap().http_routes.clone()
+    }
+
+    /// the L234Data of all targets, indexed like the target table
+    pub fn l234data(&self) -> Vec<L234Data> {
+        self.generation.read().unwrap().targets.iter().map(|t| t.l234.clone()).collect()
+    }
+
+    /// the addresses, which are resolved by ARP for the active targets without mac and linux_if,
+    /// i.e. the addresses of the targets or of their gateway
+    pub fn arp_targets(&self) -> Vec<u32> {
+        let generation = self.generation.read().unwrap();
+        let mut ips: Vec<u32> = generation
+            .targets
+            .iter()
+            .filter(|t| t.active)
+            .filter_map(|t| next_hop(&t.config, &generation.gateway))
+            .collect();
+        ips.sort();
+        ips.dedup();
+        ips
+    }
+
+    /// Sets the MAC address of the targets, which are reached via address ip and are resolved by ARP.
+    /// Returns the new version, None if the MAC address has not changed
+    pub fn update_mac(&self, ip: u32, mac: MacAddress) -> Option<usize> {
+        let mut generation = self.generation.write().unwrap();
+        generation.arp_cache.insert(ip, mac);
+        let gateway = generation.gateway.clone();
+        let mut changed = false;
+        for t in generation.targets.iter_mut() {
+            if next_hop(&t.config, &gateway) == Some(ip) && t.l234.mac != mac {
+                info!("target {} has MAC address {}", t.config.id, mac);
+                t.l234.mac = mac;
+                changed = true;
+            }
+        }
+        if changed {
+            Some(self.version.fetch_add(1, Ordering::AcqRel) + 1)
+        } else {
+            None
+        }
+    }
+
+    /// A new entry for an active target with a changed address, e.g. after its host name has been resolved again.
+    /// The previous entry becomes inactive, so that existing connections keep their server.
+    /// IPv6 addresses are rejected, as validate does for the configuration.
+    pub fn update_address(&self, id: &str, ip: IpAddr) -> Result<usize, String> {
+        if ip.is_ipv6() {
+            return Err(format!("IPv6 address {} of target {} is not supported by the data path", ip, id));
+        }
+        let mut generation = self.generation.write().unwrap();
+        let i = generation
+            .targets
+            .iter()
+            .position(|e| e.active && e.config.id == id)
+            .ok_or(format!("no active target {}", id))?;
+        if generation.targets.len() >= MAX_TARGETS {
+            return Err(format!("target table is full, cannot add target {}", id));
+        }
+        let mut config = generation.targets[i].config.clone();
+        config.ip = ip;
+        let index = generation.targets.len();
+        let entry = new_entry(index, &config, &generation.gateway, &generation.arp_cache)?;
+        generation.targets[i].active = false;
+        generation.targets.push(entry);
+        let version = self.version.fetch_add(1, Ordering::AcqRel) + 1;
+        info!("target {} has address {}, version {}", id, ip, version);
+        Ok(version)
+    }
+
+    /// Merges the targets of the new configuration into the table: targets are matched by id, new targets are appended,
+    /// removed targets become inactive. A target with a changed address gets a new entry, so that existing connections keep their server.
+    pub fn reload(&self, configuration: &Configuration) -> Result<usize, String> {
+        if configuration.targets.is_empty() {
+            return Err("no target configured".to_string());
+        }
+        let mut generation = self.generation.write().unwrap();
+        let mut targets = generation.targets.clone();
+        for entry in targets.iter_mut() {
+            entry.active = false;
+        }
+        for t in &configuration.targets {
+            let existing = targets.iter().position(|e| {
+                e.config.id == t.id && e.config.ip == t.ip && e.config.port == t.port && e.config.mac == t.mac
+            });
+            match existing {
+                Some(i) => {
+                    targets[i].config = t.clone();
+                    targets[i].active = true;
+                    // the gateway may have changed
+                    if let Some(mac) = resolved_mac(t, &configuration.engine.gateway, &generation.arp_cache) {
+                        targets[i].l234.mac = mac;
+                    }
+                }
+                None => {
+                    if targets.len() >= MAX_TARGETS {
+                        return Err(format!("target table is full, cannot add target {}", t.id));
+                    }
+                    let index = targets.len();
+                    targets.push(new_entry(index, t, &configuration.engine.gateway, &generation.arp_cache)?);
+                }
+            }
+        }
+        generation.targets = targets;
+        generation.timeouts = configuration.engine.timeouts.clone();
+        generation.sni_map = configuration.engine.sni_map.clone();
+        generation.alpn_map = configuration.engine.alpn_map.clone();
+        generation.http_routes = configuration.engine.http_routes.clone();
+        generation.gateway = configuration.engine.gateway.clone();
+        let version = self.version.fetch_add(1, Ordering::AcqRel) + 1;
+        info!(
+            "reloaded configuration, version {}: {} active targets",
+            version,
+            generation.targets.iter().filter(|t| t.active).count()
+        );
+        Ok(version)
+    }
+}
+
+/// format of a configuration file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// the format by the extension of the file name (.yaml, .yml, .json), defaults to toml
+    pub fn from_filename(filename: &str) -> ConfigFormat {
+        let lower = filename.to_lowercase();
+        if lower.ends_with(".yaml") || lower.ends_with(".yml") {
+            ConfigFormat::Yaml
+        } else if lower.ends_with(".json") {
+            ConfigFormat::Json
+        } else {
+            ConfigFormat::Toml
+        }
+    }
+
+    /// parses "toml", "yaml", "yml" or "json", e.g. of a command line flag
+    pub fn from_name(name: &str) -> Option<ConfigFormat> {
+        match name.to_lowercase().as_str() {
+            "toml" => Some(ConfigFormat::Toml),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the engine configuration from a toml, yaml or json file, the format is detected by the extension.
+pub fn read_configuration(filename: &str) -> Result<Configuration, String> {
+    read_configuration_as(filename, ConfigFormat::from_filename(filename))
+}
+
+/// Reads the engine configuration from a file in the given format.
+/// The engine configuration is the first table (mapping, object) which is not the netbricks table,
+/// in yaml and json files it may also be the top level, i.e. have the keys engine and targets.
+pub fn read_configuration_as(filename: &str, format: ConfigFormat) -> Result<Configuration, String> {
+    let mut content = String::new();
+    File::open(filename)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map_err(|e| format!("cannot read {}: {}", filename, e))?;
+    let no_engine = || format!("no engine configuration in {}", filename);
+    let invalid = |e: String| format!("invalid engine configuration in {}: {}", filename, e);
+    match format {
+        ConfigFormat::Toml => {
+            let value: toml::Value = content
+                .parse()
+                .map_err(|e| format!("cannot parse {}: {}", filename, e))?;
+            let table = value.as_table().ok_or(format!("{} is not a toml table", filename))?;
+            let engine = table
+                .iter()
+                .find(|(k, v)| k.as_str() != "netbricks" && v.is_table())
+                .map(|(_, v)| v.clone())
+                .ok_or_else(no_engine)?;
+            engine.try_into::<Configuration>().map_err(|e| invalid(e.to_string()))
+        }
+        ConfigFormat::Yaml => {
+            let value: serde_yaml::Value =
+                serde_yaml::from_str(&content).map_err(|e| format!("cannot parse {}: {}", filename, e))?;
+            let mapping = value.as_mapping().ok_or(format!("{} is not a yaml mapping", filename))?;
+            let engine = if mapping.contains_key(&serde_yaml::Value::from("engine")) {
+                value.clone()
+            } else {
+                mapping
+                    .iter()
+                    .find(|(k, v)| k.as_str() != Some("netbricks") && v.is_mapping())
+                    .map(|(_, v)| v.clone())
+                    .ok_or_else(no_engine)?
+            };
+            serde_yaml::from_value::<Configuration>(engine).map_err(|e| invalid(e.to_string()))
+        }
+        ConfigFormat::Json => {
+            let value: serde_json::Value =
+                serde_json::from_str(&content).map_err(|e| format!("cannot parse {}: {}", filename, e))?;
+            let object = value.as_object().ok_or(format!("{} is not a json object", filename))?;
+            let engine = if object.contains_key("engine") {
+                value.clone()
+            } else {
+                object
+                    .iter()
+                    .find(|(k, v)| k.as_str() != "netbricks" && v.is_object())
+                    .map(|(_, v)| v.clone())
+                    .ok_or_else(no_engine)?
+            };
+            serde_json::from_value::<Configuration>(engine).map_err(|e| invalid(e.to_string()))
+        }
+    }
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_sighup(_: i32) {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// installs a handler for SIGHUP, use reload_requested() to poll for the signal
+pub fn install_sighup_handler() {
+    let action = SigAction::new(SigHandler::Handler(handle_sighup), SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(Signal::SIGHUP, &action) }.expect("cannot install SIGHUP handler");
+}
+
+/// requests a reload like a SIGHUP, e.g. by the admin api
+pub fn request_reload() {
+    RELOAD_REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// returns true once after a SIGHUP was received or a reload was requested
+pub fn reload_requested() -> bool {
+    RELOAD_REQUESTED.swap(false, Ordering::SeqCst)
+}
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+
+use e2d2::interface::Pdu;
+use e2d2::native::zcsi::mbuf_avail_count;
+use netfcts::tcp_common::tcp_payload_size;
+
+const DEFAULT_MAX_SEGMENTS: usize = 8;
+const DEFAULT_MAX_BYTES: usize = 65536;
+const DEFAULT_MIN_FREE_MBUFS: u32 = 2048;
+
+/// Reordering of payload segments on both legs of established connections, so that the payload closure and the
+/// sequence number translation see the segments in order. Segments ahead of a gap are held back, until the
+/// missing segment arrives; segments beyond the limits are dropped and retransmitted by the peer.
+/// SYN, FIN and RST segments and segments without payload are never held back.
+#[derive(Deserialize, Clone)]
+pub struct ReorderConfig {
+    /// segments held back per connection and direction, defaults to 8
+    pub max_segments: Option<usize>,
+    /// payload bytes held back per connection and direction, defaults to 65536
+    pub max_bytes: Option<usize>,
+    /// below this number of free mbufs no segments are held back and the held back ones of a connection
+    /// are dropped, when it receives the next segment out of order, defaults to 2048
+    pub min_free_mbufs: Option<u32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentOrder {
+    /// the segment is the next one, a retransmission or carries no payload, it is forwarded
+    InOrder,
+    /// the segment is ahead of a gap and has been held back, it must be dropped
+    HeldBack,
+    /// the segment is ahead of a gap and exceeds the limits, it must be dropped
+    Dropped,
+}
+
+/// true, if seqn a is after seqn b
+#[inline]
+pub fn seqn_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+/// segments of one direction of a connection, which are held back
+pub struct ReorderQueue<'a> {
+    /// seqn of the next byte in order, None until the first segment is received
+    next_seqn: Option<u32>,
+    /// ordered by seqn
+    segments: VecDeque<(u32, Box<Pdu<'a>>)>,
+    bytes: usize,
+}
+
+impl<'a> ReorderQueue<'a> {
+    pub fn new() -> ReorderQueue<'a> {
+        ReorderQueue {
+            next_seqn: None,
+            segments: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// the first segment, which will be received, starts with next_seqn
+    #[inline]
+    pub fn start(&mut self, next_seqn: u32) {
+        self.next_seqn = Some(next_seqn);
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.segments.len()
+    }
+
+    #[inline]
+    fn forwarded(&mut self, seqn: u32, len: u32) {
+        let end = seqn.wrapping_add(len);
+        if self.next_seqn.map_or(true, |next| seqn_after(end, next)) {
+            self.next_seqn = Some(end);
+        }
+    }
+
+    /// classifies the segment p, it is held back, if it is ahead of a gap and within the limits
+    pub fn receive(&mut self, p: &Pdu, config: &ReorderConfig) -> SegmentOrder {
+        let tcp = p.headers().tcp(2);
+        let seqn = tcp.seq_num();
+        let payload_sz = tcp_payload_size(p);
+        if tcp.syn_flag() || tcp.rst_flag() || tcp.fin_flag() || payload_sz == 0 {
+            if tcp.fin_flag() {
+                self.forwarded(seqn, payload_sz as u32 + 1);
+            }
+            return SegmentOrder::InOrder;
+        }
+        if self.next_seqn.is_none() || !seqn_after(seqn, self.next_seqn.unwrap()) {
+            self.forwarded(seqn, payload_sz as u32);
+            return SegmentOrder::InOrder;
+        }
+        if unsafe { mbuf_avail_count() } < config.min_free_mbufs.unwrap_or(DEFAULT_MIN_FREE_MBUFS) {
+            // memory pressure: the peer retransmits the segments
+            self.segments.clear();
+            self.bytes = 0;
+            return SegmentOrder::Dropped;
+        }
+        if self.segments.len() >= config.max_segments.unwrap_or(DEFAULT_MAX_SEGMENTS)
+            || self.bytes + payload_sz > config.max_bytes.unwrap_or(DEFAULT_MAX_BYTES)
+        {
+            return SegmentOrder::Dropped;
+        }
+        let position = self.segments.iter().position(|(s, _)| !seqn_after(seqn, *s));
+        match position {
+            // a duplicate of a segment held back
+            Some(i) if self.segments[i].0 == seqn => return SegmentOrder::Dropped,
+            Some(i) => self.segments.insert(i, (seqn, Box::new(p.clone()))),
+            None => self.segments.push_back((seqn, Box::new(p.clone()))),
+        }
+        self.bytes += payload_sz;
+        SegmentOrder::HeldBack
+    }
+
+    /// the held back segments, which are in order after the last forwarded segment
+    pub fn release_in_order(&mut self) -> Vec<Box<Pdu<'a>>> {
+        let mut released = Vec::new();
+        while self.segments.front().map_or(false, |(seqn, _)| !seqn_after(*seqn, self.next_seqn.unwrap())) {
+            let (seqn, p) = self.segments.pop_front().unwrap();
+            let payload_sz = tcp_payload_size(&p);
+            self.bytes -= payload_sz;
+            self.forwarded(seqn, payload_sz as u32);
+            released.push(p);
+        }
+        released
+    }
+}
+
+/// the reorder queues of both directions of a connection
+pub struct ReorderBuffers<'a> {
+    pub c2s: ReorderQueue<'a>,
+    pub s2c: ReorderQueue<'a>,
+}
+
+impl<'a> ReorderBuffers<'a> {
+    pub fn new() -> ReorderBuffers<'a> {
+        ReorderBuffers {
+            c2s: ReorderQueue::new(),
+            s2c: ReorderQueue::new(),
+        }
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+use eui48::MacAddress;
+use netfcts::comm::PipelineId;
+
+use icmp::internet_checksum;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_HEADER_SIZE: usize = 24;
+const PCAP_RECORD_HEADER_SIZE: usize = 16;
+const ETHER_HEADER_SIZE: usize = 14;
+const IP_HEADER_SIZE: usize = 20;
+const TCP_PROTOCOL: u8 = 6;
+const ACK_FLAG: u8 = 0x10;
+const DEFAULT_WAIT_TICKS: u64 = 100;
+
+/// Replay of a pcap file into a pipeline, for regression tests of the state machine without traffic on the NIC.
+/// The pcap contains the packets of both directions at the port of the engine, e.g. recorded with tcpdump.
+/// The frames to the engine are injected into the pipeline of rx queue 0, the frames of the engine are the
+/// expectations: packets sent by the pipeline are compared with them and dropped instead of being sent.
+/// The next frame to the engine is injected, after the expected frames before it were sent. The proxy ports and
+/// the sequence numbers of the engine are learned from the sent packets and translated in the injected frames.
+/// Only IPv4 frames without IP options are replayed, the timing of the pcap is not reproduced.
+#[derive(Deserialize, Clone)]
+pub struct ReplayConfig {
+    /// pcap file with the packets of both directions at the port of the engine
+    pub input: String,
+    /// timer ticks (10 ms) to wait for an expected packet of the engine, before it is reported as missing,
+    /// defaults to 100
+    pub wait_ticks: Option<u64>,
+}
+
+#[inline]
+fn be_u16(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | bytes[1] as u16
+}
+
+#[inline]
+fn be_u32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32
+}
+
+#[inline]
+fn put_u16(bytes: &mut [u8], value: u16) {
+    bytes[0] = (value >> 8) as u8;
+    bytes[1] = value as u8;
+}
+
+#[inline]
+fn put_u32(bytes: &mut [u8], value: u32) {
+    for i in 0..4 {
+        bytes[i] = (value >> (24 - 8 * i)) as u8;
+    }
+}
+
+/// the ethernet frames of a pcap file in microsecond or nanosecond format and of either byte order
+pub fn read_pcap(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|e| format!("cannot read {}: {}", path, e))?;
+    if bytes.len() < PCAP_HEADER_SIZE {
+        return Err(format!("{} is not a pcap file", path));
+    }
+    let magic = be_u32(&bytes[0..4]);
+    let big_endian = magic == PCAP_MAGIC_MICROS || magic == PCAP_MAGIC_NANOS;
+    let little_endian = magic.swap_bytes() == PCAP_MAGIC_MICROS || magic.swap_bytes() == PCAP_MAGIC_NANOS;
+    if !big_endian && !little_endian {
+        return Err(format!("{} is not a pcap file", path));
+    }
+    let u32_at = |i: usize| {
+        let value = be_u32(&bytes[i..i + 4]);
+        if big_endian {
+            value
+        } else {
+            value.swap_bytes()
+        }
+    };
+    if u32_at(20) != 1 {
+        return Err(format!("{} does not contain ethernet frames", path));
+    }
+    let mut frames = Vec::new();
+    let mut i = PCAP_HEADER_SIZE;
+    while i + PCAP_RECORD_HEADER_SIZE <= bytes.len() {
+        let length = u32_at(i + 8) as usize;
+        i += PCAP_RECORD_HEADER_SIZE;
+        if i + length > bytes.len() {
+            return Err(format!("{} is truncated", path));
+        }
+        frames.push(bytes[i..i + length].to_vec());
+        i += length;
+    }
+    Ok(frames)
+}
+
+/// the fields of an IPv4 packet, which are compared with the expectations
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Segment {
+    src: (u32, u16),
+    dst: (u32, u16),
+    protocol: u8,
+    flags: u8,
+    seqn: u32,
+    ackn: u32,
+    payload: usize,
+}
+
+/// the segment of an ethernet frame, None for frames other than IPv4
+fn segment(frame: &[u8]) -> Option<Segment> {
+    if frame.len() < ETHER_HEADER_SIZE + IP_HEADER_SIZE || be_u16(&frame[12..14]) != 0x0800 {
+        return None;
+    }
+    let ip = &frame[ETHER_HEADER_SIZE..];
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    let length = ::std::cmp::min(be_u16(&ip[2..4]) as usize, ip.len());
+    let protocol = ip[9];
+    let mut segment = Segment {
+        src: (be_u32(&ip[12..16]), 0),
+        dst: (be_u32(&ip[16..20]), 0),
+        protocol,
+        flags: 0,
+        seqn: 0,
+        ackn: 0,
+        payload: length.saturating_sub(ihl),
+    };
+    if protocol == TCP_PROTOCOL && length >= ihl + 20 {
+        let tcp = &ip[ihl..];
+        segment.src.1 = be_u16(&tcp[0..2]);
+        segment.dst.1 = be_u16(&tcp[2..4]);
+        segment.seqn = be_u32(&tcp[4..8]);
+        segment.ackn = be_u32(&tcp[8..12]);
+        segment.flags = tcp[13];
+        segment.payload = length - ihl - (tcp[12] >> 4) as usize * 4;
+    }
+    Some(segment)
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.protocol != TCP_PROTOCOL {
+            return write!(
+                f,
+                "{} > {} protocol {}, {} bytes",
+                Ipv4Addr::from(self.src.0),
+                Ipv4Addr::from(self.dst.0),
+                self.protocol,
+                self.payload
+            );
+        }
+        let flags: String = ['F', 'S', 'R', 'P', 'A', 'U']
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.flags & (1 << i) != 0)
+            .map(|(_, c)| *c)
+            .collect();
+        write!(
+            f,
+            "{}:{} > {}:{} [{}] {} bytes",
+            Ipv4Addr::from(self.src.0),
+            self.src.1,
+            Ipv4Addr::from(self.dst.0),
+            self.dst.1,
+            flags,
+            self.payload
+        )
+    }
+}
+
+/// recomputes the IP and TCP checksums of an IPv4 frame
+fn update_checksums(frame: &mut [u8]) {
+    let ip = &mut frame[ETHER_HEADER_SIZE..];
+    let ihl = (ip[0] & 0x0f) as usize * 4;
+    let length = ::std::cmp::min(be_u16(&ip[2..4]) as usize, ip.len());
+    put_u16(&mut ip[10..12], 0);
+    let checksum = internet_checksum(&ip[..ihl]);
+    put_u16(&mut ip[10..12], checksum);
+    if ip[9] == TCP_PROTOCOL {
+        let mut pseudo = Vec::with_capacity(12 + length - ihl);
+        pseudo.extend_from_slice(&ip[12..20]);
+        pseudo.extend_from_slice(&[0, TCP_PROTOCOL, ((length - ihl) >> 8) as u8, (length - ihl) as u8]);
+        put_u16(&mut ip[ihl + 16..ihl + 18], 0);
+        pseudo.extend_from_slice(&ip[ihl..length]);
+        let checksum = internet_checksum(&pseudo);
+        put_u16(&mut ip[ihl + 16..ihl + 18], checksum);
+    }
+}
+
+/// the result of the replay of a pipeline
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    /// frames injected into the pipeline
+    pub replayed: usize,
+    /// packets of the pipeline, which matched an expected frame
+    pub matched: usize,
+    /// expected frames, which were not sent, and sent packets, which were not expected
+    pub mismatches: Vec<String>,
+    /// all frames of the pcap are replayed or were expected
+    pub finished: bool,
+}
+
+impl ReplayReport {
+    pub fn passed(&self) -> bool {
+        self.finished && self.mismatches.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "replayed= {}, matched= {}, mismatches= {}, finished= {}",
+            self.replayed,
+            self.matched,
+            self.mismatches.len(),
+            self.finished
+        )
+    }
+}
+
+/// The reports of the replaying pipelines, e.g. for the test modules.
+#[derive(Clone)]
+pub struct ReplayReports(Arc<Mutex<HashMap<PipelineId, ReplayReport>>>);
+
+impl ReplayReports {
+    pub fn new() -> ReplayReports {
+        ReplayReports(Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub fn publish(&self, pipeline_id: &PipelineId, report: &ReplayReport) {
+        self.0.lock().unwrap().insert(pipeline_id.clone(), report.clone());
+    }
+
+    pub fn snapshot(&self) -> HashMap<PipelineId, ReplayReport> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// the replay of a pcap into a pipeline, shared by the pipeline closure and the closure recording the sent packets
+pub struct Replayer {
+    pipeline_id: PipelineId,
+    wait_ticks: u64,
+    /// frames not yet injected or sent, true for the frames to the engine
+    frames: VecDeque<(bool, Vec<u8>)>,
+    /// ticks waited for the expected frame at the front
+    waited: u64,
+    /// proxy ports in the pcap -> ports of the engine
+    ports: HashMap<u16, u16>,
+    /// per flow of the engine in the pcap: seqn of the engine - seqn in the pcap
+    seqn_deltas: HashMap<((u32, u16), (u32, u16)), u32>,
+    report: ReplayReport,
+    reports: ReplayReports,
+    changed: bool,
+}
+
+impl Replayer {
+    pub fn new(
+        config: &ReplayConfig,
+        pipeline_id: &PipelineId,
+        engine_mac: MacAddress,
+        reports: ReplayReports,
+    ) -> Result<Replayer, String> {
+        let frames: VecDeque<(bool, Vec<u8>)> = read_pcap(&config.input)?
+            .into_iter()
+            .filter(|frame| segment(frame).is_some() && frame[ETHER_HEADER_SIZE] & 0x0f == 5)
+            .filter_map(|frame| {
+                if &frame[0..6] == engine_mac.as_bytes() {
+                    Some((true, frame))
+                } else if &frame[6..12] == engine_mac.as_bytes() {
+                    Some((false, frame))
+                } else {
+                    None
+                }
+            }).collect();
+        info!(
+            "{}: replaying {} frames of {}, expecting {} frames",
+            pipeline_id,
+            frames.iter().filter(|f| f.0).count(),
+            config.input,
+            frames.iter().filter(|f| !f.0).count()
+        );
+        Ok(Replayer {
+            pipeline_id: pipeline_id.clone(),
+            wait_ticks: config.wait_ticks.unwrap_or(DEFAULT_WAIT_TICKS),
+            frames,
+            waited: 0,
+            ports: HashMap::new(),
+            seqn_deltas: HashMap::new(),
+            report: ReplayReport::default(),
+            reports,
+            changed: true,
+        })
+    }
+
+    /// translates the proxy port and the ack number of a frame to the engine
+    fn translate(&self, frame: &mut Vec<u8>) {
+        let s = segment(frame).unwrap();
+        if s.protocol != TCP_PROTOCOL {
+            return;
+        }
+        let delta = self.seqn_deltas.get(&(s.dst, s.src));
+        let port = self.ports.get(&s.dst.1);
+        {
+            let tcp = &mut frame[ETHER_HEADER_SIZE + IP_HEADER_SIZE..];
+            if port.is_some() {
+                put_u16(&mut tcp[2..4], *port.unwrap());
+            }
+            if delta.is_some() && s.flags & ACK_FLAG != 0 {
+                put_u32(&mut tcp[8..12], s.ackn.wrapping_add(*delta.unwrap()));
+            }
+        }
+        update_checksums(frame);
+    }
+
+    /// called on each timer tick, returns the frames to inject now
+    pub fn due(&mut self) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        loop {
+            let inbound = match self.frames.front() {
+                Some(frame) => frame.0,
+                None => break,
+            };
+            if inbound {
+                let mut frame = self.frames.pop_front().unwrap().1;
+                self.translate(&mut frame);
+                due.push(frame);
+                self.report.replayed += 1;
+                self.changed = true;
+            } else if !due.is_empty() {
+                break;
+            } else if self.waited >= self.wait_ticks {
+                let expected = segment(&self.frames.pop_front().unwrap().1).unwrap();
+                warn!("{}: replay: missing {}", self.pipeline_id, expected);
+                self.report.mismatches.push(format!("missing {}", expected));
+                self.waited = 0;
+                self.changed = true;
+            } else {
+                self.waited += 1;
+                break;
+            }
+        }
+        if self.frames.is_empty() && !self.report.finished {
+            self.report.finished = true;
+            info!("{}: replay finished, {}", self.pipeline_id, self.report);
+        }
+        if self.changed {
+            self.reports.publish(&self.pipeline_id, &self.report);
+            self.changed = false;
+        }
+        due
+    }
+
+    fn matches(&self, expected: &Segment, sent: &Segment) -> bool {
+        let src_port = self.ports.get(&expected.src.1);
+        expected.protocol == sent.protocol
+            && expected.flags == sent.flags
+            && expected.payload == sent.payload
+            && expected.src.0 == sent.src.0
+            && expected.dst == sent.dst
+            && (src_port.is_none() || *src_port.unwrap() == sent.src.1)
+    }
+
+    /// compares a packet sent by the pipeline with the expected frames before the next frame to the engine
+    pub fn sent(&mut self, frame: &[u8]) {
+        let sent = match segment(frame) {
+            Some(sent) => sent,
+            None => return,
+        };
+        let position = self
+            .frames
+            .iter()
+            .take_while(|f| !f.0)
+            .position(|f| self.matches(&segment(&f.1).unwrap(), &sent));
+        if position.is_some() {
+            let expected = segment(&self.frames.remove(position.unwrap()).unwrap().1).unwrap();
+            self.ports.entry(expected.src.1).or_insert(sent.src.1);
+            self.seqn_deltas
+                .entry((expected.src, expected.dst))
+                .or_insert(sent.seqn.wrapping_sub(expected.seqn));
+            self.report.matched += 1;
+            self.waited = 0;
+        } else {
+            warn!("{}: replay: unexpected {}", self.pipeline_id, sent);
+            self.report.mismatches.push(format!("unexpected {}", sent));
+        }
+        self.changed = true;
+    }
+}
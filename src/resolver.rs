@@ -0,0 +1,107 @@
+use std::net::{IpAddr, ToSocketAddrs};
+use std::time::Duration;
+use std::thread;
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+
+use nix::sched::{setns, CloneFlags};
+
+use reload::TargetTable;
+use Configuration;
+
+const DEFAULT_INTERVAL_MS: u64 = 60000;
+
+/// Resolution of the targets with a host name, see TargetConfig.host.
+/// Without this configuration the host names are only resolved at startup and on a reload.
+#[derive(Deserialize, Clone)]
+pub struct DnsConfig {
+    /// milli-seconds between two resolutions of the host names, defaults to 60000
+    pub interval: Option<u64>,
+    /// network namespace of the resolver, e.g. the one of the KNI interface, defaults to the namespace of the engine
+    pub namespace: Option<String>,
+}
+
+fn enter_namespace(ns: &str) {
+    let path = format!("/var/run/netns/{}", ns);
+    match File::open(&path) {
+        Ok(f) => {
+            if let Err(e) = setns(f.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+                error!("resolver cannot enter namespace {}: {}", ns, e);
+            }
+        }
+        Err(e) => error!("resolver cannot open {}: {}", path, e),
+    }
+}
+
+/// the IPv4 address of host with the resolver of the system, the data path does not support IPv6 targets
+pub fn resolve_host(host: &str) -> Result<IpAddr, String> {
+    (host, 0)
+        .to_socket_addrs()
+        .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+        .map(|a| a.ip())
+        .find(|ip| ip.is_ipv4())
+        .ok_or(format!("no IPv4 address for {}", host))
+}
+
+/// Sets the ip of the targets with a host name to the address of the host.
+/// If a namespace is given, the host names are resolved by a thread in this namespace.
+pub fn resolve_targets(configuration: &mut Configuration, namespace: Option<&String>) -> Result<(), String> {
+    let hosts: Vec<(usize, String)> = configuration
+        .targets
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.host.is_some())
+        .map(|(i, t)| (i, t.host.clone().unwrap()))
+        .collect();
+    if hosts.is_empty() {
+        return Ok(());
+    }
+    let namespace = namespace.cloned();
+    let resolver = thread::spawn(move || {
+        if namespace.is_some() {
+            enter_namespace(namespace.as_ref().unwrap());
+        }
+        hosts
+            .into_iter()
+            .map(|(i, host)| resolve_host(&host).map(|ip| (i, ip)))
+            .collect::<Result<Vec<(usize, IpAddr)>, String>>()
+    });
+    let addresses = resolver.join().map_err(|_| "resolver thread failed".to_string())??;
+    for (i, ip) in addresses {
+        let target = &mut configuration.targets[i];
+        debug!("target {}: {} resolved to {}", target.id, target.host.as_ref().unwrap(), ip);
+        target.ip = ip;
+    }
+    Ok(())
+}
+
+/// Starts a thread, which periodically resolves the host names of the active targets.
+/// When the address of a host changes, the target gets a new entry in the target table (see TargetTable::update_address),
+/// so that new connections use the new address and existing connections keep their server.
+pub fn spawn_resolver(target_table: TargetTable, config: DnsConfig) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        if config.namespace.is_some() {
+            enter_namespace(config.namespace.as_ref().unwrap());
+        }
+        let interval = Duration::from_millis(config.interval.unwrap_or(DEFAULT_INTERVAL_MS));
+        info!("resolver started, interval= {:?}", interval);
+        loop {
+            thread::sleep(interval);
+            // the table may change by a reload
+            for entry in target_table.targets().iter().filter(|t| t.active && t.config.host.is_some()) {
+                let host = entry.config.host.as_ref().unwrap();
+                match resolve_host(host) {
+                    Ok(ip) if ip != entry.config.ip => {
+                        info!("target {}: {} changed its address from {} to {}", entry.config.id, host, entry.config.ip, ip);
+                        if let Err(e) = target_table.update_address(&entry.config.id, ip) {
+                            error!("cannot update the address of target {}: {}", entry.config.id, e);
+                        }
+                    }
+                    Ok(_) => {}
+                    // keep the previous address
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,27 @@
+const DEFAULT_ATTEMPTS: u8 = 2;
+const DEFAULT_SYN_TIMEOUT_MS: u64 = 1000;
+
+/// Retry of the delayed binding: when the selected target resets the SYN of the proxy or does not answer it within
+/// the timeout, the SYN is sent to another target, which is selected by the selection policy and has the same
+/// PROXY protocol setting. When all attempts failed, the client is reset.
+/// There is no retry, when the targets are selected by a selection closure.
+#[derive(Deserialize, Clone)]
+pub struct SynRetryConfig {
+    /// maximum number of further targets tried per connection, defaults to 2
+    pub attempts: Option<u8>,
+    /// milli-seconds to wait for the SYN-ACK of a target, defaults to 1000
+    pub timeout: Option<u64>,
+}
+
+impl SynRetryConfig {
+    #[inline]
+    pub fn attempts(&self) -> u8 {
+        self.attempts.unwrap_or(DEFAULT_ATTEMPTS)
+    }
+
+    /// timeout in cycles
+    #[inline]
+    pub fn timeout(&self, cpu_clock: u64) -> u64 {
+        self.timeout.unwrap_or(DEFAULT_SYN_TIMEOUT_MS) * cpu_clock / 1000
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::VecDeque;
+
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+
+use reorder::seqn_after;
+use socks5::replace_payload;
+
+/// rewrites older than this are forgotten, their segments are not expected to be retransmitted anymore
+const MAX_DELTAS: usize = 32;
+
+/// seqn shifted by a signed delta
+#[inline]
+pub fn shift(seqn: u32, delta: i32) -> u32 {
+    seqn.wrapping_add(delta as u32)
+}
+
+/// Sequence number adjustments of one direction of a connection, caused by payload closures which changed the length
+/// of segments. Each entry holds the end seqn of a rewritten segment, as sent by the peer, and the cumulative delta,
+/// which applies to all bytes from this seqn on.
+pub struct SeqDeltas {
+    /// ordered by seqn
+    entries: VecDeque<(u32, i32)>,
+}
+
+impl SeqDeltas {
+    pub fn new() -> SeqDeltas {
+        SeqDeltas {
+            entries: VecDeque::new(),
+        }
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// the delta of the latest rewrite, i.e. for the next new byte
+    #[inline]
+    pub fn current(&self) -> i32 {
+        self.entries.back().map_or(0, |(_, delta)| *delta)
+    }
+
+    /// the delta for a segment starting at seqn of the peer, retransmissions get the delta of their first transmission
+    pub fn at(&self, seqn: u32) -> i32 {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(end, _)| !seqn_after(*end, seqn))
+            .map_or(0, |(_, delta)| *delta)
+    }
+
+    /// the seqn of the peer for a translated seqn, e.g. for an ack number of the other side
+    pub fn original(&self, seqn: u32) -> u32 {
+        let delta = self
+            .entries
+            .iter()
+            .rev()
+            .find(|(end, delta)| !seqn_after(shift(*end, *delta), seqn))
+            .map_or(0, |(_, delta)| *delta);
+        shift(seqn, -delta)
+    }
+
+    /// the entries as (end seqn, cumulative delta), e.g. to replicate the connection, see ConnectionState
+    pub fn entries(&self) -> Vec<(u32, i32)> {
+        self.entries.iter().cloned().collect()
+    }
+
+    /// replaces the entries by entries of another engine, see entries()
+    pub fn set_entries(&mut self, entries: &[(u32, i32)]) {
+        self.entries = entries.iter().cloned().collect();
+    }
+
+    /// the segment ending at end_seqn has been rewritten with delta bytes more (or less),
+    /// rewrites of retransmitted segments are ignored, the closure must rewrite them in the same way
+    pub fn add(&mut self, end_seqn: u32, delta: i32) {
+        if delta == 0 || self.entries.back().map_or(false, |(end, _)| !seqn_after(end_seqn, *end)) {
+            return;
+        }
+        let cumulative = self.current() + delta;
+        self.entries.push_back((end_seqn, cumulative));
+        if self.entries.len() > MAX_DELTAS {
+            self.entries.pop_front();
+        }
+    }
+}
+
+/// Replaces the payload of p by payload, e.g. after a payload closure called ProxyConnection::rewrite_payload,
+/// and records the change of the length. Returns false, if the payload does not fit into the tailroom of the mbuf.
+pub fn apply_payload_rewrite(p: &mut Pdu, payload: &[u8], deltas: &mut SeqDeltas) -> bool {
+    let payload_sz = tcp_payload_size(p);
+    let end_seqn = p.headers().tcp(2).seq_num().wrapping_add(payload_sz as u32);
+    if !replace_payload(p, payload) {
+        return false;
+    }
+    deltas.add(end_seqn, payload.len() as i32 - payload_sz as i32);
+    true
+}
@@ -0,0 +1,86 @@
+/// true, if seqn a is before seqn b, modulo 2^32
+#[inline]
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+/// RTT and retransmissions of one leg of a connection, the client leg or the server leg.
+/// The RTT is measured with one payload segment in flight towards the leg at a time, from its forwarding until the ACK
+/// of the leg covers it, and smoothed as in RFC 6298. The payload segments received from the leg, which end before
+/// the highest seqn received, are counted as retransmissions. As of Karn's algorithm, a measurement is discarded,
+/// when the proxy forwards a retransmission towards the leg.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LegTiming {
+    /// smoothed RTT in cycles
+    srtt: Option<u64>,
+    /// seqn following the measured segment and tsc when it was forwarded
+    probe: Option<(u32, u64)>,
+    /// seqn following the latest payload received from the leg
+    high_seqn: Option<u32>,
+    retransmissions: u32,
+}
+
+impl LegTiming {
+    pub fn new() -> LegTiming {
+        LegTiming::default()
+    }
+
+    /// smoothed RTT in cycles, None before the first measurement
+    #[inline]
+    pub fn srtt(&self) -> Option<u64> {
+        self.srtt
+    }
+
+    #[inline]
+    pub fn retransmissions(&self) -> u32 {
+        self.retransmissions
+    }
+
+    /// a payload segment ending before seqn_end is forwarded towards the leg
+    #[inline]
+    pub fn sent(&mut self, seqn_end: u32, now: u64) {
+        if self.probe.is_none() {
+            self.probe = Some((seqn_end, now));
+        }
+    }
+
+    /// the proxy forwards a retransmission towards the leg
+    #[inline]
+    pub fn discard_probe(&mut self) {
+        self.probe = None;
+    }
+
+    /// an ACK is received from the leg
+    #[inline]
+    pub fn acked(&mut self, ackn: u32, now: u64) {
+        if let Some((seqn_end, sent)) = self.probe {
+            if !before(ackn, seqn_end) {
+                let sample = now.wrapping_sub(sent);
+                self.srtt = Some(match self.srtt {
+                    Some(srtt) => srtt - srtt / 8 + sample / 8,
+                    None => sample,
+                });
+                self.probe = None;
+            }
+        }
+    }
+
+    /// a payload segment is received from the leg, true if it is a retransmission
+    #[inline]
+    pub fn received(&mut self, seqn: u32, length: u32) -> bool {
+        if length == 0 {
+            return false;
+        }
+        let seqn_end = seqn.wrapping_add(length);
+        match self.high_seqn {
+            Some(high) if !before(high, seqn_end) => {
+                self.retransmissions += 1;
+                true
+            }
+            _ => {
+                self.high_seqn = Some(seqn_end);
+                false
+            }
+        }
+    }
+}
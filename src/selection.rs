@@ -0,0 +1,477 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+
+use cmanager::ProxyConnection;
+use health::{TargetHealth, MAX_TARGETS};
+use groups::TargetGroups;
+use limits::ConnectionCounts;
+use reload::TargetEntry;
+use breaker::{CircuitBreakers, CircuitBreakerConfig};
+use maglev::{ConsistentHashConfig, HashKey, MaglevTable, hash_of};
+
+/// probes of the Maglev table for an eligible target, before the targets are probed in order
+const MAX_MAGLEV_PROBES: u64 = 16;
+
+/// built-in server selection policies, used when no selection closure is supplied
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionPolicy {
+    RoundRobin,
+    LeastConn,
+    SrcIpHash,
+    Weighted,
+    /// minimum of smoothed response time * (connections + 1) / weight, targets without measurements are preferred
+    LeastResponseTime,
+    /// consistent hashing of the client ip, server name or host, see ConsistentHashConfig
+    Maglev,
+}
+
+impl Default for SelectionPolicy {
+    fn default() -> SelectionPolicy {
+        SelectionPolicy::RoundRobin
+    }
+}
+
+/// number of active connections per target of one pipeline,
+/// shared between the connection manager (decrements on release) and the selector.
+/// The counts of all pipelines are summed up in ConnectionCounts.
+/// It is sized for MAX_TARGETS, as a reload may add targets
+#[derive(Clone)]
+pub struct ServerLoad(Rc<Vec<Cell<u32>>>, ConnectionCounts);
+
+impl ServerLoad {
+    pub fn new(counts: ConnectionCounts) -> ServerLoad {
+        ServerLoad(Rc::new((0..MAX_TARGETS).map(|_| Cell::new(0)).collect()), counts)
+    }
+
+    #[inline]
+    pub fn get(&self, server: usize) -> u32 {
+        self.0[server].get()
+    }
+
+    #[inline]
+    pub fn inc(&self, server: usize) {
+        let cell = &self.0[server];
+        cell.set(cell.get() + 1);
+        self.1.target_bound(server);
+    }
+
+    #[inline]
+    pub fn dec(&self, server: usize) {
+        let cell = &self.0[server];
+        if cell.get() > 0 {
+            cell.set(cell.get() - 1);
+            self.1.target_unbound(server);
+        }
+    }
+
+    /// number of active connections of the target over all pipelines
+    #[inline]
+    pub fn global(&self, server: usize) -> usize {
+        self.1.target(server)
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// per pipeline state of the built-in selection policies, targets which are down are skipped
+pub struct PolicySelector {
+    policy: SelectionPolicy,
+    weights: Vec<u32>,
+    /// targets removed by a reload are inactive
+    active: Vec<bool>,
+    /// maximum number of connections per target over all pipelines
+    max_connections: Vec<Option<usize>>,
+    default_max_connections: Option<usize>,
+    load: ServerLoad,
+    health: TargetHealth,
+    groups: TargetGroups,
+    /// the target group of each target, None if the target is in no group, see TargetGroups
+    group: Vec<Option<usize>>,
+    /// the target ids of the backend of each frontend, None if the frontend proxies to all targets, see FrontendConfig
+    backends: Vec<Option<Vec<String>>>,
+    /// per frontend the targets of its backend
+    members: Vec<Option<Vec<bool>>>,
+    next: usize,
+    /// current weights of the smooth weighted round robin
+    current: Vec<i64>,
+    response_times: ResponseTimes,
+    breakers: CircuitBreakers,
+    /// None, if the target has no circuit breaker
+    breaker_configs: Vec<Option<CircuitBreakerConfig>>,
+    /// fallback targets are only selected, when no other target is eligible, see TargetConfig.fallback
+    fallback: Vec<bool>,
+    consistent_hash: ConsistentHashConfig,
+    /// the lookup table of SelectionPolicy::Maglev
+    maglev: Option<(MaglevTable, HashKey)>,
+}
+
+/// Smoothed response times per target in cycles, measured by the pipeline: the time from the SYN towards the target
+/// until its SYN-ACK and the time from forwarding the first client payload until the first payload of the target.
+/// The estimates decay, so that targets, which were slow and therefore are no longer selected, recover over time.
+pub struct ResponseTimes {
+    syn_ack: Vec<u64>,
+    first_byte: Vec<u64>,
+}
+
+impl ResponseTimes {
+    fn new() -> ResponseTimes {
+        ResponseTimes {
+            syn_ack: vec![0; MAX_TARGETS],
+            first_byte: vec![0; MAX_TARGETS],
+        }
+    }
+
+    #[inline]
+    fn smooth(estimate: &mut u64, sample: u64) {
+        // gain 1/8 as for the TCP srtt, the first sample is taken over
+        if *estimate == 0 {
+            *estimate = sample;
+        } else {
+            *estimate = *estimate - (*estimate >> 3) + (sample >> 3);
+        }
+    }
+
+    #[inline]
+    fn get(&self, i: usize) -> u64 {
+        self.syn_ack[i] + self.first_byte[i]
+    }
+
+    /// reduces all estimates by 1/16
+    fn decay(&mut self) {
+        for estimate in self.syn_ack.iter_mut().chain(self.first_byte.iter_mut()) {
+            *estimate -= *estimate >> 4;
+        }
+    }
+}
+
+impl PolicySelector {
+    pub fn new(
+        policy: SelectionPolicy,
+        targets: &Vec<TargetEntry>,
+        load: ServerLoad,
+        health: TargetHealth,
+        groups: TargetGroups,
+        breakers: CircuitBreakers,
+        default_max_connections: Option<usize>,
+        consistent_hash: Option<ConsistentHashConfig>,
+        backends: Vec<Option<Vec<String>>>,
+    ) -> PolicySelector {
+        let mut selector = PolicySelector {
+            policy,
+            current: Vec::new(),
+            weights: Vec::new(),
+            active: Vec::new(),
+            max_connections: Vec::new(),
+            default_max_connections,
+            load,
+            health,
+            groups,
+            group: Vec::new(),
+            backends,
+            members: Vec::new(),
+            next: 0,
+            response_times: ResponseTimes::new(),
+            breakers,
+            breaker_configs: Vec::new(),
+            fallback: Vec::new(),
+            consistent_hash: consistent_hash.unwrap_or_default(),
+            maglev: None,
+        };
+        selector.update_targets(targets);
+        selector
+    }
+
+    /// takes over weights and active flags after a reload of the target table
+    pub fn update_targets(&mut self, targets: &Vec<TargetEntry>) {
+        self.weights = targets.iter().map(|t| t.config.weight.unwrap_or(1)).collect();
+        self.active = targets.iter().map(|t| t.active).collect();
+        let groups = &self.groups;
+        self.group = targets
+            .iter()
+            .map(|t| t.config.group.as_ref().map(|g| groups.id(g)))
+            .collect();
+        self.members = self
+            .backends
+            .iter()
+            .map(|ids| ids.as_ref().map(|ids| targets.iter().map(|t| ids.contains(&t.config.id)).collect()))
+            .collect();
+        self.max_connections = targets
+            .iter()
+            .map(|t| t.config.max_connections.map(|m| m as usize).or(self.default_max_connections))
+            .collect();
+        self.breaker_configs = targets.iter().map(|t| t.config.circuit_breaker.clone()).collect();
+        self.fallback = targets.iter().map(|t| t.config.is_fallback()).collect();
+        self.current.resize(targets.len(), 0);
+        if self.policy == SelectionPolicy::Maglev {
+            self.maglev = Some((
+                MaglevTable::new(targets, self.consistent_hash.table_size()),
+                self.consistent_hash.key(),
+            ));
+        }
+        if self.next >= targets.len() {
+            self.next = 0;
+        }
+    }
+
+    #[inline]
+    pub fn policy(&self) -> SelectionPolicy {
+        self.policy
+    }
+
+    /// the hashed part of the connection with SelectionPolicy::Maglev, None for the other policies
+    #[inline]
+    pub fn hash_key(&self) -> Option<HashKey> {
+        self.maglev.as_ref().map(|(_, key)| *key)
+    }
+
+    /// sample of the time from the SYN until the SYN-ACK of the target
+    #[inline]
+    pub fn record_syn_ack(&mut self, i: usize, cycles: u64) {
+        ResponseTimes::smooth(&mut self.response_times.syn_ack[i], cycles);
+        if self.breaker_configs[i].is_some() {
+            self.breakers.success(i, self.breaker_configs[i].as_ref().unwrap());
+        }
+    }
+
+    /// the target reset a connection or did not answer its SYN, see CircuitBreakerConfig
+    #[inline]
+    pub fn record_failure(&mut self, i: usize) {
+        if self.breaker_configs[i].is_some() {
+            self.breakers.failure(i, self.breaker_configs[i].as_ref().unwrap());
+        }
+    }
+
+    /// a connection is bound to the target, which may be a probe of its circuit breaker
+    #[inline]
+    pub fn bound(&mut self, i: usize) {
+        if self.breaker_configs[i].is_some() {
+            self.breakers.bound(i, self.breaker_configs[i].as_ref().unwrap());
+        }
+    }
+
+    /// true, if the target has no circuit breaker or its breaker lets a connection pass
+    #[inline]
+    pub fn breaker_allows(&self, i: usize) -> bool {
+        self.breaker_configs[i]
+            .as_ref()
+            .map_or(true, |config| self.breakers.allows(i, config))
+    }
+
+    /// sample of the time from forwarding the first client payload until the first payload of the target
+    #[inline]
+    pub fn record_first_byte(&mut self, i: usize, cycles: u64) {
+        ResponseTimes::smooth(&mut self.response_times.first_byte[i], cycles);
+    }
+
+    /// called periodically (once per second) by the pipeline
+    pub fn decay_response_times(&mut self) {
+        self.response_times.decay();
+    }
+
+    /// smoothed response time of the target in cycles, 0 if not measured yet
+    #[inline]
+    pub fn response_time(&self, i: usize) -> u64 {
+        self.response_times.get(i)
+    }
+
+    /// true, if the target has reached its maximum number of connections
+    #[inline]
+    pub fn at_capacity(&self, i: usize) -> bool {
+        self.max_connections[i].map_or(false, |max| self.load.global(i) >= max)
+    }
+
+    /// true, if the target is in no target group or in the active one
+    #[inline]
+    pub fn in_active_group(&self, i: usize) -> bool {
+        self.group[i].map_or(true, |g| self.groups.is_active(g))
+    }
+
+    /// true, if the target is active, in rotation, in the active target group, not at capacity and its circuit breaker
+    /// lets the connection pass
+    #[inline]
+    pub fn eligible(&self, i: usize) -> bool {
+        self.active[i] && self.health.in_rotation(i) && self.in_active_group(i) && !self.at_capacity(i) && self.breaker_allows(i)
+    }
+
+    /// true, if the frontend proxies to all targets or the target is in its backend
+    #[inline]
+    pub fn in_backend(&self, frontend: usize, i: usize) -> bool {
+        self.members
+            .get(frontend)
+            .and_then(|members| members.as_ref())
+            .map_or(true, |members| members[i])
+    }
+
+    /// true, if the target is eligible and in the backend of the frontend of c
+    #[inline]
+    pub fn eligible_for(&self, c: &ProxyConnection, i: usize) -> bool {
+        self.in_backend(c.frontend(), i) && self.eligible(i)
+    }
+
+    #[inline]
+    pub fn is_fallback(&self, i: usize) -> bool {
+        self.fallback[i]
+    }
+
+    /// true, if the target is eligible, in the backend of the frontend and no fallback target
+    #[inline]
+    fn primary_eligible(&self, frontend: usize, i: usize) -> bool {
+        !self.fallback[i] && self.in_backend(frontend, i) && self.eligible(i)
+    }
+
+    /// a target for the policy: the primary eligible targets or, if there is none, all targets of the backend
+    #[inline]
+    fn candidate(&self, frontend: usize, any_up: bool, i: usize) -> bool {
+        if any_up {
+            self.primary_eligible(frontend, i)
+        } else {
+            self.in_backend(frontend, i)
+        }
+    }
+
+    /// the eligible fallback target of the backend with the fewest connections
+    fn select_fallback(&self, frontend: usize) -> Option<usize> {
+        (0..self.weights.len())
+            .filter(|i| self.fallback[*i] && self.in_backend(frontend, *i) && self.eligible(*i))
+            .min_by_key(|i| self.load.get(*i))
+    }
+
+    /// A target for the retry of the SYN of c, which is eligible, has not failed for c and is accepted, e.g. because it
+    /// has the same PROXY protocol setting. None, if there is no such target.
+    /// The state of the policy is not changed, so that retries do not shift the distribution of later connections:
+    /// with round robin the targets are probed from the next one of the policy on, otherwise from the failed one on.
+    /// Fallback targets are only taken, if no other target is a candidate.
+    pub fn alternate<F>(&self, c: &ProxyConnection, accept: F) -> Option<usize>
+    where
+        F: Fn(usize) -> bool,
+    {
+        let n = self.weights.len();
+        let start = if self.policy == SelectionPolicy::RoundRobin { self.next } else { c.server_index() + 1 };
+        let candidate = |i: usize| i != c.server_index() && !c.tried_server(i) && self.eligible_for(c, i) && accept(i);
+        let probe = |fallback: bool| {
+            (0..n)
+                .map(|k| (start + k) % n)
+                .find(|i| self.fallback[*i] == fallback && candidate(*i))
+        };
+        probe(false).or_else(|| probe(true))
+    }
+
+    /// returns the index of the selected target of the backend of the frontend of c, fallback targets are skipped;
+    /// if no target is eligible, i.e. all are down or at capacity, a fallback target is selected,
+    /// without an eligible fallback target the targets are selected as if they were eligible
+    pub fn select(&mut self, c: &ProxyConnection) -> usize {
+        let n = self.weights.len();
+        let frontend = c.frontend();
+        let any_up = (0..n).any(|i| self.primary_eligible(frontend, i));
+        if !any_up {
+            let fallback = self.select_fallback(frontend);
+            if fallback.is_some() {
+                debug!("all targets are down, tripped or at capacity, selecting fallback target {}", fallback.unwrap());
+                return fallback.unwrap();
+            }
+            warn!("all targets are down, tripped or at capacity");
+        }
+        match self.policy {
+            SelectionPolicy::RoundRobin => {
+                let mut i = self.next;
+                for _ in 0..n {
+                    if self.candidate(frontend, any_up, i) {
+                        break;
+                    }
+                    i = if i + 1 < n { i + 1 } else { 0 };
+                }
+                self.next = if i + 1 < n { i + 1 } else { 0 };
+                i
+            }
+            SelectionPolicy::LeastConn => {
+                // minimum of load/weight, compared by cross-multiplication
+                let mut best = None;
+                for i in 0..n {
+                    if !self.candidate(frontend, any_up, i) {
+                        continue;
+                    }
+                    if best.is_none()
+                        || (self.load.get(i) as u64) * (self.weights[best.unwrap()] as u64)
+                            < (self.load.get(best.unwrap()) as u64) * (self.weights[i] as u64)
+                    {
+                        best = Some(i);
+                    }
+                }
+                best.unwrap_or(0)
+            }
+            SelectionPolicy::SrcIpHash => {
+                let mut hasher = FnvHasher::default();
+                hasher.write_u128(c.client_sock().map(|s| s.0).unwrap_or(0));
+                // without targets 0 as for the other policies, validate rejects a configuration without targets
+                let mut i = if n > 0 { (hasher.finish() % n as u64) as usize } else { 0 };
+                // probe for the next target which is up, so that only clients of a down target are remapped
+                for _ in 0..n {
+                    if self.candidate(frontend, any_up, i) {
+                        break;
+                    }
+                    i = if i + 1 < n { i + 1 } else { 0 };
+                }
+                i
+            }
+            SelectionPolicy::Weighted => {
+                // smooth weighted round robin
+                let mut best = None;
+                let mut total_weight = 0;
+                for i in 0..n {
+                    if !self.candidate(frontend, any_up, i) {
+                        continue;
+                    }
+                    self.current[i] += self.weights[i] as i64;
+                    total_weight += self.weights[i] as i64;
+                    if best.is_none() || self.current[i] > self.current[best.unwrap()] {
+                        best = Some(i);
+                    }
+                }
+                let best = best.unwrap_or(0);
+                self.current[best] -= total_weight;
+                best
+            }
+            SelectionPolicy::LeastResponseTime => {
+                // minimum of (response time + 1) * (load + 1) / weight, compared by cross-multiplication
+                let score = |i: usize| (self.response_times.get(i) as u128 + 1) * (self.load.get(i) as u128 + 1);
+                let mut best = None;
+                for i in 0..n {
+                    if !self.candidate(frontend, any_up, i) {
+                        continue;
+                    }
+                    if best.is_none()
+                        || score(i) * (self.weights[best.unwrap()] as u128) < score(best.unwrap()) * (self.weights[i] as u128)
+                    {
+                        best = Some(i);
+                    }
+                }
+                best.unwrap_or(0)
+            }
+            SelectionPolicy::Maglev => {
+                let (table, key) = self.maglev.as_ref().unwrap();
+                let hash = hash_of(c, *key);
+                let mut i = table.lookup(hash, 0).unwrap_or(0);
+                // further entries of the table, so that only the keys of a down target are remapped
+                let mut k = 1;
+                while !self.candidate(frontend, any_up, i) && k <= MAX_MAGLEV_PROBES {
+                    i = table.lookup(hash, k).unwrap_or(0);
+                    k += 1;
+                }
+                for _ in 0..n {
+                    if self.candidate(frontend, any_up, i) {
+                        break;
+                    }
+                    i = if i + 1 < n { i + 1 } else { 0 };
+                }
+                i
+            }
+        }
+    }
+}
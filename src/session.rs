@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use bincode;
+
+use ha::ConnectionState;
+
+/// changed with each incompatible change of ConnectionState
+const SESSION_FILE_VERSION: u32 = 1;
+/// time the pipelines get to report their connections, they report on their next timer tick
+const EXPORT_WAIT_MS: u64 = 100;
+
+#[derive(Serialize, Deserialize)]
+struct SessionFile {
+    version: u32,
+    /// connection states per pipeline
+    pipelines: HashMap<String, Vec<ConnectionState>>,
+}
+
+/// The connection table of all pipelines, written to EngineConfig.session_file on shutdown and read from it on
+/// startup, e.g. for a binary upgrade. The pipelines of the new engine restore the spliced connections with their
+/// slots and sequence number state, the endpoints retransmit the packets, which got lost during the restart.
+/// The configuration of both engines must be the same, in particular the cores and the addresses of the pipelines.
+#[derive(Clone)]
+pub struct SessionTable {
+    requested: Arc<AtomicUsize>,
+    exports: Arc<Mutex<HashMap<String, (usize, Vec<ConnectionState>)>>>,
+    imports: Arc<Mutex<HashMap<String, Vec<ConnectionState>>>>,
+}
+
+impl SessionTable {
+    pub fn new() -> SessionTable {
+        SessionTable {
+            requested: Arc::new(AtomicUsize::new(0)),
+            exports: Arc::new(Mutex::new(HashMap::new())),
+            imports: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    #[inline]
+    pub fn requested(&self) -> usize {
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// called by the pipelines on their timer tick, when the request number has changed
+    pub fn report(&self, pipeline: &str, request: usize, connections: Vec<ConnectionState>) {
+        self.exports
+            .lock()
+            .unwrap()
+            .insert(pipeline.to_string(), (request, connections));
+    }
+
+    /// the connections of the pipeline read from the session file, called once by the pipeline on its setup
+    pub fn take(&self, pipeline: &str) -> Option<Vec<ConnectionState>> {
+        self.imports.lock().unwrap().remove(pipeline)
+    }
+
+    /// reads the session file, returns the number of connections
+    pub fn load(&self, path: &str) -> Result<usize, String> {
+        let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+        let sessions: SessionFile =
+            bincode::deserialize_from(BufReader::new(file)).map_err(|e| format!("cannot read {}: {}", path, e))?;
+        if sessions.version != SESSION_FILE_VERSION {
+            return Err(format!("{} has version {}, expected {}", path, sessions.version, SESSION_FILE_VERSION));
+        }
+        let count = sessions.pipelines.values().map(|connections| connections.len()).sum();
+        *self.imports.lock().unwrap() = sessions.pipelines;
+        Ok(count)
+    }
+
+    /// requests the connections of all pipelines and writes those reported in time to the session file,
+    /// returns the number of connections
+    pub fn save(&self, path: &str) -> Result<usize, String> {
+        let request = self.requested.fetch_add(1, Ordering::AcqRel) + 1;
+        thread::sleep(Duration::from_millis(EXPORT_WAIT_MS));
+        let pipelines: HashMap<String, Vec<ConnectionState>> = self
+            .exports
+            .lock()
+            .unwrap()
+            .drain()
+            .filter(|(_, (r, _))| *r == request)
+            .map(|(pipeline, (_, connections))| (pipeline, connections))
+            .collect();
+        let count = pipelines.values().map(|connections| connections.len()).sum();
+        let sessions = SessionFile {
+            version: SESSION_FILE_VERSION,
+            pipelines,
+        };
+        let file = File::create(path).map_err(|e| format!("cannot create {}: {}", path, e))?;
+        bincode::serialize_into(BufWriter::new(file), &sessions).map_err(|e| format!("cannot write {}: {}", path, e))?;
+        Ok(count)
+    }
+}
@@ -0,0 +1,311 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::RecvTimeoutError;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use std::thread;
+use std::cmp;
+use std::arch::x86_64::_rdtsc;
+
+use e2d2::interface::Pdu;
+use nix::sched::{setns, CloneFlags};
+
+use channel::{bounded, BoundedReceiver, BoundedSender, ChannelStats, DEFAULT_QUEUE_SIZE};
+
+const SFLOW_VERSION: u32 = 5;
+const ADDRESS_TYPE_IPV4: u32 = 1;
+/// sample format and record format of the standard enterprise 0
+const FLOW_SAMPLE: u32 = 1;
+const RAW_PACKET_HEADER: u32 = 1;
+const HEADER_PROTOCOL_ETHERNET: u32 = 1;
+/// the FCS is removed by the NIC
+const FCS_SIZE: u32 = 4;
+const DEFAULT_ONE_IN: u32 = 1000;
+const DEFAULT_HEADER_SIZE: usize = 128;
+/// datagram header of an IPv4 agent
+const DATAGRAM_HEADER_SIZE: usize = 28;
+/// fits into the MTU of the KNI interface with IP and UDP header
+const MAX_DATAGRAM_SIZE: usize = 1400;
+/// pending samples are sent after this time
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Random sampling of the packets received by the pipelines, the headers of the sampled frames are sent as sFlow
+/// version 5 flow samples to a collector. Each pipeline is a data source of its own, its index is the port id in the
+/// upper and the rx queue in the lower 16 bits.
+#[derive(Deserialize, Clone)]
+pub struct SflowConfig {
+    /// address of the collector, e.g. "192.168.222.2:6343"
+    pub collector: SocketAddr,
+    /// agent address of the datagrams, e.g. the address of the KNI interface
+    pub agent: Ipv4Addr,
+    /// on average one in this number of packets is sampled, defaults to 1000
+    pub one_in: Option<u32>,
+    /// maximum number of bytes of a sampled frame sent to the collector, defaults to 128
+    pub header_size: Option<usize>,
+    /// network namespace of the exporter, e.g. the one of the KNI interface, defaults to the namespace of the engine
+    pub namespace: Option<String>,
+    /// samples queued for the exporter, further samples are dropped, defaults to 65536
+    pub queue_size: Option<usize>,
+}
+
+impl SflowConfig {
+    #[inline]
+    pub fn one_in(&self) -> u32 {
+        self.one_in.unwrap_or(DEFAULT_ONE_IN)
+    }
+
+    #[inline]
+    pub fn header_size(&self) -> usize {
+        self.header_size.unwrap_or(DEFAULT_HEADER_SIZE)
+    }
+}
+
+/// a sampled frame as sent to the exporter
+pub struct PacketSample {
+    source_id: u32,
+    sequence: u32,
+    sampling_rate: u32,
+    /// packets seen by the sampler
+    pool: u32,
+    input: u32,
+    frame_length: u32,
+    header: Vec<u8>,
+}
+
+/// the sampler of a pipeline
+pub struct PacketSampler {
+    tx: BoundedSender<PacketSample>,
+    source_id: u32,
+    input: u32,
+    one_in: u32,
+    header_size: usize,
+    /// packets until the next sample
+    skip: u32,
+    pool: u32,
+    sequence: u32,
+    random: u64,
+}
+
+impl PacketSampler {
+    /// xorshift, the interval until the next sample is uniform in 1..2 * one_in
+    #[inline]
+    fn next_skip(&mut self) -> u32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        if self.one_in <= 1 {
+            1
+        } else {
+            (self.random % (2 * self.one_in as u64 - 1)) as u32 + 1
+        }
+    }
+
+    /// called with each received frame
+    #[inline]
+    pub fn packet(&mut self, p: &Pdu) {
+        self.pool = self.pool.wrapping_add(1);
+        self.skip -= 1;
+        if self.skip > 0 {
+            return;
+        }
+        self.skip = self.next_skip();
+        self.sequence = self.sequence.wrapping_add(1);
+        let mac = p.headers().mac(0);
+        let payload = p.get_payload(0);
+        let mut header = Vec::with_capacity(self.header_size);
+        header.extend_from_slice(mac.dst.as_bytes());
+        header.extend_from_slice(mac.src.as_bytes());
+        header.push((mac.etype() >> 8) as u8);
+        header.push(mac.etype() as u8);
+        header.extend_from_slice(&payload[..cmp::min(payload.len(), self.header_size.saturating_sub(14))]);
+        header.truncate(self.header_size);
+        let sample = PacketSample {
+            source_id: self.source_id,
+            sequence: self.sequence,
+            sampling_rate: self.one_in,
+            pool: self.pool,
+            input: self.input,
+            frame_length: p.data_len() as u32 + FCS_SIZE,
+            header,
+        };
+        // dropped, while the exporter falls behind
+        self.tx.send(sample);
+    }
+}
+
+#[inline]
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+/// the flow sample with a raw packet header record
+fn encode_sample(sample: &PacketSample) -> Vec<u8> {
+    let padded = (sample.header.len() + 3) & !3;
+    let record_length = 16 + padded;
+    let sample_length = 32 + 8 + record_length;
+    let mut buf = Vec::with_capacity(8 + sample_length);
+    put_u32(&mut buf, FLOW_SAMPLE);
+    put_u32(&mut buf, sample_length as u32);
+    put_u32(&mut buf, sample.sequence);
+    put_u32(&mut buf, sample.source_id);
+    put_u32(&mut buf, sample.sampling_rate);
+    put_u32(&mut buf, sample.pool);
+    // drops
+    put_u32(&mut buf, 0);
+    put_u32(&mut buf, sample.input);
+    // output, unknown
+    put_u32(&mut buf, 0);
+    // number of records
+    put_u32(&mut buf, 1);
+    put_u32(&mut buf, RAW_PACKET_HEADER);
+    put_u32(&mut buf, record_length as u32);
+    put_u32(&mut buf, HEADER_PROTOCOL_ETHERNET);
+    put_u32(&mut buf, sample.frame_length);
+    // bytes removed from the frame before the header was taken
+    put_u32(&mut buf, FCS_SIZE);
+    put_u32(&mut buf, sample.header.len() as u32);
+    buf.extend_from_slice(&sample.header);
+    buf.resize(8 + sample_length, 0);
+    buf
+}
+
+fn enter_namespace(ns: &str) {
+    let path = format!("/var/run/netns/{}", ns);
+    match File::open(&path) {
+        Ok(f) => {
+            if let Err(e) = setns(f.as_raw_fd(), CloneFlags::CLONE_NEWNET) {
+                error!("sflow export cannot enter namespace {}: {}", ns, e);
+            }
+        }
+        Err(e) => error!("sflow export cannot open {}: {}", path, e),
+    }
+}
+
+struct Exporter {
+    socket: UdpSocket,
+    config: SflowConfig,
+    /// datagrams sent so far
+    sequence: u32,
+    started: Instant,
+}
+
+impl Exporter {
+    fn flush(&mut self, samples: &mut Vec<u8>, count: &mut u32) {
+        if *count == 0 {
+            return;
+        }
+        self.sequence = self.sequence.wrapping_add(1);
+        let uptime = self.started.elapsed();
+        let mut datagram = Vec::with_capacity(DATAGRAM_HEADER_SIZE + samples.len());
+        put_u32(&mut datagram, SFLOW_VERSION);
+        put_u32(&mut datagram, ADDRESS_TYPE_IPV4);
+        datagram.extend_from_slice(&self.config.agent.octets());
+        // sub agent id
+        put_u32(&mut datagram, 0);
+        put_u32(&mut datagram, self.sequence);
+        put_u32(&mut datagram, (uptime.as_secs() * 1000 + uptime.subsec_millis() as u64) as u32);
+        put_u32(&mut datagram, *count);
+        datagram.extend_from_slice(samples);
+        samples.clear();
+        *count = 0;
+        // samples are lost, while the collector is not reachable
+        if let Err(e) = self.socket.send_to(&datagram, &self.config.collector) {
+            warn!("sflow export to {} failed: {}", self.config.collector, e);
+        }
+    }
+}
+
+fn run_exporter(config: SflowConfig, rx: BoundedReceiver<PacketSample>) {
+    if config.namespace.is_some() {
+        enter_namespace(config.namespace.as_ref().unwrap());
+    }
+    let bind_address = if config.collector.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+    let socket = match UdpSocket::bind(bind_address) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("sflow export cannot bind a socket: {}", e);
+            return;
+        }
+    };
+    info!("sflow export to {}", config.collector);
+    let mut exporter = Exporter {
+        socket,
+        config,
+        sequence: 0,
+        started: Instant::now(),
+    };
+    let mut samples = Vec::with_capacity(MAX_DATAGRAM_SIZE);
+    let mut count = 0u32;
+    let mut last_flush = Instant::now();
+    loop {
+        match rx.recv_timeout(FLUSH_INTERVAL) {
+            Ok(sample) => {
+                let encoded = encode_sample(&sample);
+                if DATAGRAM_HEADER_SIZE + samples.len() + encoded.len() > MAX_DATAGRAM_SIZE {
+                    exporter.flush(&mut samples, &mut count);
+                    last_flush = Instant::now();
+                }
+                samples.extend_from_slice(&encoded);
+                count += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => {
+                exporter.flush(&mut samples, &mut count);
+                return;
+            }
+        }
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            exporter.flush(&mut samples, &mut count);
+            last_flush = Instant::now();
+        }
+    }
+}
+
+/// Exports the packet samples of all pipelines to the sFlow collector, see SflowConfig.
+#[derive(Clone)]
+pub struct SflowExporter {
+    current: Arc<Mutex<Option<(SflowConfig, BoundedSender<PacketSample>)>>>,
+}
+
+impl SflowExporter {
+    pub fn new() -> SflowExporter {
+        SflowExporter {
+            current: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// starts the exporter thread, pipelines must be set up afterwards to get a sampler
+    pub fn start(&self, config: &SflowConfig) -> thread::JoinHandle<()> {
+        let (tx, rx) = bounded(config.queue_size.unwrap_or(DEFAULT_QUEUE_SIZE));
+        *self.current.lock().unwrap() = Some((config.clone(), tx));
+        let config = config.clone();
+        thread::spawn(move || run_exporter(config, rx))
+    }
+
+    /// the sampler of the pipeline of the port and rx queue, None if packets are not sampled
+    pub fn sampler(&self, port_id: u16, rxq: u16) -> Option<PacketSampler> {
+        self.current.lock().unwrap().as_ref().map(|(config, tx)| {
+            let random = unsafe { _rdtsc() } | 1;
+            let mut sampler = PacketSampler {
+                tx: tx.clone(),
+                source_id: (port_id as u32) << 16 | rxq as u32,
+                // ifIndex 0 means unknown
+                input: port_id as u32 + 1,
+                one_in: config.one_in(),
+                header_size: config.header_size(),
+                skip: 1,
+                pool: 0,
+                sequence: 0,
+                random,
+            };
+            sampler.skip = sampler.next_skip();
+            sampler
+        })
+    }
+
+    /// None, if packets are not sampled
+    pub fn stats(&self) -> Option<ChannelStats> {
+        self.current.lock().unwrap().as_ref().map(|(_, tx)| tx.stats())
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use reload::TargetEntry;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const EXTENSION_ALPN: u16 = 0x0010;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+
+#[inline]
+fn read_u16(buf: &[u8], pos: usize) -> Option<u16> {
+    if pos + 2 <= buf.len() {
+        Some((buf[pos] as u16) << 8 | buf[pos + 1] as u16)
+    } else {
+        None
+    }
+}
+
+/// true, if payload starts with a TLS record, which is not complete, e.g. a ClientHello split into several segments
+pub fn client_hello_incomplete(payload: &[u8]) -> bool {
+    if payload.is_empty() || payload[0] != TLS_HANDSHAKE || payload.len() > 1 && payload[1] != 0x03 {
+        return false;
+    }
+    match read_u16(payload, 3) {
+        Some(length) => 5 + length as usize > payload.len(),
+        None => true,
+    }
+}
+
+/// Returns the data of the extension, if payload starts with a TLS ClientHello, which has the extension.
+fn client_hello_extension(payload: &[u8], extension: u16) -> Option<&[u8]> {
+    // record header: type, version (2), length (2)
+    if payload.len() < 5 || payload[0] != TLS_HANDSHAKE || payload[1] != 0x03 {
+        return None;
+    }
+    let record_end = 5 + read_u16(payload, 3)? as usize;
+    let buf = &payload[..if record_end < payload.len() { record_end } else { payload.len() }];
+    // handshake header: type, length (3)
+    if buf.len() < 9 || buf[5] != CLIENT_HELLO {
+        return None;
+    }
+    // client version (2), random (32)
+    let mut pos = 9 + 2 + 32;
+    // session id
+    pos += 1 + *buf.get(pos)? as usize;
+    // cipher suites
+    pos += 2 + read_u16(buf, pos)? as usize;
+    // compression methods
+    pos += 1 + *buf.get(pos)? as usize;
+    let extensions_end = pos + 2 + read_u16(buf, pos)? as usize;
+    pos += 2;
+    while pos + 4 <= extensions_end {
+        let ext_type = read_u16(buf, pos)?;
+        let ext_len = read_u16(buf, pos + 2)? as usize;
+        pos += 4;
+        if ext_type == extension {
+            return buf.get(pos..pos + ext_len);
+        }
+        pos += ext_len;
+    }
+    None
+}
+
+/// Returns the server name of the SNI extension, if payload starts with a TLS ClientHello.
+/// The ClientHello must be contained in the payload, i.e. in the first segment of the client or in the
+/// segments buffered before the server is selected, see PayloadBuffering.
+pub fn parse_sni(payload: &[u8]) -> Option<String> {
+    let ext = client_hello_extension(payload, EXTENSION_SERVER_NAME)?;
+    // server name list length (2), name type, name length (2), name
+    if *ext.get(2)? != NAME_TYPE_HOST_NAME {
+        return None;
+    }
+    let name_len = read_u16(ext, 3)? as usize;
+    let name = ext.get(5..5 + name_len)?;
+    String::from_utf8(name.to_vec()).ok().map(|s| s.to_lowercase())
+}
+
+/// Returns the protocols of the ALPN extension in the order of preference of the client, e.g. ["h2", "http/1.1"],
+/// if payload starts with a TLS ClientHello, see parse_sni.
+pub fn parse_alpn(payload: &[u8]) -> Option<Vec<String>> {
+    let ext = client_hello_extension(payload, EXTENSION_ALPN)?;
+    // protocol name list length (2), then length prefixed names
+    let list_end = 2 + read_u16(ext, 0)? as usize;
+    let list = ext.get(2..list_end)?;
+    let mut protocols = Vec::new();
+    let mut pos = 0;
+    while pos < list.len() {
+        let len = list[pos] as usize;
+        let name = list.get(pos + 1..pos + 1 + len)?;
+        protocols.push(String::from_utf8(name.to_vec()).ok()?);
+        pos += 1 + len;
+    }
+    if protocols.is_empty() {
+        None
+    } else {
+        Some(protocols)
+    }
+}
+
+/// Maps server names to targets, built from the sni_map of the engine configuration (server name -> target id).
+/// A name starting with "*." matches all sub-domains.
+#[derive(Clone)]
+pub struct SniMap {
+    exact: HashMap<String, usize>,
+    wildcards: Vec<(String, usize)>,
+}
+
+impl SniMap {
+    pub fn new(sni_map: &Option<HashMap<String, String>>, targets: &Vec<TargetEntry>) -> SniMap {
+        let mut map = SniMap {
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+        };
+        if sni_map.is_some() {
+            for (name, id) in sni_map.as_ref().unwrap() {
+                // only active targets are candidates, the table may contain inactive entries with the same id
+                match targets.iter().position(|t| t.active && &t.config.id == id) {
+                    Some(index) => {
+                        let name = name.to_lowercase();
+                        if name.starts_with("*.") {
+                            map.wildcards.push((name[1..].to_string(), index));
+                        } else {
+                            map.exact.insert(name, index);
+                        }
+                    }
+                    None => warn!("sni_map: unknown target id {} for server name {}", id, name),
+                }
+            }
+            // the longest suffix wins
+            map.wildcards.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        }
+        map
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.wildcards.is_empty()
+    }
+
+    /// returns the index of the target for the server name
+    pub fn lookup(&self, server_name: &str) -> Option<usize> {
+        if let Some(index) = self.exact.get(server_name) {
+            return Some(*index);
+        }
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| server_name.ends_with(suffix.as_str()))
+            .map(|(_, index)| *index)
+    }
+}
+
+/// Maps the protocols of the ALPN extension to targets, built from the alpn_map of the engine configuration
+/// (protocol -> target id), e.g. "h2" = "server1", "http/1.1" = "server2".
+#[derive(Clone)]
+pub struct AlpnMap(HashMap<String, usize>);
+
+impl AlpnMap {
+    pub fn new(alpn_map: &Option<HashMap<String, String>>, targets: &Vec<TargetEntry>) -> AlpnMap {
+        let mut map = HashMap::new();
+        if alpn_map.is_some() {
+            for (protocol, id) in alpn_map.as_ref().unwrap() {
+                match targets.iter().position(|t| t.active && &t.config.id == id) {
+                    Some(index) => {
+                        map.insert(protocol.clone(), index);
+                    }
+                    None => warn!("alpn_map: unknown target id {} for protocol {}", id, protocol),
+                }
+            }
+        }
+        AlpnMap(map)
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// returns the index of the target of the first protocol of the client, which is in the map
+    pub fn lookup(&self, protocols: &Vec<String>) -> Option<usize> {
+        protocols.iter().filter_map(|protocol| self.0.get(protocol)).next().cloned()
+    }
+}
@@ -0,0 +1,182 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnet::IpNet;
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+
+use reload::TargetEntry;
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTHENTICATION: u8 = 0x00;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REPLY_SUCCEEDED: u8 = 0x00;
+pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
+pub const REPLY_NOT_ALLOWED: u8 = 0x02;
+pub const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+#[derive(Deserialize, Clone)]
+pub struct Socks5Config {
+    /// allowed destination networks, e.g. ["192.168.222.0/24"], if None, all targets are allowed
+    pub allow: Option<Vec<String>>,
+}
+
+/// negotiation state of a SOCKS5 connection
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Socks5State {
+    Greeting,
+    Request,
+    /// the server has been selected by the CONNECT request
+    Connected,
+}
+
+#[derive(Clone, Debug)]
+pub enum Destination {
+    Ip(IpAddr, u16),
+    Name(String, u16),
+}
+
+/// returns Some(true) if the greeting offers "no authentication", None if it is not a SOCKS5 greeting
+pub fn parse_greeting(payload: &[u8]) -> Option<bool> {
+    if payload.len() < 2 || payload[0] != SOCKS_VERSION || payload.len() < 2 + payload[1] as usize {
+        return None;
+    }
+    Some(payload[2..2 + payload[1] as usize].contains(&METHOD_NO_AUTHENTICATION))
+}
+
+/// the reply to a greeting
+pub fn greeting_reply(accepted: bool) -> Vec<u8> {
+    vec![
+        SOCKS_VERSION,
+        if accepted { METHOD_NO_AUTHENTICATION } else { METHOD_NOT_ACCEPTABLE },
+    ]
+}
+
+/// parses a CONNECT request, the error is the reply code
+pub fn parse_connect_request(payload: &[u8]) -> Result<Destination, u8> {
+    if payload.len() < 7 || payload[0] != SOCKS_VERSION {
+        return Err(REPLY_GENERAL_FAILURE);
+    }
+    if payload[1] != CMD_CONNECT {
+        return Err(REPLY_COMMAND_NOT_SUPPORTED);
+    }
+    let port_at = |pos: usize| -> Result<u16, u8> {
+        if payload.len() < pos + 2 {
+            Err(REPLY_GENERAL_FAILURE)
+        } else {
+            Ok((payload[pos] as u16) << 8 | payload[pos + 1] as u16)
+        }
+    };
+    match payload[3] {
+        ATYP_IPV4 => {
+            let port = port_at(8)?;
+            Ok(Destination::Ip(
+                IpAddr::V4(Ipv4Addr::new(payload[4], payload[5], payload[6], payload[7])),
+                port,
+            ))
+        }
+        ATYP_IPV6 => {
+            let port = port_at(20)?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[4..20]);
+            Ok(Destination::Ip(IpAddr::V6(Ipv6Addr::from(octets)), port))
+        }
+        ATYP_DOMAIN => {
+            let len = payload[4] as usize;
+            let port = port_at(5 + len)?;
+            match String::from_utf8(payload[5..5 + len].to_vec()) {
+                Ok(name) => Ok(Destination::Name(name.to_lowercase(), port)),
+                Err(_) => Err(REPLY_GENERAL_FAILURE),
+            }
+        }
+        _ => Err(REPLY_ADDRESS_TYPE_NOT_SUPPORTED),
+    }
+}
+
+/// the reply to a CONNECT request with the bound address of the proxy
+pub fn connect_reply(code: u8, bound: (Ipv4Addr, u16)) -> Vec<u8> {
+    let mut reply = vec![SOCKS_VERSION, code, 0x00, ATYP_IPV4];
+    reply.extend_from_slice(&bound.0.octets());
+    reply.push((bound.1 >> 8) as u8);
+    reply.push(bound.1 as u8);
+    reply
+}
+
+/// Resolves the destinations of CONNECT requests to targets. As the engine needs the L2 address of a server,
+/// destinations must be configured targets: an ip destination matches a target by address and port,
+/// a name destination matches a target by its id and port.
+pub struct Socks5Resolver {
+    allow: Vec<IpNet>,
+    targets: Vec<TargetEntry>,
+}
+
+impl Socks5Resolver {
+    pub fn new(config: &Socks5Config, targets: &Vec<TargetEntry>) -> Socks5Resolver {
+        let mut allow = Vec::new();
+        if config.allow.is_some() {
+            for net in config.allow.as_ref().unwrap() {
+                match net.parse::<IpNet>() {
+                    Ok(net) => allow.push(net),
+                    Err(e) => error!("socks5: invalid network {} in allow: {}", net, e),
+                }
+            }
+        }
+        Socks5Resolver {
+            allow,
+            targets: targets.clone(),
+        }
+    }
+
+    pub fn update_targets(&mut self, targets: &Vec<TargetEntry>) {
+        self.targets = targets.clone();
+    }
+
+    #[inline]
+    fn allowed(&self, ip: &IpAddr) -> bool {
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+
+    /// returns the index of the target, the error is the reply code
+    pub fn resolve(&self, destination: &Destination) -> Result<usize, u8> {
+        let index = match destination {
+            Destination::Ip(ip, port) => self
+                .targets
+                .iter()
+                .position(|t| t.active && t.config.ip == *ip && t.config.port == *port),
+            Destination::Name(name, port) => self
+                .targets
+                .iter()
+                .position(|t| t.active && t.config.id.to_lowercase() == *name && t.config.port == *port),
+        };
+        match index {
+            Some(index) if self.allowed(&self.targets[index].config.ip) => Ok(index),
+            Some(_) => Err(REPLY_NOT_ALLOWED),
+            None => Err(REPLY_HOST_UNREACHABLE),
+        }
+    }
+}
+
+/// replaces the tcp payload of p by data and adapts the ip length, returns false if the tailroom of p is too small.
+/// checksums must be recalculated afterwards
+pub fn replace_payload(p: &mut Pdu, data: &[u8]) -> bool {
+    let payload_sz = tcp_payload_size(p);
+    if data.len() > payload_sz {
+        if p.get_tailroom() < data.len() - payload_sz {
+            return false;
+        }
+        p.add_padding(data.len() - payload_sz);
+    }
+    {
+        let ip = p.headers_mut().ip_mut(1);
+        let length = ip.length();
+        ip.set_length(length - payload_sz as u16 + data.len() as u16);
+    }
+    p.get_payload_mut(2)[..data.len()].copy_from_slice(data);
+    true
+}
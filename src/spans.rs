@@ -0,0 +1,57 @@
+use std::fs::OpenOptions;
+use std::net::IpAddr;
+
+use tracing;
+use tracing::{Level, Span};
+use tracing_appender;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_subscriber;
+
+use netfcts::comm::PipelineId;
+
+/// Tracing of the connections with the tracing crate: each connection has a span with the client socket and the
+/// selected server, the state transitions and the release are events of the span. The spans and events are formatted
+/// and written to the file by a background thread, the pipelines only queue them.
+#[derive(Deserialize, Clone)]
+pub struct TracingConfig {
+    /// file the spans and events are appended to
+    pub file: String,
+    /// maximum level, "trace" includes the state transitions, "debug" only the release of the connections,
+    /// defaults to "trace"
+    pub level: Option<String>,
+}
+
+/// Installs the global subscriber, which writes to the file of the configuration.
+/// The pending events are written, when the returned guard is dropped.
+pub fn init_tracing(config: &TracingConfig) -> Result<WorkerGuard, String> {
+    let level = match config.level.as_ref() {
+        Some(level) => level.parse::<Level>().map_err(|_| format!("invalid tracing level {}", level))?,
+        None => Level::TRACE,
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.file)
+        .map_err(|e| format!("cannot open {}: {}", config.file, e))?;
+    let (writer, guard) = tracing_appender::non_blocking(file);
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(writer)
+        .with_max_level(level)
+        .with_ansi(false)
+        .finish();
+    tracing::subscriber::set_global_default(subscriber).map_err(|e| format!("cannot install tracing subscriber: {}", e))?;
+    info!("tracing connections into {}", config.file);
+    Ok(guard)
+}
+
+/// the span of a connection, created when the SYN of the client is received, the server is recorded when it is selected
+pub fn connection_span(pipeline_id: &PipelineId, client: Option<(IpAddr, u16)>, port: u16) -> Span {
+    ::tracing::span!(
+        Level::TRACE,
+        "connection",
+        pipeline = %pipeline_id,
+        client = ?client,
+        port,
+        server = ::tracing::field::Empty
+    )
+}
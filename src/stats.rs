@@ -0,0 +1,229 @@
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use e2d2::native::zcsi::mbuf_avail_count;
+use netfcts::comm::PipelineId;
+
+use close::CloseReasonCounts;
+
+/// counters of the engine, which are not part of the TcpCounter of netfcts
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PipelineCounters {
+    /// SYNs refused by the rate limiter
+    pub syn_rate_limited: u64,
+    /// SYNs dropped by the access control list
+    pub syn_acl_denied: u64,
+    /// packets received from the physical port
+    pub rx_packets: u64,
+    /// packets sent to the physical port, without the packets generated by the pipeline
+    pub tx_packets: u64,
+    /// received packets passed on to the KNI interface or to the udp pipeline
+    pub kni_packets: u64,
+    /// received packets which were dropped
+    pub dropped_packets: u64,
+    /// connections in use, at the time of publishing
+    pub active_connections: u64,
+    /// events scheduled in the timer wheel, at the time of publishing
+    pub wheel_occupancy: u64,
+    /// timeouts scheduled beyond the maximum timeout of the timer wheel, which expired too early
+    pub wheel_overflows: u64,
+    /// slots of released connections in TIME_WAIT, at the time of publishing, see TimeWaitConfig
+    pub time_wait_slots: u64,
+    /// 1, if the NIC computes the checksums of the pipeline, the number of such pipelines when added up, see OffloadConfig
+    pub checksum_offload: u64,
+    /// packets held back by the pacer and sent on a later tick, see PacingConfig
+    pub paced_packets: u64,
+    /// packets dropped, because the queue of the pacer was full
+    pub pacing_drops: u64,
+    /// state transitions, which are not in the transition tables, see EngineConfig.state_audit
+    pub state_violations: u64,
+    /// SYNs sent to another server, after the selected one reset the SYN or did not answer it, see SynRetryConfig
+    pub syn_retries: u64,
+    /// fragmented datagrams, which have been reassembled, see FragmentConfig
+    pub reassembled_datagrams: u64,
+    /// incomplete datagrams in reassembly, at the time of publishing
+    pub reassembly_pending: u64,
+    /// fragments dropped, because they were malformed, exceeded the limits or their datagram expired
+    pub fragment_drops: u64,
+    /// datagrams fragmented, because they exceeded the MTU of the outgoing leg
+    pub fragmented_datagrams: u64,
+    /// connections taken over from the snapshot of the active engine, see HaConfig
+    pub replicated_connections: u64,
+    /// records of connections, whose state was allocated on the heap, because the arena of the pipeline was empty
+    pub arena_misses: u64,
+    /// forwarded packets, whose processing allocated heap memory, only counted with the feature alloc_audit
+    pub allocating_packets: u64,
+    /// released connections per close reason
+    pub close_reasons: CloseReasonCounts,
+}
+
+impl PipelineCounters {
+    pub fn add(&mut self, other: &PipelineCounters) {
+        self.syn_rate_limited += other.syn_rate_limited;
+        self.syn_acl_denied += other.syn_acl_denied;
+        self.rx_packets += other.rx_packets;
+        self.tx_packets += other.tx_packets;
+        self.kni_packets += other.kni_packets;
+        self.dropped_packets += other.dropped_packets;
+        self.active_connections += other.active_connections;
+        self.wheel_occupancy += other.wheel_occupancy;
+        self.wheel_overflows += other.wheel_overflows;
+        self.time_wait_slots += other.time_wait_slots;
+        self.checksum_offload += other.checksum_offload;
+        self.paced_packets += other.paced_packets;
+        self.pacing_drops += other.pacing_drops;
+        self.state_violations += other.state_violations;
+        self.syn_retries += other.syn_retries;
+        self.reassembled_datagrams += other.reassembled_datagrams;
+        self.reassembly_pending += other.reassembly_pending;
+        self.fragment_drops += other.fragment_drops;
+        self.fragmented_datagrams += other.fragmented_datagrams;
+        self.replicated_connections += other.replicated_connections;
+        self.arena_misses += other.arena_misses;
+        self.allocating_packets += other.allocating_packets;
+        self.close_reasons.add(&other.close_reasons);
+    }
+}
+
+impl fmt::Display for PipelineCounters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rx= {}, tx= {}, kni= {}, dropped= {}, active_connections= {}, wheel_occupancy= {}, wheel_overflows= {}, time_wait= {}, checksum_offload= {}, syn_rate_limited= {}, syn_acl_denied= {}, paced= {}, pacing_drops= {}, state_violations= {}, syn_retries= {}, reassembled= {}, reassembly_pending= {}, fragment_drops= {}, fragmented= {}, replicated= {}, arena_misses= {}, allocating= {}, closed: {}",
+            self.rx_packets,
+            self.tx_packets,
+            self.kni_packets,
+            self.dropped_packets,
+            self.active_connections,
+            self.wheel_occupancy,
+            self.wheel_overflows,
+            self.time_wait_slots,
+            self.checksum_offload,
+            self.syn_rate_limited,
+            self.syn_acl_denied,
+            self.paced_packets,
+            self.pacing_drops,
+            self.state_violations,
+            self.syn_retries,
+            self.reassembled_datagrams,
+            self.reassembly_pending,
+            self.fragment_drops,
+            self.fragmented_datagrams,
+            self.replicated_connections,
+            self.arena_misses,
+            self.allocating_packets,
+            self.close_reasons
+        )
+    }
+}
+
+/// Busy and idle cycles of the scheduler thread of a core, with busy polling the thread is always at 100% CPU.
+/// A scheduler round counts as busy, if the pipelines of the core received packets in the round, see CycleMeter.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CoreLoad {
+    pub busy_cycles: u64,
+    pub idle_cycles: u64,
+    pub rounds: u64,
+    /// busy cycles in percent of the cycles of the last period of publishing
+    pub utilization: f64,
+    /// cycles of the longest round of the last period of publishing
+    pub max_round_cycles: u64,
+}
+
+impl CoreLoad {
+    /// busy cycles in percent of all cycles since the start
+    pub fn average_utilization(&self) -> f64 {
+        let cycles = self.busy_cycles + self.idle_cycles;
+        if cycles > 0 {
+            self.busy_cycles as f64 * 100.0 / cycles as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+impl fmt::Display for CoreLoad {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "utilization= {:.1}%, average= {:.1}%, rounds= {}, max_round_cycles= {}",
+            self.utilization,
+            self.average_utilization(),
+            self.rounds,
+            self.max_round_cycles
+        )
+    }
+}
+
+/// The counters of all pipelines, each pipeline publishes its counters periodically on a timer tick,
+/// and the load of the cores.
+#[derive(Clone)]
+pub struct EngineStats(Arc<Mutex<HashMap<PipelineId, PipelineCounters>>>, Arc<Mutex<HashMap<i32, CoreLoad>>>);
+
+impl EngineStats {
+    pub fn new() -> EngineStats {
+        EngineStats(Arc::new(Mutex::new(HashMap::new())), Arc::new(Mutex::new(HashMap::new())))
+    }
+
+    pub fn publish_core(&self, core: i32, load: &CoreLoad) {
+        self.1.lock().unwrap().insert(core, *load);
+    }
+
+    /// the load of the cores, sorted by core
+    pub fn cores(&self) -> Vec<(i32, CoreLoad)> {
+        let mut cores: Vec<(i32, CoreLoad)> = self.1.lock().unwrap().iter().map(|(c, l)| (*c, *l)).collect();
+        cores.sort_by_key(|(core, _)| *core);
+        cores
+    }
+
+    pub fn publish(&self, pipeline_id: &PipelineId, counters: &PipelineCounters) {
+        self.0.lock().unwrap().insert(pipeline_id.clone(), *counters);
+    }
+
+    pub fn snapshot(&self) -> HashMap<PipelineId, PipelineCounters> {
+        self.0.lock().unwrap().clone()
+    }
+
+    /// sum over all pipelines
+    pub fn total(&self) -> PipelineCounters {
+        let mut total = PipelineCounters::default();
+        for counters in self.0.lock().unwrap().values() {
+            total.add(counters);
+        }
+        total
+    }
+}
+
+/// Starts a thread, which logs the packet rates and counters of each pipeline, the load of the cores and the available
+/// mbufs every interval.
+pub fn spawn_stats_logger(stats: EngineStats, interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let mut previous = stats.snapshot();
+        let mut last = Instant::now();
+        loop {
+            thread::sleep(interval);
+            let current = stats.snapshot();
+            let elapsed = last.elapsed();
+            last = Instant::now();
+            let seconds = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            for (pipeline_id, counters) in &current {
+                let before = previous.get(pipeline_id).cloned().unwrap_or_default();
+                info!(
+                    "{}: rx= {:.0} pps, tx= {:.0} pps, {}",
+                    pipeline_id,
+                    (counters.rx_packets - before.rx_packets) as f64 / seconds,
+                    (counters.tx_packets - before.tx_packets) as f64 / seconds,
+                    counters
+                );
+            }
+            for (core, load) in stats.cores() {
+                info!("core {}: {}", core, load);
+            }
+            info!("available mbufs in memory pool= {}", unsafe { mbuf_avail_count() });
+            previous = current;
+        }
+    })
+}
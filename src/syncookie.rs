@@ -0,0 +1,35 @@
+use std::hash::Hasher;
+
+use fnv::FnvHasher;
+
+use cmanager::ClientSock;
+
+/// a cookie is valid for two time slots
+const SLOT_SECONDS: u64 = 64;
+
+/// The initial sequence number of the proxy towards the client, when SYN cookies are used.
+/// The upper 8 bits are the time slot, the lower 24 bits a keyed hash of the client socket, the client isn and the slot.
+/// The MSS of the client is not encoded, towards the target the configured MSS is used, if any.
+#[inline]
+pub fn syn_cookie(secret: u64, client: &ClientSock, client_isn: u32, slot: u8) -> u32 {
+    let mut hasher = FnvHasher::default();
+    hasher.write_u64(secret);
+    hasher.write_u128(client.0);
+    hasher.write_u16(client.1);
+    hasher.write_u32(client_isn);
+    hasher.write_u8(slot);
+    (slot as u32) << 24 | (hasher.finish() as u32 & 0x00FF_FFFF)
+}
+
+/// the current time slot
+#[inline]
+pub fn cookie_slot(now: u64, cpu_clock: u64) -> u8 {
+    (now / cpu_clock / SLOT_SECONDS) as u8
+}
+
+/// checks the cookie, i.e. the ack number minus one of the client ACK, client_isn is the seq number of the ACK minus one
+#[inline]
+pub fn syn_cookie_valid(secret: u64, client: &ClientSock, client_isn: u32, cookie: u32, now_slot: u8) -> bool {
+    let slot = (cookie >> 24) as u8;
+    now_slot.wrapping_sub(slot) <= 1 && syn_cookie(secret, client, client_isn, slot) == cookie
+}
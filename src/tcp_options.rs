@@ -0,0 +1,254 @@
+use std::cmp;
+
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::tcp_payload_size;
+
+use proxy_protocol::insert_into_payload;
+use reload::TargetEntry;
+
+const TCP_HEADER_SIZE: usize = 20;
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+const OPTION_WINDOW_SCALE: u8 = 3;
+const OPTION_SACK_PERMITTED: u8 = 4;
+const OPTION_SACK: u8 = 5;
+/// RFC 7323
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// Handling of TCP options in the handshakes of the proxy. Options which are not enabled are stripped.
+/// Timestamps are always stripped: the proxy answers the client handshake before the timestamp clock of the server
+/// is known, and the delayed first payload packet of the client cannot carry options.
+/// Window scaling and SACK are not offered to clients with SYN cookies, as the cookie cannot encode them.
+#[derive(Deserialize, Clone)]
+pub struct TcpOptionsConfig {
+    /// if present, window scaling is offered to clients which announce it, with this shift (0 - 14);
+    /// the scale of the client is announced to the server and windows are rescaled, when the legs differ
+    pub window_scale: Option<u8>,
+    /// if true, SACK-permitted is passed through and the SACK blocks are translated to the sequence numbers of the other leg
+    pub sack: Option<bool>,
+}
+
+/// options of a SYN or SYN-ACK, which the proxy evaluates
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SynOptions {
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    pub sack_permitted: bool,
+}
+
+/// Window scale shifts of the four directions of a connection, all zero if window scaling is not in effect.
+/// The proxy announces the shift of the client to the server, windows are rescaled where the legs differ,
+/// i.e. if the server does not scale or when the proxy's shift towards the client differs from the one of the server.
+#[derive(Clone, Copy, Default, Debug, Serialize, Deserialize)]
+pub struct WindowShifts {
+    /// window scaling is in effect on the client leg
+    pub client_leg: bool,
+    /// window scaling is in effect on the server leg
+    pub server_leg: bool,
+    pub client: u8,
+    pub to_client: u8,
+    pub server: u8,
+    pub to_server: u8,
+}
+
+#[inline]
+fn rescale(window: u16, from: u8, to: u8) -> u16 {
+    cmp::min(((window as u32) << from) >> to, 0xFFFF) as u16
+}
+
+impl WindowShifts {
+    #[inline]
+    pub fn rescales_c2s(&self) -> bool {
+        self.client != self.to_server
+    }
+
+    #[inline]
+    pub fn rescales_s2c(&self) -> bool {
+        self.server != self.to_client
+    }
+
+    /// window of a client packet as announced to the server
+    #[inline]
+    pub fn c2s(&self, window: u16) -> u16 {
+        rescale(window, self.client, self.to_server)
+    }
+
+    /// window of a server packet as announced to the client
+    #[inline]
+    pub fn s2c(&self, window: u16) -> u16 {
+        rescale(window, self.server, self.to_client)
+    }
+
+    /// the client SYN announced shift and the proxy answers with its own shift
+    pub fn client_syn(&mut self, client: u8, proxy: u8) {
+        self.client_leg = true;
+        self.client = cmp::min(client, MAX_WINDOW_SCALE);
+        self.to_client = cmp::min(proxy, MAX_WINDOW_SCALE);
+    }
+
+    /// the shift announced to the server in the SYN, None if window scaling is not in effect on the client leg
+    #[inline]
+    pub fn announced_to_server(&self) -> Option<u8> {
+        if self.client_leg {
+            Some(self.client)
+        } else {
+            None
+        }
+    }
+
+    /// the SYN-ACK of the server announced the shift or no window scaling
+    pub fn server_synack(&mut self, server: Option<u8>) {
+        if self.client_leg && server.is_some() {
+            self.server_leg = true;
+            self.server = cmp::min(server.unwrap(), MAX_WINDOW_SCALE);
+            self.to_server = self.client;
+        } else {
+            self.server_leg = false;
+            self.server = 0;
+            self.to_server = 0;
+        }
+    }
+}
+
+/// the options of the TCP header of p, i.e. the bytes between the fixed header and the payload
+fn options(p: &Pdu) -> &[u8] {
+    let segment = p.get_payload(1);
+    let header_size = (p.headers().tcp(2).data_offset() as usize * 4).min(segment.len());
+    if header_size > TCP_HEADER_SIZE {
+        &segment[TCP_HEADER_SIZE..header_size]
+    } else {
+        &[]
+    }
+}
+
+/// positions of the options in the option bytes as (kind, start of value, end of value),
+/// parsing stops at the end of option list or at a malformed option
+fn option_positions(options: &[u8]) -> Vec<(u8, usize, usize)> {
+    let mut positions = Vec::new();
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPTION_END => break,
+            OPTION_NOP => i += 1,
+            kind => {
+                if i + 1 >= options.len() || (options[i + 1] as usize) < 2 || i + options[i + 1] as usize > options.len() {
+                    break;
+                }
+                let len = options[i + 1] as usize;
+                positions.push((kind, i + 2, i + len));
+                i += len;
+            }
+        }
+    }
+    positions
+}
+
+/// the options of p as (kind, value)
+fn parse_options(p: &Pdu) -> Vec<(u8, &[u8])> {
+    let options = options(p);
+    option_positions(options)
+        .into_iter()
+        .map(|(kind, start, end)| (kind, &options[start..end]))
+        .collect()
+}
+
+/// the MSS option of a SYN or SYN-ACK
+pub fn mss_option(p: &Pdu) -> Option<u16> {
+    syn_options(p).mss
+}
+
+/// the MSS, window scale and SACK-permitted options of a SYN or SYN-ACK
+pub fn syn_options(p: &Pdu) -> SynOptions {
+    let mut syn_options = SynOptions::default();
+    for (kind, value) in parse_options(p) {
+        match (kind, value.len()) {
+            (OPTION_MSS, 2) => syn_options.mss = Some((value[0] as u16) << 8 | value[1] as u16),
+            (OPTION_WINDOW_SCALE, 1) => syn_options.window_scale = Some(value[0]),
+            (OPTION_SACK_PERMITTED, 0) => syn_options.sack_permitted = true,
+            _ => {}
+        }
+    }
+    syn_options
+}
+
+/// Appends options to a segment without payload and without options, e.g. a SYN after remove_tcp_options.
+/// The options are padded with NOPs to a multiple of four bytes. Returns false, if p has a payload or not enough tailroom.
+pub fn add_options(p: &mut Pdu, options: &[u8]) -> bool {
+    if tcp_payload_size(p) != 0 || p.headers().tcp(2).data_offset() != 5 {
+        return false;
+    }
+    let mut padded = options.to_vec();
+    while padded.len() % 4 != 0 {
+        padded.insert(0, OPTION_NOP);
+    }
+    if padded.is_empty() {
+        return true;
+    }
+    if !insert_into_payload(p, &padded) {
+        return false;
+    }
+    p.headers_mut().tcp_mut(2).set_data_offset(5 + (padded.len() / 4) as u8);
+    true
+}
+
+/// Appends an MSS option to a segment without payload and without options, see add_options.
+pub fn add_mss_option(p: &mut Pdu, mss: u16) -> bool {
+    add_options(p, &[OPTION_MSS, 4, (mss >> 8) as u8, mss as u8])
+}
+
+/// the option bytes of a SYN or SYN-ACK of the proxy, in the usual layout of MSS, window scale and SACK-permitted
+pub fn syn_option_bytes(mss: Option<u16>, window_scale: Option<u8>, sack_permitted: bool) -> Vec<u8> {
+    let mut options = Vec::with_capacity(12);
+    if mss.is_some() {
+        let mss = mss.unwrap();
+        options.extend_from_slice(&[OPTION_MSS, 4, (mss >> 8) as u8, mss as u8]);
+    }
+    if window_scale.is_some() {
+        options.extend_from_slice(&[OPTION_NOP, OPTION_WINDOW_SCALE, 3, window_scale.unwrap()]);
+    }
+    if sack_permitted {
+        options.extend_from_slice(&[OPTION_NOP, OPTION_NOP, OPTION_SACK_PERMITTED, 2]);
+    }
+    options
+}
+
+/// translates the edges of the SACK blocks of p, e.g. to the sequence numbers of the other leg
+pub fn translate_sack_blocks<F>(p: &mut Pdu, translate: F)
+where
+    F: Fn(u32) -> u32,
+{
+    let header_size = p.headers().tcp(2).data_offset() as usize * 4;
+    if header_size <= TCP_HEADER_SIZE {
+        return;
+    }
+    let segment = p.get_payload_mut(1);
+    let header_size = cmp::min(header_size, segment.len());
+    let options = &mut segment[TCP_HEADER_SIZE..header_size];
+    for (kind, start, end) in option_positions(options) {
+        if kind == OPTION_SACK {
+            let mut i = start;
+            while i + 4 <= end {
+                let edge = translate(
+                    (options[i] as u32) << 24 | (options[i + 1] as u32) << 16 | (options[i + 2] as u32) << 8 | options[i + 3] as u32,
+                );
+                options[i] = (edge >> 24) as u8;
+                options[i + 1] = (edge >> 16) as u8;
+                options[i + 2] = (edge >> 8) as u8;
+                options[i + 3] = edge as u8;
+                i += 4;
+            }
+        }
+    }
+}
+
+/// The MSS the proxy announces to the clients, None if neither the engine nor an active target has an MSS configured.
+/// As the target of a connection is selected after the client handshake, it is the minimum of all active targets.
+pub fn mss_towards_clients(engine_mss: Option<u16>, targets: &[TargetEntry]) -> Option<u16> {
+    targets
+        .iter()
+        .filter(|entry| entry.active)
+        .filter_map(|entry| entry.config.mss)
+        .chain(engine_mss)
+        .min()
+}
@@ -0,0 +1,389 @@
+use std::arch::x86_64::_rdtsc;
+use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::vec::Drain;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The time source of a wheel in TSC cycles. The pipelines use the TscClock, tests use a MockClock to advance
+/// the time of the wheel deterministically.
+pub trait Clock {
+    fn now(&self) -> u64;
+    fn cycles_per_second(&self) -> u64;
+
+    /// the duration in cycles of the clock
+    #[inline]
+    fn cycles(&self, duration: &Duration) -> u64 {
+        (duration.as_nanos() * self.cycles_per_second() as u128 / 1_000_000_000) as u64
+    }
+}
+
+/// Measures the TSC frequency against the monotonic clock of the OS, in cycles per second.
+/// The TSC must be invariant, i.e. it must not depend on the frequency scaling of the core.
+pub fn calibrate_tsc(interval: Duration) -> u64 {
+    let start = Instant::now();
+    let tsc_start = unsafe { _rdtsc() };
+    thread::sleep(interval);
+    let tsc_end = unsafe { _rdtsc() };
+    let elapsed = start.elapsed();
+    ((tsc_end - tsc_start) as u128 * 1_000_000_000 / elapsed.as_nanos()) as u64
+}
+
+/// reads the TSC of the core
+#[derive(Clone, Copy)]
+pub struct TscClock {
+    cycles_per_second: u64,
+}
+
+impl TscClock {
+    pub fn new(cycles_per_second: u64) -> TscClock {
+        TscClock { cycles_per_second }
+    }
+}
+
+impl Clock for TscClock {
+    #[inline]
+    fn now(&self) -> u64 {
+        unsafe { _rdtsc() }
+    }
+
+    #[inline]
+    fn cycles_per_second(&self) -> u64 {
+        self.cycles_per_second
+    }
+}
+
+/// A clock which only advances when told to, clones share the time.
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<AtomicU64>,
+    cycles_per_second: u64,
+}
+
+impl MockClock {
+    pub fn new(start: u64, cycles_per_second: u64) -> MockClock {
+        MockClock {
+            now: Arc::new(AtomicU64::new(start)),
+            cycles_per_second,
+        }
+    }
+
+    pub fn advance(&self, duration: &Duration) {
+        let cycles = self.cycles(duration);
+        self.now.fetch_add(cycles, Ordering::AcqRel);
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.store(now, Ordering::Release);
+    }
+}
+
+impl Clock for MockClock {
+    #[inline]
+    fn now(&self) -> u64 {
+        self.now.load(Ordering::Acquire)
+    }
+
+    #[inline]
+    fn cycles_per_second(&self) -> u64 {
+        self.cycles_per_second
+    }
+}
+
+/// A hashed timer wheel: slot k holds the events due in the k-th resolution interval after the start of the wheel,
+/// it is drained when the interval has passed. Slots are reused after no_slots intervals.
+struct SlotWheel<T> {
+    slots: Vec<Vec<T>>,
+    resolution: u64,
+    start: u64,
+    /// number of slots drained since the start
+    drained: u64,
+}
+
+impl<T> SlotWheel<T> {
+    fn new(no_slots: usize, resolution_cycles: u64, slot_capacity: usize, start: u64) -> SlotWheel<T> {
+        SlotWheel {
+            slots: (0..no_slots).map(|_| Vec::with_capacity(slot_capacity)).collect(),
+            resolution: resolution_cycles,
+            start,
+            drained: 0,
+        }
+    }
+
+    #[inline]
+    fn get_max_timeout_cycles(&self) -> u64 {
+        (self.slots.len() as u64 - 1) * self.resolution
+    }
+
+    /// schedules value to expire after `when` cycles from now, returns slot and index of the event
+    fn schedule(&mut self, now: u64, when: u64, value: T) -> (u16, u16) {
+        let due = now.saturating_sub(self.start) + ::std::cmp::min(when, self.get_max_timeout_cycles());
+        // the slot of an overdue wheel is still drained next
+        let slot = ::std::cmp::max(due / self.resolution, self.drained);
+        let slot = ::std::cmp::min(slot, self.drained + self.slots.len() as u64 - 1) as usize % self.slots.len();
+        self.slots[slot].push(value);
+        (slot as u16, (self.slots[slot].len() - 1) as u16)
+    }
+
+    fn replace(&mut self, slot_and_index: (u16, u16), value: T) -> Option<T> {
+        self.slots[slot_and_index.0 as usize]
+            .get_mut(slot_and_index.1 as usize)
+            .map(|old| mem::replace(old, value))
+    }
+
+    /// drains the next slot, if its interval has passed; true, if further slots are due
+    fn tick<'b>(&'b mut self, now: u64) -> (Option<Drain<'b, T>>, bool) {
+        let elapsed = now.saturating_sub(self.start);
+        if elapsed < (self.drained + 1) * self.resolution {
+            return (None, false);
+        }
+        let slot = (self.drained % self.slots.len() as u64) as usize;
+        self.drained += 1;
+        let more = elapsed >= (self.drained + 1) * self.resolution;
+        (Some(self.slots[slot].drain(..)), more)
+    }
+
+    /// drains all slots, whose interval has passed, in one pass
+    fn drain_due<F: FnMut(T)>(&mut self, now: u64, f: &mut F) {
+        let due = now.saturating_sub(self.start) / self.resolution;
+        if due <= self.drained {
+            return;
+        }
+        // a wheel, which is behind by more than a revolution, drains each slot once
+        let first = ::std::cmp::max(self.drained, due.saturating_sub(self.slots.len() as u64));
+        for slot in first..due {
+            let slot = (slot % self.slots.len() as u64) as usize;
+            for e in self.slots[slot].drain(..) {
+                f(e);
+            }
+        }
+        self.drained = due;
+    }
+}
+
+/// Handle of a scheduled event, returned by CancellableWheel::schedule.
+/// The generation protects against cancelling an event which reuses the slot position of an already expired one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimerToken {
+    slot_and_index: (u16, u16),
+    generation: u32,
+}
+
+impl TimerToken {
+    /// a token which does not refer to any event
+    pub fn none() -> TimerToken {
+        TimerToken {
+            slot_and_index: (0, 0),
+            generation: 0,
+        }
+    }
+
+    #[inline]
+    pub fn is_none(&self) -> bool {
+        self.generation == 0
+    }
+
+    #[inline]
+    pub fn slot_and_index(&self) -> (u16, u16) {
+        self.slot_and_index
+    }
+}
+
+/// A timer wheel which supports cancellation of scheduled events.
+/// Cancelled events are tombstoned in their slot and skipped when the slot is drained.
+pub struct CancellableWheel<T> {
+    wheel: SlotWheel<Option<(T, u32)>>,
+    clock: Box<Clock + Send>,
+    generation: u32,
+    scheduled: u64,
+    cancelled: u64,
+    expired: u64,
+    overflowed: u64,
+    /// number of scheduled events, which have been neither cancelled nor drained
+    pending: usize,
+}
+
+impl<T> CancellableWheel<T>
+where
+    T: Copy + PartialEq,
+{
+    /// a wheel, which reads the TSC, cpu_clock are its cycles per second
+    pub fn new(no_slots: usize, resolution_cycles: u64, slot_capacity: usize, cpu_clock: u64) -> CancellableWheel<T> {
+        CancellableWheel::with_clock(no_slots, resolution_cycles, slot_capacity, Box::new(TscClock::new(cpu_clock)))
+    }
+
+    /// a wheel, which reads the time from clock, e.g. from a MockClock
+    pub fn with_clock(
+        no_slots: usize,
+        resolution_cycles: u64,
+        slot_capacity: usize,
+        clock: Box<Clock + Send>,
+    ) -> CancellableWheel<T> {
+        CancellableWheel {
+            wheel: SlotWheel::new(no_slots, resolution_cycles, slot_capacity, clock.now()),
+            clock,
+            generation: 0,
+            scheduled: 0,
+            cancelled: 0,
+            expired: 0,
+            overflowed: 0,
+            pending: 0,
+        }
+    }
+
+    #[inline]
+    pub fn resolution(&self) -> u64 {
+        self.wheel.resolution
+    }
+
+    /// the time of the clock of the wheel
+    #[inline]
+    pub fn now(&self) -> u64 {
+        self.clock.now()
+    }
+
+    #[inline]
+    pub fn get_max_timeout_cycles(&self) -> u64 {
+        self.wheel.get_max_timeout_cycles()
+    }
+
+    /// number of events which have been scheduled so far
+    #[inline]
+    pub fn scheduled(&self) -> u64 {
+        self.scheduled
+    }
+
+    /// number of events which have been cancelled so far
+    #[inline]
+    pub fn cancelled(&self) -> u64 {
+        self.cancelled
+    }
+
+    /// number of events which have been drained so far
+    #[inline]
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// number of events, which were scheduled beyond get_max_timeout_cycles
+    #[inline]
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// number of scheduled events per slot, the slot of the next tick first
+    pub fn slot_occupancy(&self) -> Vec<usize> {
+        let n = self.wheel.slots.len();
+        let next = (self.wheel.drained % n as u64) as usize;
+        (0..n)
+            .map(|i| self.wheel.slots[(next + i) % n].iter().filter(|e| e.is_some()).count())
+            .collect()
+    }
+
+    /// number of events still scheduled
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// schedules value to expire after `when` cycles, an event beyond get_max_timeout_cycles expires after
+    /// get_max_timeout_cycles and is counted as overflowed
+    #[inline]
+    pub fn schedule(&mut self, when: &u64, value: T) -> TimerToken {
+        if *when > self.get_max_timeout_cycles() {
+            self.overflowed += 1;
+        }
+        // generation 0 is reserved for TimerToken::none()
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.generation = 1;
+        }
+        let now = self.clock.now();
+        let slot_and_index = self.wheel.schedule(now, *when, Some((value, self.generation)));
+        self.scheduled += 1;
+        self.pending += 1;
+        TimerToken {
+            slot_and_index,
+            generation: self.generation,
+        }
+    }
+
+    /// same as schedule, but an event beyond get_max_timeout_cycles is not scheduled
+    pub fn try_schedule(&mut self, when: &u64, value: T) -> Result<TimerToken, String> {
+        if *when > self.get_max_timeout_cycles() {
+            self.overflowed += 1;
+            return Err(format!(
+                "timeout of {} cycles exceeds the maximum of {} cycles",
+                when,
+                self.get_max_timeout_cycles()
+            ));
+        }
+        Ok(self.schedule(when, value))
+    }
+
+    /// schedules value to expire after the duration, which is limited to get_max_timeout_cycles
+    #[inline]
+    pub fn schedule_in(&mut self, duration: &Duration, value: T) -> TimerToken {
+        let when = self.clock.cycles(duration);
+        self.schedule(&when, value)
+    }
+
+    /// cancels the event, returns the value of the event, if it was still scheduled
+    #[inline]
+    pub fn cancel(&mut self, token: &TimerToken) -> Option<T> {
+        if token.is_none() {
+            return None;
+        }
+        match self.wheel.replace(token.slot_and_index, None) {
+            Some(Some((value, generation))) => {
+                if generation == token.generation {
+                    self.cancelled += 1;
+                    self.pending -= 1;
+                    Some(value)
+                } else {
+                    // the slot position was reused by a later event, put it back
+                    self.wheel.replace(token.slot_and_index, Some((value, generation)));
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// drains all due slots in one pass and calls f with each event, which was not cancelled,
+    /// returns the number of events
+    pub fn tick_all<F: FnMut(T)>(&mut self, now: &u64, f: &mut F) -> usize {
+        let pending = &mut self.pending;
+        let mut count = 0;
+        self.wheel.drain_due(*now, &mut |e: Option<(T, u32)>| {
+            if let Some((value, _)) = e {
+                *pending -= 1;
+                count += 1;
+                f(value);
+            }
+        });
+        self.expired += count as u64;
+        count
+    }
+
+    /// drains the next due slot, skipping cancelled events; the bool is true, if further slots are due
+    #[inline]
+    pub fn tick<'b>(&'b mut self, now: &u64) -> (Option<impl Iterator<Item = T> + 'b>, bool) {
+        let pending = &mut self.pending;
+        let expired = &mut self.expired;
+        let (drain, more) = self.wheel.tick(*now);
+        (
+            drain.map(move |d| {
+                d.filter_map(move |e| {
+                    e.map(|(value, _)| {
+                        *pending -= 1;
+                        *expired += 1;
+                        value
+                    })
+                })
+            }),
+            more,
+        )
+    }
+}
@@ -0,0 +1,432 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+
+use ipnet::IpNet;
+
+use health::MAX_TARGETS;
+use cmanager::MAX_SOURCE_IPS;
+use vlan::MAX_VLAN_ID;
+use mtu::{MIN_MTU, MAX_MTU};
+use polling::MAX_RX_BURST;
+use watchdog::MIN_WATCHDOG_INTERVAL;
+use replay::read_pcap;
+use maglev::is_prime;
+use selection::SelectionPolicy;
+use Configuration;
+
+/// RFC 879, the smallest MSS every host must accept
+const MIN_MSS: u16 = 536;
+/// RFC 7323
+const MAX_WINDOW_SCALE: u8 = 14;
+
+/// a problem of the configuration, path is the field, e.g. "targets[1].port"
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+struct Problems(Vec<ConfigProblem>);
+
+impl Problems {
+    fn add<P: Into<String>, R: Into<String>>(&mut self, path: P, reason: R) {
+        self.0.push(ConfigProblem {
+            path: path.into(),
+            reason: reason.into(),
+        });
+    }
+
+    fn networks(&mut self, path: &str, networks: &Option<Vec<String>>) {
+        if networks.is_some() {
+            for (i, net) in networks.as_ref().unwrap().iter().enumerate() {
+                if net.parse::<IpNet>().is_err() {
+                    self.add(format!("{}[{}]", path, i), format!("{} is not a network, e.g. 10.0.0.0/8", net));
+                }
+            }
+        }
+    }
+
+    fn mss(&mut self, path: String, mss: Option<u16>) {
+        if mss.is_some() && mss.unwrap() < MIN_MSS {
+            self.add(path, format!("must be at least {}", MIN_MSS));
+        }
+    }
+
+    fn vlan(&mut self, path: String, vlan: Option<u16>) {
+        if vlan.is_some() && (vlan.unwrap() == 0 || vlan.unwrap() > MAX_VLAN_ID) {
+            self.add(path, format!("must be a VLAN id from 1 to {}", MAX_VLAN_ID));
+        }
+    }
+
+    fn mtu(&mut self, path: &str, mtu: Option<u16>) {
+        if mtu.is_some() && (mtu.unwrap() < MIN_MTU || mtu.unwrap() > MAX_MTU) {
+            self.add(path, format!("must be an MTU from {} to {}", MIN_MTU, MAX_MTU));
+        }
+    }
+
+    fn not_zero<T: PartialEq + Default>(&mut self, path: &str, value: Option<T>) {
+        if value.is_some() && value.unwrap() == T::default() {
+            self.add(path, "must not be 0");
+        }
+    }
+}
+
+impl Configuration {
+    /// Checks the values, which deserialize but cannot work, e.g. port 0 or duplicate target ids.
+    /// Returns all problems found, an empty list if the configuration is valid.
+    pub fn validate(&self) -> Vec<ConfigProblem> {
+        let mut problems = Problems(Vec::new());
+        let engine = &self.engine;
+
+        if self.targets.is_empty() {
+            problems.add("targets", "no target configured");
+        }
+        if self.targets.len() > MAX_TARGETS {
+            problems.add("targets", format!("more than {} targets configured", MAX_TARGETS));
+        }
+        if !self.targets.is_empty() && self.targets.iter().all(|t| t.is_fallback()) {
+            problems.add("targets", "all targets are fallback targets");
+        }
+        let mut ids = HashSet::new();
+        for (i, target) in self.targets.iter().enumerate() {
+            let path = format!("targets[{}]", i);
+            if target.id.is_empty() {
+                problems.add(format!("{}.id", path), "must not be empty");
+            } else if !ids.insert(target.id.as_str()) {
+                problems.add(format!("{}.id", path), format!("duplicate target id {}", target.id));
+            }
+            if target.ip.is_unspecified() && target.host.is_none() {
+                problems.add(format!("{}.ip", path), "either ip or host is required");
+            }
+            if target.ip.is_ipv6() {
+                problems.add(format!("{}.ip", path), "IPv6 targets are not supported by the data path");
+            }
+            if target.port == 0 {
+                problems.add(format!("{}.port", path), "must not be 0");
+            }
+            if target.mac.is_none() && target.linux_if.is_none() && engine.arp.is_none() {
+                problems.add(path.clone(), "either mac, linux_if or engine.arp is required to address the target");
+            }
+            if engine.kniless.is_some() && target.mac.is_none() && engine.arp.is_none() {
+                problems.add(path.clone(), "either mac or engine.arp is required to address the target without KNI");
+            }
+            if target.weight == Some(0) {
+                problems.add(format!("{}.weight", path), "must not be 0");
+            }
+            if target.max_connections == Some(0) {
+                problems.add(format!("{}.max_connections", path), "must not be 0");
+            }
+            problems.mss(format!("{}.mss", path), target.mss);
+            problems.vlan(format!("{}.vlan", path), target.vlan);
+            if target.group.is_some() && engine.target_groups.is_none() {
+                problems.add(format!("{}.group", path), "requires engine.target_groups");
+            }
+            if target.vlan.is_some() && engine.vlan.is_none() {
+                problems.add(format!("{}.vlan", path), "requires engine.vlan");
+            }
+            if target.circuit_breaker.is_some() {
+                let breaker = target.circuit_breaker.as_ref().unwrap();
+                if !(breaker.failure_ratio > 0.0 && breaker.failure_ratio < 1.0) {
+                    problems.add(
+                        format!("{}.circuit_breaker.failure_ratio", path),
+                        format!("{} is not in (0, 1)", breaker.failure_ratio),
+                    );
+                }
+                problems.not_zero(&format!("{}.circuit_breaker.window", path), breaker.window);
+                problems.not_zero(&format!("{}.circuit_breaker.probes", path), breaker.probes);
+            }
+        }
+
+        if engine.port == 0 {
+            problems.add("engine.port", "must not be 0");
+        }
+        if engine.udp.is_some() && engine.udp.as_ref().unwrap().port == 0 {
+            problems.add("engine.udp.port", "must not be 0");
+        }
+        problems.mss("engine.mss".to_string(), engine.mss);
+        if engine.sni_map.is_some() {
+            for (name, id) in engine.sni_map.as_ref().unwrap() {
+                if !ids.contains(id.as_str()) {
+                    problems.add(format!("engine.sni_map.\"{}\"", name), format!("unknown target id {}", id));
+                }
+            }
+        }
+        if engine.alpn_map.is_some() {
+            for (protocol, id) in engine.alpn_map.as_ref().unwrap() {
+                if !ids.contains(id.as_str()) {
+                    problems.add(format!("engine.alpn_map.\"{}\"", protocol), format!("unknown target id {}", id));
+                }
+            }
+        }
+        if engine.http_routes.is_some() {
+            for (i, route) in engine.http_routes.as_ref().unwrap().iter().enumerate() {
+                if !ids.contains(route.target.as_str()) {
+                    problems.add(
+                        format!("engine.http_routes[{}].target", i),
+                        format!("unknown target id {}", route.target),
+                    );
+                }
+            }
+        }
+        if engine.acl.is_some() {
+            let acl = engine.acl.as_ref().unwrap();
+            problems.networks("engine.acl.allow", &acl.allow);
+            problems.networks("engine.acl.deny", &acl.deny);
+        }
+        if engine.socks5.is_some() {
+            problems.networks("engine.socks5.allow", &engine.socks5.as_ref().unwrap().allow);
+        }
+        if engine.admin_address.is_some() && engine.admin_address.as_ref().unwrap().parse::<SocketAddr>().is_err() {
+            problems.add("engine.admin_address", "must be an address with port, e.g. 192.168.222.1:8080");
+        }
+        if engine.rate_limit.is_some() && engine.rate_limit.as_ref().unwrap().rate == 0 {
+            problems.add("engine.rate_limit.rate", "must not be 0");
+        }
+        problems.not_zero("engine.max_connections", engine.max_connections);
+        problems.not_zero("engine.max_connections_per_target", engine.max_connections_per_target);
+        problems.not_zero("engine.stats_interval", engine.stats_interval);
+        if engine.keepalive.is_some() {
+            let keepalive = engine.keepalive.as_ref().unwrap();
+            problems.not_zero("engine.keepalive.idle", keepalive.idle);
+            problems.not_zero("engine.keepalive.interval", keepalive.interval);
+        }
+        if engine.syn_retry.is_some() {
+            problems.not_zero("engine.syn_retry.timeout", engine.syn_retry.as_ref().unwrap().timeout);
+        }
+        if engine.tcp_options.is_some() {
+            let window_scale = engine.tcp_options.as_ref().unwrap().window_scale;
+            if window_scale.is_some() && window_scale.unwrap() > MAX_WINDOW_SCALE {
+                problems.add("engine.tcp_options.window_scale", format!("must not exceed {}", MAX_WINDOW_SCALE));
+            }
+        }
+        if engine.payload_buffering.is_some() {
+            let buffering = engine.payload_buffering.as_ref().unwrap();
+            problems.not_zero("engine.payload_buffering.max_bytes", buffering.max_bytes);
+            problems.not_zero("engine.payload_buffering.max_segments", buffering.max_segments);
+        }
+        if engine.reorder.is_some() {
+            let reorder = engine.reorder.as_ref().unwrap();
+            problems.not_zero("engine.reorder.max_bytes", reorder.max_bytes);
+            problems.not_zero("engine.reorder.max_segments", reorder.max_segments);
+        }
+        if engine.gateway.is_some() {
+            let gateway = engine.gateway.as_ref().unwrap();
+            if gateway.mac.is_none() && engine.arp.is_none() {
+                problems.add("engine.gateway.mac", "is required without engine.arp");
+            }
+            if gateway.local_network.is_some() && gateway.local_network.as_ref().unwrap().parse::<IpNet>().is_err() {
+                problems.add("engine.gateway.local_network", "must be a network, e.g. 192.168.222.0/24");
+            }
+        }
+        if engine.vlan.is_some() {
+            for (port, vlan) in &engine.vlan.as_ref().unwrap().ports {
+                problems.vlan(format!("engine.vlan.ports.\"{}\"", port), Some(*vlan));
+            }
+        }
+        if engine.target_groups.is_some() {
+            let active = &engine.target_groups.as_ref().unwrap().active;
+            if !self.targets.iter().any(|t| t.group.as_ref() == Some(active)) {
+                problems.add("engine.target_groups.active", format!("no target in group {}", active));
+            }
+        }
+        if engine.pacing.is_some() && engine.pacing.as_ref().unwrap().packets_per_tick == 0 {
+            problems.add("engine.pacing.packets_per_tick", "must not be 0");
+        }
+        problems.not_zero("engine.pacing.max_queued", engine.pacing.as_ref().and_then(|p| p.max_queued));
+        if engine.dns.is_some() {
+            problems.not_zero("engine.dns.interval", engine.dns.as_ref().unwrap().interval);
+        }
+        if engine.connection_table.is_some() {
+            let table = engine.connection_table.as_ref().unwrap();
+            problems.not_zero("engine.connection_table.max_flows", table.max_flows);
+            if table.load_factor.is_some() {
+                let load_factor = table.load_factor.unwrap();
+                if !(load_factor > 0.0 && load_factor < 1.0) {
+                    problems.add("engine.connection_table.load_factor", format!("{} is not in (0, 1)", load_factor));
+                }
+            }
+        }
+        if engine.source_pool.is_some() {
+            let pool = engine.source_pool.as_ref().unwrap();
+            if engine.transparent.unwrap_or(false) {
+                problems.add("engine.source_pool", "is not used in transparent mode");
+            }
+            if pool.ips.len() >= MAX_SOURCE_IPS {
+                problems.add("engine.source_pool.ips", format!("more than {} addresses", MAX_SOURCE_IPS - 1));
+            }
+            if pool.min_port.is_some() && pool.max_port.is_some() && pool.min_port.unwrap() > pool.max_port.unwrap() {
+                problems.add("engine.source_pool.min_port", "must not exceed max_port");
+            }
+        }
+        if engine.time_wait.is_some() {
+            problems.not_zero("engine.time_wait.two_msl", engine.time_wait.as_ref().unwrap().two_msl);
+        }
+        if engine.mtu.is_some() {
+            let mtu = engine.mtu.as_ref().unwrap();
+            problems.mtu("engine.mtu.pci", mtu.pci);
+            problems.mtu("engine.mtu.kni", mtu.kni);
+        }
+        if engine.fragments.is_some() {
+            let fragments = engine.fragments.as_ref().unwrap();
+            problems.not_zero("engine.fragments.max_datagrams", fragments.max_datagrams);
+            problems.not_zero("engine.fragments.timeout", fragments.timeout);
+            problems.mtu("engine.fragments.mtu_towards_clients", fragments.mtu_towards_clients);
+            problems.mtu("engine.fragments.mtu_towards_targets", fragments.mtu_towards_targets);
+        }
+        if engine.inline.is_some() {
+            let inline = engine.inline.as_ref().unwrap();
+            if inline.client_port == inline.server_port {
+                problems.add("engine.inline.server_port", "must differ from client_port");
+            }
+            if engine.udp.is_some() {
+                problems.add("engine.udp", "is not supported in inline mode");
+            }
+            if engine.replay.is_some() {
+                problems.add("engine.replay", "is not supported in inline mode");
+            }
+        }
+        if engine.ha.is_some() {
+            let ha = engine.ha.as_ref().unwrap();
+            problems.not_zero("engine.ha.interval", ha.interval);
+            if ha.peer.port() == 0 {
+                problems.add("engine.ha.peer", "needs a port");
+            }
+        }
+        if engine.consistent_hash.is_some() {
+            let table_size = engine.consistent_hash.as_ref().unwrap().table_size();
+            if !is_prime(table_size) || table_size <= self.targets.len() {
+                problems.add("engine.consistent_hash.table_size", "must be a prime larger than the number of targets");
+            }
+            if engine.selection != Some(SelectionPolicy::Maglev) {
+                problems.add("engine.consistent_hash", "is only used by the maglev selection policy");
+            }
+        }
+        if engine.session_file.is_some() && engine.drain_timeout.is_some() {
+            problems.add("engine.session_file", "cannot be combined with drain_timeout, a drain closes the connections");
+        }
+        if engine.record_sampling.is_some() {
+            let sampling = engine.record_sampling.as_ref().unwrap();
+            problems.not_zero("engine.record_sampling.one_in", sampling.one_in);
+            if sampling.pipelines.is_some() {
+                for (pipeline, one_in) in sampling.pipelines.as_ref().unwrap() {
+                    problems.not_zero(&format!("engine.record_sampling.pipelines.{}", pipeline), Some(*one_in));
+                }
+            }
+            if !engine.detailed_records.unwrap_or(false) {
+                problems.add("engine.record_sampling", "requires detailed_records");
+            }
+        }
+        if engine.ipfix.is_some() {
+            let ipfix = engine.ipfix.as_ref().unwrap();
+            problems.not_zero("engine.ipfix.template_interval", ipfix.template_interval);
+            problems.not_zero("engine.ipfix.queue_size", ipfix.queue_size);
+            if ipfix.collector.port() == 0 {
+                problems.add("engine.ipfix.collector", "needs a port");
+            }
+        }
+        if engine.sflow.is_some() {
+            let sflow = engine.sflow.as_ref().unwrap();
+            problems.not_zero("engine.sflow.one_in", sflow.one_in);
+            problems.not_zero("engine.sflow.queue_size", sflow.queue_size);
+            if sflow.header_size() < 14 || sflow.header_size() > 1024 {
+                problems.add("engine.sflow.header_size", "must be between 14 and 1024");
+            }
+            if sflow.collector.port() == 0 {
+                problems.add("engine.sflow.collector", "needs a port");
+            }
+        }
+        if engine.event_export.is_some() {
+            problems.not_zero("engine.event_export.queue_size", engine.event_export.as_ref().unwrap().queue_size);
+        }
+        if engine.load_test.is_some() {
+            let load_test = engine.load_test.as_ref().unwrap();
+            problems.not_zero("engine.load_test.rate", load_test.rate);
+            problems.not_zero("engine.load_test.payload_size", load_test.payload_size);
+            problems.not_zero("engine.load_test.duration", load_test.duration);
+            problems.not_zero("engine.load_test.timeout", load_test.timeout);
+            if load_test.proxy.port() == 0 {
+                problems.add("engine.load_test.proxy", "needs a port");
+            }
+            if load_test.cores.as_ref().map_or(0, |cores| cores.len()) > load_test.rate() as usize {
+                problems.add("engine.load_test.cores", "more cores than connections per second");
+            }
+        }
+        if engine.polling.is_some() {
+            let polling = engine.polling.as_ref().unwrap();
+            problems.not_zero("engine.polling.idle_after", polling.idle_after);
+            problems.not_zero("engine.polling.sleep_us", polling.sleep_us);
+            if polling.rx_burst.map_or(false, |burst| burst == 0 || burst > MAX_RX_BURST) {
+                problems.add("engine.polling.rx_burst", format!("must be between 1 and {}", MAX_RX_BURST));
+            }
+        }
+        if engine.watchdog.is_some() {
+            let watchdog = engine.watchdog.as_ref().unwrap();
+            if watchdog.interval.map_or(false, |interval| interval < MIN_WATCHDOG_INTERVAL) {
+                problems.add("engine.watchdog.interval", format!("must be at least {} ms", MIN_WATCHDOG_INTERVAL));
+            }
+            problems.not_zero("engine.watchdog.max_round_us", watchdog.max_round_us);
+        }
+        if self.backends.is_some() {
+            for (name, targets) in self.backends.as_ref().unwrap() {
+                if targets.is_empty() {
+                    problems.add(format!("backends.\"{}\"", name), "no target configured");
+                }
+                for id in targets.iter().filter(|id| !ids.contains(id.as_str())) {
+                    problems.add(format!("backends.\"{}\"", name), format!("unknown target id {}", id));
+                }
+            }
+        }
+        if self.frontends.is_some() {
+            let mut names = HashSet::new();
+            let mut ports = HashSet::new();
+            ports.insert(engine.port);
+            for (i, frontend) in self.frontends.as_ref().unwrap().iter().enumerate() {
+                let path = format!("frontends[{}]", i);
+                if frontend.name.is_empty() {
+                    problems.add(format!("{}.name", path), "must not be empty");
+                } else if !names.insert(frontend.name.as_str()) {
+                    problems.add(format!("{}.name", path), format!("duplicate frontend name {}", frontend.name));
+                }
+                if frontend.port == 0 {
+                    problems.add(format!("{}.port", path), "must not be 0");
+                } else if !ports.insert(frontend.port) {
+                    let detail = format!("port {} is used by engine.port or another frontend", frontend.port);
+                    problems.add(format!("{}.port", path), detail);
+                }
+                if !self.backends.as_ref().map_or(false, |backends| backends.contains_key(&frontend.backend)) {
+                    problems.add(format!("{}.backend", path), format!("unknown backend {}", frontend.backend));
+                }
+                if frontend.socks5.is_some() {
+                    problems.networks(&format!("{}.socks5.allow", path), &frontend.socks5.as_ref().unwrap().allow);
+                }
+            }
+            if self.frontends.as_ref().unwrap().len() >= u8::max_value() as usize {
+                problems.add("frontends", format!("more than {} frontends configured", u8::max_value() - 1));
+            }
+        }
+        if engine.replay.is_some() {
+            if let Err(e) = read_pcap(&engine.replay.as_ref().unwrap().input) {
+                problems.add("engine.replay.input", e);
+            }
+        }
+        problems.0
+    }
+
+    /// validate as a result, the error lists all problems
+    pub fn check(&self) -> Result<(), String> {
+        let problems = self.validate();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+            Err(format!("invalid configuration: {}", problems.join("; ")))
+        }
+    }
+}
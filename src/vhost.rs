@@ -0,0 +1,40 @@
+/// prefixes of the DPDK vhost PMD, e.g. "net_vhost0,iface=/var/run/vhost-user0.sock,queues=2,client=1"
+const VHOST_PREFIXES: [&str; 2] = ["net_vhost", "eth_vhost"];
+
+/// A vhost-user port, i.e. a port of the DPDK vhost PMD, which is used instead of a NIC, when the engine runs as
+/// a VNF, e.g. in a VM with a vhost-user backend or alongside a DPDK based vswitch. The port has no flow director
+/// and no checksum offload, its MTU is negotiated with the virtio frontend.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VhostUserPort {
+    /// path of the unix domain socket
+    pub iface: String,
+    /// if true, the engine connects to the socket of the vswitch, otherwise the frontend connects to the engine
+    pub client: bool,
+    pub queues: u16,
+}
+
+impl VhostUserPort {
+    /// the vhost-user port of the port name, None if it is not a vhost-user port
+    pub fn of_port(port_name: &str) -> Option<VhostUserPort> {
+        if !VHOST_PREFIXES.iter().any(|prefix| port_name.starts_with(prefix)) {
+            return None;
+        }
+        let mut iface = None;
+        let mut client = false;
+        let mut queues = 1;
+        for arg in port_name.split(',').skip(1) {
+            let mut key_value = arg.splitn(2, '=');
+            match (key_value.next(), key_value.next()) {
+                (Some("iface"), Some(path)) => iface = Some(path.to_string()),
+                (Some("client"), Some(value)) => client = value == "1",
+                (Some("queues"), Some(value)) => queues = value.parse().unwrap_or(1),
+                _ => (),
+            }
+        }
+        Some(VhostUserPort {
+            iface: iface?,
+            client,
+            queues,
+        })
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use e2d2::interface::Pdu;
+use netfcts::tcp_common::L234Data;
+
+use reload::TargetEntry;
+
+/// the VLAN id in the tag control information, i.e. without priority and DEI
+const VLAN_ID_MASK: u16 = 0x0fff;
+pub const MAX_VLAN_ID: u16 = 4094;
+
+/// 802.1Q VLANs of the physical ports.
+/// The tags are stripped by the NIC on receive and inserted by the NIC on transmit (VLAN rx/tx offload of the port),
+/// so the state machine still works on the untagged header stack, and the header lengths of the checksum offload
+/// are those of the untagged frame. Frames of a VLAN, which is neither the one of the port nor of a target, are dropped.
+/// Note: frames passed to and received from the KNI interface are untagged.
+#[derive(Deserialize, Clone)]
+pub struct VlanConfig {
+    /// VLAN id of each physical port by its name, e.g. "0000:00:08.0", towards the clients and the targets without vlan
+    #[serde(default)]
+    pub ports: HashMap<String, u16>,
+}
+
+/// VLANs of a pipeline: the one of its port and the one of each target, indexed like the servers
+pub struct Vlans {
+    port: Option<u16>,
+    targets: Vec<Option<u16>>,
+}
+
+impl Vlans {
+    pub fn new(config: &VlanConfig, port_name: &str, targets: &[TargetEntry]) -> Vlans {
+        let mut vlans = Vlans {
+            port: config.ports.get(port_name).cloned(),
+            targets: Vec::new(),
+        };
+        vlans.update_targets(targets);
+        vlans
+    }
+
+    pub fn update_targets(&mut self, targets: &[TargetEntry]) {
+        self.targets = targets.iter().map(|t| t.config.vlan.or(self.port)).collect();
+    }
+
+    /// true, if a frame received with this tag control information, None if untagged, is processed
+    pub fn accepts(&self, tci: Option<u16>) -> bool {
+        let vlan = tci.map(|tci| tci & VLAN_ID_MASK);
+        vlan == self.port || self.targets.contains(&vlan)
+    }
+
+    /// the VLAN of frames to ip, i.e. of the target with this address, otherwise the one of the port
+    pub fn towards(&self, ip: u32, servers: &[L234Data]) -> Option<u16> {
+        match servers.iter().position(|s| s.ip == ip) {
+            Some(i) if i < self.targets.len() => self.targets[i],
+            _ => self.port,
+        }
+    }
+}
+
+/// requests the insertion of the VLAN tag towards the destination of p, if there is one
+#[inline]
+pub fn tag_towards_destination(p: &mut Pdu, vlans: &Option<Vlans>, servers: &[L234Data]) {
+    if vlans.is_some() {
+        let vlan = vlans.as_ref().unwrap().towards(p.headers().ip(1).dst(), servers);
+        if vlan.is_some() {
+            p.set_vlan_tx_offload(vlan.unwrap());
+        }
+    }
+}
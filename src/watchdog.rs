@@ -0,0 +1,98 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use stats::EngineStats;
+
+pub const MIN_WATCHDOG_INTERVAL: u64 = 2000;
+const DEFAULT_MAX_ROUND_US: u64 = 10_000;
+/// alerts kept for the control channel
+const MAX_ALERTS: usize = 100;
+
+/// A thread, which checks the progress of the scheduler threads of the cores, i.e. of their pipelines.
+/// A core stalls, if its scheduler did not complete a round since the last check, e.g. because a task does not
+/// return. A latency spike is a scheduler round, which took longer than max_round_us, the packets in the rx queues of
+/// the core wait for this time. Alerts are logged and listed by the alerts command of the control channel.
+#[derive(Deserialize, Clone)]
+pub struct WatchdogConfig {
+    /// milli-seconds between two checks, defaults to and at least 2000, as the cores publish their load once per second
+    pub interval: Option<u64>,
+    /// longest acceptable scheduler round in micro-seconds, defaults to 10000
+    pub max_round_us: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WatchdogAlert {
+    /// seconds since the epoch
+    pub time: u64,
+    pub core: i32,
+    pub detail: String,
+}
+
+impl fmt::Display for WatchdogAlert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} core {}: {}", self.time, self.core, self.detail)
+    }
+}
+
+/// The alerts raised by the watchdog, the latest MAX_ALERTS are kept.
+#[derive(Clone)]
+pub struct Watchdog {
+    alerts: Arc<Mutex<VecDeque<WatchdogAlert>>>,
+}
+
+impl Watchdog {
+    pub fn new() -> Watchdog {
+        Watchdog {
+            alerts: Arc::new(Mutex::new(VecDeque::new())),
+        }
+    }
+
+    fn raise(&self, core: i32, detail: String) {
+        error!("watchdog: core {}: {}", core, detail);
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0))
+            .as_secs();
+        let mut alerts = self.alerts.lock().unwrap();
+        if alerts.len() == MAX_ALERTS {
+            alerts.pop_front();
+        }
+        alerts.push_back(WatchdogAlert { time, core, detail });
+    }
+
+    /// oldest alert first
+    pub fn alerts(&self) -> Vec<WatchdogAlert> {
+        self.alerts.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// starts the watchdog thread, the alerts of stalled cores are repeated on each check until the core recovers
+    pub fn start(&self, config: &WatchdogConfig, stats: EngineStats, cpu_clock: u64) -> thread::JoinHandle<()> {
+        let interval = Duration::from_millis(config.interval.unwrap_or(MIN_WATCHDOG_INTERVAL));
+        let max_round = config.max_round_us.unwrap_or(DEFAULT_MAX_ROUND_US) * cpu_clock / 1_000_000;
+        let watchdog = self.clone();
+        thread::spawn(move || {
+            let mut rounds: HashMap<i32, u64> = HashMap::new();
+            loop {
+                thread::sleep(interval);
+                let pipelines = stats.snapshot();
+                for (core, load) in stats.cores() {
+                    if rounds.get(&core).map_or(false, |r| *r == load.rounds) {
+                        let stalled: Vec<String> = pipelines
+                            .keys()
+                            .filter(|pipeline_id| pipeline_id.core as i32 == core)
+                            .map(|pipeline_id| pipeline_id.to_string())
+                            .collect();
+                        watchdog.raise(core, format!("stalled, no scheduler round, pipelines {}", stalled.join(", ")));
+                    } else if load.max_round_cycles > max_round {
+                        let us = load.max_round_cycles * 1_000_000 / cpu_clock;
+                        watchdog.raise(core, format!("latency spike, scheduler round of {} us", us));
+                    }
+                    rounds.insert(core, load.rounds);
+                }
+            }
+        })
+    }
+}
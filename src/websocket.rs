@@ -0,0 +1,186 @@
+use std::cmp;
+
+use cmanager::ProxyConnection;
+use reorder::seqn_after;
+
+/// fin, opcode (1), mask flag and payload length (1), extended length (8), masking key (4)
+const MAX_FRAME_HEADER_SIZE: usize = 14;
+
+/// the header of a WebSocket frame, RFC 6455 section 5.2
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WebSocketFrame {
+    pub fin: bool,
+    /// e.g. 0x1 text, 0x2 binary, 0x8 close
+    pub opcode: u8,
+    /// frames of the client are masked
+    pub masked: bool,
+    /// length of the payload of the frame
+    pub length: u64,
+}
+
+/// the frame headers in the payload of one direction
+#[derive(Clone, Debug, Default)]
+struct FrameReader {
+    /// seqn of the next byte, None before the first segment after the upgrade
+    next_seqn: Option<u32>,
+    /// bytes of the current frame header received so far
+    header: Vec<u8>,
+    /// bytes of the payload of the current frame still to come
+    remaining: u64,
+    /// bytes are missing, the frames are no longer followed
+    lost: bool,
+}
+
+impl FrameReader {
+    /// calls on_frame for each frame header completed by the payload of the segment starting at seqn,
+    /// bytes of retransmitted segments, which have been seen already, are skipped
+    fn read<F>(&mut self, seqn: u32, payload: &[u8], mut on_frame: F)
+    where
+        F: FnMut(&WebSocketFrame),
+    {
+        if self.lost {
+            return;
+        }
+        let mut pos = 0;
+        if let Some(next) = self.next_seqn {
+            if seqn_after(seqn, next) {
+                warn!("bytes of the WebSocket stream are missing, frames are no longer followed");
+                self.lost = true;
+                return;
+            }
+            let seen = next.wrapping_sub(seqn) as usize;
+            if seen >= payload.len() {
+                return;
+            }
+            pos = seen;
+        }
+        self.next_seqn = Some(seqn.wrapping_add(payload.len() as u32));
+        while pos < payload.len() {
+            if self.remaining > 0 {
+                let skipped = cmp::min(self.remaining, (payload.len() - pos) as u64);
+                self.remaining -= skipped;
+                pos += skipped as usize;
+                continue;
+            }
+            self.header.push(payload[pos]);
+            pos += 1;
+            if let Some(frame) = parse_frame_header(&self.header) {
+                on_frame(&frame);
+                self.remaining = frame.length;
+                self.header.clear();
+            } else if self.header.len() >= MAX_FRAME_HEADER_SIZE {
+                self.lost = true;
+                return;
+            }
+        }
+    }
+}
+
+/// the frame header, None if it is incomplete
+fn parse_frame_header(header: &[u8]) -> Option<WebSocketFrame> {
+    if header.len() < 2 {
+        return None;
+    }
+    let masked = header[1] & 0x80 != 0;
+    let (length, extended) = match header[1] & 0x7f {
+        126 => (header.get(2..4)?.iter().fold(0u64, |l, b| l << 8 | *b as u64), 2),
+        127 => (header.get(2..10)?.iter().fold(0u64, |l, b| l << 8 | *b as u64), 8),
+        length => (length as u64, 0),
+    };
+    if header.len() < 2 + extended + if masked { 4 } else { 0 } {
+        return None;
+    }
+    Some(WebSocketFrame {
+        fin: header[0] & 0x80 != 0,
+        opcode: header[0] & 0x0f,
+        masked,
+        length,
+    })
+}
+
+/// true, if the header of the HTTP message in payload has the field name with token in its comma separated value
+fn has_header_token(payload: &[u8], name: &str, token: &str) -> bool {
+    let end = payload.windows(4).position(|w| w == b"\r\n\r\n").unwrap_or(payload.len());
+    String::from_utf8_lossy(&payload[..end]).split("\r\n").skip(1).any(|line| {
+        let mut name_value = line.splitn(2, ':');
+        name_value.next().map_or(false, |n| n.trim().eq_ignore_ascii_case(name))
+            && name_value
+                .next()
+                .map_or(false, |value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+    })
+}
+
+/// The WebSocket state of a connection, see websocket_aware.
+#[derive(Clone, Debug, Default)]
+pub struct WebSocketState {
+    /// the client requested the upgrade, the response of the server is pending
+    requested: bool,
+    /// the server switched to the WebSocket protocol
+    upgraded: bool,
+    c2s: FrameReader,
+    s2c: FrameReader,
+}
+
+impl WebSocketState {
+    #[inline]
+    pub fn upgraded(&self) -> bool {
+        self.upgraded
+    }
+
+    /// follows the HTTP upgrade, returns the length of the HTTP header of the 101 response of the server
+    fn observe(&mut self, from_client: bool, payload: &[u8]) -> Option<usize> {
+        if from_client {
+            if payload.starts_with(b"GET ")
+                && has_header_token(payload, "upgrade", "websocket")
+                && has_header_token(payload, "connection", "upgrade")
+            {
+                self.requested = true;
+            }
+        } else if self.requested && payload.starts_with(b"HTTP/1.") {
+            self.requested = false;
+            if payload.get(9..12) == Some(&b"101"[..]) && has_header_token(payload, "upgrade", "websocket") {
+                self.upgraded = true;
+                return payload.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4);
+            }
+        }
+        None
+    }
+}
+
+/// Wraps the payload closure of one direction, e.g. one which inspects HTTP: the payload is passed to http, until the
+/// server switched the connection to the WebSocket protocol. From then on on_frame is called for each frame header,
+/// with true for frames of the client, and http is no longer called, so that it does not corrupt the frames.
+/// Both payload closures of the engine must be wrapped, as the upgrade is requested by the client and confirmed by
+/// the server.
+pub fn websocket_aware<F, W>(
+    from_client: bool,
+    http: F,
+    on_frame: Option<W>,
+) -> impl Fn(&mut ProxyConnection, &mut [u8], usize) + Sized + Send + Sync + Clone + 'static
+where
+    F: Fn(&mut ProxyConnection, &mut [u8], usize) + Send + Sync + Clone + 'static,
+    W: Fn(&mut ProxyConnection, &WebSocketFrame, bool) + Send + Sync + Clone + 'static,
+{
+    move |c: &mut ProxyConnection, payload: &mut [u8], tailroom: usize| {
+        let mut state = c.websocket.take().unwrap_or_default();
+        let seqn = c.payload_seqn;
+        let mut frames_at = None;
+        if state.upgraded {
+            frames_at = Some(0);
+        } else {
+            http(c, payload, tailroom);
+            if let Some(header_len) = state.observe(from_client, payload) {
+                frames_at = Some(header_len);
+            }
+        }
+        if frames_at.is_some() && on_frame.is_some() {
+            let start = cmp::min(frames_at.unwrap(), payload.len());
+            let reader = if from_client { &mut state.c2s } else { &mut state.s2c };
+            let on_frame = on_frame.as_ref().unwrap();
+            reader.read(seqn.wrapping_add(start as u32), &payload[start..], |frame| {
+                on_frame(c, frame, from_client)
+            });
+        }
+        c.websocket = Some(state);
+    }
+}
@@ -65,6 +65,14 @@ case $TASK in
         echo ./tests/client_syn_fin.2.toml > tests/toml_file.txt
         sudo -E env "PATH=$PATH" $executable --nocapture
         ;;
+    replay)
+        export RUST_LOG="tcp_proxy=info,replay=info,e2d2=info"
+        export RUST_BACKTRACE=1
+        executable=`cargo test $2 $3 $4 --no-run --message-format=json --test replay | jq -r 'select((.profile.test == true) and (.target.name == "replay")) | .filenames[]'`
+        echo $executable
+        echo ./tests/replay.toml > tests/toml_file.txt
+        sudo -E env "PATH=$PATH" $executable --nocapture
+        ;;
     all)
         ./test.sh test_rfs_ip $2
         ./test.sh test_rfs_port $2
@@ -32,8 +32,8 @@ use netfcts::conrecord::{HasTcpState};
 use netfcts::{RunTime, Store64};
 use netfcts::comm::{MessageFrom, MessageTo};
 
-use tcp_proxy::{ProxyConnection, Configuration, Extension};
-use tcp_proxy::setup_pipes_delayed_proxy;
+use tcp_proxy::{ProxyConnection, Configuration, Extension, SharedState};
+use tcp_proxy::{setup_pipes_delayed_proxy, NoPayload};
 
 #[test]
 fn delayed_binding_proxy() {
@@ -79,7 +79,7 @@ fn delayed_binding_proxy() {
             mac: srv_cfg
                 .mac
                 .unwrap_or_else(|| get_mac_from_ifname(srv_cfg.linux_if.as_ref().unwrap()).unwrap()),
-            ip: u32::from(srv_cfg.ip),
+            ip: srv_cfg.ipv4(),
             port: srv_cfg.port,
             server_id: srv_cfg.id.clone(),
             index: i,
@@ -113,6 +113,9 @@ fn delayed_binding_proxy() {
     // this is the closure, which may modify the payload of client to server packets in a TCP connection
     let f_process_payload_c_s = |_c: &mut ProxyConnection, _payload: &mut [u8], _tailroom: usize| {};
 
+    let shared = SharedState::new(configuration);
+    shared.start_health_checks(configuration);
+
     run_time.start_schedulers().expect("cannot start schedulers");
 
     let run_configuration_cloned = run_configuration.clone();
@@ -125,8 +128,10 @@ fn delayed_binding_proxy() {
                     s,
                     run_configuration_cloned.clone(),
                     l234data.clone(),
-                    f_by_payload.clone(),
+                    shared.clone(),
+                    Some(f_by_payload.clone()),
                     f_process_payload_c_s.clone(),
+                    None::<NoPayload>,
                 );
             },
         ))
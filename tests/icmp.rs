@@ -0,0 +1,35 @@
+extern crate tcp_proxy;
+
+use tcp_proxy::IcmpConfig;
+
+const UDP_PORT: u16 = 5353;
+/// first proxy port of the pipeline, the udp flows use the proxy ports from here on
+const PORT_BASE: u16 = 40960;
+
+fn config(port_unreachable: Option<bool>) -> IcmpConfig {
+    IcmpConfig {
+        echo: None,
+        forward_errors: None,
+        port_unreachable,
+    }
+}
+
+#[test]
+fn server_replies_are_not_port_unreachable() {
+    let config = config(Some(true));
+    // a target replies to a udp flow on a proxy port
+    assert!(!config.answers_port_unreachable(PORT_BASE, Some(UDP_PORT), PORT_BASE));
+    assert!(!config.answers_port_unreachable(PORT_BASE + 17, Some(UDP_PORT), PORT_BASE));
+    // a client sends to the udp port
+    assert!(!config.answers_port_unreachable(UDP_PORT, Some(UDP_PORT), PORT_BASE));
+    // a port, which is not proxied
+    assert!(config.answers_port_unreachable(7, Some(UDP_PORT), PORT_BASE));
+    // without udp proxy no udp port is proxied
+    assert!(config.answers_port_unreachable(PORT_BASE + 17, None, PORT_BASE));
+}
+
+#[test]
+fn port_unreachable_defaults_to_off() {
+    assert!(!config(None).answers_port_unreachable(7, None, PORT_BASE));
+    assert!(!config(Some(false)).answers_port_unreachable(7, Some(UDP_PORT), PORT_BASE));
+}
@@ -0,0 +1,129 @@
+extern crate e2d2;
+extern crate tcp_proxy;
+#[macro_use]
+extern crate log;
+extern crate env_logger;
+extern crate netfcts;
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::thread;
+use std::collections::HashMap;
+use std::process;
+
+use e2d2::interface::PmdPort;
+use e2d2::scheduler::StandaloneScheduler;
+
+use netfcts::tcp_common::L234Data;
+use netfcts::system::get_mac_from_ifname;
+use netfcts::{RunTime, Store64};
+use netfcts::comm::MessageFrom;
+
+use tcp_proxy::{ProxyConnection, Configuration, Extension, SharedState};
+use tcp_proxy::{setup_pipes_delayed_proxy, NoPayload};
+
+#[test]
+fn replay_pcap() {
+    env_logger::init();
+
+    // cannot directly read toml file from command line, as cargo test owns it. Thus we take a detour and read it from a file.
+    const INDIRECTION_FILE: &str = "./tests/toml_file.txt";
+    // seconds to wait for the end of the replay
+    const MAX_WAIT: usize = 30;
+
+    let mut run_time: RunTime<Configuration, Store64<Extension>> = match RunTime::init_indirectly(INDIRECTION_FILE) {
+        Ok(run_time) => run_time,
+        Err(err) => panic!("failed to initialize RunTime {}", err),
+    };
+
+    // setup flowdirector for physical ports:
+    run_time.setup_flowdirector().expect("failed to setup flowdirector");
+
+    let run_configuration = run_time.run_configuration.clone();
+    let configuration = &run_configuration.engine_configuration;
+
+    if configuration.engine.replay.is_none() {
+        error!("missing parameter 'replay' in configuration file {}", run_time.toml_filename());
+        process::exit(1);
+    };
+
+    info!("Replaying {} ..", configuration.engine.replay.as_ref().unwrap().input);
+
+    let l234data: Vec<L234Data> = configuration
+        .targets
+        .iter()
+        .enumerate()
+        .map(|(i, srv_cfg)| L234Data {
+            mac: srv_cfg
+                .mac
+                .unwrap_or_else(|| get_mac_from_ifname(srv_cfg.linux_if.as_ref().unwrap()).unwrap()),
+            ip: srv_cfg.ipv4(),
+            port: srv_cfg.port,
+            server_id: srv_cfg.id.clone(),
+            index: i,
+        })
+        .collect();
+
+    let l234data_clone = l234data.clone();
+    // the same selection as in the client_syn_fin test, which recorded the pcap
+    let f_by_payload = move |c: &mut ProxyConnection| {
+        let s = String::from_utf8(c.payload_packet.as_ref().unwrap().get_payload(2).to_vec()).unwrap();
+        let stars: usize = s.split(" ").next().unwrap().parse().unwrap();
+        c.set_server_index((stars % l234data_clone.len()) as u8);
+    };
+
+    let f_process_payload_c_s = |_c: &mut ProxyConnection, _payload: &mut [u8], _tailroom: usize| {};
+
+    let shared = SharedState::new(configuration);
+    let reports = shared.replay.clone();
+
+    run_time.start_schedulers().expect("cannot start schedulers");
+
+    let run_configuration_cloned = run_configuration.clone();
+    run_time
+        .install_pipeline_on_cores(Box::new(
+            move |core: i32, pmd_ports: HashMap<String, Arc<PmdPort>>, s: &mut StandaloneScheduler| {
+                setup_pipes_delayed_proxy(
+                    core,
+                    pmd_ports,
+                    s,
+                    run_configuration_cloned.clone(),
+                    l234data.clone(),
+                    shared.clone(),
+                    Some(f_by_payload.clone()),
+                    f_process_payload_c_s.clone(),
+                    None::<NoPayload>,
+                );
+            },
+        ))
+        .expect("cannot install pipelines");
+
+    // start the run_time receive thread
+    run_time.start();
+
+    let (mtx, _reply_mrx) = run_time.get_main_channel().expect("cannot get main channel");
+    mtx.send(MessageFrom::StartEngine).unwrap();
+
+    let mut waited = 0;
+    while waited < MAX_WAIT && (reports.snapshot().is_empty() || reports.snapshot().values().any(|r| !r.finished)) {
+        thread::sleep(Duration::from_millis(1000));
+        waited += 1;
+    }
+
+    let reports = reports.snapshot();
+    assert!(!reports.is_empty(), "no pipeline replayed the pcap");
+    for (pipeline_id, report) in &reports {
+        info!("{}: {}", pipeline_id, report);
+        for mismatch in &report.mismatches {
+            error!("{}: {}", pipeline_id, mismatch);
+        }
+        assert!(report.passed());
+    }
+
+    mtx.send(MessageFrom::Exit).unwrap();
+    thread::sleep(Duration::from_millis(2000));
+
+    info!("terminating ProxyEngine ...");
+    println!("\nPASSED\n");
+    std::process::exit(0);
+}
@@ -0,0 +1,22 @@
+extern crate tcp_proxy;
+
+use std::net::Ipv4Addr;
+
+use tcp_proxy::addressed_to_proxy;
+
+#[test]
+fn segments_to_pool_addresses_are_for_the_proxy() {
+    let proxy_ip = u32::from(Ipv4Addr::new(192, 168, 222, 1));
+    let pipeline_ip = u32::from(Ipv4Addr::new(192, 168, 222, 2));
+    let pool_ip = u32::from(Ipv4Addr::new(192, 168, 222, 10));
+    // the address of the pipeline comes first, then the addresses of the pool
+    let source_ips = vec![pipeline_ip, pool_ip];
+
+    assert!(addressed_to_proxy(proxy_ip, proxy_ip, &source_ips));
+    assert!(addressed_to_proxy(pipeline_ip, proxy_ip, &source_ips));
+    // e.g. the SYN-ACK of a server to a connection from the second pool address
+    assert!(addressed_to_proxy(pool_ip, proxy_ip, &source_ips));
+    assert!(!addressed_to_proxy(u32::from(Ipv4Addr::new(192, 168, 222, 11)), proxy_ip, &source_ips));
+    // without source pool
+    assert!(!addressed_to_proxy(pool_ip, proxy_ip, &source_ips[..1]));
+}
@@ -0,0 +1,27 @@
+extern crate tcp_proxy;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use tcp_proxy::{read_configuration, TargetTable};
+
+#[test]
+fn update_address_rejects_ipv6() {
+    let configuration = read_configuration("./tests/targets.toml").expect("cannot read configuration");
+    let targets = TargetTable::new(&configuration);
+    let version = targets.version();
+
+    // e.g. a host name, which has no IPv4 address anymore
+    assert!(targets.update_address("server 1", IpAddr::V6(Ipv6Addr::LOCALHOST)).is_err());
+    assert_eq!(targets.version(), version);
+    assert_eq!(targets.l234data()[0].ip, u32::from(Ipv4Addr::new(192, 168, 222, 3)));
+
+    let ip = Ipv4Addr::new(192, 168, 222, 4);
+    assert!(targets.update_address("server 1", IpAddr::V4(ip)).is_ok());
+    let server1: Vec<u32> = targets
+        .targets()
+        .iter()
+        .filter(|t| t.active && t.config.id == "server 1")
+        .map(|t| t.l234.ip)
+        .collect();
+    assert_eq!(server1, vec![u32::from(ip)]);
+}
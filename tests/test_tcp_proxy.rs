@@ -35,8 +35,8 @@ use netfcts::io::{ print_tcp_counters, print_rx_tx_counters};
 use netfcts::conrecord::{HasTcpState, ConRecord};
 use netfcts::{RunTime, Store64};
 
-use tcp_proxy::{ProxyConnection, Extension, ProxyMode, Configuration};
-use tcp_proxy::{setup_pipes_delayed_proxy};
+use tcp_proxy::{ProxyConnection, Extension, ProxyMode, Configuration, SharedState};
+use tcp_proxy::{setup_pipes_delayed_proxy, NoPayload};
 use netfcts::comm::{MessageFrom, MessageTo};
 
 #[test]
@@ -84,7 +84,7 @@ fn delayed_binding_proxy() {
             mac: srv_cfg
                 .mac
                 .unwrap_or_else(|| get_mac_from_ifname(srv_cfg.linux_if.as_ref().unwrap()).unwrap()),
-            ip: u32::from(srv_cfg.ip),
+            ip: srv_cfg.ipv4(),
             port: srv_cfg.port,
             server_id: srv_cfg.id.clone(),
             index: i,
@@ -141,6 +141,9 @@ fn delayed_binding_proxy() {
         } */
     };
 
+    let shared = SharedState::new(configuration);
+    shared.start_health_checks(configuration);
+
     run_time.start_schedulers().expect("cannot start schedulers");
 
     if *run_configuration
@@ -161,8 +164,10 @@ fn delayed_binding_proxy() {
                         s,
                         run_configuration_cloned.clone(),
                         l234data.clone(),
-                        f_by_payload.clone(),
+                        shared.clone(),
+                        Some(f_by_payload.clone()),
                         f_process_payload_c_s.clone(),
+                        None::<NoPayload>,
                     );
                 },
             ))